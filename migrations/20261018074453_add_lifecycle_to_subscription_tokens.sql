@@ -0,0 +1,14 @@
+-- Tokens are only valid for a limited amount of time and can be used once.
+BEGIN;
+    ALTER TABLE subscription_tokens
+        ADD COLUMN created_at timestamptz NOT NULL DEFAULT now(),
+        ADD COLUMN expires_at timestamptz NULL,
+        ADD COLUMN consumed_at timestamptz NULL;
+    -- Existing tokens get the default validity of 24 hours
+    UPDATE subscription_tokens
+        SET expires_at = created_at + interval '24 hours'
+        WHERE expires_at IS NULL;
+    ALTER TABLE subscription_tokens ALTER COLUMN expires_at SET NOT NULL;
+    -- The sweeper looks up expired tokens
+    CREATE INDEX subscription_tokens_expires_at_idx ON subscription_tokens (expires_at);
+COMMIT;
@@ -0,0 +1,7 @@
+-- One row per email that still has to go out for an issue.
+CREATE TABLE issue_delivery_queue (
+    newsletter_issue_id uuid NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    subscriber_email TEXT NOT NULL,
+    PRIMARY KEY(newsletter_issue_id, subscriber_email)
+);
@@ -0,0 +1,5 @@
+-- Failed deliveries are retried later instead of being picked up again right away.
+ALTER TABLE issue_delivery_queue
+    ADD COLUMN n_attempts INT NOT NULL DEFAULT 0,
+    ADD COLUMN next_attempt_at timestamptz NOT NULL DEFAULT now();
+CREATE INDEX issue_delivery_queue_next_attempt_at_idx ON issue_delivery_queue (next_attempt_at);
@@ -0,0 +1,7 @@
+-- Admins allowed to publish issues and manage the service.
+-- Passwords are stored as Argon2id PHC strings, never in clear text.
+CREATE TABLE users(
+    user_id uuid PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL
+);
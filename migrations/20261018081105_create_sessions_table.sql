@@ -0,0 +1,7 @@
+-- Server-side state of the admin sessions, the cookie only carries the key.
+CREATE TABLE sessions(
+    session_key TEXT PRIMARY KEY,
+    state JSONB NOT NULL,
+    expires_at timestamptz NOT NULL
+);
+CREATE INDEX sessions_expires_at_idx ON sessions (expires_at);
@@ -0,0 +1,2 @@
+-- Subscribers who leave the list are kept with the 'unsubscribed' status.
+ALTER TABLE subscriptions ADD COLUMN unsubscribed_at timestamptz NULL;
@@ -0,0 +1,7 @@
+-- Choices made in the preference center, subscribers without a row get the defaults.
+CREATE TABLE subscriber_preferences(
+    subscriber_id uuid PRIMARY KEY REFERENCES subscriptions (id),
+    digest_frequency TEXT NOT NULL,
+    topics TEXT[] NOT NULL,
+    updated_at timestamptz NOT NULL
+);
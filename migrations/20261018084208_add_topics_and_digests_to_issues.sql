@@ -0,0 +1,8 @@
+-- Subscribers who picked their topics only get the issues about one of them,
+-- issues without a topic go to everybody.
+ALTER TABLE newsletter_issues ADD COLUMN topic TEXT NULL;
+
+-- The deliveries of a subscriber flagged as part of a digest go out together,
+-- in a single email, once they are all due.
+ALTER TABLE issue_delivery_queue ADD COLUMN digest BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE issue_delivery_dead_letters ADD COLUMN digest BOOLEAN NOT NULL DEFAULT FALSE;
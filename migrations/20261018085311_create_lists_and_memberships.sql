@@ -0,0 +1,48 @@
+-- Subscribers can join several lists, the confirmation status now belongs
+-- to each membership. Everything stored so far moves to a default list.
+BEGIN;
+    CREATE TABLE lists(
+        list_id uuid PRIMARY KEY,
+        slug TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL,
+        -- The sender configured for the email client is used when left out
+        sender_email TEXT NULL,
+        sender_name TEXT NULL,
+        -- Branding of the issues sent to the list
+        logo_url TEXT NULL,
+        accent_color TEXT NULL,
+        footer_text TEXT NULL,
+        created_at timestamptz NOT NULL
+    );
+    INSERT INTO lists (list_id, slug, name, created_at)
+    VALUES ('8b8c3a4e-5f5a-4f51-9a9e-0c2f8f4d9b61', 'newsletter', 'Newsletter', now());
+
+    CREATE TABLE list_memberships(
+        list_id uuid NOT NULL REFERENCES lists (list_id),
+        subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+        status TEXT NOT NULL,
+        subscribed_at timestamptz NOT NULL,
+        confirmed_at timestamptz NULL,
+        unsubscribed_at timestamptz NULL,
+        PRIMARY KEY (list_id, subscriber_id)
+    );
+    INSERT INTO list_memberships (list_id, subscriber_id, status, subscribed_at, unsubscribed_at)
+    SELECT '8b8c3a4e-5f5a-4f51-9a9e-0c2f8f4d9b61', id, status, subscribed_at, unsubscribed_at
+    FROM subscriptions;
+    ALTER TABLE subscriptions DROP COLUMN status;
+    ALTER TABLE subscriptions DROP COLUMN unsubscribed_at;
+
+    -- Confirmation links confirm a single membership
+    ALTER TABLE subscription_tokens ADD COLUMN list_id uuid NULL REFERENCES lists (list_id);
+    UPDATE subscription_tokens SET list_id = '8b8c3a4e-5f5a-4f51-9a9e-0c2f8f4d9b61';
+    ALTER TABLE subscription_tokens ALTER COLUMN list_id SET NOT NULL;
+
+    -- Deliveries are sent with the sender and branding of the list
+    ALTER TABLE issue_delivery_queue ADD COLUMN list_id uuid NULL REFERENCES lists (list_id);
+    UPDATE issue_delivery_queue SET list_id = '8b8c3a4e-5f5a-4f51-9a9e-0c2f8f4d9b61';
+    ALTER TABLE issue_delivery_queue ALTER COLUMN list_id SET NOT NULL;
+
+    ALTER TABLE issue_delivery_dead_letters ADD COLUMN list_id uuid NULL REFERENCES lists (list_id);
+    UPDATE issue_delivery_dead_letters SET list_id = '8b8c3a4e-5f5a-4f51-9a9e-0c2f8f4d9b61';
+    ALTER TABLE issue_delivery_dead_letters ALTER COLUMN list_id SET NOT NULL;
+COMMIT;
@@ -0,0 +1,18 @@
+-- Custom data about subscribers, segments filter on it
+ALTER TABLE subscriptions ADD COLUMN attributes JSONB NOT NULL DEFAULT '{}';
+
+-- First time a subscriber opened an issue, recorded by the tracking pixel
+CREATE TABLE issue_opens(
+    newsletter_issue_id uuid NOT NULL REFERENCES newsletter_issues (newsletter_issue_id),
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+    opened_at timestamptz NOT NULL,
+    PRIMARY KEY (newsletter_issue_id, subscriber_id)
+);
+
+CREATE TABLE segments(
+    segment_id uuid PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    -- The filter as written by the admin, it is compiled whenever it is used
+    filter TEXT NOT NULL,
+    created_at timestamptz NOT NULL
+);
@@ -0,0 +1,14 @@
+-- Custom fields admins define, subscribers fill them in when signing up
+-- and the values end up in `subscriptions.attributes`
+CREATE TABLE subscriber_fields(
+    name TEXT PRIMARY KEY,
+    field_type TEXT NOT NULL CHECK (field_type IN ('string', 'number', 'bool', 'date', 'enum')),
+    required BOOLEAN NOT NULL,
+    -- The allowed values of `enum` fields, empty for the other types
+    options TEXT[] NOT NULL,
+    created_at timestamptz NOT NULL
+);
+
+-- The attributes sent with a sign-up are held by its token: they are only
+-- stored on the subscriber once the owner of the address confirms.
+ALTER TABLE subscription_tokens ADD COLUMN attributes JSONB NOT NULL DEFAULT '{}';
@@ -0,0 +1,9 @@
+-- Which provider delivered each email of an issue, for auditing.
+CREATE TABLE issue_deliveries (
+    newsletter_issue_id uuid NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    subscriber_email TEXT NOT NULL,
+    provider TEXT NOT NULL,
+    delivered_at timestamptz NOT NULL,
+    PRIMARY KEY(newsletter_issue_id, subscriber_email)
+);
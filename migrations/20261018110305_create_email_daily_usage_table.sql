@@ -0,0 +1,8 @@
+-- How many emails went through each provider every day (UTC), so that
+-- daily quotas hold across restarts and replicas.
+CREATE TABLE email_daily_usage (
+    provider TEXT NOT NULL,
+    day date NOT NULL,
+    n_sent INT NOT NULL,
+    PRIMARY KEY(provider, day)
+);
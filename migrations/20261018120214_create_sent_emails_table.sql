@@ -0,0 +1,9 @@
+-- Which provider delivered each email sent outside of issues, such as
+-- confirmations, for auditing.
+CREATE TABLE sent_emails (
+    sent_email_id uuid PRIMARY KEY,
+    template TEXT NOT NULL,
+    recipient TEXT NOT NULL,
+    provider TEXT NOT NULL,
+    sent_at timestamptz NOT NULL
+);
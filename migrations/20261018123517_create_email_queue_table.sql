@@ -0,0 +1,28 @@
+-- Emails the request handlers leave to be sent in the background,
+-- such as welcome emails.
+CREATE TABLE email_queue (
+    email_id uuid PRIMARY KEY,
+    template TEXT NOT NULL,
+    subscriber_id uuid NOT NULL
+        REFERENCES subscriptions (id),
+    -- The list the email is about, none for every list
+    list_id uuid
+        REFERENCES lists (list_id),
+    n_attempts INT NOT NULL DEFAULT 0,
+    next_attempt_at timestamptz NOT NULL
+);
+CREATE INDEX email_queue_next_attempt_at_idx ON email_queue (next_attempt_at);
+
+-- Queued emails that kept failing until we gave up on them,
+-- kept for an admin to look into.
+CREATE TABLE email_dead_letters (
+    email_id uuid PRIMARY KEY,
+    template TEXT NOT NULL,
+    subscriber_id uuid NOT NULL
+        REFERENCES subscriptions (id),
+    list_id uuid
+        REFERENCES lists (list_id),
+    n_attempts INT NOT NULL,
+    last_error TEXT NOT NULL,
+    failed_at timestamptz NOT NULL
+);
@@ -0,0 +1,4 @@
+-- How many times an email was put off because a provider was over its
+-- quota, which does not count as a failed attempt up to a point.
+ALTER TABLE issue_delivery_queue ADD COLUMN n_throttled INT NOT NULL DEFAULT 0;
+ALTER TABLE email_queue ADD COLUMN n_throttled INT NOT NULL DEFAULT 0;
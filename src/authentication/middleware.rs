@@ -0,0 +1,194 @@
+use std::ops::Deref;
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    error::InternalError,
+    http::header::{self, HeaderMap, HeaderValue},
+    web, FromRequest, HttpMessage, HttpResponse
+};
+use actix_web_lab::middleware::Next;
+use anyhow::Context;
+use secrecy::Secret;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::{validate_credentials, AuthError, Credentials};
+use crate::{session_state::TypedSession, utils::{e500, see_other}};
+
+/// The id of the authenticated user, available to the handlers behind
+/// `reject_anonymous_users` and `redirect_anonymous_users` as `web::ReqData<UserId>`.
+#[derive(Copy, Clone, Debug)]
+pub struct UserId(Uuid);
+
+impl std::fmt::Display for UserId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Deref for UserId {
+    type Target = Uuid;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Only lets logged-in users or requests carrying valid Basic credentials
+/// reach the wrapped routes, everybody else gets a 401.
+pub async fn reject_anonymous_users(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+
+    if let Some(user_id) = session_user_id(&mut req).await? {
+        req.extensions_mut().insert(UserId(user_id));
+        return next.call(req).await;
+    }
+
+    let pool = {
+        let (http_request, payload) = req.parts_mut();
+        web::Data::<PgPool>::from_request(http_request, payload).await
+    }?;
+
+    let credentials = basic_authentication(req.headers())
+        .map_err(AuthError::InvalidCredentials)
+        .map_err(unauthorized)?;
+
+    let user_id = validate_credentials(credentials, &pool)
+        .await
+        .map_err(unauthorized)?;
+
+    req.extensions_mut().insert(UserId(user_id));
+    next.call(req).await
+}
+
+/// Only lets logged-in users reach the wrapped pages,
+/// everybody else is sent to the login form.
+pub async fn redirect_anonymous_users(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+
+    match session_user_id(&mut req).await? {
+        Some(user_id) => {
+            req.extensions_mut().insert(UserId(user_id));
+            next.call(req).await
+        }
+        None => {
+            let response = see_other("/login");
+            let e = anyhow::anyhow!("The user has not logged in");
+            Err(InternalError::from_response(e, response).into())
+        }
+    }
+}
+
+async fn session_user_id(req: &mut ServiceRequest) -> Result<Option<Uuid>, actix_web::Error> {
+    let session = {
+        let (http_request, payload) = req.parts_mut();
+        TypedSession::from_request(http_request, payload).await
+    }?;
+
+    session.get_user_id().map_err(e500)
+}
+
+/// Extracts `username:password` from an `Authorization: Basic` header.
+pub fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, anyhow::Error> {
+
+    // The header value, if present, must be a valid UTF8 string
+    let header_value = headers
+        .get("Authorization")
+        .context("The 'Authorization' header was missing")?
+        .to_str()
+        .context("The 'Authorization' header was not a valid UTF8 string.")?;
+
+    let base64encoded_segment = header_value
+        .strip_prefix("Basic ")
+        .context("The authorization scheme was not 'Basic'.")?;
+
+    let decoded_bytes = base64::decode_config(base64encoded_segment, base64::STANDARD)
+        .context("Failed to base64-decode 'Basic' credentials.")?;
+
+    let decoded_credentials = String::from_utf8(decoded_bytes)
+        .context("The decoded credential string is not valid UTF8.")?;
+
+    // Split into two segments, using ':' as delimitator
+    let mut credentials = decoded_credentials.splitn(2, ':');
+    let username = credentials
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("A username must be provided in 'Basic' auth."))?
+        .to_string();
+    let password = credentials
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("A password must be provided in 'Basic' auth."))?
+        .to_string();
+
+    Ok(Credentials {
+        username,
+        password: Secret::new(password)
+    })
+}
+
+/// Maps authentication failures to a 401 asking the client for Basic credentials.
+/// Anything unexpected is reported as a 500 instead.
+fn unauthorized(e: AuthError) -> actix_web::Error {
+    match e {
+        AuthError::InvalidCredentials(_) => {
+            let mut response = HttpResponse::Unauthorized().finish();
+            let header_value = HeaderValue::from_str(r#"Basic realm="admin""#).unwrap();
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, header_value);
+            InternalError::from_response(e, response).into()
+        }
+        AuthError::UnexpectedError(_) => {
+            tracing::error!(error.cause_chain = ?e, "Failed to authenticate the request");
+            InternalError::from_response(e, HttpResponse::InternalServerError().finish()).into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderMap, HeaderValue};
+    use claim::{assert_err, assert_ok};
+    use secrecy::ExposeSecret;
+
+    use super::basic_authentication;
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            actix_web::http::header::AUTHORIZATION,
+            HeaderValue::from_str(authorization).unwrap()
+        );
+        headers
+    }
+
+    #[test]
+    fn valid_basic_credentials_are_decoded() {
+        let encoded = base64::encode("ursula:le:guin");
+        let credentials = assert_ok!(basic_authentication(&headers(&format!("Basic {}", encoded))));
+
+        assert_eq!(credentials.username, "ursula");
+        // Only the first ':' separates the username from the password
+        assert_eq!(credentials.password.expose_secret(), "le:guin");
+    }
+
+    #[test]
+    fn a_missing_header_is_rejected() {
+        assert_err!(basic_authentication(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn other_schemes_are_rejected() {
+        assert_err!(basic_authentication(&headers("Bearer a-token")));
+    }
+
+    #[test]
+    fn credentials_without_a_password_are_rejected() {
+        let encoded = base64::encode("ursula");
+        assert_err!(basic_authentication(&headers(&format!("Basic {}", encoded))));
+    }
+}
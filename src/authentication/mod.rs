@@ -0,0 +1,5 @@
+mod middleware;
+mod password;
+
+pub use middleware::{basic_authentication, redirect_anonymous_users, reject_anonymous_users, UserId};
+pub use password::{change_password, create_first_user, validate_credentials, AuthError, Credentials};
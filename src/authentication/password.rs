@@ -0,0 +1,209 @@
+use anyhow::Context;
+use argon2::{
+    password_hash::SaltString, Algorithm, Argon2, Params, PasswordHash, PasswordHasher,
+    PasswordVerifier, Version
+};
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::telemetry::spawn_blocking_with_tracing;
+
+#[derive(Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub password: Secret<String>
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuthError {
+    #[error("Invalid credentials.")]
+    InvalidCredentials(#[source] anyhow::Error),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error)
+}
+
+/// Returns the id of the user if the credentials are valid.
+#[tracing::instrument(
+    name = "Validate credentials",
+    skip(credentials, pool)
+)]
+pub async fn validate_credentials(
+    credentials: Credentials,
+    pool: &PgPool
+) -> Result<Uuid, AuthError> {
+
+    let mut user_id = None;
+    // We verify a password against a dummy hash when the user does not exist:
+    // it takes as long as verifying a real one, so response times do not
+    // reveal which usernames are valid.
+    let mut expected_password_hash = Secret::new(
+        "$argon2id$v=19$m=15000,t=2,p=1$\
+        gZiV/M1gPc22ElAH/Jh1Hw$\
+        CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno"
+            .to_string()
+    );
+
+    if let Some((stored_user_id, stored_password_hash)) =
+        get_stored_credentials(&credentials.username, pool).await?
+    {
+        user_id = Some(stored_user_id);
+        expected_password_hash = stored_password_hash;
+    }
+
+    // Hashing is CPU-bound, keep it away from the async executor.
+    spawn_blocking_with_tracing(move || {
+        verify_password_hash(expected_password_hash, credentials.password)
+    })
+    .await
+    .context("Failed to spawn blocking task.")??;
+
+    // This is only set to `Some` if we found credentials in the store.
+    // So, even if the default password ends up matching the provided
+    // password, we never authenticate a non-existing user.
+    user_id
+        .ok_or_else(|| anyhow::anyhow!("Unknown username."))
+        .map_err(AuthError::InvalidCredentials)
+}
+
+#[tracing::instrument(
+    name = "Verify password hash",
+    skip(expected_password_hash, password_candidate)
+)]
+fn verify_password_hash(
+    expected_password_hash: Secret<String>,
+    password_candidate: Secret<String>
+) -> Result<(), AuthError> {
+
+    let expected_password_hash = PasswordHash::new(expected_password_hash.expose_secret())
+        .context("Failed to parse hash in PHC string format.")?;
+
+    Argon2::default()
+        .verify_password(
+            password_candidate.expose_secret().as_bytes(),
+            &expected_password_hash
+        )
+        .context("Invalid password.")
+        .map_err(AuthError::InvalidCredentials)
+}
+
+/// Replaces the password of a user with a freshly salted Argon2id hash.
+#[tracing::instrument(
+    name = "Change password",
+    skip(password, pool)
+)]
+pub async fn change_password(
+    user_id: Uuid,
+    password: Secret<String>,
+    pool: &PgPool
+) -> Result<(), anyhow::Error> {
+
+    // Hashing is CPU-bound, keep it away from the async executor.
+    let password_hash = spawn_blocking_with_tracing(move || compute_password_hash(password))
+        .await?
+        .context("Failed to hash password.")?;
+
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET password_hash = $1
+        WHERE user_id = $2
+        "#,
+        password_hash.expose_secret(),
+        user_id
+        )
+        .execute(pool)
+        .await
+        .context("Failed to change user's password in the database.")?;
+
+    Ok(())
+}
+
+/// Creates the user `username` when there is no user at all yet.
+///
+/// The first admin is created this way, from the configuration: returns
+/// whether it had to be. Later admins are added by the existing ones, so
+/// renaming the configured admin does not create another one.
+#[tracing::instrument(
+    name = "Create the first user",
+    skip(password, pool)
+)]
+pub async fn create_first_user(
+    username: &str,
+    password: Secret<String>,
+    pool: &PgPool
+) -> Result<bool, anyhow::Error> {
+
+    // Checked first: there is no point hashing the password on every start
+    let has_users = sqlx::query!(r#"SELECT EXISTS(SELECT 1 FROM users) as "exists!""#)
+        .fetch_one(pool)
+        .await
+        .context("Failed to look for existing users.")?
+        .exists;
+    if has_users {
+        return Ok(false);
+    }
+
+    // Hashing is CPU-bound, keep it away from the async executor.
+    let password_hash = spawn_blocking_with_tracing(move || compute_password_hash(password))
+        .await?
+        .context("Failed to hash password.")?;
+
+    // Replicas starting together may all get here, only one of them creates the user
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO users (user_id, username, password_hash)
+        SELECT $1, $2, $3
+        WHERE NOT EXISTS (SELECT 1 FROM users)
+        ON CONFLICT (username) DO NOTHING
+        "#,
+        Uuid::new_v4(),
+        username,
+        password_hash.expose_secret()
+        )
+        .execute(pool)
+        .await
+        .context("Failed to store the new user in the database.")?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Uses the same parameters as the hash of the dummy password, so that
+/// verifying any password takes roughly the same time.
+fn compute_password_hash(password: Secret<String>) -> Result<Secret<String>, anyhow::Error> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let password_hash = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(15000, 2, 1, None).unwrap()
+    )
+    .hash_password(password.expose_secret().as_bytes(), &salt)?
+    .to_string();
+
+    Ok(Secret::new(password_hash))
+}
+
+#[tracing::instrument(
+    name = "Get stored credentials",
+    skip(username, pool)
+)]
+async fn get_stored_credentials(
+    username: &str,
+    pool: &PgPool
+) -> Result<Option<(Uuid, Secret<String>)>, anyhow::Error> {
+
+    let row = sqlx::query!(
+        r#"
+        SELECT user_id, password_hash
+        FROM users
+        WHERE username = $1
+        "#,
+        username
+        )
+        .fetch_optional(pool)
+        .await
+        .context("Failed to perform a query to retrieve stored credentials.")?
+        .map(|row| (row.user_id, Secret::new(row.password_hash)));
+
+    Ok(row)
+}
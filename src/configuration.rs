@@ -1,17 +1,121 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use actix_web::cookie::Key;
+use anyhow::Context;
 use config::Config;
+use hmac::{Hmac, Mac};
+use rand::Rng;
 use secrecy::Secret;
 use secrecy::ExposeSecret;
-use sqlx::ConnectOptions;
+use sqlx::{ConnectOptions, PgPool};
 use sqlx::postgres::PgConnectOptions;
+use sha2::Sha512;
 
 use crate::domain::SubscriberEmail;
+use crate::email_client::{
+    EmailClient,
+    EmailTransport,
+    MailgunTransport,
+    OutboxTransport,
+    PostmarkTransport,
+    RateLimit,
+    SendGridTransport,
+    SesTransport,
+    SmtpTransport
+};
+use crate::email_templates::EmailTemplates;
+use crate::signed_token::TokenSigner;
 
 #[derive(serde::Deserialize, Clone)]
 pub struct EmailClientSettings {
-    pub base_url: String,
     pub sender_email: String,
-    pub authorization_token: Secret<String>,
-    pub timeout_milliseconds: u64
+    pub timeout_milliseconds: u64,
+    // The names of the transports emails go through, the first of them that
+    // is available
+    pub transport_order: Vec<String>,
+    // By name, so that `APP_EMAIL_CLIENT__TRANSPORTS__<NAME>__...` reaches them
+    pub transports: HashMap<String, TransportSettings>,
+    pub circuit_breaker: CircuitBreakerSettings
+}
+
+/// One of the transports emails can leave the application through.
+#[derive(serde::Deserialize, Clone)]
+pub struct TransportSettings {
+    // Postmark when left out
+    #[serde(default)]
+    pub kind: EmailTransportKind,
+    // API of the HTTP providers
+    #[serde(default)]
+    pub base_url: String,
+    // Postmark server token, SendGrid or Mailgun API key
+    pub authorization_token: Option<Secret<String>>,
+    // Required by the `mailgun` transport
+    pub mailgun: Option<MailgunSettings>,
+    // Required by the `ses` transport
+    pub ses: Option<SesSettings>,
+    // Required by the `smtp` transport
+    pub smtp: Option<SmtpSettings>,
+    #[serde(default)]
+    pub outbox: OutboxSettings,
+    #[serde(default)]
+    pub rate_limit: RateLimit
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct CircuitBreakerSettings {
+    // A transport unavailable that many times in a row is only tried after the others...
+    pub failure_threshold: u32,
+    // ...for that long, then it is probed again
+    pub cooldown_seconds: u64
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailTransportKind {
+    #[default]
+    Postmark,
+    SendGrid,
+    Mailgun,
+    Ses,
+    Smtp,
+    // Development only, nothing is sent
+    Outbox
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct MailgunSettings {
+    // The sending domain, such as `mg.example.com`
+    pub domain: String
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SesSettings {
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: Secret<String>
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SmtpSettings {
+    pub host: String,
+    pub port: u16,
+    // Both or none of them
+    pub username: Option<String>,
+    pub password: Option<Secret<String>>,
+    // Only turn it off for a relay on the local machine
+    #[serde(default = "starttls_by_default")]
+    pub starttls: bool
+}
+
+fn starttls_by_default() -> bool {
+    true
+}
+
+#[derive(serde::Deserialize, Clone, Default)]
+pub struct OutboxSettings {
+    // Emails are printed to stdout when left out
+    pub directory: Option<String>
 }
 
 impl EmailClientSettings {
@@ -23,13 +127,249 @@ impl EmailClientSettings {
     pub fn sender(&self) -> Result<SubscriberEmail, String> {
         SubscriberEmail::parse(self.sender_email.clone())
     }
+
+    /// Builds the client sending through the configured transports.
+    ///
+    /// Daily quotas are counted in the database behind `pool`.
+    pub fn client(self, pool: &PgPool) -> Result<EmailClient, anyhow::Error> {
+        let sender_email = self.sender()
+            .map_err(anyhow::Error::msg)
+            .context("Invalid sender email address.")?;
+        if self.transport_order.is_empty() {
+            anyhow::bail!("At least one email transport must be listed in `transport_order`.");
+        }
+        // Their daily quotas are counted by name
+        let mut names = HashSet::new();
+        if let Some(name) = self.transport_order.iter().find(|name| !names.insert(*name)) {
+            anyhow::bail!("The `{}` email transport is listed more than once in `transport_order`.", name);
+        }
+        let timeout = self.timeout();
+        let mut transports = self.transport_order
+            .iter()
+            .map(|name| {
+                let transport = self.transports
+                    .get(name)
+                    .cloned()
+                    .with_context(|| format!("There are no settings for the `{}` email transport.", name))?;
+                let rate_limit = transport.rate_limit;
+                let transport = transport
+                    .transport(timeout)
+                    .with_context(|| format!("Invalid settings for the `{}` email transport.", name))?;
+                Ok((name.clone(), transport, rate_limit))
+            })
+            .collect::<Result<Vec<_>, anyhow::Error>>()?;
+        let primary = transports.remove(0);
+        Ok(EmailClient::with_failover(
+            primary,
+            transports,
+            sender_email,
+            self.circuit_breaker.failure_threshold,
+            std::time::Duration::from_secs(self.circuit_breaker.cooldown_seconds),
+            Some(pool.clone())
+        ))
+    }
+}
+
+impl TransportSettings {
+
+    pub fn transport(self, timeout: std::time::Duration) -> Result<Box<dyn EmailTransport>, anyhow::Error> {
+        let kind = self.kind;
+        let authorization_token = || {
+            self.authorization_token
+                .clone()
+                .with_context(|| format!("The {:?} transport needs an `authorization_token`.", kind))
+        };
+        let transport: Box<dyn EmailTransport> = match kind {
+            EmailTransportKind::Postmark => Box::new(
+                PostmarkTransport::new(self.base_url, authorization_token()?, timeout)
+            ),
+            EmailTransportKind::SendGrid => Box::new(
+                SendGridTransport::new(self.base_url, authorization_token()?, timeout)
+            ),
+            EmailTransportKind::Mailgun => {
+                let api_key = authorization_token()?;
+                let mailgun = self.mailgun
+                    .context("The mailgun transport needs the `mailgun` settings.")?;
+                Box::new(MailgunTransport::new(self.base_url, mailgun.domain, api_key, timeout))
+            }
+            EmailTransportKind::Ses => {
+                let ses = self.ses
+                    .context("The ses transport needs the `ses` settings.")?;
+                Box::new(SesTransport::new(
+                    self.base_url,
+                    ses.region,
+                    ses.access_key_id,
+                    ses.secret_access_key,
+                    timeout
+                ))
+            }
+            EmailTransportKind::Smtp => {
+                let smtp = self.smtp
+                    .context("The smtp transport needs the `smtp` settings.")?;
+                let credentials = match (smtp.username, smtp.password) {
+                    (Some(username), Some(password)) => Some((username, password)),
+                    (None, None) => None,
+                    _ => anyhow::bail!("The SMTP username and password go together.")
+                };
+                Box::new(SmtpTransport::new(&smtp.host, smtp.port, smtp.starttls, credentials, timeout)?)
+            }
+            EmailTransportKind::Outbox => Box::new(
+                OutboxTransport::new(self.outbox.directory.map(PathBuf::from))
+            )
+        };
+        Ok(transport)
+    }
 }
 
 #[derive(serde::Deserialize, Clone)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
-    pub email_client: EmailClientSettings
+    pub email_client: EmailClientSettings,
+    pub subscription_tokens: SubscriptionTokenSettings,
+    pub issue_delivery: IssueDeliverySettings,
+    pub email_queue: EmailQueueSettings,
+    pub idempotency: IdempotencySettings,
+    pub preferences: PreferenceSettings,
+    pub lists: ListSettings,
+    pub email_templates: EmailTemplateSettings,
+    pub admin: AdminSettings
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct IssueDeliverySettings {
+    // A delivery is moved to the dead letters once it failed that many times
+    pub max_attempts: i32,
+    // Deliveries put off by a quota that many times count their next
+    // throttled tries as failed attempts
+    pub max_throttled_retries: i32,
+    // Delay before the first retry, it doubles with every further attempt
+    pub backoff_base_milliseconds: u64,
+    // Upper bound for the delay between two attempts
+    pub backoff_max_milliseconds: u64,
+    // How many deliveries a worker sends at once
+    pub batch_size: u32,
+    // How long a worker has to settle the deliveries it picked, other
+    // workers pick them up again afterwards
+    pub lease_seconds: u64
+}
+
+impl IssueDeliverySettings {
+
+    /// Delay before the next attempt, after `n_attempts` failed ones.
+    pub fn backoff(&self, n_attempts: i32) -> std::time::Duration {
+        backoff(self.backoff_base_milliseconds, self.backoff_max_milliseconds, n_attempts)
+    }
+
+    pub fn lease(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.lease_seconds)
+    }
+}
+
+/// The emails the request handlers queue, such as confirmation or welcome emails.
+#[derive(serde::Deserialize, Clone)]
+pub struct EmailQueueSettings {
+    // An email is moved to the dead letters once it failed that many times
+    pub max_attempts: i32,
+    // Emails put off by a quota that many times count their next throttled
+    // tries as failed attempts
+    pub max_throttled_retries: i32,
+    // Delay before the first retry, it doubles with every further attempt
+    pub backoff_base_milliseconds: u64,
+    // Upper bound for the delay between two attempts
+    pub backoff_max_milliseconds: u64,
+    // How long a worker has to send the email it picked, other workers
+    // pick it up again afterwards
+    pub lease_seconds: u64
+}
+
+impl EmailQueueSettings {
+
+    /// Delay before the next attempt, after `n_attempts` failed ones.
+    pub fn backoff(&self, n_attempts: i32) -> std::time::Duration {
+        backoff(self.backoff_base_milliseconds, self.backoff_max_milliseconds, n_attempts)
+    }
+
+    pub fn lease(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.lease_seconds)
+    }
+}
+
+/// An exponential delay, from `base_milliseconds` up to `max_milliseconds`.
+///
+/// The delay is jittered between half and the full value so that tasks
+/// failing together do not all retry at the same time.
+fn backoff(base_milliseconds: u64, max_milliseconds: u64, n_attempts: i32) -> std::time::Duration {
+    let exponent = n_attempts.saturating_sub(1).clamp(0, 31) as u32;
+    let delay = base_milliseconds
+        .saturating_mul(2u64.pow(exponent))
+        .min(max_milliseconds);
+    let jittered = delay / 2 + rand::thread_rng().gen_range(0..=delay / 2);
+    std::time::Duration::from_millis(jittered)
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SubscriptionTokenSettings {
+    // How long a confirmation link stays valid after it has been sent
+    pub expiry_hours: i64,
+    // How often the background sweeper purges expired tokens
+    pub sweeper_interval_seconds: u64
+}
+
+impl SubscriptionTokenSettings {
+
+    pub fn expiry(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.expiry_hours)
+    }
+
+    pub fn sweeper_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.sweeper_interval_seconds)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct IdempotencySettings {
+    // How long a stored response is replayed before the sweeper removes it
+    pub expiry_hours: i64
+}
+
+impl IdempotencySettings {
+
+    pub fn expiry(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.expiry_hours)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct PreferenceSettings {
+    // Topics subscribers can pick from in the preference center
+    pub topics: Vec<String>
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct ListSettings {
+    // The list that existed before the service supported several of them
+    pub default_slug: String
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct AdminSettings {
+    pub username: String,
+    // The admin is created on startup when there is no user yet and this is set,
+    // from the `APP_ADMIN__PASSWORD` environment variable rather than a file
+    pub password: Option<Secret<String>>
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct EmailTemplateSettings {
+    // Relative to the working directory of the application
+    pub directory: String
+}
+
+impl EmailTemplateSettings {
+    pub fn templates(&self) -> Result<EmailTemplates, tera::Error> {
+        EmailTemplates::from_directory(&self.directory)
+    }
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -44,7 +384,38 @@ pub struct DatabaseSettings {
 #[derive(serde::Deserialize, Clone)]
 pub struct ApplicationSettings {
     pub port: u16,
-    pub host: String
+    pub host: String,
+    // Public URL the application is reachable at, used to build links in emails
+    pub base_url: String,
+    // The keys signing cookies and the tokens of our links are derived from it
+    pub hmac_secret: Secret<String>,
+    // How long the links we send out, to unsubscribe or manage preferences, keep working
+    pub signed_token_max_age_days: u64
+}
+
+impl ApplicationSettings {
+
+    /// Signs and encrypts the session and flash message cookies.
+    pub fn cookie_key(&self) -> Key {
+        Key::from(&self.derive_key(b"cookies"))
+    }
+
+    /// Signs the tokens of the links we send out.
+    pub fn token_signer(&self) -> TokenSigner {
+        TokenSigner::new(
+            Secret::new(self.derive_key(b"signed-tokens").to_vec()),
+            std::time::Duration::from_secs(self.signed_token_max_age_days * 24 * 60 * 60)
+        )
+    }
+
+    // Each use gets a key of its own, so a key leaking out of one cannot be
+    // used for the others.
+    fn derive_key(&self, purpose: &[u8]) -> [u8; 64] {
+        let mut mac = Hmac::<Sha512>::new_from_slice(self.hmac_secret.expose_secret().as_bytes())
+            .expect("HMAC accepts keys of any size");
+        mac.update(purpose);
+        mac.finalize().into_bytes().into()
+    }
 }
 
 pub enum Environment {
@@ -94,6 +465,10 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
     settings.merge(
         config::File::from(configuration_directory.join(environment.as_str())).required(true)
     )?;
+
+    // Add in settings from environment variables (with a prefix of APP and '__' as separator)
+    // E.g. `APP_APPLICATION__BASE_URL=https://example.com` would set `Settings.application.base_url`
+    settings.merge(config::Environment::with_prefix("app").separator("__"))?;
     
     settings.try_into()
 }
@@ -120,7 +495,7 @@ impl DatabaseSettings {
         PgConnectOptions::new()
         .host(&self.host)
         .username(&self.username)
-        .password(&self.password.expose_secret())
+        .password(self.password.expose_secret())
         .port(self.port)
     }
         
@@ -130,4 +505,77 @@ impl DatabaseSettings {
         options.log_statements(tracing::log::LevelFilter::Trace);
         options
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use secrecy::Secret;
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::{
+        CircuitBreakerSettings,
+        EmailClientSettings,
+        EmailTransportKind,
+        IssueDeliverySettings,
+        OutboxSettings,
+        TransportSettings
+    };
+
+    fn settings() -> IssueDeliverySettings {
+        IssueDeliverySettings {
+            max_attempts: 5,
+            max_throttled_retries: 100,
+            backoff_base_milliseconds: 1000,
+            backoff_max_milliseconds: 60_000,
+            batch_size: 500,
+            lease_seconds: 300
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_with_every_attempt() {
+        let settings = settings();
+        for (n_attempts, delay) in [(1, 1000), (2, 2000), (3, 4000), (4, 8000)] {
+            let backoff = settings.backoff(n_attempts).as_millis() as u64;
+            assert!(
+                (delay / 2..=delay).contains(&backoff),
+                "{}ms is not a valid backoff after {} attempts", backoff, n_attempts
+            );
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let settings = settings();
+        let backoff = settings.backoff(1000).as_millis() as u64;
+        assert!(backoff <= settings.backoff_max_milliseconds);
+    }
+
+    #[tokio::test]
+    async fn transports_cannot_be_listed_twice() {
+        let transport = TransportSettings {
+            kind: EmailTransportKind::Postmark,
+            base_url: "https://api.postmarkapp.com".into(),
+            authorization_token: Some(Secret::new("token".into())),
+            mailgun: None,
+            ses: None,
+            smtp: None,
+            outbox: OutboxSettings::default(),
+            rate_limit: Default::default()
+        };
+        let settings = EmailClientSettings {
+            sender_email: "rust@lists.com".into(),
+            timeout_milliseconds: 1000,
+            transport_order: vec!["postmark".into(), "postmark".into()],
+            transports: HashMap::from([("postmark".into(), transport)]),
+            circuit_breaker: CircuitBreakerSettings { failure_threshold: 5, cooldown_seconds: 30 }
+        };
+        let pool = PgPoolOptions::new().connect_lazy("postgres://localhost/newsletter").unwrap();
+
+        let e = settings.client(&pool).err().unwrap();
+
+        assert!(e.to_string().contains("listed more than once"));
+    }
+}
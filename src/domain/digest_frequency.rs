@@ -0,0 +1,59 @@
+/// How often a subscriber wants to hear from us: every issue as soon as it
+/// is published, or the issues of the day or of the week in a single digest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DigestFrequency {
+    #[default]
+    Immediate,
+    Daily,
+    Weekly
+}
+
+impl DigestFrequency {
+    pub const ALL: [DigestFrequency; 3] = [
+        DigestFrequency::Immediate,
+        DigestFrequency::Daily,
+        DigestFrequency::Weekly
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DigestFrequency::Immediate => "immediate",
+            DigestFrequency::Daily => "daily",
+            DigestFrequency::Weekly => "weekly"
+        }
+    }
+}
+
+impl TryFrom<String> for DigestFrequency {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.to_lowercase().as_str() {
+            "immediate" => Ok(Self::Immediate),
+            "daily" => Ok(Self::Daily),
+            "weekly" => Ok(Self::Weekly),
+            other => Err(format!(
+                "{} is not a supported digest frequency. Use either `immediate`, `daily` or `weekly`.",
+                other
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DigestFrequency;
+    use claim::{assert_err, assert_ok_eq};
+
+    #[test]
+    fn every_frequency_round_trips() {
+        for frequency in DigestFrequency::ALL {
+            assert_ok_eq!(DigestFrequency::try_from(frequency.as_str().to_string()), frequency);
+        }
+    }
+
+    #[test]
+    fn an_unknown_frequency_is_rejected() {
+        assert_err!(DigestFrequency::try_from("hourly".to_string()));
+    }
+}
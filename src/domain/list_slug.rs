@@ -0,0 +1,67 @@
+/// The identifier of a mailing list in URLs and API requests, e.g. `rust-weekly`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListSlug(String);
+
+impl ListSlug {
+    /// Slugs end up in links, so we only accept lowercase ASCII letters,
+    /// digits and dashes, up to 50 characters.
+    pub fn parse(s: String) -> Result<ListSlug, String> {
+        let is_empty = s.is_empty();
+        let is_too_long = s.len() > 50;
+        let contains_forbidden_characters = !s
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+
+        if is_empty || is_too_long || contains_forbidden_characters {
+            Err(format!("{} is not a valid list slug.", s))
+        } else {
+            Ok(Self(s))
+        }
+    }
+}
+
+impl AsRef<str> for ListSlug {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for ListSlug {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+
+    use super::ListSlug;
+
+    #[test]
+    fn a_valid_slug_is_parsed_successfully() {
+        assert_ok!(ListSlug::parse("rust-weekly-2".to_string()));
+    }
+
+    #[test]
+    fn a_50_characters_long_slug_is_valid() {
+        assert_ok!(ListSlug::parse("a".repeat(50)));
+    }
+
+    #[test]
+    fn a_slug_longer_than_50_characters_is_rejected() {
+        assert_err!(ListSlug::parse("a".repeat(51)));
+    }
+
+    #[test]
+    fn empty_string_is_rejected() {
+        assert_err!(ListSlug::parse("".to_string()));
+    }
+
+    #[test]
+    fn slugs_containing_an_invalid_character_are_rejected() {
+        for slug in ["Rust", "rust weekly", "rust_weekly", "rust/weekly", "ünïcode"] {
+            assert_err!(ListSlug::parse(slug.to_string()));
+        }
+    }
+}
@@ -1,7 +1,15 @@
+mod digest_frequency;
+mod list_slug;
 mod new_subscriber;
+mod subscriber_attributes;
 mod subscriber_name;
 mod subscriber_email;
+mod validation_error;
 
+pub use digest_frequency::DigestFrequency;
+pub use list_slug::ListSlug;
 pub use new_subscriber::NewSubscriber;
+pub use subscriber_attributes::{FieldDefinition, FieldType, SubscriberAttributes};
 pub use subscriber_email::SubscriberEmail;
 pub use subscriber_name::SubscriberName;
+pub use validation_error::ValidationError;
@@ -1,7 +1,9 @@
+use crate::domain::SubscriberAttributes;
 use crate::domain::SubscriberName;
 use crate::domain::SubscriberEmail;
 
 pub struct NewSubscriber {
     pub email: SubscriberEmail,
-    pub name: SubscriberName
-}
\ No newline at end of file
+    pub name: SubscriberName,
+    pub attributes: SubscriberAttributes
+}
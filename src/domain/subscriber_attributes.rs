@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+
+use chrono::NaiveDate;
+use serde_json::{Map, Number, Value};
+
+use crate::domain::ValidationError;
+
+// Sign-up fields, as well as the words segment filters treat specially
+const RESERVED_NAMES: [&str; 10] = [
+    "email", "name", "list", "subscribed_at", "opened", "and", "or", "not", "true", "false"
+];
+
+/// The type of the values of a custom subscriber field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldType {
+    String,
+    Number,
+    Bool,
+    // `YYYY-MM-DD`
+    Date,
+    // One of the given options
+    Enum(Vec<String>)
+}
+
+impl FieldType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FieldType::String => "string",
+            FieldType::Number => "number",
+            FieldType::Bool => "bool",
+            FieldType::Date => "date",
+            FieldType::Enum(_) => "enum"
+        }
+    }
+
+    /// Only `enum` fields take options, at least one of them.
+    pub fn parse(field_type: &str, options: Vec<String>) -> Result<FieldType, ValidationError> {
+        let field_type = match field_type {
+            "string" => FieldType::String,
+            "number" => FieldType::Number,
+            "bool" => FieldType::Bool,
+            "date" => FieldType::Date,
+            "enum" => {
+                if options.is_empty() {
+                    return Err(ValidationError::new("options", "Enum fields need options.".into()));
+                }
+                for (i, option) in options.iter().enumerate() {
+                    if option.trim().is_empty() || options[..i].contains(option) {
+                        return Err(ValidationError::new(
+                            "options",
+                            format!("`{}` is empty or repeated.", option)
+                        ));
+                    }
+                }
+                return Ok(FieldType::Enum(options));
+            }
+            other => return Err(ValidationError::new(
+                "field_type",
+                format!(
+                    "{} is not a supported field type. Use either `string`, `number`, `bool`, `date` or `enum`.",
+                    other
+                )
+            ))
+        };
+        if !options.is_empty() {
+            return Err(ValidationError::new(
+                "options",
+                format!("{} fields do not take options.", field_type.as_str())
+            ));
+        }
+        Ok(field_type)
+    }
+
+    /// The allowed values of enum fields, empty for the other types.
+    pub fn options(&self) -> &[String] {
+        match self {
+            FieldType::Enum(options) => options,
+            _ => &[]
+        }
+    }
+}
+
+/// A custom field admins define for subscribers.
+#[derive(Debug, Clone)]
+pub struct FieldDefinition {
+    pub name: String,
+    pub field_type: FieldType,
+    pub required: bool
+}
+
+impl FieldDefinition {
+    /// Names are lowercase identifiers, so that segment filters can refer to them,
+    /// and never shadow the fields of the sign-up form.
+    pub fn parse(name: String, field_type: FieldType, required: bool) -> Result<Self, ValidationError> {
+        let is_identifier = name.starts_with(|c: char| c.is_ascii_lowercase())
+            && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !is_identifier || name.len() > 50 {
+            return Err(ValidationError::new(
+                "name",
+                format!(
+                    "{} is not a valid field name. Use up to 50 lowercase letters, digits and underscores, starting with a letter.",
+                    name
+                )
+            ));
+        }
+        if RESERVED_NAMES.contains(&name.as_str()) {
+            return Err(ValidationError::new("name", format!("{} is a reserved name.", name)));
+        }
+        Ok(Self { name, field_type, required })
+    }
+
+    /// Form values are always strings, JSON ones can also be numbers or booleans.
+    fn parse_value(&self, value: Value) -> Result<Value, String> {
+        match (&self.field_type, value) {
+            (FieldType::String, Value::String(s)) if s.chars().count() <= 1000 => Ok(Value::String(s)),
+            (FieldType::Number, Value::Number(n)) => Ok(Value::Number(n)),
+            (FieldType::Number, Value::String(s)) => parse_number(s.trim())
+                .ok_or_else(|| format!("{} is not a number.", s)),
+            (FieldType::Bool, Value::Bool(b)) => Ok(Value::Bool(b)),
+            (FieldType::Bool, Value::String(s)) => match s.as_str() {
+                "true" => Ok(Value::Bool(true)),
+                "false" => Ok(Value::Bool(false)),
+                _ => Err(format!("{} is neither `true` nor `false`.", s))
+            },
+            (FieldType::Date, Value::String(s)) => NaiveDate::parse_from_str(&s, "%Y-%m-%d")
+                .map(|date| Value::String(date.to_string()))
+                .map_err(|_| format!("{} is not a date such as 2024-01-31.", s)),
+            (FieldType::Enum(options), Value::String(s)) if options.contains(&s) => Ok(Value::String(s)),
+            (FieldType::Enum(options), value) => Err(format!(
+                "{} is not one of {}.",
+                value,
+                options.join(", ")
+            )),
+            (field_type, value) => Err(format!("{} is not a valid {}.", value, field_type.as_str()))
+        }
+    }
+}
+
+fn parse_number(s: &str) -> Option<Value> {
+    if let Ok(n) = s.parse::<i64>() {
+        return Some(Value::Number(n.into()));
+    }
+    s.parse::<f64>().ok().and_then(Number::from_f64).map(Value::Number)
+}
+
+/// The values of the custom fields of a subscriber, checked against their definitions.
+#[derive(Debug)]
+pub struct SubscriberAttributes(Value);
+
+impl SubscriberAttributes {
+    /// Empty values count as missing: forms send empty strings for inputs left blank.
+    pub fn parse(
+        values: HashMap<String, Value>,
+        definitions: &[FieldDefinition]
+    ) -> Result<SubscriberAttributes, ValidationError> {
+        let mut values = values;
+        let mut attributes = Map::new();
+        for definition in definitions {
+            match values.remove(&definition.name) {
+                None | Some(Value::Null) => {}
+                Some(Value::String(s)) if s.is_empty() => {}
+                Some(value) => {
+                    let value = definition.parse_value(value)
+                        .map_err(|reason| ValidationError::new(definition.name.clone(), reason))?;
+                    attributes.insert(definition.name.clone(), value);
+                    continue;
+                }
+            }
+            if definition.required {
+                return Err(ValidationError::new(
+                    definition.name.clone(),
+                    format!("{} is required.", definition.name)
+                ));
+            }
+        }
+        // Whatever is left was not defined by the admins
+        if let Some(name) = values.into_keys().next() {
+            return Err(ValidationError::new(name.clone(), format!("{} is not a known field.", name)));
+        }
+        Ok(Self(Value::Object(attributes)))
+    }
+}
+
+impl AsRef<Value> for SubscriberAttributes {
+    fn as_ref(&self) -> &Value {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use claim::{assert_err, assert_ok};
+    use serde_json::{json, Value};
+
+    use super::{FieldDefinition, FieldType, SubscriberAttributes};
+
+    fn definitions() -> Vec<FieldDefinition> {
+        vec![
+            FieldDefinition::parse("company".into(), FieldType::String, false).unwrap(),
+            FieldDefinition::parse("seats".into(), FieldType::Number, false).unwrap(),
+            FieldDefinition::parse("beta".into(), FieldType::Bool, false).unwrap(),
+            FieldDefinition::parse("birthday".into(), FieldType::Date, false).unwrap(),
+            FieldDefinition::parse(
+                "plan".into(),
+                FieldType::Enum(vec!["free".into(), "pro".into()]),
+                true
+            ).unwrap()
+        ]
+    }
+
+    fn values(values: Value) -> HashMap<String, Value> {
+        serde_json::from_value(values).unwrap()
+    }
+
+    #[test]
+    fn form_values_are_converted_to_their_type() {
+        let attributes = assert_ok!(SubscriberAttributes::parse(
+            values(json!({
+                "company": "Acme",
+                "seats": "12",
+                "beta": "true",
+                "birthday": "1929-10-21",
+                "plan": "pro"
+            })),
+            &definitions()
+        ));
+
+        assert_eq!(
+            attributes.as_ref(),
+            &json!({
+                "company": "Acme",
+                "seats": 12,
+                "beta": true,
+                "birthday": "1929-10-21",
+                "plan": "pro"
+            })
+        );
+    }
+
+    #[test]
+    fn json_values_are_accepted_as_they_are() {
+        let attributes = assert_ok!(SubscriberAttributes::parse(
+            values(json!({"seats": 2.5, "beta": false, "plan": "free"})),
+            &definitions()
+        ));
+
+        assert_eq!(attributes.as_ref(), &json!({"seats": 2.5, "beta": false, "plan": "free"}));
+    }
+
+    #[test]
+    fn blank_optional_fields_are_left_out() {
+        let attributes = assert_ok!(SubscriberAttributes::parse(
+            values(json!({"company": "", "seats": null, "plan": "free"})),
+            &definitions()
+        ));
+
+        assert_eq!(attributes.as_ref(), &json!({"plan": "free"}));
+    }
+
+    #[test]
+    fn required_fields_must_be_filled_in() {
+        for plan in [json!({}), json!({"plan": ""})] {
+            let e = assert_err!(SubscriberAttributes::parse(values(plan), &definitions()));
+            assert_eq!(e.field, "plan");
+        }
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let test_cases = [
+            (json!({"seats": "a dozen"}), "seats"),
+            (json!({"seats": "NaN"}), "seats"),
+            (json!({"beta": "yes"}), "beta"),
+            (json!({"beta": 1}), "beta"),
+            (json!({"birthday": "21/10/1929"}), "birthday"),
+            (json!({"company": 42}), "company"),
+            (json!({"company": "a".repeat(1001)}), "company")
+        ];
+
+        for (mut invalid_values, field) in test_cases {
+            invalid_values["plan"] = "pro".into();
+            let e = assert_err!(SubscriberAttributes::parse(values(invalid_values), &definitions()));
+            assert_eq!(e.field, field);
+        }
+    }
+
+    #[test]
+    fn enum_values_must_be_one_of_the_options() {
+        let e = assert_err!(SubscriberAttributes::parse(
+            values(json!({"plan": "enterprise"})),
+            &definitions()
+        ));
+        assert_eq!(e.field, "plan");
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let e = assert_err!(SubscriberAttributes::parse(
+            values(json!({"plan": "pro", "shoe_size": "42"})),
+            &definitions()
+        ));
+        assert_eq!(e.field, "shoe_size");
+    }
+
+    #[test]
+    fn field_names_are_lowercase_identifiers() {
+        assert_ok!(FieldDefinition::parse("seats_2".into(), FieldType::Number, false));
+        for name in ["", "Seats", "2seats", "shoe size", "email", "subscribed_at", "not"] {
+            assert_err!(FieldDefinition::parse(name.into(), FieldType::Number, false));
+        }
+        assert_err!(FieldDefinition::parse("a".repeat(51), FieldType::Number, false));
+    }
+
+    #[test]
+    fn only_enum_fields_take_options() {
+        assert_ok!(FieldType::parse("enum", vec!["free".into(), "pro".into()]));
+        assert_err!(FieldType::parse("enum", vec![]));
+        assert_err!(FieldType::parse("enum", vec!["free".into(), "free".into()]));
+        assert_err!(FieldType::parse("string", vec!["free".into()]));
+        assert_err!(FieldType::parse("timestamp", vec![]));
+    }
+}
@@ -0,0 +1,17 @@
+use std::borrow::Cow;
+
+/// A field of a request that did not satisfy our validation constraints.
+///
+/// Most fields are known at compile time, custom subscriber fields are named by admins.
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid {field}: {reason}")]
+pub struct ValidationError {
+    pub field: Cow<'static, str>,
+    pub reason: String
+}
+
+impl ValidationError {
+    pub fn new(field: impl Into<Cow<'static, str>>, reason: String) -> Self {
+        Self { field: field.into(), reason }
+    }
+}
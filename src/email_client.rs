@@ -1,243 +0,0 @@
-use reqwest::Client;
-use secrecy::{Secret, ExposeSecret};
-
-use crate::domain::SubscriberEmail;
-
-pub struct EmailClient {
-    http_client: Client,
-    base_url: String,
-    sender: SubscriberEmail,
-    // We do not want to log this by any accident
-    authorization_token: Secret<String>
-}
-
-#[derive(serde::Serialize)]
-#[serde(rename_all = "PascalCase")]
-pub struct SendEmailRequest<'a> {
-    from: &'a str,
-    to: &'a str,
-    subject: &'a str,
-    html_body: &'a str,
-    text_body: &'a str,
-}
-
-impl EmailClient {
-
-    pub fn new(
-        base_url: String,
-        sender: SubscriberEmail,
-        authorization_token: Secret<String>,
-        timeout: std::time::Duration
-    ) -> Self {
-
-        let http_client = Client::builder()
-            .timeout(timeout)
-            .build()
-            .unwrap();
-
-        Self {
-            http_client,
-            base_url,
-            sender,
-            authorization_token
-        }
-    }
-
-    pub async fn send_email(
-        &self,
-        recipient: SubscriberEmail,
-        subject: &str,
-        html_content: &str,
-        text_content: &str
-    ) -> Result<(), reqwest::Error> {
-
-        let url = format!("{}/email", self.base_url);
-        let request_body = SendEmailRequest {
-            from: self.sender.as_ref(),
-            to: recipient.as_ref(),
-            subject: subject,
-            html_body: html_content,
-            text_body: text_content
-        };
-
-        self.http_client
-            .post(&url)
-            .header(
-                "X-Postmark-Server-Token",
-                self.authorization_token.expose_secret()
-            )
-            .json(&request_body)
-            .send()
-            .await?
-            .error_for_status()?;
-
-        Ok(())
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use claim::{assert_err, assert_ok};
-    use fake::Faker;
-    use fake::faker::lorem::en::{Sentence, Paragraph};
-    use fake::{faker::internet::en::SafeEmail, Fake};
-    use secrecy::Secret;
-    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
-    use wiremock::matchers::{header_exists, header, path, method, any};
-    use crate::configuration;
-    use crate::domain::SubscriberEmail;
-    use super::EmailClient;
-
-    struct SendEmailBodyMatcher;
-
-    /// Generates a random email subject
-    fn subject() -> String {
-        Sentence(1..2).fake()
-    }
-
-    // Generates a random content
-    fn content() -> String {
-        Paragraph(1..10).fake()
-    }
-
-    // Generates a random subscriber email
-    fn email() -> SubscriberEmail {
-        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
-    }
-
-    // Gets a test instance of `EmailClient`
-    fn email_client(base_url: String) -> EmailClient {
-        EmailClient::new(
-            base_url,
-            email(),
-            Secret::new(Faker.fake()),
-            // Settings lower delay for tests (prod is 1000ms)
-            std::time::Duration::from_millis(200) 
-        )
-    }
-
-    impl wiremock::Match for SendEmailBodyMatcher {
-        fn matches(&self, request: &Request) -> bool {
-            // Try to parse the body as a JSON value
-            let result: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
-            if let Ok(body) = result {
-                dbg!(&body);
-                body.get("From").is_some()
-                && body.get("To").is_some()
-                && body.get("Subject").is_some()
-                && body.get("HtmlBody").is_some()
-                && body.get("TextBody").is_some()
-            } else {
-                false
-            }
-        }
-    }
-
-    #[tokio::test]
-    async fn send_email_times_out_if_the_server_takes_too_long() {
-        // Arrange
-        let mock_server = MockServer::start().await;
-        let sender = SubscriberEmail::parse(SafeEmail().fake()).expect("Could not parse");
-
-        let email_client = email_client(mock_server.uri());
-
-        let subscriber_email = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
-        let subject = Sentence(1..2).fake::<String>();
-        let content: String = Paragraph(1..10).fake::<String>();
-
-        let response = ResponseTemplate::new(200)
-            .set_delay(std::time::Duration::from_secs(180));
-
-        Mock::given(any())
-            .respond_with(response)
-            .expect(1)
-            .mount(&mock_server)
-            .await;
-        
-        // Act
-        let outcome = email_client
-            .send_email(
-                subscriber_email,
-                &subject,
-                &content,
-                &content
-            )
-            .await;
-        
-        // Assert
-        assert_err!(outcome);
-
-    }
-
-    #[tokio::test]
-    async fn send_email_fails_if_the_server_returns_500() {
-        // Arrange
-        let mock_server = MockServer::start().await;
-        let sender = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
-
-        let email_client = EmailClient::new(
-            mock_server.uri(),
-            sender,
-            Secret::new(Faker.fake()),
-            std::time::Duration::from_millis(200) 
-        );
-
-        let subscriber_email = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
-        let subject: String = Sentence(1..2).fake();
-        let content: String = Paragraph(1..10).fake();
-
-        Mock::given(any())
-            .respond_with(ResponseTemplate::new(500))
-            .expect(1)
-            .mount(&mock_server)
-            .await;
-
-        // Act
-        let outcome = email_client
-            .send_email(
-                subscriber_email,
-                &subject,
-                &content,
-                &content
-            )
-            .await;
-
-        assert_err!(outcome);
-    }
-
-    #[tokio::test]
-    async fn send_email_sends_the_expected_request() {
-        // Arrange
-        let mock_server = MockServer::start().await;
-        let sender = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
-        let email_client = EmailClient::new(
-            mock_server.uri(),
-            sender,
-            Secret::new(Faker.fake()),
-            std::time::Duration::from_millis(200)
-        );
-        
-        Mock::given(header_exists("X-Postmark-Server-Token"))
-            .and(header("Content-Type", "application/json"))
-            .and(path("/email"))
-            .and(method("POST"))
-            // Custom Matcher
-            .and(SendEmailBodyMatcher)
-            .respond_with(ResponseTemplate::new(200))
-            .expect(1)
-            .mount(&mock_server)
-            .await;
-        
-        let subscriber_email = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
-        let subject: String = Sentence(1..2).fake();
-        let content: String = Paragraph(1..10).fake();
-
-        // Act
-        let response = email_client
-            .send_email(subscriber_email, &subject, &content, &content)
-            .await;
-
-        // Assert
-        assert_ok!(response);
-    }
-}
\ No newline at end of file
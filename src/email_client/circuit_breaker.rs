@@ -0,0 +1,121 @@
+use std::{sync::Mutex, time::{Duration, Instant}};
+
+/// Tracks the health of an email provider.
+///
+/// The breaker trips once the provider was unavailable `failure_threshold`
+/// times in a row: it is then skipped for `cooldown`, after which one call
+/// is let through to probe it. The breaker closes again as soon as the
+/// provider answers.
+pub(super) struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>
+}
+
+#[derive(Default)]
+struct State {
+    consecutive_failures: u32,
+    // Set while the breaker is open
+    open_until: Option<Instant>
+}
+
+impl CircuitBreaker {
+
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            state: Mutex::new(State::default())
+        }
+    }
+
+    /// Whether the provider should be called.
+    pub fn allows_call(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            None => true,
+            Some(open_until) if Instant::now() < open_until => false,
+            Some(_) => {
+                // Half open: this call probes the provider, the others keep
+                // skipping it until we know how the probe went.
+                state.open_until = Some(Instant::now() + self.cooldown);
+                true
+            }
+        }
+    }
+
+    /// Records an answer from the provider, whatever it was.
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures = 0;
+        state.open_until = None;
+    }
+
+    /// Records the provider being unavailable, returns whether the breaker just tripped.
+    pub fn record_failure(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        if state.consecutive_failures < self.failure_threshold {
+            return false;
+        }
+        let tripped = state.open_until.is_none();
+        state.open_until = Some(Instant::now() + self.cooldown);
+        tripped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::CircuitBreaker;
+
+    #[test]
+    fn the_breaker_trips_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+
+        assert!(!breaker.record_failure());
+        assert!(!breaker.record_failure());
+        assert!(breaker.allows_call());
+        assert!(breaker.record_failure());
+        assert!(!breaker.allows_call());
+    }
+
+    #[test]
+    fn successes_reset_the_count_of_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+
+        assert!(breaker.allows_call());
+    }
+
+    #[test]
+    fn a_single_probe_goes_through_once_the_cooldown_is_over() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        breaker.record_failure();
+        assert!(!breaker.allows_call());
+
+        std::thread::sleep(Duration::from_millis(60));
+
+        assert!(breaker.allows_call());
+        assert!(!breaker.allows_call());
+        // The probe succeeded
+        breaker.record_success();
+        assert!(breaker.allows_call());
+    }
+
+    #[test]
+    fn a_failed_probe_keeps_the_breaker_open() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(60));
+
+        assert!(breaker.allows_call());
+        // Still open, it does not count as tripping again
+        assert!(!breaker.record_failure());
+        assert!(!breaker.allows_call());
+    }
+}
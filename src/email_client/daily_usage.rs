@@ -0,0 +1,116 @@
+use std::{num::NonZeroU32, time::Duration};
+
+use chrono::{Duration as ChronoDuration, NaiveDate, Utc};
+use sqlx::PgPool;
+
+/// Counts the emails sent through each provider every day, in the database.
+///
+/// Unlike the per-second quota, which every process keeps on its own, daily
+/// quotas are shared by the replicas and survive restarts. Days start at
+/// midnight UTC.
+#[derive(Clone)]
+pub(super) struct DailyUsage {
+    pool: PgPool
+}
+
+/// The share of a daily quota taken by a batch.
+pub(super) struct Reservation {
+    // The day the emails are counted against
+    pub day: NaiveDate,
+    // How many of them fit in what was left of the quota, possibly none
+    pub n: usize,
+    // How long until the quota is renewed
+    pub renewed_in: Duration
+}
+
+impl DailyUsage {
+
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Counts up to `n` more emails sent through `provider` today, as many as
+    /// `limit` leaves room for.
+    pub async fn reserve(
+        &self,
+        provider: &str,
+        n: usize,
+        limit: NonZeroU32
+    ) -> Result<Reservation, anyhow::Error> {
+
+        let now = Utc::now();
+        let today = now.date().naive_utc();
+        let limit = i64::from(limit.get());
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query!(
+            r#"
+            INSERT INTO email_daily_usage (provider, day, n_sent)
+            VALUES ($1, $2, 0)
+            ON CONFLICT (provider, day) DO NOTHING
+            "#,
+            provider,
+            today
+            )
+            .execute(&mut transaction)
+            .await?;
+        // Other replicas wait for us to be done with today's count
+        let n_sent = sqlx::query!(
+            r#"
+            SELECT n_sent FROM email_daily_usage
+            WHERE provider = $1 AND day = $2
+            FOR UPDATE
+            "#,
+            provider,
+            today
+            )
+            .fetch_one(&mut transaction)
+            .await?
+            .n_sent;
+        let n = usize::try_from((limit - i64::from(n_sent)).max(0))?.min(n);
+        if n > 0 {
+            sqlx::query!(
+                r#"
+                UPDATE email_daily_usage
+                SET n_sent = n_sent + $3
+                WHERE provider = $1 AND day = $2
+                "#,
+                provider,
+                today,
+                i32::try_from(n)?
+                )
+                .execute(&mut transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+
+        let tomorrow = (today + ChronoDuration::days(1)).and_hms(0, 0, 0);
+        let renewed_in = (tomorrow - now.naive_utc()).to_std().unwrap_or_default();
+        Ok(Reservation { day: today, n, renewed_in })
+    }
+
+    /// Stops counting `n` emails of `reservation` that `provider` did not
+    /// send after all.
+    pub async fn release(
+        &self,
+        provider: &str,
+        reservation: &Reservation,
+        n: usize
+    ) -> Result<(), anyhow::Error> {
+
+        let n = i32::try_from(n)?;
+        sqlx::query!(
+            r#"
+            UPDATE email_daily_usage
+            SET n_sent = GREATEST(n_sent - $3, 0)
+            WHERE provider = $1 AND day = $2
+            "#,
+            provider,
+            reservation.day,
+            n
+            )
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
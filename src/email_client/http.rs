@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use reqwest::{header::RETRY_AFTER, RequestBuilder, Response, StatusCode};
+use serde_json::Value;
+
+use super::EmailError;
+
+/// Sends a request to the API of an email provider.
+///
+/// Failures are classified the same way for every provider: timeouts, network
+/// errors, `408` and `5xx` responses mean the provider is unavailable, `429`
+/// responses that it is throttling us, other `4xx` responses are rejections.
+/// `401` and `403` responses are about our account rather than the email, a
+/// bad key or a suspended account, so they count as the provider being
+/// unavailable too.
+/// Rejections `is_throttling` recognizes from the JSON body of the response
+/// are the provider throttling us all the same, such as an account out of
+/// credits.
+/// The `Retry-After` header of `429` responses is kept, in seconds. The error
+/// message of the provider, which `error_message` extracts from the JSON body
+/// of the response, ends up in the error.
+///
+/// Successful responses are returned for callers that need their body.
+pub(super) async fn send_request(
+    request: RequestBuilder,
+    provider: &str,
+    error_message: fn(&Value) -> Option<&str>,
+    is_throttling: fn(&Value) -> bool
+) -> Result<Response, EmailError> {
+
+    let response = request.send().await.map_err(|e| {
+        let permanent = e.is_builder();
+        let e = anyhow::Error::new(e).context(format!("Failed to call {}.", provider));
+        if permanent { EmailError::Permanent(e) } else { EmailError::Unavailable(e) }
+    })?;
+
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs);
+    let body = response.text().await.unwrap_or_default();
+    let json = serde_json::from_str::<Value>(&body).ok();
+    let message = json
+        .as_ref()
+        .and_then(|json| error_message(json).map(str::to_string))
+        .unwrap_or(body);
+    let e = anyhow::anyhow!("{} responded with {}: {}", provider, status, message);
+
+    if status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::UNAUTHORIZED
+        || status == StatusCode::FORBIDDEN
+        || status.is_server_error()
+    {
+        Err(EmailError::Unavailable(e))
+    } else if status == StatusCode::TOO_MANY_REQUESTS || json.as_ref().is_some_and(is_throttling) {
+        Err(EmailError::Throttled { retry_after, source: e })
+    } else {
+        Err(EmailError::Permanent(e))
+    }
+}
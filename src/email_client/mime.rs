@@ -0,0 +1,60 @@
+use anyhow::Context;
+use lettre::message::{header::{HeaderName, HeaderValue}, Mailbox, Message, MultiPart};
+
+use super::Email;
+
+/// The MIME message of `email`: a `multipart/alternative` with its text and HTML parts.
+pub(super) fn to_mime(email: &Email<'_>) -> Result<Message, anyhow::Error> {
+
+    let sender = email.sender.as_ref().parse().context("Invalid sender address.")?;
+    let recipient = email.recipient.as_ref().parse().context("Invalid recipient address.")?;
+    let mut builder = Message::builder()
+        .from(Mailbox::new(email.sender_name.map(str::to_string), sender))
+        .to(Mailbox::new(None, recipient))
+        .subject(email.subject);
+    for (name, value) in email.headers {
+        let name = HeaderName::new_from_ascii(name.to_string())
+            .with_context(|| format!("{} is not a valid header name.", name))?;
+        builder = builder.raw_header(HeaderValue::new(name, value.to_string()));
+    }
+
+    builder
+        .multipart(MultiPart::alternative_plain_html(
+            email.text_content.to_string(),
+            email.html_content.to_string()
+        ))
+        .context("Failed to build the MIME message.")
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::assert_ok;
+
+    use super::to_mime;
+    use crate::{domain::SubscriberEmail, email_client::Email};
+
+    #[test]
+    fn both_parts_and_the_custom_headers_are_in_the_message() {
+        let sender = SubscriberEmail::parse("rust@lists.com".into()).unwrap();
+        let recipient = SubscriberEmail::parse("ursula@example.com".into()).unwrap();
+        let email = Email {
+            sender: &sender,
+            sender_name: Some("Rust \"Weekly\""),
+            recipient: &recipient,
+            subject: "Issue #1",
+            html_content: "<p>Hello</p>",
+            text_content: "Hello",
+            headers: &[("List-Unsubscribe", "<https://unsubscribe.me>")]
+        };
+
+        let message = assert_ok!(to_mime(&email));
+        let formatted = String::from_utf8(message.formatted()).unwrap();
+
+        assert!(formatted.contains("From: \"Rust \\\"Weekly\\\"\" <rust@lists.com>\r\n"));
+        assert!(formatted.contains("To: ursula@example.com\r\n"));
+        assert!(formatted.contains("Subject: Issue #1\r\n"));
+        assert!(formatted.contains("List-Unsubscribe: <https://unsubscribe.me>\r\n"));
+        assert!(formatted.contains("Content-Type: multipart/alternative"));
+        assert!(formatted.contains("<p>Hello</p>"));
+    }
+}
@@ -0,0 +1,767 @@
+mod circuit_breaker;
+mod daily_usage;
+mod http;
+mod mailgun;
+mod mime;
+mod outbox;
+mod postmark;
+mod rate_limiter;
+mod sendgrid;
+mod ses;
+mod sigv4;
+mod smtp;
+
+pub use mailgun::MailgunTransport;
+pub use outbox::OutboxTransport;
+pub use postmark::PostmarkTransport;
+pub use rate_limiter::RateLimit;
+pub use sendgrid::SendGridTransport;
+pub use ses::SesTransport;
+pub use smtp::SmtpTransport;
+
+use std::{num::NonZeroU32, sync::Arc, time::Duration};
+
+use sqlx::PgPool;
+
+use crate::{domain::SubscriberEmail, problem::error_chain_fmt};
+
+use circuit_breaker::CircuitBreaker;
+use daily_usage::{DailyUsage, Reservation};
+use rate_limiter::RateLimiter;
+
+/// An email, ready to be handed over to a transport.
+#[derive(Clone, Copy)]
+pub struct Email<'a> {
+    pub sender: &'a SubscriberEmail,
+    pub sender_name: Option<&'a str>,
+    pub recipient: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+    // (name, value) pairs
+    pub headers: &'a [(&'a str, &'a str)]
+}
+
+impl Email<'_> {
+    /// The `From` header: the sender address, with its display name if there is one.
+    fn sender_header(&self) -> String {
+        match self.sender_name {
+            // Quotes would end the display name early
+            Some(name) => format!("\"{}\" <{}>", name.replace('"', ""), self.sender.as_ref()),
+            None => self.sender.as_ref().to_string()
+        }
+    }
+}
+
+/// Why an email was not sent, in a way that does not depend on the transport.
+#[derive(thiserror::Error)]
+pub enum EmailError {
+    // Sending it again would fail the same way: an invalid address,
+    // rejected content, wrong credentials...
+    #[error("The email was rejected.")]
+    Permanent(#[source] anyhow::Error),
+    // It may go through later: a full outbox, a busy SMTP relay...
+    #[error("The email could not be sent for now.")]
+    Transient(#[source] anyhow::Error),
+    // Timeouts, outages: another provider may take it
+    #[error("The email provider is unavailable.")]
+    Unavailable(#[source] anyhow::Error),
+    // Over a quota, of the provider or our own: it may go through after `retry_after`
+    #[error("The email provider is throttling us.")]
+    Throttled {
+        retry_after: Option<Duration>,
+        #[source]
+        source: anyhow::Error
+    }
+}
+
+impl EmailError {
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, EmailError::Permanent(_))
+    }
+
+    pub fn is_unavailable(&self) -> bool {
+        matches!(self, EmailError::Unavailable(_))
+    }
+
+    /// How long to wait before trying again, when we know.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            EmailError::Throttled { retry_after, .. } => *retry_after,
+            _ => None
+        }
+    }
+
+    /// Whether the next provider may take the email.
+    fn fails_over(&self) -> bool {
+        matches!(self, EmailError::Unavailable(_) | EmailError::Throttled { .. })
+    }
+
+    /// The outcome of each of the `n` emails of a batch that failed as a whole.
+    fn for_each_of(self, n: usize) -> Vec<Result<(), EmailError>> {
+        // `anyhow::Error` cannot be cloned, its messages can
+        let copy = |e: &anyhow::Error| anyhow::anyhow!("{:#}", e);
+        (0..n)
+            .map(|_| Err(match &self {
+                EmailError::Permanent(e) => EmailError::Permanent(copy(e)),
+                EmailError::Transient(e) => EmailError::Transient(copy(e)),
+                EmailError::Unavailable(e) => EmailError::Unavailable(copy(e)),
+                EmailError::Throttled { retry_after, source } => EmailError::Throttled {
+                    retry_after: *retry_after,
+                    source: copy(source)
+                }
+            }))
+            .collect()
+    }
+}
+
+impl std::fmt::Debug for EmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+/// A way of getting emails to their recipients: an email API, an SMTP relay...
+#[async_trait::async_trait]
+pub trait EmailTransport: Send + Sync {
+    /// Names the provider in logs and delivery records.
+    fn name(&self) -> &'static str;
+
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError>;
+
+    /// Sends several emails, returning the outcome of each in the same order.
+    ///
+    /// Transports with a batch API send them in one go, the others one by one.
+    async fn send_batch(&self, emails: &[Email<'_>]) -> Vec<Result<(), EmailError>> {
+        let mut outcomes = Vec::with_capacity(emails.len());
+        for email in emails {
+            outcomes.push(self.send(email).await);
+        }
+        outcomes
+    }
+}
+
+/// Per-message overrides of the client defaults.
+#[derive(Default)]
+pub struct MessageOptions<'a> {
+    // Falls back to the sender the client was built with
+    pub sender: Option<&'a SubscriberEmail>,
+    pub sender_name: Option<&'a str>,
+    // (name, value) pairs
+    pub headers: &'a [(&'a str, &'a str)]
+}
+
+/// An email of a batch sent with `EmailClient::send_batch`.
+pub struct BatchEmail<'a> {
+    pub recipient: SubscriberEmail,
+    pub subject: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+    pub options: MessageOptions<'a>
+}
+
+/// An email that went out.
+#[derive(Debug)]
+pub struct Delivered {
+    // The name of the transport which took it
+    pub provider: &'static str
+}
+
+struct Provider {
+    // The name it is configured under, which keys its daily quota
+    name: String,
+    transport: Box<dyn EmailTransport>,
+    breaker: CircuitBreaker,
+    limiter: RateLimiter,
+    per_day: Option<NonZeroU32>
+}
+
+// Cloning is cheap: clones share the transports, their connections, health and quotas.
+#[derive(Clone)]
+pub struct EmailClient {
+    providers: Arc<[Provider]>,
+    // Keeps the daily quotas, there are none without it
+    daily_usage: Option<DailyUsage>,
+    sender: SubscriberEmail
+}
+
+impl EmailClient {
+
+    // The most Postmark accepts in a batch call
+    pub const MAX_BATCH_SIZE: usize = 500;
+
+    pub fn new(transport: impl EmailTransport + 'static, sender: SubscriberEmail) -> Self {
+        // With nowhere else to go, the breaker never keeps us from calling the transport
+        let name = transport.name().to_string();
+        Self::with_failover((name, Box::new(transport), RateLimit::default()), vec![], sender, u32::MAX, Duration::ZERO, None)
+    }
+
+    /// A client sending through `primary`, or the first of `fallbacks` that
+    /// is available, within its rate limit. Each comes with the name it is
+    /// configured under, which must be unique.
+    ///
+    /// Emails go to the next transport when one is unavailable or throttling
+    /// us. A transport unavailable `failure_threshold` times in a row is
+    /// skipped for `cooldown`, then probed again.
+    ///
+    /// Daily quotas are counted in the database behind `pool`, they are not
+    /// enforced without one.
+    pub fn with_failover(
+        primary: (String, Box<dyn EmailTransport>, RateLimit),
+        fallbacks: Vec<(String, Box<dyn EmailTransport>, RateLimit)>,
+        sender: SubscriberEmail,
+        failure_threshold: u32,
+        cooldown: Duration,
+        pool: Option<PgPool>
+    ) -> Self {
+        let providers = std::iter::once(primary)
+            .chain(fallbacks)
+            .map(|(name, transport, rate_limit)| Provider {
+                name,
+                transport,
+                breaker: CircuitBreaker::new(failure_threshold, cooldown),
+                limiter: RateLimiter::new(rate_limit.per_second),
+                per_day: rate_limit.per_day
+            })
+            .collect();
+        Self { providers, daily_usage: pool.map(DailyUsage::new), sender }
+    }
+
+    /// The most emails worth handing over at once: `MAX_BATCH_SIZE`, or the
+    /// lowest per-second quota of the transports.
+    ///
+    /// Larger batches borrow tokens from the rate limiter, making the emails
+    /// sent after them wait until they are paid back.
+    pub fn max_batch_size(&self) -> usize {
+        self.providers
+            .iter()
+            .filter_map(|provider| provider.limiter.max_burst())
+            .fold(Self::MAX_BATCH_SIZE, usize::min)
+    }
+
+    pub async fn send_email(
+        &self,
+        recipient: SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str
+    ) -> Result<Delivered, EmailError> {
+        self.send_email_with_options(
+            recipient,
+            subject,
+            html_content,
+            text_content,
+            &MessageOptions::default()
+        )
+        .await
+    }
+
+    /// Like `send_email`, overriding the sender or adding custom headers.
+    pub async fn send_email_with_options(
+        &self,
+        recipient: SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        options: &MessageOptions<'_>
+    ) -> Result<Delivered, EmailError> {
+
+        let email = Email {
+            sender: options.sender.unwrap_or(&self.sender),
+            sender_name: options.sender_name,
+            recipient: &recipient,
+            subject,
+            html_content,
+            text_content,
+            headers: options.headers
+        };
+        self.deliver(&[email]).await.pop().unwrap()
+    }
+
+    /// Sends `emails`, in batches of up to `MAX_BATCH_SIZE` for transports
+    /// supporting it, and returns the outcome of each in the same order.
+    ///
+    /// Emails fail on their own: some can be rejected while the others
+    /// of their batch go out.
+    pub async fn send_batch(&self, emails: &[BatchEmail<'_>]) -> Vec<Result<Delivered, EmailError>> {
+
+        let emails: Vec<Email> = emails
+            .iter()
+            .map(|email| Email {
+                sender: email.options.sender.unwrap_or(&self.sender),
+                sender_name: email.options.sender_name,
+                recipient: &email.recipient,
+                subject: email.subject,
+                html_content: email.html_content,
+                text_content: email.text_content,
+                headers: email.options.headers
+            })
+            .collect();
+        self.deliver(&emails).await
+    }
+
+    /// Hands `emails` to the providers in turn, until none is left that
+    /// failed because its provider was unavailable or throttling us.
+    async fn deliver(&self, emails: &[Email<'_>]) -> Vec<Result<Delivered, EmailError>> {
+
+        let mut outcomes: Vec<Option<Result<Delivered, EmailError>>> = emails.iter().map(|_| None).collect();
+        let mut pending: Vec<usize> = (0..emails.len()).collect();
+        for provider in self.providers.iter() {
+            if pending.is_empty() {
+                break;
+            }
+            // Providers with an open breaker are skipped until it lets a
+            // probe through. Breakers are only asked when their turn comes,
+            // not to spend a probe on a provider we end up not calling.
+            if !provider.breaker.allows_call() {
+                for i in &pending {
+                    // Whatever happened with the providers before tells more
+                    outcomes[*i].get_or_insert_with(|| Err(EmailError::Unavailable(anyhow::anyhow!(
+                        "The circuit breaker of {} is open.",
+                        provider.transport.name()
+                    ))));
+                }
+                continue;
+            }
+            pending = self.deliver_through(provider, emails, pending, &mut outcomes).await;
+        }
+
+        // There is always a primary provider, so every email got an outcome,
+        // if only that of being skipped
+        outcomes.into_iter().map(Option::unwrap).collect()
+    }
+
+    /// Hands the `pending` emails to `provider`, returns those left over for
+    /// the next one.
+    async fn deliver_through(
+        &self,
+        provider: &Provider,
+        emails: &[Email<'_>],
+        pending: Vec<usize>,
+        outcomes: &mut [Option<Result<Delivered, EmailError>>]
+    ) -> Vec<usize> {
+
+        let name = provider.transport.name();
+        // Batches within the per-second quota let other callers have their turn
+        let batch_size = provider.limiter
+            .max_burst()
+            .map_or(Self::MAX_BATCH_SIZE, |burst| burst.min(Self::MAX_BATCH_SIZE));
+        let mut left_over = Vec::new();
+        for batch in pending.chunks(batch_size) {
+            let wait = match provider.limiter.reserve(batch.len()) {
+                Ok(wait) => wait,
+                Err(retry_after) => {
+                    for i in batch {
+                        left_over.push(*i);
+                        outcomes[*i] = Some(Err(EmailError::Throttled {
+                            retry_after: Some(retry_after),
+                            source: anyhow::anyhow!("Over the rate limit of {}.", name)
+                        }));
+                    }
+                    continue;
+                }
+            };
+            // What does not fit in the daily quota waits for tomorrow, or the next provider
+            let reservation = self.reserve_daily_usage(provider, batch.len()).await;
+            let n_reserved = reservation.as_ref().map_or(batch.len(), |reservation| reservation.n);
+            let (batch, over_quota) = batch.split_at(n_reserved);
+            if !over_quota.is_empty() {
+                // Their tokens go to whoever comes next
+                provider.limiter.release(over_quota.len());
+                let renewed_in = reservation.as_ref().map(|reservation| reservation.renewed_in);
+                for i in over_quota {
+                    left_over.push(*i);
+                    outcomes[*i] = Some(Err(EmailError::Throttled {
+                        retry_after: renewed_in,
+                        source: anyhow::anyhow!("Over the daily quota of {}.", name)
+                    }));
+                }
+            }
+            if batch.is_empty() {
+                continue;
+            }
+            tokio::time::sleep(wait).await;
+
+            let results = match batch {
+                // A batch of one is an ordinary email
+                [i] => vec![provider.transport.send(&emails[*i]).await],
+                _ => {
+                    let batch_emails: Vec<Email> = batch.iter().map(|i| emails[*i]).collect();
+                    provider.transport.send_batch(&batch_emails).await
+                }
+            };
+
+            if results.iter().any(|result| matches!(result, Err(e) if e.is_unavailable())) {
+                if provider.breaker.record_failure() {
+                    tracing::warn!(provider = name, "The email provider is unavailable, failing over");
+                }
+            } else {
+                provider.breaker.record_success();
+            }
+            if let Some(Err(e)) = results.iter().find(|result| matches!(result, Err(EmailError::Throttled { .. }))) {
+                // Without a `Retry-After`, give the provider a moment anyway
+                provider.limiter.pause(e.retry_after().unwrap_or(Duration::from_secs(1)));
+            }
+            let n_failed = results.iter().filter(|result| result.is_err()).count();
+            if let (Some(reservation), true) = (&reservation, n_failed > 0) {
+                self.release_daily_usage(provider, reservation, n_failed).await;
+            }
+
+            for (i, result) in batch.iter().zip(results) {
+                match result {
+                    Ok(()) => {
+                        tracing::info!(
+                            provider = name,
+                            recipient = %emails[*i].recipient.as_ref(),
+                            "Sent an email"
+                        );
+                        outcomes[*i] = Some(Ok(Delivered { provider: name }));
+                    }
+                    Err(e) if e.fails_over() => {
+                        tracing::warn!(error.cause_chain = ?e, provider = name, "Failed to send an email");
+                        left_over.push(*i);
+                        outcomes[*i] = Some(Err(e));
+                    }
+                    Err(e) => outcomes[*i] = Some(Err(e))
+                }
+            }
+        }
+        left_over
+    }
+
+    /// Counts up to `n` emails against the daily quota of `provider`, when
+    /// it has one and it can be counted.
+    async fn reserve_daily_usage(&self, provider: &Provider, n: usize) -> Option<Reservation> {
+        let (daily_usage, per_day) = match (&self.daily_usage, provider.per_day) {
+            (Some(daily_usage), Some(per_day)) => (daily_usage, per_day),
+            _ => return None
+        };
+        match daily_usage.reserve(&provider.name, n, per_day).await {
+            Ok(reservation) => Some(reservation),
+            Err(e) => {
+                // The provider enforces its quota anyway, and tells us when we go over it
+                tracing::error!(
+                    error.cause_chain = ?e,
+                    provider = provider.transport.name(),
+                    "Failed to count the emails sent today"
+                );
+                None
+            }
+        }
+    }
+
+    /// Gives back the share of `reservation` taken by `n` emails `provider`
+    /// failed to send.
+    async fn release_daily_usage(&self, provider: &Provider, reservation: &Reservation, n: usize) {
+        let daily_usage = match &self.daily_usage {
+            Some(daily_usage) => daily_usage,
+            None => return
+        };
+        if let Err(e) = daily_usage.release(&provider.name, reservation, n).await {
+            tracing::error!(
+                error.cause_chain = ?e,
+                provider = provider.transport.name(),
+                "Failed to count the emails sent today"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroU32, time::{Duration, Instant}};
+
+    use claim::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{any, path};
+
+    use super::{EmailClient, PostmarkTransport, RateLimit, SendGridTransport};
+    use crate::domain::SubscriberEmail;
+
+    // Postmark, falling over to SendGrid
+    fn email_client(
+        postmark: &MockServer,
+        sendgrid: &MockServer,
+        failure_threshold: u32,
+        cooldown: Duration
+    ) -> EmailClient {
+        let timeout = Duration::from_millis(200);
+        EmailClient::with_failover(
+            (
+                "postmark".into(),
+                Box::new(PostmarkTransport::new(postmark.uri(), Secret::new("token".into()), timeout)),
+                RateLimit::default()
+            ),
+            vec![(
+                "sendgrid".into(),
+                Box::new(SendGridTransport::new(sendgrid.uri(), Secret::new("key".into()), timeout)),
+                RateLimit::default()
+            )],
+            SubscriberEmail::parse("rust@lists.com".into()).unwrap(),
+            failure_threshold,
+            cooldown,
+            None
+        )
+    }
+
+    fn recipient() -> SubscriberEmail {
+        SubscriberEmail::parse("ursula@example.com".into()).unwrap()
+    }
+
+    async fn mount_sendgrid(server: &MockServer, expected_calls: u64) {
+        Mock::given(path("/v3/mail/send"))
+            .respond_with(ResponseTemplate::new(202))
+            .expect(expected_calls)
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn emails_go_to_the_next_transport_when_one_is_unavailable() {
+        // Arrange
+        let (postmark, sendgrid) = (MockServer::start().await, MockServer::start().await);
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&postmark)
+            .await;
+        mount_sendgrid(&sendgrid, 1).await;
+        let email_client = email_client(&postmark, &sendgrid, 5, Duration::from_secs(60));
+
+        // Act
+        let outcome = email_client.send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello").await;
+
+        // Assert
+        assert_eq!(assert_ok!(outcome).provider, "sendgrid");
+    }
+
+    #[tokio::test]
+    async fn rejected_emails_are_not_sent_elsewhere() {
+        // Arrange
+        let (postmark, sendgrid) = (MockServer::start().await, MockServer::start().await);
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422))
+            .expect(1)
+            .mount(&postmark)
+            .await;
+        mount_sendgrid(&sendgrid, 0).await;
+        let email_client = email_client(&postmark, &sendgrid, 5, Duration::from_secs(60));
+
+        // Act
+        let outcome = email_client.send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello").await;
+
+        // Assert
+        assert!(assert_err!(outcome).is_permanent());
+    }
+
+    #[tokio::test]
+    async fn a_transport_failing_repeatedly_is_skipped() {
+        // Arrange
+        let (postmark, sendgrid) = (MockServer::start().await, MockServer::start().await);
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .expect(2)
+            .mount(&postmark)
+            .await;
+        mount_sendgrid(&sendgrid, 3).await;
+        let email_client = email_client(&postmark, &sendgrid, 2, Duration::from_secs(60));
+
+        // Act
+        for _ in 0..3 {
+            let outcome = email_client.send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello").await;
+
+            // Assert
+            assert_eq!(assert_ok!(outcome).provider, "sendgrid");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_skipped_transport_is_probed_once_the_cooldown_is_over() {
+        // Arrange
+        let (postmark, sendgrid) = (MockServer::start().await, MockServer::start().await);
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&postmark)
+            .await;
+        Mock::given(path("/email"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&postmark)
+            .await;
+        mount_sendgrid(&sendgrid, 2).await;
+        let email_client = email_client(&postmark, &sendgrid, 1, Duration::from_millis(100));
+        let send = || email_client.send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello");
+
+        // Act
+        let failed_over = send().await;
+        let skipped = send().await;
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let probed = send().await;
+
+        // Assert
+        assert_eq!(assert_ok!(failed_over).provider, "sendgrid");
+        assert_eq!(assert_ok!(skipped).provider, "sendgrid");
+        assert_eq!(assert_ok!(probed).provider, "postmark");
+    }
+
+    #[tokio::test]
+    async fn a_lone_transport_failing_repeatedly_is_not_called_until_the_cooldown_is_over() {
+        // Arrange
+        let postmark = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&postmark)
+            .await;
+        let email_client = EmailClient::with_failover(
+            (
+                "postmark".into(),
+                Box::new(PostmarkTransport::new(postmark.uri(), Secret::new("token".into()), Duration::from_millis(200))),
+                RateLimit::default()
+            ),
+            vec![],
+            SubscriberEmail::parse("rust@lists.com".into()).unwrap(),
+            1,
+            Duration::from_secs(60),
+            None
+        );
+        let send = || email_client.send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello");
+
+        // Act
+        let failed = send().await;
+        let skipped = send().await;
+
+        // Assert
+        assert!(assert_err!(failed).is_unavailable());
+        let e = assert_err!(skipped);
+        assert!(e.is_unavailable());
+        assert!(format!("{:?}", e).contains("circuit breaker"));
+    }
+
+    #[tokio::test]
+    async fn transports_not_called_keep_their_probe() {
+        // Arrange
+        let (postmark, sendgrid) = (MockServer::start().await, MockServer::start().await);
+        Mock::given(path("/email"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&postmark)
+            .await;
+        mount_sendgrid(&sendgrid, 0).await;
+        let email_client = email_client(&postmark, &sendgrid, 1, Duration::from_millis(100));
+        email_client.providers[1].breaker.record_failure();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        // Act
+        let outcome = email_client.send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello").await;
+
+        // Assert
+        assert_eq!(assert_ok!(outcome).provider, "postmark");
+        // SendGrid was not needed, the next email can still probe it
+        assert!(email_client.providers[1].breaker.allows_call());
+    }
+
+    #[tokio::test]
+    async fn a_throttling_transport_is_paused_for_as_long_as_it_asks() {
+        // Arrange
+        let (postmark, sendgrid) = (MockServer::start().await, MockServer::start().await);
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "30"))
+            .expect(1)
+            .mount(&postmark)
+            .await;
+        mount_sendgrid(&sendgrid, 2).await;
+        let email_client = email_client(&postmark, &sendgrid, 5, Duration::from_secs(60));
+
+        // Act
+        for _ in 0..2 {
+            let outcome = email_client.send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello").await;
+
+            // Assert
+            assert_eq!(assert_ok!(outcome).provider, "sendgrid");
+        }
+    }
+
+    #[tokio::test]
+    async fn throttled_emails_tell_when_to_try_again() {
+        // Arrange
+        let postmark = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "30"))
+            .expect(1)
+            .mount(&postmark)
+            .await;
+        let email_client = EmailClient::new(
+            PostmarkTransport::new(postmark.uri(), Secret::new("token".into()), Duration::from_millis(200)),
+            SubscriberEmail::parse("rust@lists.com".into()).unwrap()
+        );
+        let send = || email_client.send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello");
+
+        // Act
+        let throttled = send().await;
+        // Postmark is not called again until then
+        let paused = send().await;
+
+        // Assert
+        assert_eq!(assert_err!(throttled).retry_after(), Some(Duration::from_secs(30)));
+        let retry_after = assert_err!(paused).retry_after().unwrap();
+        assert!(retry_after > Duration::from_secs(29) && retry_after <= Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn emails_are_sent_within_the_rate_limit() {
+        // Arrange
+        let sendgrid = MockServer::start().await;
+        mount_sendgrid(&sendgrid, 3).await;
+        let email_client = EmailClient::with_failover(
+            (
+                "sendgrid".into(),
+                Box::new(SendGridTransport::new(sendgrid.uri(), Secret::new("key".into()), Duration::from_millis(200))),
+                RateLimit { per_second: NonZeroU32::new(2), per_day: None }
+            ),
+            vec![],
+            SubscriberEmail::parse("rust@lists.com".into()).unwrap(),
+            5,
+            Duration::from_secs(60),
+            None
+        );
+        let start = Instant::now();
+
+        // Act
+        for _ in 0..3 {
+            let outcome = email_client.send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello").await;
+            assert_ok!(outcome);
+        }
+
+        // Assert
+        // Two emails right away, the third once a token came back
+        assert!(start.elapsed() >= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn batches_stay_within_the_per_second_quota() {
+        let timeout = Duration::from_millis(200);
+        let rate_limited = EmailClient::with_failover(
+            (
+                "sendgrid".into(),
+                Box::new(SendGridTransport::new("https://sendgrid".into(), Secret::new("key".into()), timeout)),
+                RateLimit { per_second: NonZeroU32::new(10), per_day: None }
+            ),
+            vec![],
+            SubscriberEmail::parse("rust@lists.com".into()).unwrap(),
+            5,
+            Duration::from_secs(60),
+            None
+        );
+        let unlimited = EmailClient::new(
+            SendGridTransport::new("https://sendgrid".into(), Secret::new("key".into()), timeout),
+            SubscriberEmail::parse("rust@lists.com".into()).unwrap()
+        );
+
+        assert_eq!(rate_limited.max_batch_size(), 10);
+        assert_eq!(unlimited.max_batch_size(), EmailClient::MAX_BATCH_SIZE);
+    }
+}
@@ -0,0 +1,96 @@
+use std::{io::Write, path::PathBuf};
+
+use anyhow::Context;
+use chrono::Utc;
+use uuid::Uuid;
+
+use super::{mime::to_mime, Email, EmailError, EmailTransport};
+
+/// Keeps emails on this machine instead of sending them, for development.
+///
+/// Each email is written as an `.eml` file, which mail clients can open,
+/// to the given directory, or printed to stdout when there is none.
+pub struct OutboxTransport {
+    directory: Option<PathBuf>
+}
+
+impl OutboxTransport {
+    pub fn new(directory: Option<PathBuf>) -> Self {
+        Self { directory }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailTransport for OutboxTransport {
+    fn name(&self) -> &'static str {
+        "outbox"
+    }
+
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError> {
+
+        let message = to_mime(email).map_err(EmailError::Permanent)?.formatted();
+        match &self.directory {
+            Some(directory) => {
+                // Named after the time they were sent, so that they list in order
+                let path = directory.join(format!(
+                    "{}-{}.eml",
+                    Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+                    Uuid::new_v4()
+                ));
+                tokio::fs::create_dir_all(directory)
+                    .await
+                    .context("Failed to create the outbox directory.")
+                    .map_err(EmailError::Transient)?;
+                tokio::fs::write(&path, message)
+                    .await
+                    .context("Failed to write the email to the outbox.")
+                    .map_err(EmailError::Transient)?;
+                tracing::info!(path = %path.display(), "Saved an email to the outbox");
+            }
+            None => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&message)
+                    .and_then(|_| stdout.write_all(b"\r\n\r\n"))
+                    .context("Failed to print the email.")
+                    .map_err(EmailError::Transient)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::assert_ok;
+    use uuid::Uuid;
+
+    use super::OutboxTransport;
+    use crate::{domain::SubscriberEmail, email_client::EmailClient};
+
+    #[tokio::test]
+    async fn emails_are_saved_to_the_outbox_directory() {
+        // Arrange
+        let directory = std::env::temp_dir().join(format!("outbox-{}", Uuid::new_v4()));
+        let email_client = EmailClient::new(
+            OutboxTransport::new(Some(directory.clone())),
+            SubscriberEmail::parse("rust@lists.com".into()).unwrap()
+        );
+        let recipient = SubscriberEmail::parse("ursula@example.com".into()).unwrap();
+
+        // Act
+        let outcome = email_client
+            .send_email(recipient, "Issue #1", "<p>Hello</p>", "Hello")
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+        let files: Vec<_> = std::fs::read_dir(&directory).unwrap().map(|f| f.unwrap().path()).collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+        let saved = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(saved.contains("To: ursula@example.com\r\n"));
+        assert!(saved.contains("Subject: Issue #1\r\n"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,674 @@
+use reqwest::Client;
+use secrecy::{Secret, ExposeSecret};
+
+use super::{http::send_request, Email, EmailError, EmailTransport};
+
+/// Sends emails through Postmark's HTTP API.
+pub struct PostmarkTransport {
+    http_client: Client,
+    base_url: String,
+    // We do not want to log this by any accident
+    authorization_token: Secret<String>
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SendEmailRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    html_body: &'a str,
+    text_body: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<EmailHeader<'a>>
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EmailHeader<'a> {
+    name: &'a str,
+    value: &'a str
+}
+
+impl<'a> SendEmailRequest<'a> {
+    fn new(email: &'a Email<'_>, from: &'a str) -> Self {
+        Self {
+            from,
+            to: email.recipient.as_ref(),
+            subject: email.subject,
+            html_body: email.html_content,
+            text_body: email.text_content,
+            headers: email.headers
+                .iter()
+                .map(|(name, value)| EmailHeader { name, value })
+                .collect()
+        }
+    }
+}
+
+/// What happened to one of the emails of a batch.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BatchResult {
+    // 0 when the email was accepted
+    error_code: i64,
+    message: String
+}
+
+impl BatchResult {
+    fn into_error(self) -> EmailError {
+        let e = anyhow::anyhow!(
+            "Postmark rejected the email with error code {}: {}",
+            self.error_code,
+            self.message
+        );
+        if is_throttling(self.error_code) {
+            EmailError::Throttled { retry_after: None, source: e }
+        } else {
+            EmailError::Permanent(e)
+        }
+    }
+}
+
+/// Rate limits and used up credits clear with time, the other error codes
+/// are about the email itself.
+fn is_throttling(error_code: i64) -> bool {
+    // Rate limit exceeded, not allowed to send (out of credits)
+    matches!(error_code, 429 | 405)
+}
+
+// The error code of a failed request, in its JSON body
+fn is_throttling_response(body: &serde_json::Value) -> bool {
+    body["ErrorCode"].as_i64().is_some_and(is_throttling)
+}
+
+impl PostmarkTransport {
+
+    pub fn new(
+        base_url: String,
+        authorization_token: Secret<String>,
+        timeout: std::time::Duration
+    ) -> Self {
+
+        let http_client = Client::builder()
+            .timeout(timeout)
+            .build()
+            .unwrap();
+
+        Self {
+            http_client,
+            base_url,
+            authorization_token
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailTransport for PostmarkTransport {
+    fn name(&self) -> &'static str {
+        "postmark"
+    }
+
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError> {
+
+        let url = format!("{}/email", self.base_url);
+        let from = email.sender_header();
+        let request_body = SendEmailRequest::new(email, &from);
+
+        let request = self.http_client
+            .post(&url)
+            .header(
+                "X-Postmark-Server-Token",
+                self.authorization_token.expose_secret()
+            )
+            .json(&request_body);
+        send_request(request, "Postmark", |body| body["Message"].as_str(), is_throttling_response).await?;
+        Ok(())
+    }
+
+    async fn send_batch(&self, emails: &[Email<'_>]) -> Vec<Result<(), EmailError>> {
+
+        let url = format!("{}/email/batch", self.base_url);
+        let froms: Vec<String> = emails.iter().map(Email::sender_header).collect();
+        let request_body: Vec<SendEmailRequest> = emails
+            .iter()
+            .zip(&froms)
+            .map(|(email, from)| SendEmailRequest::new(email, from))
+            .collect();
+
+        let request = self.http_client
+            .post(&url)
+            .header(
+                "X-Postmark-Server-Token",
+                self.authorization_token.expose_secret()
+            )
+            .json(&request_body);
+        let response = match send_request(request, "Postmark", |body| body["Message"].as_str(), is_throttling_response).await {
+            Ok(response) => response,
+            Err(e) => return e.for_each_of(emails.len())
+        };
+        // Postmark accepted the batch: sending it again would duplicate the
+        // emails that went out, whatever the body says.
+        let results: Vec<BatchResult> = match response.json().await {
+            Ok(results) => results,
+            Err(e) => {
+                tracing::error!(
+                    error.cause_chain = ?e,
+                    "Failed to read the batch results from Postmark, assuming every email went out"
+                );
+                Vec::new()
+            }
+        };
+
+        // Results come in the order of the emails
+        let mut results = results.into_iter();
+        emails
+            .iter()
+            .map(|email| match results.next() {
+                Some(result) if result.error_code == 0 => Ok(()),
+                Some(result) => Err(result.into_error()),
+                None => {
+                    tracing::warn!(
+                        recipient = %email.recipient.as_ref(),
+                        "Postmark did not report on the email, assuming it went out"
+                    );
+                    Ok(())
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+    use fake::Faker;
+    use fake::faker::lorem::en::{Sentence, Paragraph};
+    use fake::{faker::internet::en::SafeEmail, Fake};
+    use secrecy::Secret;
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+    use wiremock::matchers::{header_exists, header, path, method, any, body_partial_json};
+    use crate::{domain::SubscriberEmail, email_client::{BatchEmail, EmailClient, EmailError, MessageOptions}};
+    use super::PostmarkTransport;
+
+    struct SendEmailBodyMatcher;
+
+    /// Generates a random email subject
+    fn subject() -> String {
+        Sentence(1..2).fake()
+    }
+
+    // Generates a random content
+    fn content() -> String {
+        Paragraph(1..10).fake()
+    }
+
+    // Generates a random subscriber email
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    // Gets a test instance of `EmailClient`
+    fn email_client(base_url: String) -> EmailClient {
+        EmailClient::new(
+            PostmarkTransport::new(
+                base_url,
+                Secret::new(Faker.fake()),
+                // Settings lower delay for tests (prod is 1000ms)
+                std::time::Duration::from_millis(200)
+            ),
+            email()
+        )
+    }
+
+    // A batch of `n` emails to random recipients
+    fn batch(n: usize) -> Vec<BatchEmail<'static>> {
+        (0..n)
+            .map(|_| BatchEmail {
+                recipient: email(),
+                subject: "Issue #1",
+                html_content: "<p>Hello</p>",
+                text_content: "Hello",
+                options: MessageOptions::default()
+            })
+            .collect()
+    }
+
+    impl wiremock::Match for SendEmailBodyMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            // Try to parse the body as a JSON value
+            let result: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
+            if let Ok(body) = result {
+                body.get("From").is_some()
+                && body.get("To").is_some()
+                && body.get("Subject").is_some()
+                && body.get("HtmlBody").is_some()
+                && body.get("TextBody").is_some()
+            } else {
+                false
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn send_email_times_out_if_the_server_takes_too_long() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        let subscriber_email = email();
+        let subject = subject();
+        let content = content();
+
+        let response = ResponseTemplate::new(200)
+            .set_delay(std::time::Duration::from_secs(180));
+
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        
+        // Act
+        let outcome = email_client
+            .send_email(
+                subscriber_email,
+                &subject,
+                &content,
+                &content
+            )
+            .await;
+        
+        // Assert
+        assert_err!(outcome);
+
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_server_returns_500() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let sender = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+
+        let email_client = EmailClient::new(
+            PostmarkTransport::new(
+                mock_server.uri(),
+                Secret::new(Faker.fake()),
+                std::time::Duration::from_millis(200)
+            ),
+            sender
+        );
+
+        let subscriber_email = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        let subject: String = Sentence(1..2).fake();
+        let content: String = Paragraph(1..10).fake();
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(
+                subscriber_email,
+                &subject,
+                &content,
+                &content
+            )
+            .await;
+
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_errors_tell_whether_retrying_may_help() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+                "ErrorCode": 406,
+                "Message": "You tried to send to a recipient that has been marked as inactive."
+            })))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let rejected = email_client.send_email(email(), &subject(), &content(), &content()).await;
+        let unavailable = email_client.send_email(email(), &subject(), &content(), &content()).await;
+
+        // Assert
+        let e = assert_err!(rejected);
+        assert!(e.is_permanent());
+        assert!(format!("{:?}", e).contains("marked as inactive"));
+        assert!(!assert_err!(unavailable).is_permanent());
+    }
+
+    #[tokio::test]
+    async fn send_email_tells_rate_limited_emails_from_rejected_ones() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        // Clients of their own: a throttled email pauses the next ones
+        let send = || async {
+            email_client(mock_server.uri()).send_email(email(), &subject(), &content(), &content()).await
+        };
+
+        for error_code in [429, 405, 300] {
+            Mock::given(any())
+                .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+                    "ErrorCode": error_code,
+                    "Message": "The email was not sent."
+                })))
+                .up_to_n_times(1)
+                .mount(&mock_server)
+                .await;
+        }
+
+        // Act
+        let rate_limited = send().await;
+        let out_of_credits = send().await;
+        let invalid = send().await;
+
+        // Assert
+        assert!(matches!(rate_limited, Err(EmailError::Throttled { .. })));
+        assert!(matches!(out_of_credits, Err(EmailError::Throttled { .. })));
+        assert!(assert_err!(invalid).is_permanent());
+    }
+
+    #[tokio::test]
+    async fn rejected_credentials_make_the_provider_unavailable() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(401).set_body_json(serde_json::json!({
+                "ErrorCode": 10,
+                "Message": "The Server Token you provided in the X-Postmark-Server-Token request header was invalid."
+            })))
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client.send_email(email(), &subject(), &content(), &content()).await;
+
+        // Assert
+        let e = assert_err!(outcome);
+        assert!(e.is_unavailable());
+        assert!(format!("{:?}", e).contains("Server Token you provided"));
+    }
+
+    #[tokio::test]
+    async fn send_email_sends_the_expected_request() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let sender = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        let email_client = EmailClient::new(
+            PostmarkTransport::new(
+                mock_server.uri(),
+                Secret::new(Faker.fake()),
+                std::time::Duration::from_millis(200)
+            ),
+            sender
+        );
+        
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(header("Content-Type", "application/json"))
+            .and(path("/email"))
+            .and(method("POST"))
+            // Custom Matcher
+            .and(SendEmailBodyMatcher)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        
+        let subscriber_email = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        let subject: String = Sentence(1..2).fake();
+        let content: String = Paragraph(1..10).fake();
+
+        // Act
+        let response = email_client
+            .send_email(subscriber_email, &subject, &content, &content)
+            .await;
+
+        // Assert
+        assert_ok!(response);
+    }
+
+    #[tokio::test]
+    async fn send_email_with_options_forwards_the_custom_headers() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .and(body_partial_json(serde_json::json!({
+                "Headers": [{"Name": "List-Unsubscribe", "Value": "<https://unsubscribe.me>"}]
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let response = email_client
+            .send_email_with_options(
+                email(),
+                &subject(),
+                &content(),
+                &content(),
+                &MessageOptions {
+                    headers: &[("List-Unsubscribe", "<https://unsubscribe.me>")],
+                    ..MessageOptions::default()
+                }
+            )
+            .await;
+
+        // Assert
+        assert_ok!(response);
+    }
+
+    #[tokio::test]
+    async fn send_email_with_options_sends_on_behalf_of_the_given_sender() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let sender = SubscriberEmail::parse("rust@lists.com".into()).unwrap();
+
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .and(body_partial_json(serde_json::json!({
+                "From": "\"Rust Weekly\" <rust@lists.com>"
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let response = email_client
+            .send_email_with_options(
+                email(),
+                &subject(),
+                &content(),
+                &content(),
+                &MessageOptions {
+                    sender: Some(&sender),
+                    sender_name: Some("Rust Weekly"),
+                    ..MessageOptions::default()
+                }
+            )
+            .await;
+
+        // Assert
+        assert_ok!(response);
+    }
+
+    #[tokio::test]
+    async fn send_batch_sends_the_emails_in_one_request() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let emails = batch(3);
+
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(path("/email/batch"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                {"ErrorCode": 0, "Message": "OK"},
+                {"ErrorCode": 0, "Message": "OK"},
+                {"ErrorCode": 0, "Message": "OK"}
+            ])))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcomes = email_client.send_batch(&emails).await;
+
+        // Assert
+        assert_eq!(outcomes.len(), 3);
+        outcomes.into_iter().for_each(|outcome| { assert_ok!(outcome); });
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+        let recipients: Vec<_> = body.iter().map(|email| email["To"].as_str().unwrap()).collect();
+        let expected: Vec<_> = emails.iter().map(|email| email.recipient.as_ref()).collect();
+        assert_eq!(recipients, expected);
+    }
+
+    #[tokio::test]
+    async fn send_batch_reports_failures_per_recipient() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                {"ErrorCode": 0, "Message": "OK"},
+                {"ErrorCode": 406, "Message": "You tried to send to a recipient that has been marked as inactive."},
+                {"ErrorCode": 0, "Message": "OK"}
+            ])))
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let mut outcomes = email_client.send_batch(&batch(3)).await.into_iter();
+
+        // Assert
+        assert_ok!(outcomes.next().unwrap());
+        let e = assert_err!(outcomes.next().unwrap());
+        assert!(e.is_permanent());
+        assert!(format!("{:?}", e).contains("marked as inactive"));
+        assert_ok!(outcomes.next().unwrap());
+    }
+
+    #[tokio::test]
+    async fn send_batch_does_not_resend_emails_postmark_accepted_without_reporting_on_them() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("<html>Accepted</html>"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                {"ErrorCode": 0, "Message": "OK"}
+            ])))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let unreadable = email_client.send_batch(&batch(2)).await;
+        let incomplete = email_client.send_batch(&batch(2)).await;
+
+        // Assert
+        unreadable.into_iter().chain(incomplete).for_each(|outcome| { assert_ok!(outcome); });
+    }
+
+    #[tokio::test]
+    async fn send_batch_tells_rate_limited_emails_from_rejected_ones() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                {"ErrorCode": 429, "Message": "Rate limit exceeded."},
+                {"ErrorCode": 405, "Message": "Not allowed to send: you have run out of credits."},
+                {"ErrorCode": 300, "Message": "Invalid email request."}
+            ])))
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let mut outcomes = email_client.send_batch(&batch(3)).await.into_iter();
+
+        // Assert
+        assert!(matches!(outcomes.next().unwrap(), Err(EmailError::Throttled { .. })));
+        assert!(matches!(outcomes.next().unwrap(), Err(EmailError::Throttled { .. })));
+        assert!(assert_err!(outcomes.next().unwrap()).is_permanent());
+    }
+
+    #[tokio::test]
+    async fn send_batch_fails_every_email_when_the_request_fails() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcomes = email_client.send_batch(&batch(2)).await;
+
+        // Assert
+        assert_eq!(outcomes.len(), 2);
+        for outcome in outcomes {
+            assert!(!assert_err!(outcome).is_permanent());
+        }
+    }
+
+    #[tokio::test]
+    async fn send_batch_splits_the_emails_into_batches_postmark_accepts() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let ok = serde_json::json!({"ErrorCode": 0, "Message": "OK"});
+
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(vec![ok; EmailClient::MAX_BATCH_SIZE]))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        // The email left over is sent on its own
+        Mock::given(path("/email"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcomes = email_client.send_batch(&batch(EmailClient::MAX_BATCH_SIZE + 1)).await;
+
+        // Assert
+        assert_eq!(outcomes.len(), EmailClient::MAX_BATCH_SIZE + 1);
+        outcomes.into_iter().for_each(|outcome| { assert_ok!(outcome); });
+    }
+}
@@ -0,0 +1,197 @@
+use std::{num::NonZeroU32, sync::Mutex};
+
+use tokio::time::{Duration, Instant};
+
+/// The quotas of a provider, none when left out.
+///
+/// The per-second quota is kept by every process on its own, the daily quota
+/// is counted in the database.
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default)]
+#[serde(default)]
+pub struct RateLimit {
+    pub per_second: Option<NonZeroU32>,
+    pub per_day: Option<NonZeroU32>
+}
+
+/// Keeps the emails sent through a provider within its per-second quota.
+///
+/// The quota is a token bucket: each email takes a token, and tokens come
+/// back at the rate of the quota. Batches take as many tokens as they have
+/// emails, possibly more than the bucket holds, and whoever comes next waits
+/// for the tokens they borrowed.
+pub(super) struct RateLimiter {
+    state: Mutex<State>
+}
+
+struct State {
+    per_second: Option<Bucket>,
+    // Set when the provider told us to back off
+    paused_until: Option<Instant>
+}
+
+struct Bucket {
+    capacity: f64,
+    tokens: f64,
+    // Tokens per second
+    refill_rate: f64,
+    refilled_at: Instant
+}
+
+impl Bucket {
+
+    fn new(capacity: NonZeroU32, period: Duration) -> Self {
+        let capacity = f64::from(capacity.get());
+        Self {
+            capacity,
+            tokens: capacity,
+            refill_rate: capacity / period.as_secs_f64(),
+            refilled_at: Instant::now()
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_rate).min(self.capacity);
+        self.refilled_at = now;
+    }
+
+    /// How long until `n` tokens can be taken. Bursts larger than the bucket
+    /// only wait for it to be full.
+    fn wait_for(&self, n: f64) -> Duration {
+        let missing = n.min(self.capacity) - self.tokens;
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / self.refill_rate)
+        }
+    }
+}
+
+impl RateLimiter {
+
+    pub fn new(per_second: Option<NonZeroU32>) -> Self {
+        let state = State {
+            per_second: per_second.map(|n| Bucket::new(n, Duration::from_secs(1))),
+            paused_until: None
+        };
+        Self { state: Mutex::new(state) }
+    }
+
+    /// The most emails worth sending at once, the per-second quota if there is one.
+    pub fn max_burst(&self) -> Option<usize> {
+        let state = self.state.lock().unwrap();
+        state.per_second.as_ref().map(|bucket| bucket.capacity as usize)
+    }
+
+    /// Takes the tokens of `n` emails, and returns how long to wait before sending them.
+    ///
+    /// Nothing is taken when the provider asked us to back off: the error
+    /// tells how long that lasts.
+    pub fn reserve(&self, n: usize) -> Result<Duration, Duration> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(paused_until) = state.paused_until {
+            if now < paused_until {
+                return Err(paused_until - now);
+            }
+            state.paused_until = None;
+        }
+
+        let n = n as f64;
+        let mut wait = Duration::ZERO;
+        if let Some(per_second) = &mut state.per_second {
+            per_second.refill(now);
+            wait = per_second.wait_for(n);
+            per_second.tokens -= n;
+        }
+        Ok(wait)
+    }
+
+    /// Gives back the tokens of `n` emails that were not sent after all.
+    pub fn release(&self, n: usize) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(per_second) = &mut state.per_second {
+            per_second.tokens = (per_second.tokens + n as f64).min(per_second.capacity);
+        }
+    }
+
+    /// Stops sending for `duration`, as a `Retry-After` header asks.
+    pub fn pause(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut state = self.state.lock().unwrap();
+        state.paused_until = Some(state.paused_until.map_or(until, |paused_until| paused_until.max(until)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use claim::{assert_err, assert_ok};
+    use tokio::time::Duration;
+
+    use super::RateLimiter;
+
+    fn limiter(per_second: Option<u32>) -> RateLimiter {
+        RateLimiter::new(per_second.and_then(NonZeroU32::new))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn emails_within_the_quota_go_out_right_away() {
+        let limiter = limiter(Some(10));
+
+        assert_eq!(assert_ok!(limiter.reserve(4)), Duration::ZERO);
+        assert_eq!(assert_ok!(limiter.reserve(6)), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn emails_over_the_quota_wait_for_tokens() {
+        let limiter = limiter(Some(10));
+        limiter.reserve(10).unwrap();
+
+        assert_eq!(assert_ok!(limiter.reserve(5)), Duration::from_millis(500));
+        // The tokens of the previous reservation are gone
+        assert_eq!(assert_ok!(limiter.reserve(1)), Duration::from_millis(600));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batches_larger_than_the_bucket_borrow_tokens() {
+        let limiter = limiter(Some(10));
+
+        assert_eq!(assert_ok!(limiter.reserve(30)), Duration::ZERO);
+        // 20 tokens borrowed, then one more
+        assert_eq!(assert_ok!(limiter.reserve(1)), Duration::from_millis(2100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tokens_come_back_over_time() {
+        let limiter = limiter(Some(10));
+        limiter.reserve(10).unwrap();
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        assert_eq!(assert_ok!(limiter.reserve(10)), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn released_tokens_can_be_taken_again() {
+        let limiter = limiter(Some(10));
+        limiter.reserve(10).unwrap();
+
+        limiter.release(4);
+
+        assert_eq!(assert_ok!(limiter.reserve(4)), Duration::ZERO);
+        assert_eq!(assert_ok!(limiter.reserve(1)), Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn nothing_goes_out_while_paused() {
+        let limiter = limiter(None);
+        limiter.pause(Duration::from_secs(30));
+
+        assert_eq!(assert_err!(limiter.reserve(1)), Duration::from_secs(30));
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(assert_ok!(limiter.reserve(1)), Duration::ZERO);
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+
+use reqwest::Client;
+use secrecy::{ExposeSecret, Secret};
+
+use super::{http::send_request, Email, EmailError, EmailTransport};
+
+/// Sends emails through SendGrid's v3 Mail Send API.
+pub struct SendGridTransport {
+    http_client: Client,
+    base_url: String,
+    api_key: Secret<String>
+}
+
+#[derive(serde::Serialize)]
+struct MailSendRequest<'a> {
+    personalizations: [Personalization<'a>; 1],
+    from: Address<'a>,
+    subject: &'a str,
+    // Plain text first, as SendGrid requires
+    content: [Content<'a>; 2],
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    headers: HashMap<&'a str, &'a str>
+}
+
+#[derive(serde::Serialize)]
+struct Personalization<'a> {
+    to: [Address<'a>; 1]
+}
+
+#[derive(serde::Serialize)]
+struct Address<'a> {
+    email: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>
+}
+
+#[derive(serde::Serialize)]
+struct Content<'a> {
+    #[serde(rename = "type")]
+    content_type: &'a str,
+    value: &'a str
+}
+
+impl SendGridTransport {
+
+    pub fn new(
+        base_url: String,
+        api_key: Secret<String>,
+        timeout: std::time::Duration
+    ) -> Self {
+
+        let http_client = Client::builder()
+            .timeout(timeout)
+            .build()
+            .unwrap();
+
+        Self {
+            http_client,
+            base_url,
+            api_key
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailTransport for SendGridTransport {
+    fn name(&self) -> &'static str {
+        "sendgrid"
+    }
+
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError> {
+
+        let url = format!("{}/v3/mail/send", self.base_url);
+        let request_body = MailSendRequest {
+            personalizations: [Personalization {
+                to: [Address { email: email.recipient.as_ref(), name: None }]
+            }],
+            from: Address { email: email.sender.as_ref(), name: email.sender_name },
+            subject: email.subject,
+            content: [
+                Content { content_type: "text/plain", value: email.text_content },
+                Content { content_type: "text/html", value: email.html_content }
+            ],
+            headers: email.headers.iter().copied().collect()
+        };
+
+        let request = self.http_client
+            .post(&url)
+            .bearer_auth(self.api_key.expose_secret())
+            .json(&request_body);
+        send_request(request, "SendGrid", |body| body["errors"][0]["message"].as_str(), |_| false).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{body_json, header, method, path};
+
+    use super::SendGridTransport;
+    use crate::{domain::SubscriberEmail, email_client::{EmailClient, MessageOptions}};
+
+    fn email_client(base_url: String) -> EmailClient {
+        EmailClient::new(
+            SendGridTransport::new(
+                base_url,
+                Secret::new("sendgrid-key".into()),
+                std::time::Duration::from_millis(200)
+            ),
+            SubscriberEmail::parse("rust@lists.com".into()).unwrap()
+        )
+    }
+
+    fn recipient() -> SubscriberEmail {
+        SubscriberEmail::parse("ursula@example.com".into()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn send_email_sends_the_expected_request() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/v3/mail/send"))
+            .and(method("POST"))
+            .and(header("Authorization", "Bearer sendgrid-key"))
+            .and(body_json(serde_json::json!({
+                "personalizations": [{"to": [{"email": "ursula@example.com"}]}],
+                "from": {"email": "rust@lists.com", "name": "Rust Weekly"},
+                "subject": "Issue #1",
+                "content": [
+                    {"type": "text/plain", "value": "Hello"},
+                    {"type": "text/html", "value": "<p>Hello</p>"}
+                ],
+                "headers": {"List-Unsubscribe": "<https://unsubscribe.me>"}
+            })))
+            .respond_with(ResponseTemplate::new(202))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client(mock_server.uri())
+            .send_email_with_options(
+                recipient(),
+                "Issue #1",
+                "<p>Hello</p>",
+                "Hello",
+                &MessageOptions {
+                    sender_name: Some("Rust Weekly"),
+                    headers: &[("List-Unsubscribe", "<https://unsubscribe.me>")],
+                    ..MessageOptions::default()
+                }
+            )
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn rejected_emails_are_permanent_failures() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/v3/mail/send"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "errors": [{"message": "Does not contain a valid address.", "field": "personalizations.0.to"}]
+            })))
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client(mock_server.uri())
+            .send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello")
+            .await;
+
+        // Assert
+        let e = assert_err!(outcome);
+        assert!(e.is_permanent());
+        assert!(format!("{:?}", e).contains("Does not contain a valid address."));
+    }
+
+    #[tokio::test]
+    async fn throttled_emails_are_transient_failures() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/v3/mail/send"))
+            .respond_with(ResponseTemplate::new(429))
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client(mock_server.uri())
+            .send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello")
+            .await;
+
+        // Assert
+        assert!(!assert_err!(outcome).is_permanent());
+    }
+
+    #[tokio::test]
+    async fn rejected_credentials_make_the_provider_unavailable() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/v3/mail/send"))
+            .respond_with(ResponseTemplate::new(401).set_body_json(serde_json::json!({
+                "errors": [{"message": "The provided authorization grant is invalid, expired, or revoked"}]
+            })))
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client(mock_server.uri())
+            .send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello")
+            .await;
+
+        // Assert
+        let e = assert_err!(outcome);
+        assert!(e.is_unavailable());
+        assert!(format!("{:?}", e).contains("authorization grant is invalid"));
+    }
+}
@@ -0,0 +1,312 @@
+use chrono::Utc;
+use reqwest::{Client, Url};
+use secrecy::Secret;
+
+use super::{http::send_request, sigv4::SigningKey, Email, EmailError, EmailTransport};
+
+/// Sends emails through the Amazon SES v2 API, signing requests with
+/// AWS Signature Version 4.
+pub struct SesTransport {
+    http_client: Client,
+    // `https://email.<region>.amazonaws.com`
+    base_url: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: Secret<String>
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendEmailRequest<'a> {
+    from_email_address: &'a str,
+    destination: Destination<'a>,
+    content: Content<'a>
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Destination<'a> {
+    to_addresses: [&'a str; 1]
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Content<'a> {
+    simple: SimpleMessage<'a>
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SimpleMessage<'a> {
+    subject: Text<'a>,
+    body: Body<'a>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<Header<'a>>
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Body<'a> {
+    text: Text<'a>,
+    html: Text<'a>
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Text<'a> {
+    data: &'a str,
+    charset: &'static str
+}
+
+impl<'a> Text<'a> {
+    fn utf8(data: &'a str) -> Self {
+        Self { data, charset: "UTF-8" }
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Header<'a> {
+    name: &'a str,
+    value: &'a str
+}
+
+impl SesTransport {
+
+    pub fn new(
+        base_url: String,
+        region: String,
+        access_key_id: String,
+        secret_access_key: Secret<String>,
+        timeout: std::time::Duration
+    ) -> Self {
+
+        let http_client = Client::builder()
+            .timeout(timeout)
+            .build()
+            .unwrap();
+
+        Self {
+            http_client,
+            base_url,
+            region,
+            access_key_id,
+            secret_access_key
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailTransport for SesTransport {
+    fn name(&self) -> &'static str {
+        "ses"
+    }
+
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError> {
+
+        let url = Url::parse(&format!("{}/v2/email/outbound-emails", self.base_url))
+            .map_err(|e| EmailError::Permanent(anyhow::Error::new(e).context("Invalid SES endpoint.")))?;
+        let from = email.sender_header();
+        let request_body = SendEmailRequest {
+            from_email_address: &from,
+            destination: Destination { to_addresses: [email.recipient.as_ref()] },
+            content: Content {
+                simple: SimpleMessage {
+                    subject: Text::utf8(email.subject),
+                    body: Body {
+                        text: Text::utf8(email.text_content),
+                        html: Text::utf8(email.html_content)
+                    },
+                    headers: email.headers
+                        .iter()
+                        .map(|(name, value)| Header { name, value })
+                        .collect()
+                }
+            }
+        };
+        // The signature covers the exact bytes we send
+        let payload = serde_json::to_vec(&request_body)
+            .map_err(|e| EmailError::Permanent(e.into()))?;
+
+        let content_type = "application/json";
+        let signature = SigningKey {
+            access_key_id: &self.access_key_id,
+            secret_access_key: &self.secret_access_key,
+            region: &self.region,
+            service: "ses"
+        }
+        .sign("POST", &url, &[("Content-Type", content_type)], &payload, Utc::now());
+
+        let request = self.http_client
+            .post(url)
+            .header("Content-Type", content_type)
+            .header("X-Amz-Date", signature.x_amz_date)
+            .header("Authorization", signature.authorization)
+            .body(payload);
+        send_request(request, "SES", |body| body["message"].as_str(), |_| false).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+    use wiremock::matchers::{body_json, header, header_exists, method, path};
+
+    use super::SesTransport;
+    use crate::{domain::SubscriberEmail, email_client::{EmailClient, MessageOptions}};
+
+    fn email_client(base_url: String) -> EmailClient {
+        EmailClient::new(
+            SesTransport::new(
+                base_url,
+                "eu-west-1".into(),
+                "AKIDEXAMPLE".into(),
+                Secret::new("ses-secret".into()),
+                std::time::Duration::from_millis(200)
+            ),
+            SubscriberEmail::parse("rust@lists.com".into()).unwrap()
+        )
+    }
+
+    fn recipient() -> SubscriberEmail {
+        SubscriberEmail::parse("ursula@example.com".into()).unwrap()
+    }
+
+    struct SignedForSes;
+
+    impl wiremock::Match for SignedForSes {
+        fn matches(&self, request: &Request) -> bool {
+            // wiremock splits header values on commas
+            let authorization = match request.headers.get(&"Authorization".into()) {
+                Some(values) => values.iter().map(|v| v.as_str()).collect::<Vec<_>>().join(","),
+                None => return false
+            };
+            authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/")
+                && authorization.contains("/eu-west-1/ses/aws4_request,")
+                && authorization.contains("SignedHeaders=content-type;host;x-amz-date,")
+                && authorization.contains("Signature=")
+        }
+    }
+
+    #[tokio::test]
+    async fn send_email_sends_the_expected_request() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/v2/email/outbound-emails"))
+            .and(method("POST"))
+            .and(header("Content-Type", "application/json"))
+            .and(header_exists("X-Amz-Date"))
+            .and(SignedForSes)
+            .and(body_json(serde_json::json!({
+                "FromEmailAddress": "\"Rust Weekly\" <rust@lists.com>",
+                "Destination": {"ToAddresses": ["ursula@example.com"]},
+                "Content": {
+                    "Simple": {
+                        "Subject": {"Data": "Issue #1", "Charset": "UTF-8"},
+                        "Body": {
+                            "Text": {"Data": "Hello", "Charset": "UTF-8"},
+                            "Html": {"Data": "<p>Hello</p>", "Charset": "UTF-8"}
+                        },
+                        "Headers": [{"Name": "List-Unsubscribe", "Value": "<https://unsubscribe.me>"}]
+                    }
+                }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"MessageId": "1"})))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client(mock_server.uri())
+            .send_email_with_options(
+                recipient(),
+                "Issue #1",
+                "<p>Hello</p>",
+                "Hello",
+                &MessageOptions {
+                    sender_name: Some("Rust Weekly"),
+                    headers: &[("List-Unsubscribe", "<https://unsubscribe.me>")],
+                    ..MessageOptions::default()
+                }
+            )
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn rejected_emails_are_permanent_failures() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/v2/email/outbound-emails"))
+            .respond_with(
+                ResponseTemplate::new(400)
+                    .insert_header("x-amzn-ErrorType", "MessageRejected")
+                    .set_body_json(serde_json::json!({
+                        "message": "Email address is not verified."
+                    }))
+            )
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client(mock_server.uri())
+            .send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello")
+            .await;
+
+        // Assert
+        let e = assert_err!(outcome);
+        assert!(e.is_permanent());
+        assert!(format!("{:?}", e).contains("Email address is not verified."));
+    }
+
+    #[tokio::test]
+    async fn throttled_emails_are_transient_failures() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/v2/email/outbound-emails"))
+            .respond_with(ResponseTemplate::new(429).set_body_json(serde_json::json!({
+                "message": "Maximum sending rate exceeded."
+            })))
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client(mock_server.uri())
+            .send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello")
+            .await;
+
+        // Assert
+        assert!(!assert_err!(outcome).is_permanent());
+    }
+
+    #[tokio::test]
+    async fn rejected_credentials_make_the_provider_unavailable() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/v2/email/outbound-emails"))
+            .respond_with(
+                ResponseTemplate::new(403)
+                    .insert_header("x-amzn-ErrorType", "UnrecognizedClientException")
+                    .set_body_json(serde_json::json!({
+                        "message": "The security token included in the request is invalid."
+                    }))
+            )
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client(mock_server.uri())
+            .send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello")
+            .await;
+
+        // Assert
+        let e = assert_err!(outcome);
+        assert!(e.is_unavailable());
+        assert!(format!("{:?}", e).contains("security token included in the request is invalid"));
+    }
+}
@@ -0,0 +1,217 @@
+use anyhow::Context;
+use lettre::{transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use secrecy::{ExposeSecret, Secret};
+
+use super::{mime::to_mime, Email, EmailError, EmailTransport};
+
+/// Sends emails through an SMTP relay.
+///
+/// STARTTLS is required, sending fails with relays that do not offer it:
+/// credentials must never travel in clear text. Connections are pooled.
+pub struct SmtpTransport(AsyncSmtpTransport<Tokio1Executor>);
+
+impl SmtpTransport {
+
+    /// `starttls` should only be turned off for a relay on the local machine,
+    /// such as MailHog during development.
+    pub fn new(
+        host: &str,
+        port: u16,
+        starttls: bool,
+        credentials: Option<(String, Secret<String>)>,
+        timeout: std::time::Duration
+    ) -> Result<Self, anyhow::Error> {
+
+        let builder = if starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+                .context("Failed to set up TLS for the SMTP relay.")?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+        };
+        let mut builder = builder.port(port).timeout(Some(timeout));
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(
+                username,
+                password.expose_secret().to_string()
+            ));
+        }
+
+        Ok(Self(builder.build()))
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailTransport for SmtpTransport {
+    fn name(&self) -> &'static str {
+        "smtp"
+    }
+
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError> {
+
+        let message = to_mime(email).map_err(EmailError::Permanent)?;
+        self.0.send(message).await.map_err(|e| {
+            // `5xx` replies are rejections, `4xx` replies ask us to come back later,
+            // anything else (network or TLS errors, timeouts) means the relay is down
+            let (permanent, transient) = (e.is_permanent(), e.is_transient());
+            let e = anyhow::Error::new(e).context("The SMTP relay did not accept the email.");
+            if permanent {
+                EmailError::Permanent(e)
+            } else if transient {
+                EmailError::Transient(e)
+            } else {
+                EmailError::Unavailable(e)
+            }
+        })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::oneshot
+    };
+
+    use super::SmtpTransport;
+    use crate::{domain::SubscriberEmail, email_client::{EmailClient, MessageOptions}};
+
+    /// A relay accepting a single email, which it sends back once the session is over.
+    /// It does not offer STARTTLS.
+    async fn fake_relay(reply_to_data: &'static str) -> (u16, oneshot::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = oneshot::channel();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut session = String::new();
+            let mut in_data = false;
+
+            writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+            while let Ok(Some(line)) = lines.next_line().await {
+                session.push_str(&line);
+                session.push('\n');
+                let reply = if in_data {
+                    if line != "." {
+                        continue;
+                    }
+                    in_data = false;
+                    reply_to_data
+                } else if line.starts_with("EHLO") {
+                    "250-localhost\r\n250 AUTH PLAIN LOGIN\r\n"
+                } else if line.starts_with("AUTH") {
+                    "235 Authentication succeeded\r\n"
+                } else if line == "DATA" {
+                    in_data = true;
+                    "354 Go ahead\r\n"
+                } else if line == "QUIT" {
+                    writer.write_all(b"221 Bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    "250 OK\r\n"
+                };
+                if writer.write_all(reply.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+            let _ = sender.send(session);
+        });
+
+        (port, receiver)
+    }
+
+    fn email_client(transport: SmtpTransport) -> EmailClient {
+        EmailClient::new(transport, SubscriberEmail::parse("rust@lists.com".into()).unwrap())
+    }
+
+    fn recipient() -> SubscriberEmail {
+        SubscriberEmail::parse("ursula@example.com".into()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn send_email_hands_the_message_to_the_relay() {
+        // Arrange
+        let (port, session) = fake_relay("250 Queued\r\n").await;
+        let transport = assert_ok!(SmtpTransport::new(
+            "127.0.0.1",
+            port,
+            false,
+            Some(("user".into(), secrecy::Secret::new("password".into()))),
+            std::time::Duration::from_secs(2)
+        ));
+        let email_client = email_client(transport);
+
+        // Act
+        let outcome = email_client
+            .send_email_with_options(
+                recipient(),
+                "Issue #1",
+                "<p>Hello</p>",
+                "Hello",
+                &MessageOptions {
+                    headers: &[("List-Unsubscribe", "<https://unsubscribe.me>")],
+                    ..MessageOptions::default()
+                }
+            )
+            .await;
+        // Close the pooled connection so that the relay reports the session
+        drop(email_client);
+
+        // Assert
+        assert_ok!(outcome);
+        let session = session.await.unwrap();
+        assert!(session.contains("AUTH PLAIN"));
+        assert!(session.contains("MAIL FROM:<rust@lists.com>"));
+        assert!(session.contains("RCPT TO:<ursula@example.com>"));
+        assert!(session.contains("Subject: Issue #1"));
+        assert!(session.contains("List-Unsubscribe: <https://unsubscribe.me>"));
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_relay_rejects_the_message() {
+        // Arrange
+        let (port, _session) = fake_relay("554 Rejected\r\n").await;
+        let transport = assert_ok!(SmtpTransport::new(
+            "127.0.0.1",
+            port,
+            false,
+            None,
+            std::time::Duration::from_secs(2)
+        ));
+
+        // Act
+        let outcome = email_client(transport)
+            .send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello")
+            .await;
+
+        // Assert
+        assert!(assert_err!(outcome).is_permanent());
+    }
+
+    #[tokio::test]
+    async fn relays_without_starttls_are_refused() {
+        // Arrange
+        let (port, _session) = fake_relay("250 Queued\r\n").await;
+        let transport = assert_ok!(SmtpTransport::new(
+            "127.0.0.1",
+            port,
+            true,
+            None,
+            std::time::Duration::from_secs(2)
+        ));
+
+        // Act
+        let outcome = email_client(transport)
+            .send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello")
+            .await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+}
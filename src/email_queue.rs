@@ -0,0 +1,443 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::Span;
+use uuid::Uuid;
+
+use crate::{
+    configuration::EmailQueueSettings,
+    domain::SubscriberEmail,
+    email_client::{EmailClient, EmailError, MessageOptions},
+    email_templates::{EmailTemplate, EmailTemplates},
+    issue_delivery_worker::ExecutionOutcome,
+    lists::get_list,
+    problem::error_chain_fmt,
+    sent_emails::record_sent_email,
+    signed_token::{TokenPurpose, TokenSigner}
+};
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+struct QueuedEmail {
+    email_id: Uuid,
+    template: String,
+    subscriber_id: Uuid,
+    list_id: Option<Uuid>,
+    n_attempts: i32,
+    n_throttled: i32
+}
+
+/// Why a queued email did not go out.
+#[derive(thiserror::Error)]
+enum SendError {
+    // The provider did not send it
+    #[error(transparent)]
+    Provider(#[from] EmailError),
+    // We could not read the subscriber, the list or the token: the database
+    // may be back next time
+    #[error(transparent)]
+    Storage(anyhow::Error),
+    // The email cannot be built, it would fail the same way next time
+    #[error(transparent)]
+    Undeliverable(anyhow::Error)
+}
+
+impl std::fmt::Debug for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl SendError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            SendError::Provider(e) => !e.is_permanent(),
+            SendError::Storage(_) => true,
+            SendError::Undeliverable(_) => false
+        }
+    }
+}
+
+/// Queues `template` for the subscriber, about the given list or about every
+/// list, as part of `transaction`.
+///
+/// The request handlers answer without waiting for the provider, which keeps
+/// their response time the same whether an email is sent or not. The email
+/// goes out in the background, and is retried like issue deliveries.
+#[tracing::instrument(skip(transaction))]
+pub async fn enqueue_email(
+    transaction: &mut PgTransaction,
+    template: EmailTemplate,
+    subscriber_id: Uuid,
+    list_id: Option<Uuid>
+) -> Result<(), sqlx::Error> {
+
+    sqlx::query!(
+        r#"
+        INSERT INTO email_queue (email_id, template, subscriber_id, list_id, next_attempt_at)
+        VALUES ($1, $2, $3, $4, now())
+        "#,
+        Uuid::new_v4(),
+        template.name(),
+        subscriber_id,
+        list_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+    Ok(())
+}
+
+/// Drops the emails of `template` still waiting for the subscriber, about the
+/// given list, as part of `transaction`.
+#[tracing::instrument(skip(transaction))]
+pub async fn cancel_queued_emails(
+    transaction: &mut PgTransaction,
+    template: EmailTemplate,
+    subscriber_id: Uuid,
+    list_id: Uuid
+) -> Result<(), sqlx::Error> {
+
+    sqlx::query!(
+        r#"
+        DELETE FROM email_queue
+        WHERE template = $1 AND subscriber_id = $2 AND list_id = $3
+        "#,
+        template.name(),
+        subscriber_id,
+        list_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+    Ok(())
+}
+
+/// Sends the emails of `email_queue` until the application stops.
+pub async fn run_email_queue_until_stopped(
+    pool: PgPool,
+    email_client: EmailClient,
+    templates: EmailTemplates,
+    settings: EmailQueueSettings,
+    base_url: String,
+    signer: TokenSigner
+) {
+    loop {
+        match try_send_queued_email(&pool, &email_client, &templates, &settings, &base_url, &signer).await {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Send a queued email",
+    skip_all,
+    fields(template = tracing::field::Empty),
+    err
+)]
+pub async fn try_send_queued_email(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    settings: &EmailQueueSettings,
+    base_url: &str,
+    signer: &TokenSigner
+) -> Result<ExecutionOutcome, anyhow::Error> {
+
+    let email = match claim_email(pool, settings.lease()).await? {
+        Some(email) => email,
+        None => return Ok(ExecutionOutcome::EmptyQueue)
+    };
+    Span::current().record("template", &email.template.as_str());
+
+    let outcome = send_email(pool, email_client, templates, &email, base_url, signer).await;
+    let mut transaction = pool.begin().await?;
+    match outcome {
+        Ok(()) => delete_email(&mut transaction, &email).await?,
+        Err(e) => {
+            tracing::error!(error.cause_chain = ?e, "Failed to send a queued email");
+            let n_attempts = email.n_attempts + 1;
+            let retry_after = match &e {
+                SendError::Provider(e) => e.retry_after(),
+                _ => None
+            };
+            if let Some(retry_after) = retry_after.filter(|_| email.n_throttled < settings.max_throttled_retries) {
+                // Waiting for a quota to come back is not a failed attempt,
+                // unless it never does
+                schedule_retry(&mut transaction, &email, email.n_attempts, email.n_throttled + 1, retry_after).await?;
+            } else if e.is_retryable() && n_attempts < settings.max_attempts {
+                let backoff = settings.backoff(n_attempts).max(retry_after.unwrap_or_default());
+                schedule_retry(&mut transaction, &email, n_attempts, email.n_throttled, backoff).await?;
+            } else {
+                // Nothing to retry, or retrying would fail the same way.
+                // `{:#}` keeps the causes, such as the response of the provider
+                let last_error = format!("{:#}", anyhow::Error::from(e));
+                move_to_dead_letters(&mut transaction, &email, n_attempts, &last_error).await?;
+            }
+        }
+    }
+    transaction.commit().await?;
+
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+/// Renders `email` and sends it, recording which provider it went through.
+async fn send_email(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    email: &QueuedEmail,
+    base_url: &str,
+    signer: &TokenSigner
+) -> Result<(), SendError> {
+
+    let template = EmailTemplate::from_name(&email.template)
+        .with_context(|| format!("{} is not a known template.", email.template))
+        .map_err(SendError::Undeliverable)?;
+    let subscriber = sqlx::query!(
+        r#"SELECT email, name FROM subscriptions WHERE id = $1"#,
+        email.subscriber_id
+        )
+        .fetch_one(pool)
+        .await
+        .context("Failed to retrieve the subscriber.")
+        .map_err(SendError::Storage)?;
+    let list = match email.list_id {
+        Some(list_id) => Some(
+            get_list(pool, list_id)
+                .await
+                .context("Failed to retrieve the list.")
+                .map_err(SendError::Storage)?
+        ),
+        None => None
+    };
+    let recipient = SubscriberEmail::parse(subscriber.email)
+        .map_err(|e| SendError::Undeliverable(anyhow::Error::msg(e)))?;
+
+    let mut context = tera::Context::new();
+    context.insert("list", &list);
+    context.insert("name", &subscriber.name);
+    let subject = match (template, &list) {
+        (EmailTemplate::Confirmation, Some(list)) => {
+            let subscription_token = match get_pending_token(pool, email.subscriber_id, list.list_id)
+                .await
+                .map_err(SendError::Storage)?
+            {
+                Some(subscription_token) => subscription_token,
+                None => {
+                    // Confirmed or expired since it was queued: there is nothing left to confirm
+                    tracing::info!("Skipping a confirmation email without a pending token");
+                    return Ok(());
+                }
+            };
+            let confirmation_link = format!(
+                "{}/subscriptions/confirm?subscription_token={}",
+                base_url,
+                subscription_token
+            );
+            context.insert("confirmation_link", &confirmation_link);
+            format!("Welcome to {}!", list.name)
+        }
+        (EmailTemplate::Welcome, Some(list)) => {
+            let preferences_link = format!(
+                "{}/subscriptions/preferences?token={}",
+                base_url,
+                signer.sign(TokenPurpose::ManagePreferences, recipient.as_ref())
+            );
+            context.insert("preferences_link", &preferences_link);
+            format!("You are subscribed to {}", list.name)
+        }
+        (EmailTemplate::UnsubscribeConfirmation, Some(list)) => {
+            format!("You have been unsubscribed from {}", list.name)
+        }
+        (EmailTemplate::UnsubscribeConfirmation, None) => "You have been unsubscribed".to_string(),
+        (template, _) => {
+            return Err(SendError::Undeliverable(anyhow::anyhow!("{} emails cannot be queued.", template.name())));
+        }
+    };
+    let rendered = templates
+        .render(template, &context)
+        .with_context(|| format!("Failed to render the {} email.", template.name()))
+        .map_err(SendError::Undeliverable)?;
+
+    let delivered = email_client
+        .send_email_with_options(
+            recipient.clone(),
+            &subject,
+            &rendered.html,
+            &rendered.text,
+            &MessageOptions {
+                sender: list.as_ref().and_then(|list| list.sender_email.as_ref()),
+                sender_name: list.as_ref().and_then(|list| list.sender_name.as_deref()),
+                ..MessageOptions::default()
+            }
+        )
+        .await?;
+
+    // The email is gone, sending it again would not help
+    if let Err(e) = record_sent_email(pool, template, recipient.as_ref(), delivered.provider).await {
+        tracing::error!(error.cause_chain = ?e, "Failed to record a sent email");
+    }
+
+    Ok(())
+}
+
+/// The token confirming the pending membership of the subscriber to the list.
+#[tracing::instrument(skip(pool))]
+async fn get_pending_token(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+    list_id: Uuid
+) -> Result<Option<String>, anyhow::Error> {
+
+    let token = sqlx::query!(
+        r#"
+        SELECT subscription_token
+        FROM subscription_tokens
+        WHERE
+            subscriber_id = $1 AND
+            list_id = $2 AND
+            consumed_at IS NULL AND
+            expires_at > now()
+        ORDER BY created_at DESC
+        LIMIT 1
+        "#,
+        subscriber_id,
+        list_id
+        )
+        .fetch_optional(pool)
+        .await
+        .context("Failed to retrieve the subscription token.")?;
+
+    Ok(token.map(|token| token.subscription_token))
+}
+
+/// Picks the next email that is due, and leases it for `lease`, the same way
+/// the issue delivery worker picks its tasks.
+#[tracing::instrument(skip_all)]
+async fn claim_email(
+    pool: &PgPool,
+    lease: Duration
+) -> Result<Option<QueuedEmail>, anyhow::Error> {
+
+    let leased_until = Utc::now() + chrono::Duration::from_std(lease)?;
+    let email = sqlx::query_as!(
+        QueuedEmail,
+        r#"
+        WITH due AS (
+            SELECT email_id
+            FROM email_queue
+            WHERE next_attempt_at <= now()
+            ORDER BY next_attempt_at
+            FOR UPDATE
+            SKIP LOCKED
+            LIMIT 1
+        )
+        UPDATE email_queue
+        SET next_attempt_at = $1
+        FROM due
+        WHERE email_queue.email_id = due.email_id
+        RETURNING
+            email_queue.email_id AS "email_id!",
+            email_queue.template AS "template!",
+            email_queue.subscriber_id AS "subscriber_id!",
+            email_queue.list_id,
+            email_queue.n_attempts AS "n_attempts!",
+            email_queue.n_throttled AS "n_throttled!"
+        "#,
+        leased_until
+        )
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(email)
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_email(
+    transaction: &mut PgTransaction,
+    email: &QueuedEmail
+) -> Result<(), anyhow::Error> {
+
+    sqlx::query!(
+        r#"DELETE FROM email_queue WHERE email_id = $1"#,
+        email.email_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(transaction, email))]
+async fn move_to_dead_letters(
+    transaction: &mut PgTransaction,
+    email: &QueuedEmail,
+    n_attempts: i32,
+    last_error: &str
+) -> Result<(), anyhow::Error> {
+
+    sqlx::query!(
+        r#"
+        INSERT INTO email_dead_letters (
+            email_id,
+            template,
+            subscriber_id,
+            list_id,
+            n_attempts,
+            last_error,
+            failed_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, now())
+        "#,
+        email.email_id,
+        email.template,
+        email.subscriber_id,
+        email.list_id,
+        n_attempts,
+        last_error
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+    tracing::warn!("Giving up on a queued email after {} attempts", n_attempts);
+    delete_email(transaction, email).await
+}
+
+#[tracing::instrument(skip(transaction, email))]
+async fn schedule_retry(
+    transaction: &mut PgTransaction,
+    email: &QueuedEmail,
+    n_attempts: i32,
+    n_throttled: i32,
+    backoff: Duration
+) -> Result<(), anyhow::Error> {
+
+    let next_attempt_at = Utc::now() + chrono::Duration::from_std(backoff)?;
+    sqlx::query!(
+        r#"
+        UPDATE email_queue
+        SET
+            n_attempts = $2,
+            n_throttled = $3,
+            next_attempt_at = $4
+        WHERE email_id = $1
+        "#,
+        email.email_id,
+        n_attempts,
+        n_throttled,
+        next_attempt_at
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+    Ok(())
+}
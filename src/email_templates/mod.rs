@@ -0,0 +1,264 @@
+mod plain_text;
+
+pub use plain_text::html_to_text;
+
+use tera::{Context, Tera};
+
+/// The emails the service sends.
+///
+/// Each of them is rendered from `<name>.html` in the templates directory,
+/// and from `<name>.txt` for the plain text part if there is one.
+#[derive(Debug, Clone, Copy)]
+pub enum EmailTemplate {
+    Confirmation,
+    Welcome,
+    Issue,
+    Digest,
+    UnsubscribeConfirmation
+}
+
+impl EmailTemplate {
+    pub const ALL: [EmailTemplate; 5] = [
+        EmailTemplate::Confirmation,
+        EmailTemplate::Welcome,
+        EmailTemplate::Issue,
+        EmailTemplate::Digest,
+        EmailTemplate::UnsubscribeConfirmation
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EmailTemplate::Confirmation => "confirmation",
+            EmailTemplate::Welcome => "welcome",
+            EmailTemplate::Issue => "issue",
+            EmailTemplate::Digest => "digest",
+            EmailTemplate::UnsubscribeConfirmation => "unsubscribe-confirmation"
+        }
+    }
+
+    /// The template with the given name, as returned by `name`.
+    pub fn from_name(name: &str) -> Option<EmailTemplate> {
+        EmailTemplate::ALL.into_iter().find(|template| template.name() == name)
+    }
+}
+
+/// The pages of the preference center and the like, rendered from the same
+/// directory as the emails so that they share their layout and escaping.
+///
+/// Each of them is rendered from `<name>.html`.
+#[derive(Debug, Clone, Copy)]
+pub enum PageTemplate {
+    Preferences
+}
+
+impl PageTemplate {
+    pub const ALL: [PageTemplate; 1] = [PageTemplate::Preferences];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PageTemplate::Preferences => "preferences"
+        }
+    }
+}
+
+/// Both parts of an email, ready to be sent.
+#[derive(Debug)]
+pub struct RenderedEmail {
+    pub html: String,
+    pub text: String
+}
+
+/// The templates of our emails and pages, loaded once at startup.
+///
+/// Templates can extend each other (`{% extends "layout.html" %}`).
+/// Values are HTML-escaped in `.html` templates, unless they go through
+/// the `safe` filter, and left untouched in `.txt` ones.
+#[derive(Clone, Debug)]
+pub struct EmailTemplates(Tera);
+
+impl EmailTemplates {
+    /// Fails if a template does not compile or if one of ours is missing,
+    /// we would rather not start than fail to send emails later on.
+    pub fn from_directory(directory: &str) -> Result<Self, tera::Error> {
+        let mut tera = Tera::new(&format!("{}/**/*", directory))?;
+        // The default escaping also escapes `/`, which mangles links for no benefit
+        tera.set_escape_fn(htmlescape::encode_minimal);
+
+        let names = EmailTemplate::ALL
+            .iter()
+            .map(EmailTemplate::name)
+            .chain(PageTemplate::ALL.iter().map(PageTemplate::name));
+        for name in names {
+            let name = format!("{}.html", name);
+            if !tera.get_template_names().any(|n| n == name) {
+                return Err(tera::Error::template_not_found(name));
+            }
+        }
+        Ok(Self(tera))
+    }
+
+    /// Renders a page, as a whole HTML document.
+    pub fn render_page(&self, page: PageTemplate, context: &Context) -> Result<String, tera::Error> {
+        self.0.render(&format!("{}.html", page.name()), context)
+    }
+
+    /// Renders the template, generating the plain text part from the HTML
+    /// when the template has no text version.
+    pub fn render(&self, template: EmailTemplate, context: &Context) -> Result<RenderedEmail, tera::Error> {
+        let html = self.0.render(&format!("{}.html", template.name()), context)?;
+        let text_name = format!("{}.txt", template.name());
+        let text = if self.0.get_template_names().any(|n| n == text_name) {
+            self.0.render(&text_name, context)?.trim_end().to_string()
+        } else {
+            html_to_text(&html)
+        };
+        Ok(RenderedEmail { html, text })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+    use tera::Context;
+    use uuid::Uuid;
+
+    use super::{EmailTemplate, EmailTemplates, PageTemplate};
+    use crate::lists::List;
+
+    fn templates() -> EmailTemplates {
+        assert_ok!(EmailTemplates::from_directory("templates"))
+    }
+
+    fn list() -> List {
+        List {
+            list_id: Uuid::new_v4(),
+            slug: "rust".into(),
+            name: "Rust <Weekly>".into(),
+            sender_email: None,
+            sender_name: None,
+            logo_url: Some("https://example.com/rust.png".into()),
+            accent_color: Some("#ff6600".into()),
+            footer_text: Some("You get this because you love Rust.".into())
+        }
+    }
+
+    #[test]
+    fn every_email_has_a_template() {
+        let templates = templates();
+        let mut context = Context::new();
+        context.insert("list", &list());
+        context.insert("name", "Ursula");
+        context.insert("confirmation_link", "https://example.com/confirm");
+        context.insert("preferences_link", "https://example.com/preferences");
+        context.insert("unsubscribe_link", "https://example.com/unsubscribe");
+        context.insert("open_pixel", "https://example.com/opens");
+        context.insert("issue", &serde_json::json!({
+            "title": "Issue #1",
+            "html_content": "<p>Hello</p>",
+            "text_content": "Hello"
+        }));
+        context.insert("subject", "Rust <Weekly> digest: Issue #1");
+        context.insert("issues", &serde_json::json!([{
+            "title": "Issue #1",
+            "html_content": "<p>Hello</p>",
+            "text_content": "Hello",
+            "open_pixel": "https://example.com/opens"
+        }]));
+
+        for template in EmailTemplate::ALL {
+            assert_ok!(templates.render(template, &context));
+        }
+    }
+
+    #[test]
+    fn a_missing_directory_is_rejected() {
+        assert_err!(EmailTemplates::from_directory("no-such-directory"));
+    }
+
+    #[test]
+    fn emails_are_wrapped_in_the_branding_of_their_list() {
+        let mut context = Context::new();
+        context.insert("list", &list());
+        context.insert("name", "Ursula");
+        context.insert("preferences_link", "https://example.com/preferences?token=a&b");
+
+        let email = assert_ok!(templates().render(EmailTemplate::Welcome, &context));
+
+        assert!(email.html.contains(r#"<div style="border-top: 4px solid #ff6600;">"#));
+        assert!(email.html.contains(r#"<img src="https://example.com/rust.png" alt="Rust &lt;Weekly&gt;">"#));
+        assert!(email.html.contains(r#"<a href="https://example.com/preferences?token=a&b">"#));
+        assert!(email.html.contains("<p>You get this because you love Rust.</p>"));
+        assert!(email.text.ends_with("You get this because you love Rust."));
+    }
+
+    #[test]
+    fn subscriber_data_is_escaped_in_html_only() {
+        let mut context = Context::new();
+        context.insert("list", &list());
+        context.insert("name", "<script>alert(1)</script>");
+        context.insert("preferences_link", "https://example.com/preferences");
+
+        let email = assert_ok!(templates().render(EmailTemplate::Welcome, &context));
+
+        assert!(!email.html.contains("<script>"));
+        assert!(email.html.contains("Hi &lt;script&gt;alert(1)&lt;/script&gt;,"));
+        assert!(email.text.contains("Hi <script>alert(1)</script>,"));
+    }
+
+    #[test]
+    fn text_templates_are_used_when_there_is_one() {
+        let mut context = Context::new();
+        context.insert("list", &list());
+        context.insert("confirmation_link", "https://example.com/confirm");
+
+        let email = assert_ok!(templates().render(EmailTemplate::Confirmation, &context));
+
+        assert_eq!(
+            email.text,
+            "Welcome to Rust <Weekly>!\n\
+            Visit https://example.com/confirm to confirm your subscription.\n\n\
+            You get this because you love Rust."
+        );
+    }
+
+    #[test]
+    fn lists_without_branding_get_a_plain_layout() {
+        let mut list = list();
+        list.logo_url = None;
+        list.accent_color = None;
+        list.footer_text = None;
+        let mut context = Context::new();
+        context.insert("list", &list);
+        context.insert("confirmation_link", "https://example.com/confirm");
+
+        let email = assert_ok!(templates().render(EmailTemplate::Confirmation, &context));
+
+        assert!(email.html.contains("<div>"));
+        assert!(!email.html.contains("<img"));
+        assert!(email.text.ends_with("to confirm your subscription."));
+    }
+
+    #[test]
+    fn pages_escape_subscriber_data() {
+        let mut context = Context::new();
+        context.insert("messages", &["<b>Saved</b>"]);
+        context.insert("query", "token=a%2Bb");
+        context.insert("name", "\"><script>alert(1)</script>");
+        context.insert("memberships", &serde_json::json!([
+            {"slug": "rust", "name": "Rust <Weekly>", "status": "confirmed"}
+        ]));
+        context.insert("topics", &serde_json::json!([{"name": "events", "checked": true}]));
+        context.insert("digest_frequencies", &["immediate", "weekly"]);
+        context.insert("digest_frequency", "weekly");
+        context.insert("unsubscribe_query", &Some("token=c"));
+
+        let page = assert_ok!(templates().render_page(PageTemplate::Preferences, &context));
+
+        assert!(!page.contains("<script>"));
+        assert!(page.contains(r#"value="&quot;&gt;&lt;script&gt;alert(1)&lt;/script&gt;""#));
+        assert!(page.contains("<p><i>&lt;b&gt;Saved&lt;/b&gt;</i></p>"));
+        assert!(page.contains(r#"value="rust" checked> Rust &lt;Weekly&gt;"#));
+        assert!(page.contains(r#"value="weekly" checked"#));
+        assert!(page.contains(r#"action="/subscriptions/unsubscribe?token=c""#));
+    }
+}
@@ -0,0 +1,277 @@
+/// Turns the HTML of an email into its plain text alternative.
+///
+/// This is not a general purpose converter: it handles what our templates and
+/// issues are made of. Block elements start new paragraphs, list items get a
+/// dash, links are followed by their URL and images are dropped.
+pub fn html_to_text(html: &str) -> String {
+    let mut converter = Converter::default();
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        converter.push_text(&rest[..start]);
+        rest = &rest[start..];
+        let end = match tag_end(rest) {
+            Some(end) => end,
+            // An unterminated tag, there is nothing to keep after it
+            None => break
+        };
+        converter.push_tag(&rest[1..end]);
+        rest = &rest[end + 1..];
+    }
+    if converter.skipped.is_none() {
+        converter.push_text(rest);
+    }
+
+    converter.finish()
+}
+
+/// The index of the `>` closing the tag `s` starts with, ignoring the ones in quoted attributes.
+fn tag_end(s: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+#[derive(Default)]
+struct Converter {
+    text: String,
+    // Elements whose content is not displayed, such as `<title>`
+    skipped: Option<String>,
+    // The targets of the links we are in
+    links: Vec<Option<String>>,
+    // Where the text of the innermost link starts
+    link_starts: Vec<usize>
+}
+
+impl Converter {
+    fn push_text(&mut self, html: &str) {
+        if self.skipped.is_some() {
+            return;
+        }
+        // Whitespace collapses as it does in a browser
+        let decoded = decode_entities(html).replace('\u{a0}', " ");
+        for (i, word) in decoded.split(|c: char| c.is_ascii_whitespace()).enumerate() {
+            if i > 0 && !self.text.is_empty() && !self.text.ends_with([' ', '\n']) {
+                self.text.push(' ');
+            }
+            self.text.push_str(word);
+        }
+    }
+
+    fn push_tag(&mut self, tag: &str) {
+        if tag.starts_with('!') || tag.starts_with('?') {
+            // Comments, doctypes and processing instructions
+            return;
+        }
+        let is_closing = tag.starts_with('/');
+        let tag = tag.trim_start_matches('/').trim_end_matches('/');
+        let name_end = tag.find(|c: char| c.is_ascii_whitespace()).unwrap_or(tag.len());
+        let name = tag[..name_end].to_ascii_lowercase();
+
+        if let Some(skipped) = &self.skipped {
+            if is_closing && *skipped == name {
+                self.skipped = None;
+            }
+            return;
+        }
+
+        match (name.as_str(), is_closing) {
+            ("head" | "title" | "style" | "script", false) => self.skipped = Some(name),
+            ("br", _) => self.line_break(),
+            ("li", false) => {
+                self.line_break();
+                self.text.push_str("- ");
+            }
+            ("p" | "div" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "ul" | "ol" | "table"
+                | "blockquote" | "pre" | "hr", _) => self.paragraph_break(),
+            ("tr", _) => self.line_break(),
+            ("a", false) => {
+                self.links.push(attribute(&tag[name_end..], "href"));
+                self.link_starts.push(self.text.len());
+            }
+            ("a", true) => {
+                let (href, start) = match (self.links.pop(), self.link_starts.pop()) {
+                    (Some(href), Some(start)) => (href, start),
+                    _ => return
+                };
+                if let Some(href) = href {
+                    // Links showing their own URL are left as they are
+                    if self.text[start..].trim() != href {
+                        self.text.push_str(&format!(" ({})", href));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn line_break(&mut self) {
+        while self.text.ends_with(' ') {
+            self.text.pop();
+        }
+        self.text.push('\n');
+    }
+
+    fn paragraph_break(&mut self) {
+        while self.text.ends_with(' ') {
+            self.text.pop();
+        }
+        if self.text.is_empty() || self.text.ends_with("\n\n") {
+            return;
+        }
+        self.text.push_str(if self.text.ends_with('\n') { "\n" } else { "\n\n" });
+    }
+
+    fn finish(self) -> String {
+        let lines: Vec<_> = self.text.lines().map(str::trim).collect();
+        let mut text = String::new();
+        let mut blank_lines = 0;
+        for line in lines {
+            if line.is_empty() {
+                blank_lines += 1;
+                continue;
+            }
+            if !text.is_empty() {
+                text.push_str(if blank_lines > 0 { "\n\n" } else { "\n" });
+            }
+            blank_lines = 0;
+            text.push_str(line);
+        }
+        text
+    }
+}
+
+/// Returns the decoded value of the attribute `name`, if the tag has it.
+fn attribute(attributes: &str, name: &str) -> Option<String> {
+    let mut rest = attributes;
+    loop {
+        rest = rest.trim_start();
+        let name_end = rest.find(|c: char| c == '=' || c.is_ascii_whitespace())?;
+        let attribute_name = &rest[..name_end];
+        rest = rest[name_end..].trim_start();
+        let value = match rest.strip_prefix('=') {
+            Some(value) => value.trim_start(),
+            // An attribute without a value
+            None => continue
+        };
+        let (value, next) = match value.chars().next()? {
+            quote @ ('"' | '\'') => {
+                let end = value[1..].find(quote)? + 1;
+                (&value[1..end], &value[end + 1..])
+            }
+            _ => {
+                let end = value.find(|c: char| c.is_ascii_whitespace()).unwrap_or(value.len());
+                (&value[..end], &value[end..])
+            }
+        };
+        if attribute_name.eq_ignore_ascii_case(name) {
+            return Some(decode_entities(value));
+        }
+        rest = next;
+    }
+}
+
+/// Decodes the character references our templates produce, leaving unknown ones as they are.
+fn decode_entities(s: &str) -> String {
+    let mut decoded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            // Entities are short, a far away `;` belongs to something else
+            Some(end) if end <= 10 => end,
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let entity = &rest[1..end];
+        let character = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32)
+        };
+        match character {
+            Some(character) => {
+                decoded.push(character);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::html_to_text;
+
+    #[test]
+    fn paragraphs_are_separated_by_a_blank_line() {
+        assert_eq!(
+            html_to_text("<div>\n    <p>Hello,</p>\n    <p>Welcome   aboard!</p>\n</div>"),
+            "Hello,\n\nWelcome aboard!"
+        );
+    }
+
+    #[test]
+    fn links_are_followed_by_their_url() {
+        assert_eq!(
+            html_to_text(r#"<p>Click <a href="https://example.com/?a=1&amp;b=2">here</a>.</p>"#),
+            "Click here (https://example.com/?a=1&b=2)."
+        );
+        assert_eq!(
+            html_to_text(r#"<a href="https://example.com">https://example.com</a>"#),
+            "https://example.com"
+        );
+    }
+
+    #[test]
+    fn entities_are_decoded() {
+        assert_eq!(
+            html_to_text("<p>Tom &amp; Jerry &lt;3 &#39;cheese&#x27; &unknown; AT&T</p>"),
+            "Tom & Jerry <3 'cheese' &unknown; AT&T"
+        );
+    }
+
+    #[test]
+    fn hidden_content_and_images_are_dropped() {
+        let html = r#"<!DOCTYPE html>
+<html>
+<head><title>Rust Weekly</title><style>p { color: red; }</style></head>
+<body><p><img src="https://example.com/logo.png" alt="Logo"></p><p>Issue #1</p>
+<!-- a comment --><img src="https://example.com/pixel" width="1" height="1" alt=""></body>
+</html>"#;
+        assert_eq!(html_to_text(html), "Issue #1");
+    }
+
+    #[test]
+    fn lists_and_line_breaks_are_kept() {
+        assert_eq!(
+            html_to_text("<p>Topics:<br>today</p><ul><li>Rust</li><li>Postgres</li></ul><p>Bye</p>"),
+            "Topics:\ntoday\n\n- Rust\n- Postgres\n\nBye"
+        );
+    }
+}
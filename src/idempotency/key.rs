@@ -0,0 +1,84 @@
+use actix_web::http::header::HeaderMap;
+
+#[derive(Debug)]
+pub struct IdempotencyKey(String);
+
+impl IdempotencyKey {
+    const HEADER: &'static str = "Idempotency-Key";
+    // Long enough for a UUID or a hash, short enough to keep the index small.
+    const MAX_LENGTH: usize = 50;
+
+    /// Returns an instance of `IdempotencyKey` if the input is neither empty
+    /// nor longer than we are willing to store.
+    pub fn parse(s: String) -> Result<IdempotencyKey, String> {
+        if s.is_empty() {
+            return Err("The idempotency key cannot be empty.".into());
+        }
+        if s.len() >= Self::MAX_LENGTH {
+            return Err(format!(
+                "The idempotency key must be shorter than {} characters.",
+                Self::MAX_LENGTH
+            ));
+        }
+        Ok(Self(s))
+    }
+
+    /// Reads the key from the `Idempotency-Key` header, clients are free to leave it out.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<IdempotencyKey>, String> {
+        match headers.get(Self::HEADER) {
+            None => Ok(None),
+            Some(value) => {
+                let value = value
+                    .to_str()
+                    .map_err(|_| "The idempotency key must be a valid string.".to_string())?;
+                Self::parse(value.to_owned()).map(Some)
+            }
+        }
+    }
+}
+
+impl AsRef<str> for IdempotencyKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdempotencyKey;
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+    use claim::{assert_err, assert_ok, assert_none};
+
+    #[test]
+    fn an_empty_key_is_rejected() {
+        assert_err!(IdempotencyKey::parse("".to_string()));
+    }
+
+    #[test]
+    fn a_49_characters_long_key_is_valid() {
+        assert_ok!(IdempotencyKey::parse("a".repeat(49)));
+    }
+
+    #[test]
+    fn a_key_longer_than_49_characters_is_rejected() {
+        assert_err!(IdempotencyKey::parse("a".repeat(50)));
+    }
+
+    #[test]
+    fn a_missing_header_is_not_an_error() {
+        let key = IdempotencyKey::from_headers(&HeaderMap::new());
+        assert_none!(assert_ok!(key));
+    }
+
+    #[test]
+    fn the_key_is_read_from_the_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("idempotency-key"),
+            HeaderValue::from_static("a-key")
+        );
+
+        let key = IdempotencyKey::from_headers(&headers).unwrap().unwrap();
+        assert_eq!(key.as_ref(), "a-key");
+    }
+}
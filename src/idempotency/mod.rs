@@ -0,0 +1,5 @@
+mod key;
+mod persistence;
+
+pub use key::IdempotencyKey;
+pub use persistence::{hash_request, save_response, try_processing, IdempotencyError, NextAction};
@@ -0,0 +1,215 @@
+use actix_web::{body::to_bytes, http::StatusCode, HttpResponse};
+use anyhow::Context;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use sqlx::{postgres::PgHasArrayType, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use super::IdempotencyKey;
+use crate::problem::error_chain_fmt;
+
+#[derive(Debug, sqlx::Type)]
+#[sqlx(type_name = "header_pair")]
+struct HeaderPairRecord {
+    name: String,
+    value: Vec<u8>
+}
+
+impl PgHasArrayType for HeaderPairRecord {
+    fn array_type_info() -> sqlx::postgres::PgTypeInfo {
+        sqlx::postgres::PgTypeInfo::with_name("_header_pair")
+    }
+}
+
+pub enum NextAction {
+    // The request has not been seen before, handle it inside this
+    // transaction and hand it back to `save_response`.
+    StartProcessing(Box<Transaction<'static, Postgres>>),
+    // The request has already been handled, replay the stored response.
+    ReturnSavedResponse(HttpResponse)
+}
+
+#[derive(thiserror::Error)]
+pub enum IdempotencyError {
+    #[error("The idempotency key was already used for a different request.")]
+    KeyReused,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error)
+}
+
+impl std::fmt::Debug for IdempotencyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+/// Hashes the body of a request, to tell whether a retry is the same request.
+pub fn hash_request(body: &impl serde::Serialize) -> Result<Vec<u8>, anyhow::Error> {
+    let body = serde_json::to_vec(body).context("Failed to serialize the request.")?;
+    Ok(Sha256::digest(body).to_vec())
+}
+
+/// Claims the idempotency key for the user.
+///
+/// The row is inserted in a transaction that stays open until the response
+/// is saved: a concurrent request with the same key blocks on the insert until
+/// the first one commits, then gets the stored response back. If the first
+/// request fails, its transaction rolls back and the next one starts over.
+///
+/// A key claimed more than `expiry` ago is claimed again, as if it was new.
+/// Reusing a key that is still valid for a request with another body is an error.
+#[tracing::instrument(
+    name = "Try processing an idempotent request",
+    skip(pool, request_hash)
+)]
+pub async fn try_processing(
+    pool: &PgPool,
+    idempotency_key: &IdempotencyKey,
+    user_id: Uuid,
+    request_hash: &[u8],
+    expiry: chrono::Duration
+) -> Result<NextAction, IdempotencyError> {
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let now = Utc::now();
+    let n_claimed_rows = sqlx::query!(
+        r#"
+        INSERT INTO idempotency (
+            user_id,
+            idempotency_key,
+            request_hash,
+            created_at
+        )
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (user_id, idempotency_key) DO UPDATE
+        SET
+            request_hash = EXCLUDED.request_hash,
+            created_at = EXCLUDED.created_at,
+            response_status_code = NULL,
+            response_headers = NULL,
+            response_body = NULL
+        WHERE idempotency.created_at < $5
+        "#,
+        user_id,
+        idempotency_key.as_ref(),
+        request_hash,
+        now,
+        now - expiry
+        )
+        .execute(&mut transaction)
+        .await
+        .context("Failed to claim the idempotency key.")?
+        .rows_affected();
+
+    if n_claimed_rows > 0 {
+        return Ok(NextAction::StartProcessing(Box::new(transaction)));
+    }
+
+    let (saved_hash, saved_response) = get_saved_response(pool, idempotency_key, user_id)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("We expected a saved response, we didn't find it."))?;
+    if saved_hash != request_hash {
+        return Err(IdempotencyError::KeyReused);
+    }
+    Ok(NextAction::ReturnSavedResponse(saved_response))
+}
+
+#[tracing::instrument(
+    name = "Get saved response",
+    skip(pool)
+)]
+async fn get_saved_response(
+    pool: &PgPool,
+    idempotency_key: &IdempotencyKey,
+    user_id: Uuid
+) -> Result<Option<(Vec<u8>, HttpResponse)>, anyhow::Error> {
+
+    let saved_response = sqlx::query!(
+        r#"
+        SELECT
+            request_hash,
+            response_status_code as "response_status_code!",
+            response_headers as "response_headers!: Vec<HeaderPairRecord>",
+            response_body as "response_body!"
+        FROM idempotency
+        WHERE
+            user_id = $1 AND
+            idempotency_key = $2
+        "#,
+        user_id,
+        idempotency_key.as_ref()
+        )
+        .fetch_optional(pool)
+        .await
+        .context("Failed to retrieve a saved response.")?;
+
+    match saved_response {
+        Some(r) => {
+            let status_code = StatusCode::from_u16(r.response_status_code.try_into()?)?;
+            let mut response = HttpResponse::build(status_code);
+            for HeaderPairRecord { name, value } in r.response_headers {
+                response.append_header((name, value));
+            }
+            Ok(Some((r.request_hash, response.body(r.response_body))))
+        }
+        None => Ok(None)
+    }
+}
+
+/// Stores the response next to the idempotency key and commits the
+/// transaction opened by `try_processing`.
+#[tracing::instrument(
+    name = "Save response",
+    skip(transaction, http_response)
+)]
+pub async fn save_response(
+    mut transaction: Transaction<'static, Postgres>,
+    idempotency_key: &IdempotencyKey,
+    user_id: Uuid,
+    http_response: HttpResponse
+) -> Result<HttpResponse, anyhow::Error> {
+
+    let (response_head, body) = http_response.into_parts();
+    // `MessageBody::Error` is not `Send` + `Sync`,
+    // therefore it doesn't play nicely with `anyhow`
+    let body = to_bytes(body).await.map_err(|e| anyhow::anyhow!("{}", e))?;
+    let status_code = response_head.status().as_u16() as i16;
+    let headers = response_head
+        .headers()
+        .iter()
+        .map(|(name, value)| HeaderPairRecord {
+            name: name.as_str().to_owned(),
+            value: value.as_bytes().to_owned()
+        })
+        .collect::<Vec<_>>();
+
+    // `query_unchecked!` because the macros cannot check custom composite types
+    sqlx::query_unchecked!(
+        r#"
+        UPDATE idempotency
+        SET
+            response_status_code = $3,
+            response_headers = $4,
+            response_body = $5
+        WHERE
+            user_id = $1 AND
+            idempotency_key = $2
+        "#,
+        user_id,
+        idempotency_key.as_ref(),
+        status_code,
+        headers,
+        body.as_ref()
+        )
+        .execute(&mut transaction)
+        .await
+        .context("Failed to save the response.")?;
+    transaction.commit().await.context("Failed to commit the idempotent request.")?;
+
+    // `into_parts` consumed the body, put it back together
+    let http_response = response_head.set_body(body).map_into_boxed_body();
+    Ok(http_response)
+}
@@ -0,0 +1,729 @@
+use std::{collections::HashMap, time::Duration};
+
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::Span;
+use uuid::Uuid;
+
+use crate::{
+    configuration::IssueDeliverySettings,
+    domain::SubscriberEmail,
+    email_client::{BatchEmail, EmailClient, EmailError, MessageOptions},
+    email_templates::{EmailTemplate, EmailTemplates, RenderedEmail},
+    lists::{get_lists_by_id, List},
+    merge_tags::{MergeTemplate, Recipient},
+    signed_token::{TokenPurpose, TokenSigner}
+};
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue
+}
+
+struct DeliveryTask {
+    newsletter_issue_id: Uuid,
+    subscriber_email: String,
+    // The list the subscriber receives the issue through
+    list_id: Uuid,
+    // Part of a daily or weekly digest, sent along with the other deliveries
+    // of the digest rather than on its own
+    digest: bool,
+    n_attempts: i32,
+    n_throttled: i32
+}
+
+#[derive(serde::Serialize)]
+struct NewsletterIssue {
+    title: String,
+    text_content: String,
+    html_content: String
+}
+
+impl NewsletterIssue {
+    /// The copy of the issue `recipient` gets, with its merge tags rendered.
+    ///
+    /// Publishing checks the merge tags. An issue edited in the database since
+    /// may not parse anymore: it fails rather than show its tags as written.
+    fn personalize(&self, recipient: &Recipient) -> Result<NewsletterIssue, anyhow::Error> {
+        let render = |content: &str, render: fn(&MergeTemplate, &Recipient) -> String| {
+            MergeTemplate::parse(content)
+                .map(|template| render(&template, recipient))
+                .map_err(|e| anyhow::anyhow!("The issue has an invalid merge tag: {}", e))
+        };
+        Ok(NewsletterIssue {
+            title: render(&self.title, MergeTemplate::render_text)?,
+            text_content: render(&self.text_content, MergeTemplate::render_text)?,
+            html_content: render(&self.html_content, MergeTemplate::render_html)?
+        })
+    }
+}
+
+/// An issue in a digest, along with its own open pixel.
+#[derive(serde::Serialize)]
+struct DigestIssue {
+    #[serde(flatten)]
+    issue: NewsletterIssue,
+    open_pixel: String
+}
+
+struct Subscriber {
+    name: String,
+    attributes: serde_json::Value,
+    // The lists they are still a confirmed member of
+    confirmed_lists: Vec<Uuid>
+}
+
+/// Pulls delivery tasks from `issue_delivery_queue` until the application stops.
+///
+/// Tasks are only removed once the email went out, so nothing is lost if the
+/// process dies half way through an issue. Several workers, in this process or
+/// in other replicas, can safely consume the same queue.
+pub async fn run_worker_until_stopped(
+    pool: PgPool,
+    email_client: EmailClient,
+    templates: EmailTemplates,
+    settings: IssueDeliverySettings,
+    base_url: String,
+    signer: TokenSigner
+) {
+    loop {
+        match try_execute_task(&pool, &email_client, &templates, &settings, &base_url, &signer).await {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
+
+/// An issue rendered for one of its recipients, waiting to be sent.
+struct Delivery {
+    recipient: SubscriberEmail,
+    subject: String,
+    email: RenderedEmail,
+    sender: Option<SubscriberEmail>,
+    sender_name: Option<String>,
+    list_unsubscribe: String
+}
+
+/// What the deliveries of a batch are made of.
+struct BatchData {
+    issues: HashMap<Uuid, NewsletterIssue>,
+    lists: HashMap<Uuid, List>,
+    subscribers: HashMap<String, Subscriber>
+}
+
+impl BatchData {
+    /// Renders the issues of `tasks` for `recipient`: a single issue on its
+    /// own, or the issues of a digest together.
+    ///
+    /// The tasks of a digest all go to the same subscriber through the same list.
+    fn delivery(
+        &self,
+        tasks: &[DeliveryTask],
+        recipient: SubscriberEmail,
+        templates: &EmailTemplates,
+        base_url: &str,
+        signer: &TokenSigner
+    ) -> Result<Delivery, anyhow::Error> {
+
+        let task = &tasks[0];
+        let list = self.lists
+            .get(&task.list_id)
+            .ok_or_else(|| anyhow::anyhow!("The list {} does not exist.", task.list_id))?;
+        let subscriber = self.subscribers
+            .get(&task.subscriber_email)
+            .ok_or_else(|| anyhow::anyhow!("The subscriber {} does not exist.", task.subscriber_email))?;
+
+        let unsubscribe_link = format!(
+            "{}/subscriptions/unsubscribe?list={}&token={}",
+            base_url,
+            list.slug,
+            signer.sign(TokenPurpose::UnsubscribeFromList(&list.slug), recipient.as_ref())
+        );
+        let preferences_link = format!(
+            "{}/subscriptions/preferences?token={}",
+            base_url,
+            signer.sign(TokenPurpose::ManagePreferences, recipient.as_ref())
+        );
+        let merge_recipient = Recipient {
+            name: &subscriber.name,
+            email: recipient.as_ref(),
+            custom: &subscriber.attributes,
+            unsubscribe_url: &unsubscribe_link,
+            preferences_url: &preferences_link
+        };
+        let mut issues = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            let issue = self.issues
+                .get(&task.newsletter_issue_id)
+                .ok_or_else(|| anyhow::anyhow!("The issue {} does not exist.", task.newsletter_issue_id))?;
+            // Segments can filter on engagement, which the pixel records
+            let open_pixel = format!(
+                "{}/subscriptions/opens?issue={}&token={}",
+                base_url,
+                task.newsletter_issue_id,
+                signer.sign(TokenPurpose::TrackOpens, recipient.as_ref())
+            );
+            issues.push(DigestIssue {
+                issue: issue.personalize(&merge_recipient)?,
+                open_pixel
+            });
+        }
+
+        let mut context = tera::Context::new();
+        context.insert("list", list);
+        context.insert("unsubscribe_link", &unsubscribe_link);
+        context.insert("preferences_link", &preferences_link);
+        let (subject, email) = if task.digest {
+            let subject = match issues.as_slice() {
+                [issue] => format!("{} digest: {}", list.name, issue.issue.title),
+                issues => format!("{} digest: {} new issues", list.name, issues.len())
+            };
+            context.insert("subject", &subject);
+            context.insert("issues", &issues);
+            (subject, templates.render(EmailTemplate::Digest, &context)?)
+        } else {
+            let DigestIssue { issue, open_pixel } = issues
+                .pop()
+                .ok_or_else(|| anyhow::anyhow!("There is no issue to deliver."))?;
+            context.insert("issue", &issue);
+            context.insert("open_pixel", &open_pixel);
+            (issue.title, templates.render(EmailTemplate::Issue, &context)?)
+        };
+
+        Ok(Delivery {
+            recipient,
+            subject,
+            email,
+            sender: list.sender_email.clone(),
+            sender_name: list.sender_name.clone(),
+            // RFC 8058: mailbox providers show an unsubscribe button which
+            // POSTs to the link without the subscriber leaving their inbox.
+            list_unsubscribe: format!("<{}>", unsubscribe_link)
+        })
+    }
+}
+
+/// What happened to a delivery task.
+enum TaskOutcome {
+    // The email went out through this provider
+    Delivered(&'static str),
+    // The email could not be sent
+    Failed(EmailError),
+    // The email could not be built: its issue, list or subscriber is gone,
+    // or its template or merge tags failed. It would fail the same way next time.
+    Undeliverable(anyhow::Error),
+    // The stored address of the subscriber is invalid, or they left the list
+    Dropped
+}
+
+/// Sends up to `settings.batch_size` of the deliveries that are due, at once,
+/// fewer when the rate limit of the email client is lower. The deliveries of
+/// a digest count as one: they are sent together, in a single email.
+///
+/// The deliveries are leased while their emails are sent, no lock is held in
+/// the meantime. Each email is then completed, retried or moved to the dead
+/// letters on its own, depending on what happened to it: failing to record
+/// one outcome does not affect the others.
+#[tracing::instrument(
+    name = "Execute a batch of issue delivery tasks",
+    skip_all,
+    fields(n_tasks = tracing::field::Empty),
+    err
+)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    settings: &IssueDeliverySettings,
+    base_url: &str,
+    signer: &TokenSigner
+) -> Result<ExecutionOutcome, anyhow::Error> {
+
+    // Batches within the per-second quota go out without waiting for tokens,
+    // and leave some for the emails sent by the request handlers.
+    let batch_size = u32::try_from(email_client.max_batch_size())
+        .map_or(settings.batch_size, |max| max.min(settings.batch_size));
+    let tasks = claim_tasks(pool, batch_size, settings.lease()).await?;
+    if tasks.is_empty() {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    }
+    Span::current().record("n_tasks", &tasks.len());
+
+    // The tasks of a batch mostly share their issue and list. Nothing has been
+    // sent if loading them fails: the tasks are picked up again once their
+    // lease expires.
+    let mut issue_ids: Vec<Uuid> = tasks.iter().map(|task| task.newsletter_issue_id).collect();
+    issue_ids.sort();
+    issue_ids.dedup();
+    let mut list_ids: Vec<Uuid> = tasks.iter().map(|task| task.list_id).collect();
+    list_ids.sort();
+    list_ids.dedup();
+    let emails: Vec<String> = tasks.iter().map(|task| task.subscriber_email.clone()).collect();
+    let data = BatchData {
+        issues: get_issues(pool, &issue_ids).await?,
+        lists: get_lists_by_id(pool, &list_ids)
+            .await?
+            .into_iter()
+            .map(|list| (list.list_id, list))
+            .collect(),
+        subscribers: get_subscribers(pool, &emails).await?
+    };
+
+    // The deliveries of a digest go out in one email
+    let mut units: Vec<Vec<DeliveryTask>> = Vec::with_capacity(tasks.len());
+    let mut digests: HashMap<(String, Uuid), usize> = HashMap::new();
+    for task in tasks {
+        if !task.digest {
+            units.push(vec![task]);
+            continue;
+        }
+        match digests.get(&(task.subscriber_email.clone(), task.list_id)) {
+            Some(&i) => units[i].push(task),
+            None => {
+                digests.insert((task.subscriber_email.clone(), task.list_id), units.len());
+                units.push(vec![task]);
+            }
+        }
+    }
+
+    let mut deliveries = Vec::with_capacity(units.len());
+    for unit in units {
+        let task = &unit[0];
+        let recipient = match SubscriberEmail::parse(task.subscriber_email.clone()) {
+            Ok(recipient) => recipient,
+            Err(error) => {
+                // Retrying will not make the address valid, drop the task.
+                tracing::error!(
+                    error = %error,
+                    subscriber_email = %task.subscriber_email,
+                    "Skipping a confirmed subscriber. \
+                    Their stored contact details are invalid"
+                );
+                settle_tasks(pool, &unit, TaskOutcome::Dropped, settings).await;
+                continue;
+            }
+        };
+        // Daily and weekly deliveries are held for a while, the subscriber may have left since
+        let left_the_list = data.subscribers
+            .get(&task.subscriber_email)
+            .is_some_and(|subscriber| !subscriber.confirmed_lists.contains(&task.list_id));
+        if left_the_list {
+            tracing::info!(
+                subscriber_email = %task.subscriber_email,
+                "Skipping a subscriber who left the list since the issue was published"
+            );
+            settle_tasks(pool, &unit, TaskOutcome::Dropped, settings).await;
+            continue;
+        }
+        match data.delivery(&unit, recipient, templates, base_url, signer) {
+            Ok(delivery) => deliveries.push((unit, delivery)),
+            Err(e) => settle_tasks(pool, &unit, TaskOutcome::Undeliverable(e), settings).await
+        }
+    }
+
+    let headers: Vec<[(&str, &str); 2]> = deliveries
+        .iter()
+        .map(|(_, delivery)| [
+            ("List-Unsubscribe", delivery.list_unsubscribe.as_str()),
+            ("List-Unsubscribe-Post", "List-Unsubscribe=One-Click")
+        ])
+        .collect();
+    let emails: Vec<BatchEmail> = deliveries
+        .iter()
+        .zip(&headers)
+        .map(|((_, delivery), headers)| BatchEmail {
+            recipient: delivery.recipient.clone(),
+            subject: &delivery.subject,
+            html_content: &delivery.email.html,
+            text_content: &delivery.email.text,
+            options: MessageOptions {
+                sender: delivery.sender.as_ref(),
+                sender_name: delivery.sender_name.as_deref(),
+                headers
+            }
+        })
+        .collect();
+    let outcomes = email_client.send_batch(&emails).await;
+
+    for ((unit, _), outcome) in deliveries.iter().zip(outcomes) {
+        let outcome = match outcome {
+            Ok(delivered) => TaskOutcome::Delivered(delivered.provider),
+            Err(e) => TaskOutcome::Failed(e)
+        };
+        settle_tasks(pool, unit, outcome, settings).await;
+    }
+
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+/// Records the outcome of the email sent for `tasks`, in a transaction of
+/// their own: a single task, or the tasks of a digest.
+///
+/// Failures are logged: the tasks are left alone, and picked up again once
+/// their lease expires.
+async fn settle_tasks(
+    pool: &PgPool,
+    tasks: &[DeliveryTask],
+    outcome: TaskOutcome,
+    settings: &IssueDeliverySettings
+) {
+    if let Err(e) = try_settle_tasks(pool, tasks, outcome, settings).await {
+        tracing::error!(
+            error.cause_chain = ?e,
+            newsletter_issue_ids = ?issue_ids(tasks),
+            subscriber_email = %tasks[0].subscriber_email,
+            "Failed to record the outcome of an issue delivery"
+        );
+    }
+}
+
+fn issue_ids(tasks: &[DeliveryTask]) -> Vec<Uuid> {
+    tasks.iter().map(|task| task.newsletter_issue_id).collect()
+}
+
+async fn try_settle_tasks(
+    pool: &PgPool,
+    tasks: &[DeliveryTask],
+    outcome: TaskOutcome,
+    settings: &IssueDeliverySettings
+) -> Result<(), anyhow::Error> {
+
+    let mut transaction = pool.begin().await?;
+    match outcome {
+        TaskOutcome::Delivered(provider) => {
+            for task in tasks {
+                record_delivery(&mut transaction, task, provider).await?;
+                delete_task(&mut transaction, task).await?;
+            }
+        }
+        TaskOutcome::Dropped => {
+            for task in tasks {
+                delete_task(&mut transaction, task).await?;
+            }
+        }
+        TaskOutcome::Undeliverable(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                newsletter_issue_ids = ?issue_ids(tasks),
+                subscriber_email = %tasks[0].subscriber_email,
+                "Failed to build an issue for a confirmed subscriber"
+            );
+            let last_error = format!("{:#}", e);
+            for task in tasks {
+                move_to_dead_letters(&mut transaction, task, task.n_attempts + 1, &last_error).await?;
+            }
+        }
+        TaskOutcome::Failed(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                newsletter_issue_ids = ?issue_ids(tasks),
+                subscriber_email = %tasks[0].subscriber_email,
+                "Failed to deliver issue to a confirmed subscriber"
+            );
+            // The tasks of a digest are retried together: they share their
+            // counters from now on, and are due again at the same time.
+            let n_attempts = tasks.iter().map(|task| task.n_attempts).max().unwrap_or_default() + 1;
+            let n_throttled = tasks.iter().map(|task| task.n_throttled).max().unwrap_or_default();
+            let retry_after = e.retry_after();
+            match retry_after {
+                // Waiting for a quota to come back is not a failed attempt,
+                // unless it never does
+                Some(retry_after) if n_throttled < settings.max_throttled_retries => {
+                    for task in tasks {
+                        schedule_retry(&mut transaction, task, n_attempts - 1, n_throttled + 1, retry_after).await?;
+                    }
+                }
+                _ if e.is_permanent() || n_attempts >= settings.max_attempts => {
+                    // Retrying a rejected email would only get it rejected again.
+                    // `{:#}` keeps the causes, such as the response of the provider
+                    let last_error = format!("{:#}", anyhow::Error::from(e));
+                    for task in tasks {
+                        move_to_dead_letters(&mut transaction, task, n_attempts, &last_error).await?;
+                    }
+                }
+                _ => {
+                    let backoff = settings.backoff(n_attempts).max(retry_after.unwrap_or_default());
+                    for task in tasks {
+                        schedule_retry(&mut transaction, task, n_attempts, n_throttled, backoff).await?;
+                    }
+                }
+            }
+        }
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+/// Picks up to `batch_size` of the tasks that are due, along with the other
+/// due tasks of the digests among them, and leases them for `lease`.
+///
+/// Leased tasks are not due anymore, so other workers skip them while this
+/// one sends their emails, without a transaction held open in the meantime.
+/// Tasks a worker never settles, because it died half way through a batch,
+/// are due again once their lease expires.
+#[tracing::instrument(skip_all)]
+async fn claim_tasks(
+    pool: &PgPool,
+    batch_size: u32,
+    lease: Duration
+) -> Result<Vec<DeliveryTask>, anyhow::Error> {
+
+    let leased_until = Utc::now() + chrono::Duration::from_std(lease)?;
+    let mut transaction = pool.begin().await?;
+    // Two workers claiming at the same time could split a digest between
+    // them. Claims are a single short statement, they take turns.
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext('issue_delivery_queue'))")
+        .execute(&mut transaction)
+        .await?;
+    // `SKIP LOCKED` lets workers pick different tasks instead of waiting
+    // on the ones being settled.
+    let tasks = sqlx::query_as!(
+        DeliveryTask,
+        r#"
+        WITH picked AS (
+            SELECT newsletter_issue_id, subscriber_email, list_id, digest
+            FROM issue_delivery_queue
+            WHERE next_attempt_at <= now()
+            ORDER BY next_attempt_at
+            FOR UPDATE
+            SKIP LOCKED
+            LIMIT $1
+        ),
+        companions AS (
+            SELECT q.newsletter_issue_id, q.subscriber_email
+            FROM issue_delivery_queue q
+            JOIN picked p ON
+                p.subscriber_email = q.subscriber_email AND
+                p.list_id = q.list_id
+            WHERE
+                p.digest AND
+                q.digest AND
+                q.next_attempt_at <= now()
+            FOR UPDATE OF q
+            SKIP LOCKED
+        ),
+        due AS (
+            SELECT newsletter_issue_id, subscriber_email FROM picked
+            UNION
+            SELECT newsletter_issue_id, subscriber_email FROM companions
+        )
+        UPDATE issue_delivery_queue
+        SET next_attempt_at = $2
+        FROM due
+        WHERE
+            issue_delivery_queue.newsletter_issue_id = due.newsletter_issue_id AND
+            issue_delivery_queue.subscriber_email = due.subscriber_email
+        RETURNING
+            issue_delivery_queue.newsletter_issue_id AS "newsletter_issue_id!",
+            issue_delivery_queue.subscriber_email AS "subscriber_email!",
+            issue_delivery_queue.list_id AS "list_id!",
+            issue_delivery_queue.digest AS "digest!",
+            issue_delivery_queue.n_attempts AS "n_attempts!",
+            issue_delivery_queue.n_throttled AS "n_throttled!"
+        "#,
+        i64::from(batch_size),
+        leased_until
+        )
+        .fetch_all(&mut transaction)
+        .await?;
+    transaction.commit().await?;
+
+    Ok(tasks)
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_task(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask
+) -> Result<(), anyhow::Error> {
+
+    sqlx::query!(
+        r#"
+        DELETE FROM issue_delivery_queue
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(transaction, task))]
+async fn record_delivery(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    provider: &str
+) -> Result<(), anyhow::Error> {
+
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_deliveries (
+            newsletter_issue_id,
+            subscriber_email,
+            provider,
+            delivered_at
+        )
+        VALUES ($1, $2, $3, now())
+        ON CONFLICT (newsletter_issue_id, subscriber_email) DO UPDATE
+        SET
+            provider = EXCLUDED.provider,
+            delivered_at = EXCLUDED.delivered_at
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        provider
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(transaction, task))]
+async fn schedule_retry(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    n_attempts: i32,
+    n_throttled: i32,
+    backoff: Duration
+) -> Result<(), anyhow::Error> {
+
+    let next_attempt_at = Utc::now() + chrono::Duration::from_std(backoff)?;
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET
+            n_attempts = $3,
+            n_throttled = $4,
+            next_attempt_at = $5
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        n_attempts,
+        n_throttled,
+        next_attempt_at
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(transaction, task, last_error))]
+async fn move_to_dead_letters(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    n_attempts: i32,
+    last_error: &str
+) -> Result<(), anyhow::Error> {
+
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_dead_letters (
+            newsletter_issue_id,
+            subscriber_email,
+            list_id,
+            digest,
+            n_attempts,
+            last_error,
+            failed_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, now())
+        ON CONFLICT (newsletter_issue_id, subscriber_email) DO UPDATE
+        SET
+            list_id = EXCLUDED.list_id,
+            digest = EXCLUDED.digest,
+            n_attempts = EXCLUDED.n_attempts,
+            last_error = EXCLUDED.last_error,
+            failed_at = EXCLUDED.failed_at
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        task.list_id,
+        task.digest,
+        n_attempts,
+        last_error
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+    tracing::warn!("Giving up on delivery after {} attempts", n_attempts);
+    delete_task(transaction, task).await
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_issues(
+    pool: &PgPool,
+    issue_ids: &[Uuid]
+) -> Result<HashMap<Uuid, NewsletterIssue>, anyhow::Error> {
+
+    let issues = sqlx::query!(
+        r#"
+        SELECT newsletter_issue_id, title, text_content, html_content
+        FROM newsletter_issues
+        WHERE
+            newsletter_issue_id = ANY($1)
+        "#,
+        issue_ids
+        )
+        .fetch_all(pool)
+        .await?;
+
+    Ok(issues
+        .into_iter()
+        .map(|i| (
+            i.newsletter_issue_id,
+            NewsletterIssue { title: i.title, text_content: i.text_content, html_content: i.html_content }
+        ))
+        .collect())
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_subscribers(
+    pool: &PgPool,
+    emails: &[String]
+) -> Result<HashMap<String, Subscriber>, anyhow::Error> {
+
+    let subscribers = sqlx::query!(
+        r#"
+        SELECT
+            s.email,
+            s.name,
+            s.attributes,
+            ARRAY(
+                SELECT m.list_id
+                FROM list_memberships m
+                WHERE m.subscriber_id = s.id AND m.status = 'confirmed'
+            ) as "confirmed_lists!"
+        FROM subscriptions s
+        WHERE
+            s.email = ANY($1)
+        "#,
+        emails
+        )
+        .fetch_all(pool)
+        .await?;
+
+    Ok(subscribers
+        .into_iter()
+        .map(|s| (
+            s.email,
+            Subscriber { name: s.name, attributes: s.attributes, confirmed_lists: s.confirmed_lists }
+        ))
+        .collect())
+}
@@ -1,6 +1,22 @@
-pub mod configuration;
-pub mod domain;
-pub mod email_client;
-pub mod routes;
-pub mod startup;
-pub mod telemetry;
\ No newline at end of file
+pub mod authentication;
+pub mod configuration;
+pub mod domain;
+pub mod email_client;
+pub mod email_queue;
+pub mod email_templates;
+pub mod idempotency;
+pub mod issue_delivery_worker;
+pub mod lists;
+pub mod merge_tags;
+pub mod problem;
+pub mod routes;
+pub mod segments;
+pub mod sent_emails;
+pub mod session_state;
+pub mod session_store;
+pub mod signed_token;
+pub mod startup;
+pub mod subscriber_fields;
+pub mod sweeper;
+pub mod telemetry;
+pub mod utils;
\ No newline at end of file
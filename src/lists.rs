@@ -0,0 +1,145 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::domain::SubscriberEmail;
+
+/// A mailing list people can subscribe to.
+///
+/// Each list sends its emails with its own sender identity and branding,
+/// the settings of the email client are used for whatever is left out.
+/// The email templates lay the branding out.
+#[derive(serde::Serialize, Debug)]
+pub struct List {
+    pub list_id: Uuid,
+    pub slug: String,
+    pub name: String,
+    /// The address emails of the list are sent from, if it has its own.
+    pub sender_email: Option<SubscriberEmail>,
+    pub sender_name: Option<String>,
+    pub logo_url: Option<String>,
+    pub accent_color: Option<String>,
+    pub footer_text: Option<String>
+}
+
+/// A row of the `lists` table.
+pub(crate) struct ListRecord {
+    pub list_id: Uuid,
+    pub slug: String,
+    pub name: String,
+    pub sender_email: Option<String>,
+    pub sender_name: Option<String>,
+    pub logo_url: Option<String>,
+    pub accent_color: Option<String>,
+    pub footer_text: Option<String>
+}
+
+impl From<ListRecord> for List {
+    fn from(record: ListRecord) -> Self {
+        // Sender addresses are validated when the list is created, one going
+        // bad in the database falls back to the sender of the email client.
+        let sender_email = record.sender_email.and_then(|sender_email| {
+            SubscriberEmail::parse(sender_email)
+                .map_err(|e| {
+                    tracing::error!(list = %record.slug, "Invalid sender email stored for the list: {}", e);
+                })
+                .ok()
+        });
+        Self {
+            list_id: record.list_id,
+            slug: record.slug,
+            name: record.name,
+            sender_email,
+            sender_name: record.sender_name,
+            logo_url: record.logo_url,
+            accent_color: record.accent_color,
+            footer_text: record.footer_text
+        }
+    }
+}
+
+#[tracing::instrument(name = "Get list by slug", skip(pool))]
+pub async fn get_list_by_slug(pool: &PgPool, slug: &str) -> Result<Option<List>, sqlx::Error> {
+
+    sqlx::query_as!(
+        ListRecord,
+        r#"
+        SELECT list_id, slug, name, sender_email, sender_name, logo_url, accent_color, footer_text
+        FROM lists
+        WHERE slug = $1
+        "#,
+        slug
+        )
+        .fetch_optional(pool)
+        .await
+        .map(|record| record.map(List::from))
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )
+}
+
+#[tracing::instrument(name = "Get list by id", skip(pool))]
+pub async fn get_list(pool: &PgPool, list_id: Uuid) -> Result<List, sqlx::Error> {
+
+    sqlx::query_as!(
+        ListRecord,
+        r#"
+        SELECT list_id, slug, name, sender_email, sender_name, logo_url, accent_color, footer_text
+        FROM lists
+        WHERE list_id = $1
+        "#,
+        list_id
+        )
+        .fetch_one(pool)
+        .await
+        .map(List::from)
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )
+}
+
+#[tracing::instrument(name = "Get lists by id", skip(pool))]
+pub async fn get_lists_by_id(pool: &PgPool, list_ids: &[Uuid]) -> Result<Vec<List>, sqlx::Error> {
+
+    sqlx::query_as!(
+        ListRecord,
+        r#"
+        SELECT list_id, slug, name, sender_email, sender_name, logo_url, accent_color, footer_text
+        FROM lists
+        WHERE list_id = ANY($1)
+        "#,
+        list_ids
+        )
+        .fetch_all(pool)
+        .await
+        .map(|records| records.into_iter().map(List::from).collect())
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )
+}
+
+#[tracing::instrument(name = "Get all lists", skip(pool))]
+pub async fn get_lists(pool: &PgPool) -> Result<Vec<List>, sqlx::Error> {
+
+    sqlx::query_as!(
+        ListRecord,
+        r#"
+        SELECT list_id, slug, name, sender_email, sender_name, logo_url, accent_color, footer_text
+        FROM lists
+        ORDER BY slug
+        "#
+        )
+        .fetch_all(pool)
+        .await
+        .map(|records| records.into_iter().map(List::from).collect())
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )
+}
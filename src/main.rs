@@ -1,4 +1,4 @@
-use newsletter_service::{configuration::{get_configuration, DatabaseSettings}, telemetry::{get_subscriber, init_subscriber}, startup::{run, Application}, email_client::EmailClient};
+use newsletter_service::{configuration::{get_configuration, DatabaseSettings}, telemetry::{get_subscriber, init_subscriber}, startup::Application};
 use secrecy::ExposeSecret;
 use sqlx::{PgPool, PgConnection, Connection, Pool, Postgres, Executor};
 use uuid::Uuid;
@@ -23,7 +23,7 @@ async fn main() -> std::io::Result<()> {
 }
 
 pub async fn configure_database(config: &DatabaseSettings) -> Pool<Postgres> {
-    let mut con = PgConnection::connect(&config.connection_string_without_db().expose_secret())
+    let mut con = PgConnection::connect(config.connection_string_without_db().expose_secret())
                                                             .await
                                                             .expect("Failed to connect");
     con.execute(format!(r#"CREATE DATABASE "{}";"#, config.database_name).as_str())
@@ -31,7 +31,7 @@ pub async fn configure_database(config: &DatabaseSettings) -> Pool<Postgres> {
                 .expect("Could not create Table");
     
     // Migrate DB
-    let connection_pool = PgPool::connect_lazy(&config.connection_string().expose_secret())
+    let connection_pool = PgPool::connect_lazy(config.connection_string().expose_secret())
                                                             .expect("Failed to connect to Postgres");
 
     sqlx::migrate!("./migrations")
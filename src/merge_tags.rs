@@ -0,0 +1,319 @@
+use serde_json::Value;
+
+use crate::domain::FieldDefinition;
+
+/// Issue content with merge tags, which are replaced for each recipient:
+///
+/// ```text
+/// Hi {{ name }}, how are things at {{ custom.company | default: "work" }}?
+/// ```
+///
+/// Tags can refer to `name`, `email`, `unsubscribe_url`, `preferences_url`
+/// and to custom fields as `custom.<field>`. The `default` filter gives the
+/// text to use when the subscriber has no value, or an empty one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeTemplate {
+    parts: Vec<Part>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Tag {
+        variable: Variable,
+        default: Option<String>
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Variable {
+    Name,
+    Email,
+    UnsubscribeUrl,
+    PreferencesUrl,
+    Custom(String)
+}
+
+/// What merge tags are replaced with for one recipient.
+pub struct Recipient<'a> {
+    pub name: &'a str,
+    pub email: &'a str,
+    // The `attributes` of the subscriber
+    pub custom: &'a Value,
+    pub unsubscribe_url: &'a str,
+    pub preferences_url: &'a str
+}
+
+impl MergeTemplate {
+    /// Returns the template `s` describes, or why one of its tags is invalid.
+    ///
+    /// `{{` opens a merge tag, `{{{{` is kept as a literal `{{`.
+    pub fn parse(s: &str) -> Result<MergeTemplate, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut rest = s;
+
+        while let Some(start) = rest.find("{{") {
+            text.push_str(&rest[..start]);
+            rest = &rest[start + 2..];
+            if let Some(escaped) = rest.strip_prefix("{{") {
+                text.push_str("{{");
+                rest = escaped;
+                continue;
+            }
+            let end = tag_end(rest).ok_or_else(|| {
+                let opening: String = rest.chars().take(20).collect();
+                format!("The merge tag `{{{{{}` is never closed.", opening)
+            })?;
+            if !text.is_empty() {
+                parts.push(Part::Text(std::mem::take(&mut text)));
+            }
+            parts.push(parse_tag(&rest[..end])?);
+            rest = &rest[end + 2..];
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+
+        Ok(MergeTemplate { parts })
+    }
+
+    /// Rejects tags referring to custom fields the admins have not defined.
+    pub fn check_fields(&self, definitions: &[FieldDefinition]) -> Result<(), String> {
+        for part in &self.parts {
+            if let Part::Tag { variable: Variable::Custom(field), .. } = part {
+                if !definitions.iter().any(|d| d.name == *field) {
+                    return Err(format!("custom.{} is not a known subscriber field.", field));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders HTML content, values are escaped.
+    pub fn render_html(&self, recipient: &Recipient) -> String {
+        self.render(recipient, htmlescape::encode_minimal)
+    }
+
+    /// Renders plain text content, values are left as they are.
+    pub fn render_text(&self, recipient: &Recipient) -> String {
+        self.render(recipient, str::to_string)
+    }
+
+    fn render(&self, recipient: &Recipient, escape: fn(&str) -> String) -> String {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => rendered.push_str(text),
+                Part::Tag { variable, default } => {
+                    let value = variable.value(recipient).filter(|value| !value.is_empty());
+                    if let Some(value) = value.as_deref().or(default.as_deref()) {
+                        rendered.push_str(&escape(value));
+                    }
+                }
+            }
+        }
+        rendered
+    }
+}
+
+impl Variable {
+    fn parse(s: &str) -> Result<Variable, String> {
+        let variable = match s {
+            "name" => Variable::Name,
+            "email" => Variable::Email,
+            "unsubscribe_url" => Variable::UnsubscribeUrl,
+            "preferences_url" => Variable::PreferencesUrl,
+            _ => match s.strip_prefix("custom.") {
+                Some(field) if is_identifier(field) => Variable::Custom(field.to_string()),
+                _ => return Err(format!(
+                    "`{}` is not a known merge tag. Use `name`, `email`, `unsubscribe_url`, \
+                    `preferences_url` or `custom.<field>`.",
+                    s
+                ))
+            }
+        };
+        Ok(variable)
+    }
+
+    fn value(&self, recipient: &Recipient) -> Option<String> {
+        match self {
+            Variable::Name => Some(recipient.name.to_string()),
+            Variable::Email => Some(recipient.email.to_string()),
+            Variable::UnsubscribeUrl => Some(recipient.unsubscribe_url.to_string()),
+            Variable::PreferencesUrl => Some(recipient.preferences_url.to_string()),
+            Variable::Custom(field) => match recipient.custom.get(field)? {
+                Value::Null => None,
+                Value::String(s) => Some(s.clone()),
+                value => Some(value.to_string())
+            }
+        }
+    }
+}
+
+/// The index of the `}}` closing the tag `s` starts with, ignoring the ones in quoted defaults.
+fn tag_end(s: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '}') if s[i..].starts_with("}}") => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parses the inside of a tag: `variable` or `variable | default: "text"`.
+fn parse_tag(tag: &str) -> Result<Part, String> {
+    let (variable, filter) = match tag.split_once('|') {
+        Some((variable, filter)) => (variable, Some(filter)),
+        None => (tag, None)
+    };
+    let variable = Variable::parse(variable.trim())?;
+    let default = match filter {
+        Some(filter) => Some(parse_default(filter.trim())?),
+        None => None
+    };
+    Ok(Part::Tag { variable, default })
+}
+
+fn parse_default(filter: &str) -> Result<String, String> {
+    let (name, argument) = filter.split_once(':').unwrap_or((filter, ""));
+    if name.trim() != "default" {
+        return Err(format!(
+            "`{}` is not a supported filter, use `default: \"text\"`.",
+            name.trim()
+        ));
+    }
+    let argument = argument.trim();
+    let quote = argument.chars().next().filter(|c| matches!(c, '"' | '\''));
+    match quote {
+        Some(quote) if argument.len() >= 2
+            && argument.ends_with(quote)
+            && !argument[1..argument.len() - 1].contains(quote) => {
+            Ok(argument[1..argument.len() - 1].to_string())
+        }
+        _ => Err(format!(
+            "The default must be a single quoted text, such as `default: \"friend\"`, not `{}`.",
+            argument
+        ))
+    }
+}
+
+fn is_identifier(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_lowercase())
+        && s.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+    use serde_json::{json, Value};
+
+    use super::{MergeTemplate, Recipient};
+    use crate::domain::{FieldDefinition, FieldType};
+
+    fn recipient(custom: &Value) -> Recipient<'_> {
+        Recipient {
+            name: "Ursula",
+            email: "ursula@example.com",
+            custom,
+            unsubscribe_url: "https://example.com/unsubscribe?list=rust&token=abc",
+            preferences_url: "https://example.com/preferences?token=def"
+        }
+    }
+
+    #[test]
+    fn tags_are_replaced_with_the_values_of_the_recipient() {
+        let template = assert_ok!(MergeTemplate::parse(
+            "Hi {{name}} ({{ email }}), {{ custom.company }} has {{ custom.seats }} seats. \
+            Bye: {{ unsubscribe_url }} {{ preferences_url }}"
+        ));
+        let custom = json!({"company": "Acme", "seats": 12});
+
+        assert_eq!(
+            template.render_text(&recipient(&custom)),
+            "Hi Ursula (ursula@example.com), Acme has 12 seats. \
+            Bye: https://example.com/unsubscribe?list=rust&token=abc https://example.com/preferences?token=def"
+        );
+    }
+
+    #[test]
+    fn defaults_replace_missing_and_empty_values() {
+        let template = assert_ok!(MergeTemplate::parse(
+            r#"{{ custom.company | default: "your company" }}, {{ custom.plan|default:'free' }}, [{{ custom.team }}]"#
+        ));
+        let custom = json!({"plan": ""});
+
+        assert_eq!(template.render_text(&recipient(&custom)), "your company, free, []");
+    }
+
+    #[test]
+    fn values_are_escaped_in_html_only() {
+        let template = assert_ok!(MergeTemplate::parse(r#"<p>{{ custom.company }}</p>"#));
+        let custom = json!({"company": "<Tom & Jerry>"});
+
+        assert_eq!(template.render_html(&recipient(&custom)), "<p>&lt;Tom &amp; Jerry&gt;</p>");
+        assert_eq!(template.render_text(&recipient(&custom)), "<p><Tom & Jerry></p>");
+    }
+
+    #[test]
+    fn content_without_tags_is_left_untouched() {
+        let content = "<p>Hello { world }, 50% off!</p>";
+        let template = assert_ok!(MergeTemplate::parse(content));
+
+        assert_eq!(template.render_html(&recipient(&json!({}))), content);
+    }
+
+    #[test]
+    fn broken_tags_are_rejected() {
+        let test_cases = [
+            "Hi {{ name",
+            "Hi {{ nme }}",
+            "Hi {{ }}",
+            "Hi {{ custom. }}",
+            "Hi {{ custom.Company }}",
+            "Hi {{ name | upcase }}",
+            "Hi {{ name | default }}",
+            "Hi {{ name | default: friend }}",
+            r#"Hi {{ name | default: "friend }}"#,
+            r#"Hi {{ name | default: "a" "b" }}"#,
+            r#"Hi {{ name | default: "a" | default: "b" }}"#
+        ];
+
+        for content in test_cases {
+            assert_err!(MergeTemplate::parse(content), "{} was accepted", content);
+        }
+    }
+
+    #[test]
+    fn defaults_can_contain_braces() {
+        let template = assert_ok!(MergeTemplate::parse(r#"{{ name | default: "}}" }}!"#));
+
+        assert_eq!(template.render_text(&recipient(&json!({}))), "Ursula!");
+    }
+
+    #[test]
+    fn doubled_braces_are_kept_as_literal_braces() {
+        let template = assert_ok!(MergeTemplate::parse("Write {{{{ name }} to greet {{ name }}, {{{{}}"));
+
+        assert_eq!(
+            template.render_text(&recipient(&json!({}))),
+            "Write {{ name }} to greet Ursula, {{}}"
+        );
+    }
+
+    #[test]
+    fn custom_fields_must_be_defined() {
+        let definitions = vec![FieldDefinition::parse("company".into(), FieldType::String, false).unwrap()];
+
+        let template = assert_ok!(MergeTemplate::parse("{{ name }} at {{ custom.company }}"));
+        assert_ok!(template.check_fields(&definitions));
+
+        let template = assert_ok!(MergeTemplate::parse("{{ custom.shoe_size }}"));
+        assert_err!(template.check_fields(&definitions));
+    }
+}
@@ -0,0 +1,122 @@
+use actix_web::{
+    error::InternalError,
+    http::{header::CONTENT_TYPE, StatusCode},
+    HttpRequest, HttpResponse, ResponseError
+};
+
+/// An error body as described by RFC 7807, served as `application/problem+json`.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct ProblemDetails {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    // Extension member listing the request fields that failed validation
+    #[serde(rename = "invalid-params", default, skip_serializing_if = "Vec::is_empty")]
+    pub invalid_params: Vec<InvalidParam>
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct InvalidParam {
+    pub name: String,
+    pub reason: String
+}
+
+impl ProblemDetails {
+    /// A problem with no type of its own: the title is the reason phrase of the status code.
+    pub fn new(status: StatusCode) -> Self {
+        Self {
+            problem_type: "about:blank".into(),
+            title: status.canonical_reason().unwrap_or("Unknown error").into(),
+            status: status.as_u16(),
+            detail: None,
+            invalid_params: Vec::new()
+        }
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    pub fn with_invalid_param(mut self, name: impl Into<String>, reason: impl Into<String>) -> Self {
+        self.invalid_params.push(InvalidParam {
+            name: name.into(),
+            reason: reason.into()
+        });
+        self
+    }
+
+    pub fn into_response(self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        HttpResponse::build(status)
+            .insert_header((CONTENT_TYPE, "application/problem+json"))
+            .body(serde_json::to_string(&self).unwrap())
+    }
+}
+
+/// Turns the errors of the `Json`, `Form` and `Query` extractors into problem details,
+/// so that malformed requests get the same kind of body as the ones we reject ourselves.
+pub fn extractor_error_handler<E>(e: E, _req: &HttpRequest) -> actix_web::Error
+where
+    E: ResponseError + 'static
+{
+    let response = ProblemDetails::new(e.status_code())
+        .with_detail(e.to_string())
+        .into_response();
+    InternalError::from_response(e, response).into()
+}
+
+/// Writes the error followed by every error in its source chain, one per line.
+///
+/// Used by the `Debug` implementations of our errors, so that the logs
+/// show what went wrong all the way down.
+pub fn error_chain_fmt(
+    e: &impl std::error::Error,
+    f: &mut std::fmt::Formatter<'_>
+) -> std::fmt::Result {
+    writeln!(f, "{}\n", e)?;
+    let mut current = e.source();
+    while let Some(cause) = current {
+        writeln!(f, "Caused by:\n\t{}", cause)?;
+        current = cause.source();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProblemDetails;
+    use actix_web::{body::to_bytes, http::StatusCode};
+
+    #[tokio::test]
+    async fn problem_details_are_served_as_problem_json() {
+        let response = ProblemDetails::new(StatusCode::BAD_REQUEST)
+            .with_invalid_param("email", "not an email address")
+            .into_response();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers().get("Content-Type").unwrap(),
+            "application/problem+json"
+        );
+
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["type"], "about:blank");
+        assert_eq!(body["title"], "Bad Request");
+        assert_eq!(body["status"], 400);
+        assert!(body.get("detail").is_none());
+        assert_eq!(body["invalid-params"][0]["name"], "email");
+        assert_eq!(body["invalid-params"][0]["reason"], "not an email address");
+    }
+
+    #[test]
+    fn invalid_params_are_left_out_when_there_are_none() {
+        let problem = ProblemDetails::new(StatusCode::INTERNAL_SERVER_ERROR);
+        let body = serde_json::to_value(&problem).unwrap();
+        assert!(body.get("invalid-params").is_none());
+    }
+}
@@ -0,0 +1,66 @@
+use actix_web::{http::header::ContentType, web, HttpResponse};
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::UserId;
+
+pub async fn admin_dashboard(
+    user_id: web::ReqData<UserId>,
+    pool: web::Data<PgPool>
+) -> HttpResponse {
+
+    let username = match get_username(*user_id.into_inner(), &pool).await {
+        Ok(username) => username,
+        Err(e) => {
+            tracing::error!(error.cause_chain = ?e, "Failed to render the dashboard");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Admin dashboard</title>
+</head>
+<body>
+    <p>Welcome {}!</p>
+    <p>Available actions:</p>
+    <ol>
+        <li><a href="/admin/password">Change password</a></li>
+        <li>
+            <form name="logoutForm" action="/admin/logout" method="post">
+                <input type="submit" value="Logout">
+            </form>
+        </li>
+    </ol>
+</body>
+</html>"#,
+            htmlescape::encode_minimal(&username)
+        ))
+}
+
+#[tracing::instrument(name = "Get username", skip(pool))]
+pub async fn get_username(
+    user_id: Uuid,
+    pool: &PgPool
+) -> Result<String, anyhow::Error> {
+
+    let row = sqlx::query!(
+        r#"
+        SELECT username
+        FROM users
+        WHERE user_id = $1
+        "#,
+        user_id,
+        )
+        .fetch_one(pool)
+        .await
+        .context("Failed to perform a query to retrieve a username.")?;
+
+    Ok(row.username)
+}
@@ -0,0 +1,268 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError, web};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::problem::{error_chain_fmt, ProblemDetails};
+
+#[derive(serde::Serialize)]
+pub struct DeadLetter {
+    newsletter_issue_id: Uuid,
+    subscriber_email: String,
+    list_id: Uuid,
+    n_attempts: i32,
+    last_error: String,
+    failed_at: DateTime<Utc>
+}
+
+/// A queued email, such as a confirmation, that kept failing.
+#[derive(serde::Serialize)]
+pub struct EmailDeadLetter {
+    email_id: Uuid,
+    template: String,
+    subscriber_email: String,
+    list_id: Option<Uuid>,
+    n_attempts: i32,
+    last_error: String,
+    failed_at: DateTime<Utc>
+}
+
+/// Selects the dead letters to re-drive, every dead letter matches when a field is left out.
+#[derive(serde::Deserialize)]
+pub struct RedriveData {
+    newsletter_issue_id: Option<Uuid>,
+    subscriber_email: Option<String>
+}
+
+/// Selects the email dead letters to re-drive, the same way as `RedriveData`.
+#[derive(serde::Deserialize)]
+pub struct RedriveEmailData {
+    email_id: Option<Uuid>,
+    subscriber_email: Option<String>
+}
+
+#[derive(serde::Serialize)]
+pub struct RedriveOutcome {
+    redriven: i64,
+    /// Dead letters left where they are because their delivery is queued already.
+    already_queued: i64
+}
+
+#[derive(thiserror::Error)]
+pub enum DeadLettersError {
+    #[error("Failed to access the dead letters.")]
+    UnexpectedError(#[from] anyhow::Error)
+}
+
+impl std::fmt::Debug for DeadLettersError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for DeadLettersError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        ProblemDetails::new(self.status_code())
+            .with_detail(self.to_string())
+            .into_response()
+    }
+}
+
+#[tracing::instrument(
+    name = "List dead letters",
+    skip(pool)
+)]
+pub async fn list_dead_letters(pool: web::Data<PgPool>) -> Result<HttpResponse, DeadLettersError> {
+
+    let dead_letters = get_dead_letters(&pool)
+        .await
+        .context("Failed to retrieve the dead letters.")?;
+    Ok(HttpResponse::Ok().json(dead_letters))
+}
+
+#[tracing::instrument(
+    name = "Re-drive dead letters",
+    skip(body, pool)
+)]
+pub async fn redrive_dead_letters(
+    body: web::Json<RedriveData>,
+    pool: web::Data<PgPool>
+) -> Result<HttpResponse, DeadLettersError> {
+
+    let outcome = move_dead_letters_to_queue(&pool, &body)
+        .await
+        .context("Failed to move the dead letters back to the queue.")?;
+    Ok(HttpResponse::Ok().json(outcome))
+}
+
+#[tracing::instrument(
+    name = "List email dead letters",
+    skip(pool)
+)]
+pub async fn list_email_dead_letters(pool: web::Data<PgPool>) -> Result<HttpResponse, DeadLettersError> {
+
+    let dead_letters = get_email_dead_letters(&pool)
+        .await
+        .context("Failed to retrieve the email dead letters.")?;
+    Ok(HttpResponse::Ok().json(dead_letters))
+}
+
+#[tracing::instrument(
+    name = "Re-drive email dead letters",
+    skip(body, pool)
+)]
+pub async fn redrive_email_dead_letters(
+    body: web::Json<RedriveEmailData>,
+    pool: web::Data<PgPool>
+) -> Result<HttpResponse, DeadLettersError> {
+
+    let outcome = move_email_dead_letters_to_queue(&pool, &body)
+        .await
+        .context("Failed to move the email dead letters back to the queue.")?;
+    Ok(HttpResponse::Ok().json(outcome))
+}
+
+async fn get_dead_letters(pool: &PgPool) -> Result<Vec<DeadLetter>, sqlx::Error> {
+
+    sqlx::query_as!(
+        DeadLetter,
+        r#"
+        SELECT newsletter_issue_id, subscriber_email, list_id, n_attempts, last_error, failed_at
+        FROM issue_delivery_dead_letters
+        ORDER BY failed_at DESC
+        "#
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )
+}
+
+/// Gives the matching dead letters a fresh set of attempts.
+///
+/// A dead letter is only deleted once its delivery made it into the queue:
+/// the ones whose delivery is queued already are kept and reported.
+async fn move_dead_letters_to_queue(
+    pool: &PgPool,
+    filter: &RedriveData
+) -> Result<RedriveOutcome, sqlx::Error> {
+
+    let outcome = sqlx::query_as!(
+        RedriveOutcome,
+        r#"
+        WITH candidates AS (
+            SELECT newsletter_issue_id, subscriber_email, list_id, digest
+            FROM issue_delivery_dead_letters
+            WHERE
+                ($1::uuid IS NULL OR newsletter_issue_id = $1) AND
+                ($2::text IS NULL OR subscriber_email = $2)
+            FOR UPDATE
+        ),
+        queued AS (
+            INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_email, list_id, digest)
+            SELECT newsletter_issue_id, subscriber_email, list_id, digest FROM candidates
+            ON CONFLICT DO NOTHING
+            RETURNING newsletter_issue_id, subscriber_email
+        ),
+        redriven AS (
+            DELETE FROM issue_delivery_dead_letters d
+            USING queued q
+            WHERE
+                d.newsletter_issue_id = q.newsletter_issue_id AND
+                d.subscriber_email = q.subscriber_email
+            RETURNING d.newsletter_issue_id
+        )
+        SELECT
+            (SELECT COUNT(*) FROM redriven) as "redriven!",
+            (SELECT COUNT(*) FROM candidates) - (SELECT COUNT(*) FROM redriven) as "already_queued!"
+        "#,
+        filter.newsletter_issue_id,
+        filter.subscriber_email
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    Ok(outcome)
+}
+
+async fn get_email_dead_letters(pool: &PgPool) -> Result<Vec<EmailDeadLetter>, sqlx::Error> {
+
+    sqlx::query_as!(
+        EmailDeadLetter,
+        r#"
+        SELECT d.email_id, d.template, s.email AS subscriber_email, d.list_id, d.n_attempts, d.last_error, d.failed_at
+        FROM email_dead_letters d
+        JOIN subscriptions s ON s.id = d.subscriber_id
+        ORDER BY d.failed_at DESC
+        "#
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )
+}
+
+/// Gives the matching email dead letters a fresh set of attempts, like
+/// `move_dead_letters_to_queue` does for issue deliveries.
+async fn move_email_dead_letters_to_queue(
+    pool: &PgPool,
+    filter: &RedriveEmailData
+) -> Result<RedriveOutcome, sqlx::Error> {
+
+    let outcome = sqlx::query_as!(
+        RedriveOutcome,
+        r#"
+        WITH candidates AS (
+            SELECT d.email_id, d.template, d.subscriber_id, d.list_id
+            FROM email_dead_letters d
+            JOIN subscriptions s ON s.id = d.subscriber_id
+            WHERE
+                ($1::uuid IS NULL OR d.email_id = $1) AND
+                ($2::text IS NULL OR s.email = $2)
+            FOR UPDATE OF d
+        ),
+        queued AS (
+            INSERT INTO email_queue (email_id, template, subscriber_id, list_id, next_attempt_at)
+            SELECT email_id, template, subscriber_id, list_id, now() FROM candidates
+            ON CONFLICT DO NOTHING
+            RETURNING email_id
+        ),
+        redriven AS (
+            DELETE FROM email_dead_letters d
+            USING queued q
+            WHERE d.email_id = q.email_id
+            RETURNING d.email_id
+        )
+        SELECT
+            (SELECT COUNT(*) FROM redriven) as "redriven!",
+            (SELECT COUNT(*) FROM candidates) - (SELECT COUNT(*) FROM redriven) as "already_queued!"
+        "#,
+        filter.email_id,
+        filter.subscriber_email
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    Ok(outcome)
+}
@@ -0,0 +1,139 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError, web};
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::PgPool;
+
+use crate::{
+    domain::{FieldDefinition, FieldType, ValidationError},
+    problem::{error_chain_fmt, ProblemDetails},
+    subscriber_fields::get_field_definitions
+};
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct FieldData {
+    name: String,
+    field_type: String,
+    #[serde(default)]
+    required: bool,
+    #[serde(default)]
+    options: Vec<String>
+}
+
+impl TryFrom<FieldData> for FieldDefinition {
+    type Error = ValidationError;
+
+    fn try_from(value: FieldData) -> Result<Self, Self::Error> {
+        let field_type = FieldType::parse(&value.field_type, value.options)?;
+        FieldDefinition::parse(value.name, field_type, value.required)
+    }
+}
+
+impl From<FieldDefinition> for FieldData {
+    fn from(definition: FieldDefinition) -> Self {
+        Self {
+            options: definition.field_type.options().to_vec(),
+            field_type: definition.field_type.as_str().into(),
+            name: definition.name,
+            required: definition.required
+        }
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum FieldsError {
+    #[error(transparent)]
+    ValidationError(#[from] ValidationError),
+    #[error("A field with this name already exists.")]
+    NameTaken,
+    #[error("Failed to manage the subscriber fields.")]
+    UnexpectedError(#[from] anyhow::Error)
+}
+
+impl std::fmt::Debug for FieldsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for FieldsError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            FieldsError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            FieldsError::NameTaken => StatusCode::CONFLICT,
+            FieldsError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let problem = ProblemDetails::new(self.status_code());
+        match self {
+            FieldsError::ValidationError(e) => problem.with_invalid_param(e.field.as_ref(), &e.reason),
+            _ => problem.with_detail(self.to_string())
+        }
+        .into_response()
+    }
+}
+
+#[tracing::instrument(
+    name = "List subscriber fields",
+    skip(pool)
+)]
+pub async fn list_fields(pool: web::Data<PgPool>) -> Result<HttpResponse, FieldsError> {
+
+    let fields: Vec<FieldData> = get_field_definitions(&pool)
+        .await?
+        .into_iter()
+        .map(FieldData::from)
+        .collect();
+    Ok(HttpResponse::Ok().json(fields))
+}
+
+/// Defines a custom field, new subscribers fill it in when signing up.
+///
+/// Subscribers who signed up before keep their attributes as they are,
+/// even when the new field is required.
+#[tracing::instrument(
+    name = "Create a subscriber field",
+    skip(body, pool),
+    fields(name = %body.name)
+)]
+pub async fn create_field(
+    body: web::Json<FieldData>,
+    pool: web::Data<PgPool>
+) -> Result<HttpResponse, FieldsError> {
+
+    let definition: FieldDefinition = body.into_inner().try_into()?;
+    let inserted = insert_field(&pool, &definition)
+        .await
+        .context("Failed to store the subscriber field.")?;
+    if !inserted {
+        return Err(FieldsError::NameTaken);
+    }
+    Ok(HttpResponse::Created().json(FieldData::from(definition)))
+}
+
+/// Returns `false` if the name is already taken.
+async fn insert_field(pool: &PgPool, definition: &FieldDefinition) -> Result<bool, sqlx::Error> {
+
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO subscriber_fields (name, field_type, required, options, created_at)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (name) DO NOTHING
+        "#,
+        definition.name,
+        definition.field_type.as_str(),
+        definition.required,
+        definition.field_type.options(),
+        Utc::now()
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    Ok(result.rows_affected() == 1)
+}
@@ -0,0 +1,217 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError, web};
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    domain::{ListSlug, SubscriberEmail, SubscriberName, ValidationError},
+    lists::{get_lists, List, ListRecord},
+    problem::{error_chain_fmt, ProblemDetails}
+};
+
+#[derive(serde::Deserialize)]
+pub struct ListData {
+    slug: String,
+    name: String,
+    sender_email: Option<String>,
+    sender_name: Option<String>,
+    logo_url: Option<String>,
+    accent_color: Option<String>,
+    footer_text: Option<String>
+}
+
+/// A list about to be created, once validated.
+struct NewList {
+    slug: ListSlug,
+    name: String,
+    sender_email: Option<SubscriberEmail>,
+    sender_name: Option<SubscriberName>,
+    logo_url: Option<String>,
+    accent_color: Option<String>,
+    footer_text: Option<String>
+}
+
+impl TryFrom<ListData> for NewList {
+    type Error = ValidationError;
+
+    fn try_from(value: ListData) -> Result<Self, Self::Error> {
+        let slug = ListSlug::parse(value.slug)
+            .map_err(|reason| ValidationError::new("slug", reason))?;
+        if value.name.trim().is_empty() {
+            return Err(ValidationError::new("name", "The name is missing.".into()));
+        }
+        let sender_email = value.sender_email
+            .map(SubscriberEmail::parse)
+            .transpose()
+            .map_err(|reason| ValidationError::new("sender_email", reason))?;
+        // Display names follow the rules of subscriber names, which keep them
+        // from breaking out of the `From` header
+        let sender_name = value.sender_name
+            .map(SubscriberName::parse)
+            .transpose()
+            .map_err(|reason| ValidationError::new("sender_name", reason))?;
+        if let Some(logo_url) = &value.logo_url {
+            if !logo_url.starts_with("https://") {
+                return Err(ValidationError::new(
+                    "logo_url",
+                    format!("{} is not an https URL.", logo_url)
+                ));
+            }
+        }
+        // The color ends up in a `style` attribute, only hex colors are safe there
+        if let Some(accent_color) = &value.accent_color {
+            if !is_hex_color(accent_color) {
+                return Err(ValidationError::new(
+                    "accent_color",
+                    format!("{} is not a hex color such as #ff6600.", accent_color)
+                ));
+            }
+        }
+
+        Ok(Self {
+            slug,
+            name: value.name,
+            sender_email,
+            sender_name,
+            logo_url: value.logo_url,
+            accent_color: value.accent_color,
+            footer_text: value.footer_text
+        })
+    }
+}
+
+fn is_hex_color(s: &str) -> bool {
+    match s.strip_prefix('#') {
+        Some(digits) => {
+            (digits.len() == 3 || digits.len() == 6)
+                && digits.chars().all(|c| c.is_ascii_hexdigit())
+        }
+        None => false
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum ListsError {
+    #[error(transparent)]
+    ValidationError(#[from] ValidationError),
+    #[error("A list with this slug already exists.")]
+    SlugTaken,
+    #[error("Failed to manage the lists.")]
+    UnexpectedError(#[from] anyhow::Error)
+}
+
+impl std::fmt::Debug for ListsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ListsError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ListsError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            ListsError::SlugTaken => StatusCode::CONFLICT,
+            ListsError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let problem = ProblemDetails::new(self.status_code());
+        match self {
+            ListsError::ValidationError(e) => problem.with_invalid_param(e.field.as_ref(), &e.reason),
+            _ => problem.with_detail(self.to_string())
+        }
+        .into_response()
+    }
+}
+
+#[tracing::instrument(
+    name = "List mailing lists",
+    skip(pool)
+)]
+pub async fn list_lists(pool: web::Data<PgPool>) -> Result<HttpResponse, ListsError> {
+
+    let lists = get_lists(&pool)
+        .await
+        .context("Failed to retrieve the lists.")?;
+    Ok(HttpResponse::Ok().json(lists))
+}
+
+#[tracing::instrument(
+    name = "Create a mailing list",
+    skip(body, pool),
+    fields(slug = %body.slug)
+)]
+pub async fn create_list(
+    body: web::Json<ListData>,
+    pool: web::Data<PgPool>
+) -> Result<HttpResponse, ListsError> {
+
+    let new_list: NewList = body.into_inner().try_into()?;
+    let list = insert_list(&pool, &new_list)
+        .await
+        .context("Failed to store the new list.")?
+        .ok_or(ListsError::SlugTaken)?;
+    Ok(HttpResponse::Created().json(list))
+}
+
+/// Returns the new list, `None` if the slug is already taken.
+async fn insert_list(pool: &PgPool, new_list: &NewList) -> Result<Option<List>, sqlx::Error> {
+
+    sqlx::query_as!(
+        ListRecord,
+        r#"
+        INSERT INTO lists (
+            list_id,
+            slug,
+            name,
+            sender_email,
+            sender_name,
+            logo_url,
+            accent_color,
+            footer_text,
+            created_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        ON CONFLICT (slug) DO NOTHING
+        RETURNING list_id, slug, name, sender_email, sender_name, logo_url, accent_color, footer_text
+        "#,
+        Uuid::new_v4(),
+        new_list.slug.as_ref(),
+        new_list.name,
+        new_list.sender_email.as_ref().map(|e| e.as_ref()),
+        new_list.sender_name.as_ref().map(|n| n.as_ref()),
+        new_list.logo_url,
+        new_list.accent_color,
+        new_list.footer_text,
+        Utc::now()
+        )
+        .fetch_optional(pool)
+        .await
+        .map(|record| record.map(List::from))
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_hex_color;
+
+    #[test]
+    fn hex_colors_are_accepted() {
+        for color in ["#fff", "#FF6600", "#0a0b0c"] {
+            assert!(is_hex_color(color), "{} was rejected.", color);
+        }
+    }
+
+    #[test]
+    fn anything_else_is_rejected() {
+        for color in ["fff", "#ffff", "#gggggg", "red", "#fff;background:url(x)"] {
+            assert!(!is_hex_color(color), "{} was accepted.", color);
+        }
+    }
+}
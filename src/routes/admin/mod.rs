@@ -0,0 +1,15 @@
+mod dashboard;
+mod dead_letters;
+mod fields;
+mod lists;
+mod logout;
+mod password;
+mod segments;
+
+pub use dashboard::*;
+pub use dead_letters::*;
+pub use fields::*;
+pub use lists::*;
+pub use logout::*;
+pub use password::*;
+pub use segments::*;
@@ -0,0 +1,125 @@
+use std::fmt::Write;
+
+use actix_web::{http::header::ContentType, web, HttpResponse};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use unicode_segmentation::UnicodeSegmentation;
+
+use super::get_username;
+use crate::{
+    authentication::{self, validate_credentials, AuthError, Credentials, UserId},
+    utils::{e500, see_other}
+};
+
+const MIN_PASSWORD_LENGTH: usize = 12;
+const MAX_PASSWORD_LENGTH: usize = 128;
+
+#[derive(serde::Deserialize)]
+pub struct ChangePasswordData {
+    current_password: Secret<String>,
+    new_password: Secret<String>,
+    new_password_check: Secret<String>
+}
+
+pub async fn change_password_form(flash_messages: IncomingFlashMessages) -> HttpResponse {
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        // Messages are rendered as HTML, never trust their content
+        writeln!(msg_html, "<p><i>{}</i></p>", htmlescape::encode_minimal(m.content())).unwrap();
+    }
+
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Change Password</title>
+</head>
+<body>
+    {msg_html}
+    <form action="/admin/password" method="post">
+        <label>Current password
+            <input
+                type="password"
+                placeholder="Enter current password"
+                name="current_password"
+            >
+        </label>
+        <br>
+        <label>New password
+            <input
+                type="password"
+                placeholder="Enter new password"
+                name="new_password"
+            >
+        </label>
+        <br>
+        <label>Confirm new password
+            <input
+                type="password"
+                placeholder="Type the new password again"
+                name="new_password_check"
+            >
+        </label>
+        <br>
+        <button type="submit">Change password</button>
+    </form>
+    <p><a href="/admin/dashboard">&lt;- Back</a></p>
+</body>
+</html>"#,
+        ))
+}
+
+#[tracing::instrument(
+    name = "Change admin password",
+    skip(form, pool, user_id),
+    fields(user_id = %*user_id)
+)]
+pub async fn change_password(
+    form: web::Form<ChangePasswordData>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>
+) -> Result<HttpResponse, actix_web::Error> {
+
+    let user_id = user_id.into_inner();
+
+    if form.new_password.expose_secret() != form.new_password_check.expose_secret() {
+        FlashMessage::error(
+            "You entered two different new passwords - the field values must match."
+        ).send();
+        return Ok(see_other("/admin/password"));
+    }
+
+    let length = form.new_password.expose_secret().graphemes(true).count();
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&length) {
+        FlashMessage::error(format!(
+            "The new password must be between {} and {} characters long.",
+            MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH
+        )).send();
+        return Ok(see_other("/admin/password"));
+    }
+
+    let username = get_username(*user_id, &pool).await.map_err(e500)?;
+    let credentials = Credentials {
+        username,
+        password: form.0.current_password
+    };
+    if let Err(e) = validate_credentials(credentials, &pool).await {
+        return match e {
+            AuthError::InvalidCredentials(_) => {
+                FlashMessage::error("The current password is incorrect.").send();
+                Ok(see_other("/admin/password"))
+            }
+            AuthError::UnexpectedError(_) => Err(e500(e))
+        };
+    }
+
+    authentication::change_password(*user_id, form.0.new_password, &pool)
+        .await
+        .map_err(e500)?;
+    FlashMessage::info("Your password has been changed.").send();
+    Ok(see_other("/admin/password"))
+}
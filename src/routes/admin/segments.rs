@@ -0,0 +1,159 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError, web};
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    domain::ValidationError,
+    problem::{error_chain_fmt, ProblemDetails},
+    segments::{count_subscribers, Filter}
+};
+
+#[derive(serde::Deserialize)]
+pub struct SegmentData {
+    name: String,
+    filter: String
+}
+
+#[derive(serde::Deserialize)]
+pub struct PreviewData {
+    filter: String
+}
+
+#[derive(serde::Serialize)]
+pub struct Segment {
+    name: String,
+    filter: String,
+    // Confirmed subscribers matching the filter right now
+    subscribers: i64
+}
+
+#[derive(serde::Serialize)]
+pub struct SegmentPreview {
+    subscribers: i64
+}
+
+#[derive(thiserror::Error)]
+pub enum SegmentsError {
+    #[error(transparent)]
+    ValidationError(#[from] ValidationError),
+    #[error("A segment with this name already exists.")]
+    NameTaken,
+    #[error("Failed to manage the segments.")]
+    UnexpectedError(#[from] anyhow::Error)
+}
+
+impl std::fmt::Debug for SegmentsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for SegmentsError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SegmentsError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            SegmentsError::NameTaken => StatusCode::CONFLICT,
+            SegmentsError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let problem = ProblemDetails::new(self.status_code());
+        match self {
+            SegmentsError::ValidationError(e) => problem.with_invalid_param(e.field.as_ref(), &e.reason),
+            _ => problem.with_detail(self.to_string())
+        }
+        .into_response()
+    }
+}
+
+fn parse_filter(filter: &str) -> Result<Filter, ValidationError> {
+    Filter::parse(filter).map_err(|reason| ValidationError::new("filter", reason))
+}
+
+#[tracing::instrument(
+    name = "List segments",
+    skip(pool)
+)]
+pub async fn list_segments(pool: web::Data<PgPool>) -> Result<HttpResponse, SegmentsError> {
+
+    let rows = sqlx::query!(r#"SELECT name, filter FROM segments ORDER BY name"#)
+        .fetch_all(pool.as_ref())
+        .await
+        .context("Failed to retrieve the segments.")?;
+
+    let mut segments = Vec::with_capacity(rows.len());
+    for row in rows {
+        let filter = parse_filter(&row.filter)
+            .map_err(|e| anyhow::anyhow!(e))
+            .context("Failed to parse a stored segment filter.")?;
+        let subscribers = count_subscribers(&pool, &filter)
+            .await
+            .context("Failed to count the subscribers in a segment.")?;
+        segments.push(Segment { name: row.name, filter: row.filter, subscribers });
+    }
+    Ok(HttpResponse::Ok().json(segments))
+}
+
+/// Saves a named segment, issues can then be published to it.
+#[tracing::instrument(
+    name = "Create a segment",
+    skip(body, pool),
+    fields(name = %body.name)
+)]
+pub async fn create_segment(
+    body: web::Json<SegmentData>,
+    pool: web::Data<PgPool>
+) -> Result<HttpResponse, SegmentsError> {
+
+    let SegmentData { name, filter } = body.into_inner();
+    if name.trim().is_empty() || name.len() > 100 {
+        return Err(ValidationError::new(
+            "name",
+            "The name must be between 1 and 100 characters long.".into()
+        ).into());
+    }
+    let parsed_filter = parse_filter(&filter)?;
+
+    let inserted = sqlx::query!(
+        r#"
+        INSERT INTO segments (segment_id, name, filter, created_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (name) DO NOTHING
+        "#,
+        Uuid::new_v4(),
+        name,
+        filter,
+        Utc::now()
+        )
+        .execute(pool.as_ref())
+        .await
+        .context("Failed to store the segment.")?;
+    if inserted.rows_affected() == 0 {
+        return Err(SegmentsError::NameTaken);
+    }
+
+    let subscribers = count_subscribers(&pool, &parsed_filter)
+        .await
+        .context("Failed to count the subscribers in the segment.")?;
+    Ok(HttpResponse::Created().json(Segment { name, filter, subscribers }))
+}
+
+/// Counts the subscribers a filter matches without saving it.
+#[tracing::instrument(
+    name = "Preview a segment",
+    skip(body, pool)
+)]
+pub async fn preview_segment(
+    body: web::Json<PreviewData>,
+    pool: web::Data<PgPool>
+) -> Result<HttpResponse, SegmentsError> {
+
+    let filter = parse_filter(&body.filter)?;
+    let subscribers = count_subscribers(&pool, &filter)
+        .await
+        .context("Failed to count the subscribers in the segment.")?;
+    Ok(HttpResponse::Ok().json(SegmentPreview { subscribers }))
+}
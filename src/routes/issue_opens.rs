@@ -0,0 +1,78 @@
+use actix_web::{HttpResponse, web};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::signed_token::{TokenPurpose, TokenSigner};
+
+#[derive(serde::Deserialize)]
+pub struct OpenParameters {
+    issue: Uuid,
+    token: String
+}
+
+// The smallest transparent GIF
+const PIXEL: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00,
+    0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b
+];
+
+/// The tracking pixel embedded in every issue, it records the first time
+/// a subscriber opened it.
+///
+/// Mail clients always get the image back: a broken image would not tell
+/// them anything useful, and failures are logged.
+#[tracing::instrument(
+    name = "Record an issue open",
+    skip(parameters, pool, signer),
+    fields(newsletter_issue_id = %parameters.issue)
+)]
+pub async fn track_open(
+    parameters: web::Query<OpenParameters>,
+    pool: web::Data<PgPool>,
+    signer: web::Data<TokenSigner>
+) -> HttpResponse {
+
+    match signer.verify(TokenPurpose::TrackOpens, &parameters.token) {
+        Ok(email) => {
+            if let Err(e) = record_open(&pool, parameters.issue, &email).await {
+                tracing::error!(error.cause_chain = ?e, "Failed to record an issue open");
+            }
+        }
+        Err(_) => tracing::warn!("Ignoring an issue open with an invalid token")
+    }
+
+    HttpResponse::Ok()
+        .content_type("image/gif")
+        .insert_header(("Cache-Control", "no-store"))
+        .body(PIXEL)
+}
+
+#[tracing::instrument(
+    name = "Store an issue open",
+    skip(pool, email)
+)]
+async fn record_open(pool: &PgPool, newsletter_issue_id: Uuid, email: &str) -> Result<(), sqlx::Error> {
+
+    // Unknown issues and subscribers simply select nothing
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_opens (newsletter_issue_id, subscriber_id, opened_at)
+        SELECT i.newsletter_issue_id, s.id, now()
+        FROM newsletter_issues i, subscriptions s
+        WHERE i.newsletter_issue_id = $1 AND s.email = $2
+        ON CONFLICT DO NOTHING
+        "#,
+        newsletter_issue_id,
+        email
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    Ok(())
+}
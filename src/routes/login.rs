@@ -0,0 +1,93 @@
+use std::fmt::Write;
+
+use actix_web::{http::header::ContentType, web, HttpResponse};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
+use secrecy::Secret;
+use sqlx::PgPool;
+
+use crate::{
+    authentication::{validate_credentials, AuthError, Credentials},
+    session_state::TypedSession,
+    utils::see_other
+};
+
+#[derive(serde::Deserialize)]
+pub struct LoginFormData {
+    username: String,
+    password: Secret<String>
+}
+
+pub async fn login_form(flash_messages: IncomingFlashMessages) -> HttpResponse {
+    let mut error_html = String::new();
+    for m in flash_messages.iter() {
+        // Messages are rendered as HTML, never trust their content
+        writeln!(error_html, "<p><i>{}</i></p>", htmlescape::encode_minimal(m.content())).unwrap();
+    }
+
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Login</title>
+</head>
+<body>
+    {error_html}
+    <form action="/login" method="post">
+        <label>Username
+            <input
+                type="text"
+                placeholder="Enter Username"
+                name="username"
+            >
+        </label>
+        <label>Password
+            <input
+                type="password"
+                placeholder="Enter Password"
+                name="password"
+            >
+        </label>
+        <button type="submit">Login</button>
+    </form>
+</body>
+</html>"#,
+        ))
+}
+
+#[tracing::instrument(
+    name = "Log in an admin",
+    skip(form, pool, session),
+    fields(username = %form.username)
+)]
+pub async fn login(
+    form: web::Form<LoginFormData>,
+    pool: web::Data<PgPool>,
+    session: TypedSession
+) -> HttpResponse {
+
+    let credentials = Credentials {
+        username: form.0.username,
+        password: form.0.password
+    };
+
+    match validate_credentials(credentials, &pool).await {
+        Ok(user_id) => {
+            session.renew();
+            if session.insert_user_id(user_id).is_err() {
+                return HttpResponse::InternalServerError().finish();
+            }
+            see_other("/admin/dashboard")
+        }
+        Err(AuthError::InvalidCredentials(_)) => {
+            FlashMessage::error("Authentication failed").send();
+            see_other("/login")
+        }
+        Err(e @ AuthError::UnexpectedError(_)) => {
+            tracing::error!(error.cause_chain = ?e, "Failed to log in");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
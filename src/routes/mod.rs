@@ -1,7 +1,19 @@
+mod admin;
 mod health_check;
+mod issue_opens;
+mod login;
+mod newsletters;
+mod preferences;
 mod subscriptions;
 mod subscription_confirm;
+mod unsubscribe;
 
+pub use admin::*;
 pub use health_check::*;
+pub use issue_opens::*;
+pub use login::*;
+pub use newsletters::*;
+pub use preferences::*;
 pub use subscriptions::*;
-pub use subscription_confirm::*;
\ No newline at end of file
+pub use subscription_confirm::*;
+pub use unsubscribe::*;
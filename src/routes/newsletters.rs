@@ -0,0 +1,320 @@
+use actix_web::{http::StatusCode, HttpRequest, HttpResponse, ResponseError, web};
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::{
+    authentication::UserId,
+    configuration::{IdempotencySettings, ListSettings, PreferenceSettings},
+    domain::ValidationError,
+    idempotency::{hash_request, save_response, try_processing, IdempotencyError, IdempotencyKey, NextAction},
+    lists::get_list_by_slug,
+    merge_tags::MergeTemplate,
+    problem::{error_chain_fmt, ProblemDetails},
+    segments::{get_segment_filter, Filter},
+    subscriber_fields::get_field_definitions
+};
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct BodyData {
+    title: String,
+    content: Content,
+    // Slugs of the lists to send the issue to, the default list when left out
+    lists: Option<Vec<String>>,
+    // Name of a saved segment, only its subscribers get the issue
+    segment: Option<String>,
+    // One of the topics of the preference center, subscribers who did not
+    // pick it are left out
+    topic: Option<String>
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct Content {
+    html: String,
+    text: String
+}
+
+#[derive(thiserror::Error)]
+pub enum PublishError {
+    #[error(transparent)]
+    ValidationError(#[from] ValidationError),
+    #[error("The idempotency key was already used to publish a different issue.")]
+    IdempotencyKeyReused,
+    #[error("Failed to publish the newsletter issue.")]
+    UnexpectedError(#[from] anyhow::Error)
+}
+
+impl From<IdempotencyError> for PublishError {
+    fn from(e: IdempotencyError) -> Self {
+        match e {
+            IdempotencyError::KeyReused => PublishError::IdempotencyKeyReused,
+            IdempotencyError::UnexpectedError(e) => PublishError::UnexpectedError(e)
+        }
+    }
+}
+
+impl std::fmt::Debug for PublishError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PublishError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PublishError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            PublishError::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            PublishError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let problem = ProblemDetails::new(self.status_code());
+        match self {
+            PublishError::ValidationError(e) => problem.with_invalid_param(e.field.as_ref(), &e.reason),
+            PublishError::IdempotencyKeyReused | PublishError::UnexpectedError(_) => {
+                problem.with_detail(self.to_string())
+            }
+        }
+        .into_response()
+    }
+}
+
+/// Stores the issue and queues one delivery task per confirmed subscriber
+/// of the targeted lists.
+///
+/// Emails are sent by the issue delivery worker, the request returns
+/// as soon as the issue has been accepted. Subscribers who asked for
+/// a daily or weekly digest get it at the start of the next day or week,
+/// along with the other issues published meanwhile.
+///
+/// The title and content can hold merge tags, the worker renders them for
+/// each recipient.
+///
+/// Requests carrying an `Idempotency-Key` header are published once per key
+/// and user, retries get the response of the first request back. A key
+/// is only valid for the body it was first sent with, until it expires.
+#[tracing::instrument(
+    name = "Publish a newsletter issue",
+    skip(body, pool, lists, preferences, idempotency, user_id, request),
+    fields(user_id = %*user_id)
+)]
+pub async fn publish_newsletter(
+    body: web::Json<BodyData>,
+    pool: web::Data<PgPool>,
+    lists: web::Data<ListSettings>,
+    preferences: web::Data<PreferenceSettings>,
+    idempotency: web::Data<IdempotencySettings>,
+    user_id: web::ReqData<UserId>,
+    request: HttpRequest
+) -> Result<HttpResponse, PublishError> {
+
+    let user_id = user_id.into_inner();
+    let idempotency_key = IdempotencyKey::from_headers(request.headers())
+        .map_err(|reason| ValidationError::new("Idempotency-Key", reason))?;
+    let mut transaction = match &idempotency_key {
+        Some(idempotency_key) => {
+            let request_hash = hash_request(&*body)?;
+            match try_processing(&pool, idempotency_key, *user_id, &request_hash, idempotency.expiry()).await? {
+                NextAction::StartProcessing(transaction) => *transaction,
+                NextAction::ReturnSavedResponse(saved_response) => return Ok(saved_response)
+            }
+        }
+        None => pool
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool.")?
+    };
+
+    // Retries get the saved response back even if the lists or segments changed
+    // since. A request failing validation rolls its key back with the transaction.
+    let slugs = body.lists.clone().unwrap_or_else(|| vec![lists.default_slug.clone()]);
+    let list_ids = get_list_ids(&pool, &slugs).await?;
+    let segment = match &body.segment {
+        Some(name) => Some(
+            get_segment_filter(&pool, name)
+                .await?
+                .ok_or_else(|| ValidationError::new("segment", format!("{} is not a known segment.", name)))?
+        ),
+        None => None
+    };
+    if let Some(topic) = &body.topic {
+        if !preferences.topics.contains(topic) {
+            return Err(ValidationError::new("topic", format!("{} is not a known topic.", topic)).into());
+        }
+    }
+    check_merge_tags(&pool, &body).await?;
+
+    let issue_id = insert_newsletter_issue(&mut transaction, &body)
+        .await
+        .context("Failed to store newsletter issue details.")?;
+
+    enqueue_delivery_tasks(&mut transaction, issue_id, &list_ids, body.topic.as_deref(), segment.as_ref())
+        .await
+        .context("Failed to enqueue delivery tasks.")?;
+
+    let response = HttpResponse::Accepted().finish();
+    let response = match idempotency_key {
+        // Saving the response commits the transaction
+        Some(idempotency_key) => {
+            save_response(transaction, &idempotency_key, *user_id, response).await?
+        }
+        None => {
+            transaction
+                .commit()
+                .await
+                .context("Failed to commit SQL transaction to publish a newsletter issue.")?;
+            response
+        }
+    };
+
+    Ok(response)
+}
+
+/// Resolves the targeted lists, in the order they were given.
+async fn get_list_ids(pool: &PgPool, slugs: &[String]) -> Result<Vec<Uuid>, PublishError> {
+
+    if slugs.is_empty() {
+        return Err(ValidationError::new("lists", "At least one list must be targeted.".into()).into());
+    }
+
+    let mut list_ids = Vec::with_capacity(slugs.len());
+    for slug in slugs {
+        let list = get_list_by_slug(pool, slug)
+            .await
+            .context("Failed to retrieve the targeted lists.")?
+            .ok_or_else(|| ValidationError::new("lists", format!("{} is not a known list.", slug)))?;
+        list_ids.push(list.list_id);
+    }
+
+    Ok(list_ids)
+}
+
+/// Rejects broken merge tags now rather than when the issue is being delivered.
+async fn check_merge_tags(pool: &PgPool, body: &BodyData) -> Result<(), PublishError> {
+
+    let definitions = get_field_definitions(pool).await?;
+    for (field, content) in [
+        ("title", &body.title),
+        ("content.text", &body.content.text),
+        ("content.html", &body.content.html)
+    ] {
+        MergeTemplate::parse(content)
+            .and_then(|template| template.check_fields(&definitions))
+            .map_err(|reason| ValidationError::new(field, reason))?;
+    }
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Save newsletter issue details in the database",
+    skip_all
+)]
+async fn insert_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    body: &BodyData
+) -> Result<Uuid, sqlx::Error> {
+
+    let newsletter_issue_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues (
+            newsletter_issue_id,
+            title,
+            text_content,
+            html_content,
+            topic,
+            published_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        newsletter_issue_id,
+        body.title,
+        body.content.text,
+        body.content.html,
+        body.topic,
+        Utc::now()
+        )
+        .execute(transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    Ok(newsletter_issue_id)
+}
+
+#[tracing::instrument(
+    name = "Enqueue delivery tasks for confirmed subscribers",
+    skip(transaction)
+)]
+async fn enqueue_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    list_ids: &[Uuid],
+    topic: Option<&str>,
+    segment: Option<&Filter>
+) -> Result<(), sqlx::Error> {
+
+    // The segment filter is compiled at runtime, hence the unchecked query.
+    // Its values are bound after the issue, the lists and the topic.
+    let segment = segment.map(|filter| filter.to_sql(4));
+    // People on several of the targeted lists get the issue once,
+    // through the first of their lists in the order they were given.
+    // Subscribers without preferences get every topic, right away. Daily and
+    // weekly digests are held until the next day or week starts, in UTC: every
+    // delivery of a digest is due at the same time, the worker sends them together.
+    let sql = format!(
+        r#"
+        INSERT INTO issue_delivery_queue (
+            newsletter_issue_id,
+            subscriber_email,
+            list_id,
+            digest,
+            next_attempt_at
+        )
+        SELECT DISTINCT ON (s.email)
+            $1::uuid,
+            s.email,
+            m.list_id,
+            COALESCE(p.digest_frequency IN ('daily', 'weekly'), FALSE),
+            CASE p.digest_frequency
+                WHEN 'daily' THEN (date_trunc('day', now() AT TIME ZONE 'UTC') + interval '1 day') AT TIME ZONE 'UTC'
+                WHEN 'weekly' THEN (date_trunc('week', now() AT TIME ZONE 'UTC') + interval '1 week') AT TIME ZONE 'UTC'
+                ELSE now()
+            END
+        FROM list_memberships m
+        JOIN subscriptions s ON s.id = m.subscriber_id
+        LEFT JOIN subscriber_preferences p ON p.subscriber_id = s.id
+        WHERE
+            m.status = 'confirmed' AND
+            m.list_id = ANY($2) AND
+            ($3::text IS NULL OR p.topics IS NULL OR $3 = ANY(p.topics)) AND
+            {}
+        ORDER BY s.email, array_position($2, m.list_id)
+        "#,
+        segment.as_ref().map_or("TRUE", |segment| segment.clause.as_str())
+    );
+
+    let mut query = sqlx::query(&sql)
+        .bind(newsletter_issue_id)
+        .bind(list_ids)
+        .bind(topic);
+    for param in segment.iter().flat_map(|segment| &segment.params) {
+        query = query.bind(param);
+    }
+
+    query
+        .execute(transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    Ok(())
+}
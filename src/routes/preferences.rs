@@ -0,0 +1,538 @@
+use actix_web::{http::{header::ContentType, StatusCode}, HttpResponse, ResponseError, web};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::{
+    configuration::PreferenceSettings,
+    email_queue::enqueue_email,
+    email_templates::{EmailTemplate, EmailTemplates, PageTemplate},
+    domain::{DigestFrequency, SubscriberName, ValidationError},
+    problem::{error_chain_fmt, ProblemDetails},
+    signed_token::{TokenPurpose, TokenSigner},
+    utils::see_other
+};
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct PreferencesParameters {
+    token: String
+}
+
+/// A subscriber as shown in the preference center.
+struct StoredPreferences {
+    name: String,
+    digest_frequency: DigestFrequency,
+    // `None` until the subscriber picks their topics: they get all of them
+    topics: Option<Vec<String>>,
+    memberships: Vec<Membership>
+}
+
+/// One of the lists the subscriber joined at some point.
+#[derive(serde::Serialize)]
+struct Membership {
+    slug: String,
+    name: String,
+    status: String
+}
+
+/// The preferences submitted with the form, once validated.
+#[derive(Debug)]
+struct NewPreferences {
+    name: SubscriberName,
+    digest_frequency: DigestFrequency,
+    topics: Vec<String>,
+    // Slugs of the lists the subscriber stays on, they leave the others
+    lists: Vec<String>
+}
+
+#[derive(thiserror::Error)]
+pub enum PreferencesError {
+    #[error("The preferences link is not valid.")]
+    InvalidToken,
+    #[error("Failed to manage the preferences.")]
+    StorageError(#[source] anyhow::Error),
+    #[error("Failed to render the preferences.")]
+    RenderError(#[source] tera::Error)
+}
+
+impl std::fmt::Debug for PreferencesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PreferencesError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PreferencesError::InvalidToken => StatusCode::UNAUTHORIZED,
+            PreferencesError::StorageError(_) | PreferencesError::RenderError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        ProblemDetails::new(self.status_code())
+            .with_detail(self.to_string())
+            .into_response()
+    }
+}
+
+/// A topic of the preference center, checked if the subscriber gets its issues.
+#[derive(serde::Serialize)]
+struct TopicChoice<'a> {
+    name: &'a str,
+    checked: bool
+}
+
+pub async fn preferences_form(
+    parameters: web::Query<PreferencesParameters>,
+    pool: web::Data<PgPool>,
+    signer: web::Data<TokenSigner>,
+    settings: web::Data<PreferenceSettings>,
+    templates: web::Data<EmailTemplates>,
+    flash_messages: IncomingFlashMessages
+) -> Result<HttpResponse, PreferencesError> {
+
+    let email = signer
+        .verify(TokenPurpose::ManagePreferences, &parameters.token)
+        .map_err(|_| PreferencesError::InvalidToken)?;
+    let preferences = get_preferences(&pool, &email)
+        .await
+        .context("Failed to retrieve the subscriber preferences.")
+        .map_err(PreferencesError::StorageError)?
+        // The subscriber is gone, the link is of no use anymore
+        .ok_or(PreferencesError::InvalidToken)?;
+
+    let messages: Vec<&str> = flash_messages.iter().map(|m| m.content()).collect();
+    let topics: Vec<TopicChoice> = settings.topics
+        .iter()
+        .map(|topic| TopicChoice {
+            name: topic,
+            checked: preferences.topics.as_ref().is_none_or(|topics| topics.contains(topic))
+        })
+        .collect();
+    let digest_frequencies: Vec<&str> = DigestFrequency::ALL.iter().map(DigestFrequency::as_str).collect();
+    let is_subscribed = preferences.memberships.iter().any(|m| m.status != "unsubscribed");
+    let unsubscribe_query = is_subscribed.then(|| PreferencesParameters {
+        token: signer.sign(TokenPurpose::UnsubscribeFromAll, &email)
+    });
+
+    let mut context = tera::Context::new();
+    context.insert("messages", &messages);
+    context.insert("query", &url_query(&parameters));
+    context.insert("name", &preferences.name);
+    context.insert("memberships", &preferences.memberships);
+    context.insert("topics", &topics);
+    context.insert("digest_frequencies", &digest_frequencies);
+    context.insert("digest_frequency", preferences.digest_frequency.as_str());
+    context.insert("unsubscribe_query", &unsubscribe_query.as_ref().map(url_query));
+    let page = templates
+        .render_page(PageTemplate::Preferences, &context)
+        .map_err(PreferencesError::RenderError)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(page))
+}
+
+/// The query string of a link carrying `parameters`.
+fn url_query(parameters: &PreferencesParameters) -> String {
+    serde_urlencoded::to_string(parameters).expect("A struct of strings is always encodable")
+}
+
+/// The form sends one `topics` and one `lists` field per checked box, which is
+/// why we take the raw pairs rather than a struct.
+#[tracing::instrument(
+    name = "Update subscriber preferences",
+    skip(parameters, form, pool, signer, settings)
+)]
+pub async fn update_preferences(
+    parameters: web::Query<PreferencesParameters>,
+    form: web::Form<Vec<(String, String)>>,
+    pool: web::Data<PgPool>,
+    signer: web::Data<TokenSigner>,
+    settings: web::Data<PreferenceSettings>
+) -> Result<HttpResponse, PreferencesError> {
+
+    let email = signer
+        .verify(TokenPurpose::ManagePreferences, &parameters.token)
+        .map_err(|_| PreferencesError::InvalidToken)?;
+    let location = format!("/subscriptions/preferences?{}", url_query(&parameters));
+
+    let memberships = get_memberships(&pool, &email)
+        .await
+        .context("Failed to retrieve the list memberships.")
+        .map_err(PreferencesError::StorageError)?;
+    let confirmed_lists: Vec<String> = memberships
+        .into_iter()
+        .filter(|m| m.status == "confirmed")
+        .map(|m| m.slug)
+        .collect();
+    let new_preferences = match parse_preferences(form.into_inner(), &settings.topics, &confirmed_lists) {
+        Ok(new_preferences) => new_preferences,
+        Err(e) => {
+            FlashMessage::error(e.to_string()).send();
+            return Ok(see_other(&location));
+        }
+    };
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")
+        .map_err(PreferencesError::StorageError)?;
+
+    let subscriber_id = get_subscriber_id(&mut transaction, &email)
+        .await
+        .context("Failed to retrieve the subscriber.")
+        .map_err(PreferencesError::StorageError)?
+        .ok_or(PreferencesError::InvalidToken)?;
+
+    let left_lists = store_preferences(&mut transaction, subscriber_id, &new_preferences)
+        .await
+        .context("Failed to store the subscriber preferences.")
+        .map_err(PreferencesError::StorageError)?;
+    // Like unsubscribing through a link, leaving a list is confirmed by email
+    for list_id in left_lists {
+        enqueue_email(&mut transaction, EmailTemplate::UnsubscribeConfirmation, subscriber_id, Some(list_id))
+            .await
+            .context("Failed to queue the unsubscribe confirmation.")
+            .map_err(PreferencesError::StorageError)?;
+    }
+
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to update the preferences.")
+        .map_err(PreferencesError::StorageError)?;
+
+    FlashMessage::info("Your preferences have been saved.").send();
+    Ok(see_other(&location))
+}
+
+fn parse_preferences(
+    fields: Vec<(String, String)>,
+    available_topics: &[String],
+    confirmed_lists: &[String]
+) -> Result<NewPreferences, ValidationError> {
+
+    let mut name = None;
+    let mut digest_frequency = None;
+    let mut topics = Vec::new();
+    let mut lists = Vec::new();
+    for (field, value) in fields {
+        match field.as_str() {
+            "name" => name = Some(value),
+            "digest_frequency" => digest_frequency = Some(value),
+            "topics" => {
+                if !available_topics.contains(&value) {
+                    return Err(ValidationError::new("topics", format!("{} is not a known topic.", value)));
+                }
+                if !topics.contains(&value) {
+                    topics.push(value);
+                }
+            }
+            "lists" => {
+                // Joining a list, or confirming one, goes through the sign-up form
+                if !confirmed_lists.contains(&value) {
+                    return Err(ValidationError::new("lists", format!("{} is not one of your lists.", value)));
+                }
+                if !lists.contains(&value) {
+                    lists.push(value);
+                }
+            }
+            // Unknown fields are ignored, like the form extractor does
+            _ => {}
+        }
+    }
+
+    let name = name.ok_or_else(|| ValidationError::new("name", "The name is missing.".into()))?;
+    let name = SubscriberName::parse(name).map_err(|reason| ValidationError::new("name", reason))?;
+    let digest_frequency = match digest_frequency {
+        Some(digest_frequency) => DigestFrequency::try_from(digest_frequency)
+            .map_err(|reason| ValidationError::new("digest_frequency", reason))?,
+        None => DigestFrequency::default()
+    };
+
+    Ok(NewPreferences { name, digest_frequency, topics, lists })
+}
+
+#[tracing::instrument(
+    name = "Get subscriber preferences",
+    skip(pool, email)
+)]
+async fn get_preferences(
+    pool: &PgPool,
+    email: &str
+) -> Result<Option<StoredPreferences>, anyhow::Error> {
+
+    let row = sqlx::query!(
+        r#"
+        SELECT
+            s.name,
+            p.digest_frequency as "digest_frequency?",
+            p.topics as "topics?"
+        FROM subscriptions s
+        LEFT JOIN subscriber_preferences p ON p.subscriber_id = s.id
+        WHERE s.email = $1
+        "#,
+        email
+        )
+        .fetch_optional(pool)
+        .await?;
+    let memberships = get_memberships(pool, email).await?;
+
+    match row {
+        Some(row) => {
+            let digest_frequency = match row.digest_frequency {
+                Some(digest_frequency) => DigestFrequency::try_from(digest_frequency)
+                    .map_err(|e| anyhow::anyhow!(e))?,
+                None => DigestFrequency::default()
+            };
+            Ok(Some(StoredPreferences {
+                name: row.name,
+                digest_frequency,
+                topics: row.topics,
+                memberships
+            }))
+        }
+        None => Ok(None)
+    }
+}
+
+#[tracing::instrument(
+    name = "Get subscriber list memberships",
+    skip(pool, email)
+)]
+async fn get_memberships(pool: &PgPool, email: &str) -> Result<Vec<Membership>, sqlx::Error> {
+
+    sqlx::query_as!(
+        Membership,
+        r#"
+        SELECT l.slug, l.name, m.status
+        FROM list_memberships m
+        JOIN lists l ON l.list_id = m.list_id
+        JOIN subscriptions s ON s.id = m.subscriber_id
+        WHERE s.email = $1
+        ORDER BY l.name
+        "#,
+        email
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )
+}
+
+#[tracing::instrument(
+    name = "Get subscriber id by email",
+    skip(transaction, email)
+)]
+async fn get_subscriber_id(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &str
+) -> Result<Option<Uuid>, sqlx::Error> {
+
+    let result = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE email = $1 FOR UPDATE"#,
+        email
+        )
+        .fetch_optional(transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    Ok(result.map(|r| r.id))
+}
+
+/// Returns the ids of the lists the subscriber left.
+#[tracing::instrument(
+    name = "Store subscriber preferences",
+    skip(transaction, new_preferences)
+)]
+async fn store_preferences(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    new_preferences: &NewPreferences
+) -> Result<Vec<Uuid>, sqlx::Error> {
+
+    sqlx::query!(
+        r#"UPDATE subscriptions SET name = $2 WHERE id = $1"#,
+        subscriber_id,
+        new_preferences.name.as_ref()
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriber_preferences (subscriber_id, digest_frequency, topics, updated_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (subscriber_id) DO UPDATE
+        SET
+            digest_frequency = EXCLUDED.digest_frequency,
+            topics = EXCLUDED.topics,
+            updated_at = EXCLUDED.updated_at
+        "#,
+        subscriber_id,
+        new_preferences.digest_frequency.as_str(),
+        &new_preferences.topics,
+        Utc::now()
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    let left_lists = sqlx::query!(
+        r#"
+        UPDATE list_memberships m
+        SET status = 'unsubscribed', unsubscribed_at = now()
+        FROM lists l
+        WHERE
+            l.list_id = m.list_id AND
+            m.subscriber_id = $1 AND
+            NOT (l.slug = ANY($2)) AND
+            m.status = 'confirmed'
+        RETURNING m.list_id
+        "#,
+        subscriber_id,
+        &new_preferences.lists
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    // Like unsubscribing, leaving a list drops the deliveries queued through it
+    sqlx::query!(
+        r#"
+        DELETE FROM issue_delivery_queue q
+        USING list_memberships m, subscriptions s
+        WHERE
+            s.id = m.subscriber_id AND
+            q.subscriber_email = s.email AND
+            q.list_id = m.list_id AND
+            m.subscriber_id = $1 AND
+            m.status = 'unsubscribed'
+        "#,
+        subscriber_id
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    Ok(left_lists.into_iter().map(|r| r.list_id).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_preferences;
+    use crate::domain::DigestFrequency;
+    use claim::{assert_err, assert_ok};
+
+    fn topics() -> Vec<String> {
+        vec!["announcements".into(), "events".into()]
+    }
+
+    fn lists() -> Vec<String> {
+        vec!["newsletter".into(), "rust".into()]
+    }
+
+    fn field(name: &str, value: &str) -> (String, String) {
+        (name.into(), value.into())
+    }
+
+    #[test]
+    fn every_checked_topic_is_kept() {
+        let preferences = assert_ok!(parse_preferences(
+            vec![
+                field("name", "Ursula"),
+                field("topics", "announcements"),
+                field("topics", "events"),
+                field("digest_frequency", "weekly")
+            ],
+            &topics(),
+            &lists()
+        ));
+        assert_eq!(preferences.topics, topics());
+        assert_eq!(preferences.digest_frequency, DigestFrequency::Weekly);
+    }
+
+    #[test]
+    fn no_checked_topic_means_no_topic() {
+        let preferences = assert_ok!(parse_preferences(vec![field("name", "Ursula")], &topics(), &lists()));
+        assert!(preferences.topics.is_empty());
+        assert_eq!(preferences.digest_frequency, DigestFrequency::Immediate);
+    }
+
+    #[test]
+    fn unknown_topics_are_rejected() {
+        let e = assert_err!(parse_preferences(
+            vec![field("name", "Ursula"), field("topics", "gossip")],
+            &topics(),
+            &lists()
+        ));
+        assert_eq!(e.field, "topics");
+    }
+
+    #[test]
+    fn only_the_checked_lists_are_kept() {
+        let preferences = assert_ok!(parse_preferences(
+            vec![field("name", "Ursula"), field("lists", "rust"), field("lists", "rust")],
+            &topics(),
+            &lists()
+        ));
+        assert_eq!(preferences.lists, vec!["rust".to_string()]);
+    }
+
+    #[test]
+    fn lists_the_subscriber_never_joined_are_rejected() {
+        let e = assert_err!(parse_preferences(
+            vec![field("name", "Ursula"), field("lists", "go")],
+            &topics(),
+            &lists()
+        ));
+        assert_eq!(e.field, "lists");
+    }
+
+    #[test]
+    fn an_invalid_name_is_rejected() {
+        let e = assert_err!(parse_preferences(vec![field("name", " ")], &topics(), &lists()));
+        assert_eq!(e.field, "name");
+    }
+
+    #[test]
+    fn an_unknown_frequency_is_rejected() {
+        let e = assert_err!(parse_preferences(
+            vec![field("name", "Ursula"), field("digest_frequency", "hourly")],
+            &topics(),
+            &lists()
+        ));
+        assert_eq!(e.field, "digest_frequency");
+    }
+}
@@ -1,14 +1,238 @@
-use actix_web::{HttpResponse, web};
+use actix_web::{http::StatusCode, HttpResponse, ResponseError, web};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::{
+    email_queue::enqueue_email,
+    email_templates::EmailTemplate,
+    problem::{error_chain_fmt, ProblemDetails}
+};
 
 #[derive(serde::Deserialize)]
 pub struct Parameters {
     subscription_token: String
 }
 
+/// A subscription token as stored in the database.
+pub struct StoredToken {
+    pub subscriber_id: Uuid,
+    // The membership the token confirms
+    pub list_id: Uuid,
+    pub expires_at: DateTime<Utc>,
+    pub consumed_at: Option<DateTime<Utc>>
+}
+
+#[derive(thiserror::Error)]
+pub enum ConfirmError {
+    // Unknown tokens are not authorised to confirm anything
+    #[error("The subscription token is not valid.")]
+    UnknownToken,
+    #[error("The subscription token has already been used.")]
+    TokenAlreadyUsed,
+    #[error("The subscription token has expired.")]
+    TokenExpired,
+    #[error("Failed to confirm the subscription.")]
+    StorageError(#[source] anyhow::Error)
+}
+
+impl std::fmt::Debug for ConfirmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ConfirmError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ConfirmError::UnknownToken => StatusCode::UNAUTHORIZED,
+            ConfirmError::TokenAlreadyUsed => StatusCode::CONFLICT,
+            ConfirmError::TokenExpired => StatusCode::GONE,
+            ConfirmError::StorageError(_) => StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        ProblemDetails::new(self.status_code())
+            .with_detail(self.to_string())
+            .into_response()
+    }
+}
+
 #[tracing::instrument(
     name = "Confirm a pending subscriber",
-    skip(_parameters)
+    skip(parameters, pool)
 )]
-pub async fn confirm(_parameters: web::Query<Parameters>) -> HttpResponse {
-    HttpResponse::Ok().finish()
-}
\ No newline at end of file
+pub async fn confirm(
+    parameters: web::Query<Parameters>,
+    pool: web::Data<PgPool>
+) -> Result<HttpResponse, ConfirmError> {
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")
+        .map_err(ConfirmError::StorageError)?;
+
+    let token = get_token(&mut transaction, &parameters.subscription_token)
+        .await
+        .context("Failed to retrieve the subscription token.")
+        .map_err(ConfirmError::StorageError)?
+        .ok_or(ConfirmError::UnknownToken)?;
+
+    // Every token can be used exactly once...
+    if token.consumed_at.is_some() {
+        return Err(ConfirmError::TokenAlreadyUsed);
+    }
+
+    // ...and only until it expires.
+    if token.expires_at < Utc::now() {
+        return Err(ConfirmError::TokenExpired);
+    }
+
+    consume_token(&mut transaction, &parameters.subscription_token)
+        .await
+        .context("Failed to mark the subscription token as consumed.")
+        .map_err(ConfirmError::StorageError)?;
+
+    confirm_subscriber(&mut transaction, token.subscriber_id, token.list_id)
+        .await
+        .context("Failed to mark the list membership as confirmed.")
+        .map_err(ConfirmError::StorageError)?;
+
+    apply_token_attributes(&mut transaction, &parameters.subscription_token)
+        .await
+        .context("Failed to store the attributes held by the subscription token.")
+        .map_err(ConfirmError::StorageError)?;
+
+    enqueue_email(&mut transaction, EmailTemplate::Welcome, token.subscriber_id, Some(token.list_id))
+        .await
+        .context("Failed to queue the welcome email.")
+        .map_err(ConfirmError::StorageError)?;
+
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to confirm a subscriber.")
+        .map_err(ConfirmError::StorageError)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[tracing::instrument(
+    name = "Get subscription token",
+    skip(transaction, subscription_token)
+)]
+pub async fn get_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscription_token: &str
+) -> Result<Option<StoredToken>, sqlx::Error> {
+
+    // Lock the token row so that concurrent confirmations of the same
+    // token are serialised.
+    let result = sqlx::query_as!(
+        StoredToken,
+        r#"
+        SELECT subscriber_id, list_id, expires_at, consumed_at FROM subscription_tokens
+        WHERE subscription_token = $1
+        FOR UPDATE
+        "#,
+        subscription_token
+        )
+        .fetch_optional(transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    Ok(result)
+}
+
+#[tracing::instrument(
+    name = "Mark subscription token as consumed",
+    skip(transaction, subscription_token)
+)]
+pub async fn consume_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscription_token: &str
+) -> Result<(), sqlx::Error> {
+
+    sqlx::query!(
+        r#"UPDATE subscription_tokens SET consumed_at = now() WHERE subscription_token = $1"#,
+        subscription_token
+        )
+        .execute(transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Mark list membership as confirmed",
+    skip(transaction, subscriber_id, list_id)
+)]
+pub async fn confirm_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid
+) -> Result<(), sqlx::Error> {
+
+    sqlx::query!(
+        r#"
+        UPDATE list_memberships
+        SET status = 'confirmed', confirmed_at = now()
+        WHERE subscriber_id = $1 AND list_id = $2
+        "#,
+        subscriber_id,
+        list_id
+        )
+        .execute(transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    Ok(())
+}
+
+/// Merges the attributes sent with the sign-up into the subscriber's:
+/// new values replace old ones, the others are kept.
+#[tracing::instrument(
+    name = "Apply the attributes held by a subscription token",
+    skip(transaction, subscription_token)
+)]
+pub async fn apply_token_attributes(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscription_token: &str
+) -> Result<(), sqlx::Error> {
+
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET attributes = subscriptions.attributes || subscription_tokens.attributes
+        FROM subscription_tokens
+        WHERE subscription_tokens.subscription_token = $1
+            AND subscriptions.id = subscription_tokens.subscriber_id
+        "#,
+        subscription_token
+        )
+        .execute(transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    Ok(())
+}
@@ -1,46 +1,192 @@
-use actix_web::{HttpResponse, web};
+use std::collections::HashMap;
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError, web};
+use anyhow::Context;
 use chrono::Utc;
-use sqlx::{PgPool};
+use rand::{Rng, distributions::Alphanumeric, thread_rng};
+use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
 use unicode_segmentation::UnicodeSegmentation;
-use crate::{domain::{NewSubscriber, SubscriberName, SubscriberEmail}, email_client::EmailClient};
+use crate::{domain::{FieldDefinition, NewSubscriber, SubscriberAttributes, SubscriberName, SubscriberEmail, ValidationError}, email_queue::{cancel_queued_emails, enqueue_email}, email_templates::EmailTemplate, configuration::{ListSettings, SubscriptionTokenSettings}, lists::get_list_by_slug, problem::{error_chain_fmt, ProblemDetails}, subscriber_fields::get_field_definitions, utils::JsonOrForm};
 
 #[derive(serde::Deserialize)]
 pub struct FormData {
     email: String,
-    name: String
+    name: String,
+    // The slug of the list to join, the default list when left out
+    list: Option<String>,
+    // The custom fields defined by the admins
+    #[serde(flatten)]
+    attributes: HashMap<String, serde_json::Value>
+}
+
+impl FormData {
+    fn parse(self, fields: &[FieldDefinition]) -> Result<NewSubscriber, ValidationError> {
+        let name = SubscriberName::parse(self.name)
+            .map_err(|reason| ValidationError::new("name", reason))?;
+        let email = SubscriberEmail::parse(self.email)
+            .map_err(|reason| ValidationError::new("email", reason))?;
+        let attributes = SubscriberAttributes::parse(self.attributes, fields)?;
+        Ok(NewSubscriber { email, name, attributes })
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum SubscribeError {
+    #[error(transparent)]
+    ValidationError(#[from] ValidationError),
+    #[error("Failed to store the subscription.")]
+    StorageError(#[source] anyhow::Error)
 }
 
-impl TryFrom<FormData> for NewSubscriber {
-    type Error = String;
+impl std::fmt::Debug for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for SubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SubscribeError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            SubscribeError::StorageError(_) => StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
 
-    fn try_from(value: FormData) -> Result<Self, Self::Error> {
-        let name = SubscriberName::parse(value.name)?;
-        let email = SubscriberEmail::parse(value.email)?;
-        Ok(Self { email, name })
+    fn error_response(&self) -> HttpResponse {
+        let problem = ProblemDetails::new(self.status_code());
+        match self {
+            SubscribeError::ValidationError(e) => problem.with_invalid_param(e.field.as_ref(), &e.reason),
+            // The source chain is logged, clients only get the summary
+            _ => problem.with_detail(self.to_string())
+        }
+        .into_response()
     }
 }
 
+/// Returns the id of the subscriber, new or already known.
+///
+/// Subscribers still waiting to confirm their first membership get the name
+/// and attributes sent, so that a corrected sign-up shows in the confirmation
+/// email. Other known subscribers keep theirs: anybody can sign up with their
+/// address, the attributes sent are only applied once the owner of the address
+/// follows the confirmation link.
 #[tracing::instrument(
     name = " Saving new subscriber details in the database",
-    skip(pool, new_subscriber),
+    skip(transaction, new_subscriber),
 )]
 pub async fn insert_subscriber(
-    pool: &PgPool,
+    transaction: &mut Transaction<'_, Postgres>,
     new_subscriber: &NewSubscriber
-) -> Result<(), sqlx::Error> {
+) -> Result<Uuid, sqlx::Error> {
 
-    sqlx::query!(
+    // The conflicting row is locked even when the `WHERE` clause does not
+    // hold: concurrent sign-ups of the same address wait for each other
+    // instead of failing on the unique constraint.
+    let result = sqlx::query!(
         r#"
-        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
-        VALUES ($1, $2, $3, $4, 'pending_confirmation')
+        INSERT INTO subscriptions (id, email, name, subscribed_at, attributes)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (email) DO UPDATE
+        SET name = EXCLUDED.name, attributes = subscriptions.attributes || EXCLUDED.attributes
+        WHERE NOT EXISTS (
+            SELECT 1 FROM list_memberships
+            WHERE subscriber_id = subscriptions.id
+            AND (status <> 'pending_confirmation' OR confirmed_at IS NOT NULL)
+        )
+        RETURNING id
         "#,
         Uuid::new_v4(),
         new_subscriber.email.as_ref(),
         new_subscriber.name.as_ref(),
+        Utc::now(),
+        new_subscriber.attributes.as_ref()
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+    if let Some(result) = result {
+        return Ok(result.id);
+    }
+
+    let result = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE email = $1"#,
+        new_subscriber.email.as_ref()
+        )
+        .fetch_one(transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    Ok(result.id)
+}
+
+/// Adds the subscriber to the list, waiting for confirmation.
+///
+/// Returns `false` if the subscriber already confirmed their membership,
+/// which is left untouched.
+#[tracing::instrument(
+    name = "Request a list membership",
+    skip(transaction)
+)]
+pub async fn request_membership(
+    transaction: &mut Transaction<'_, Postgres>,
+    list_id: Uuid,
+    subscriber_id: Uuid
+) -> Result<bool, sqlx::Error> {
+
+    // The conflicting row is only updated, and returned, when the `WHERE`
+    // clause holds: people who left the list can join it again.
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO list_memberships (list_id, subscriber_id, status, subscribed_at)
+        VALUES ($1, $2, 'pending_confirmation', $3)
+        ON CONFLICT (list_id, subscriber_id) DO UPDATE
+        SET status = 'pending_confirmation', unsubscribed_at = NULL
+        WHERE list_memberships.status <> 'confirmed'
+        RETURNING status
+        "#,
+        list_id,
+        subscriber_id,
         Utc::now()
         )
-        .execute(pool)
+        .fetch_optional(transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    Ok(result.is_some())
+}
+
+#[tracing::instrument(
+    name = "Revoke pending subscription tokens",
+    skip(transaction)
+)]
+pub async fn revoke_pending_tokens(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid
+) -> Result<(), sqlx::Error> {
+
+    sqlx::query!(
+        r#"
+        DELETE FROM subscription_tokens
+        WHERE subscriber_id = $1 AND list_id = $2 AND consumed_at IS NULL
+        "#,
+        subscriber_id,
+        list_id
+        )
+        .execute(transaction)
         .await
         .map_err(|e| {
             tracing::error!("Failed to execute query: {:?}", e);
@@ -51,60 +197,147 @@ pub async fn insert_subscriber(
     Ok(())
 }
 
+/// Stores the token along with the attributes it applies once followed.
 #[tracing::instrument(
-    name = "Send a confirmation email to a new subscriber",
-    skip(email_client, new_subscriber)
+    name = "Store subscription token in the database",
+    skip(transaction, subscription_token, attributes, expiry)
 )]
-pub async fn send_confirmation_email(
-    email_client: &EmailClient,
-    new_subscriber: NewSubscriber
-) -> Result<(), reqwest::Error> {
-
-    let confirmation_link = "https://my-api.com/subscriptions/confirm";
-    let play_body = format!(
-        "Welcome to our newsletter!\nVisit {} to confirm your subscription.",
-        confirmation_link
-    );
-    let html_body = format!(
-        "Welcome to our newsletter!<br />\
-        Click <a href=\"{}\">here</a> to confirm your subscription.",
-        confirmation_link
-    );
-
-    email_client
-        .send_email(
-            new_subscriber.email,
-            "Welcome!",
-            &html_body,
-            &play_body
+pub async fn store_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+    subscription_token: &str,
+    attributes: &SubscriberAttributes,
+    expiry: chrono::Duration
+) -> Result<(), sqlx::Error> {
+
+    let created_at = Utc::now();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_tokens (subscription_token, subscriber_id, list_id, attributes, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        subscription_token,
+        subscriber_id,
+        list_id,
+        attributes.as_ref(),
+        created_at,
+        created_at + expiry
         )
+        .execute(transaction)
         .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    Ok(())
+}
+
+/// Generate a random 25-characters-long case-sensitive subscription token.
+///
+/// `thread_rng` is a cryptographically secure pseudo-random number generator,
+/// so tokens cannot be guessed from previously issued ones.
+fn generate_subscription_token() -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(25)
+        .collect()
 }
 
+/// Accepts form-encoded as well as JSON bodies.
 pub async fn subscribe(
-    form: web::Form<FormData>,
+    form: JsonOrForm<FormData>,
     connection: web::Data<PgPool>,
-    email_client: web::Data<EmailClient>
-) -> HttpResponse {
+    subscription_tokens: web::Data<SubscriptionTokenSettings>,
+    lists: web::Data<ListSettings>
+) -> Result<HttpResponse, SubscribeError> {
 
-    let new_subscriber: NewSubscriber = match form.0.try_into() {
-        Ok(subscriber) => subscriber,
-        Err(_) => return HttpResponse::BadRequest().finish()
-    };
+    let form = form.into_inner();
+    let slug = form.list.clone().unwrap_or_else(|| lists.default_slug.clone());
+    let fields = get_field_definitions(&connection)
+        .await
+        .map_err(SubscribeError::StorageError)?;
+    let new_subscriber = form.parse(&fields)?;
+    let list = get_list_by_slug(&connection, &slug)
+        .await
+        .context("Failed to retrieve the list.")
+        .map_err(SubscribeError::StorageError)?
+        .ok_or_else(|| ValidationError::new("list", format!("{} is not a known list.", slug)))?;
 
-    if insert_subscriber(&connection, &new_subscriber).await.is_err() {
-        return HttpResponse::InternalServerError().finish();
-    }
+    // The subscriber, their membership, its token and the confirmation email
+    // are stored atomically: we never want a pending membership that cannot
+    // be confirmed.
+    let mut transaction = connection
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")
+        .map_err(SubscribeError::StorageError)?;
 
-    // Send a useless email to the new subscriber. Ignoring delivery errors for now.
-    if send_confirmation_email(&email_client, new_subscriber)
+    // Somebody may have signed up with this address before, maybe to another list
+    let subscriber_id = insert_subscriber(&mut transaction, &new_subscriber)
         .await
-        .is_err()
-    {
-        return HttpResponse::InternalServerError().finish();
+        .context("Failed to store the subscriber in the database.")
+        .map_err(SubscribeError::StorageError)?;
+
+    let is_pending = request_membership(&mut transaction, list.list_id, subscriber_id)
+        .await
+        .context("Failed to store the list membership.")
+        .map_err(SubscribeError::StorageError)?;
+    // Confirmed members get no new token, hence their attributes stay as they are.
+    // We answer exactly as we would for a new subscriber
+    // to avoid revealing who is on the list.
+    if is_pending {
+        send_confirmation(&mut transaction, &new_subscriber, subscriber_id, list.list_id, &subscription_tokens)
+            .await
+            .map_err(SubscribeError::StorageError)?;
     }
 
-    HttpResponse::Ok().finish()
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to store a new subscriber.")
+        .map_err(SubscribeError::StorageError)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Issues a new token for a pending membership and queues its confirmation email.
+async fn send_confirmation(
+    transaction: &mut Transaction<'static, Postgres>,
+    new_subscriber: &NewSubscriber,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+    subscription_tokens: &SubscriptionTokenSettings
+) -> Result<(), anyhow::Error> {
+
+    // A previous email might have been lost: rotate the token and send it again.
+    revoke_pending_tokens(transaction, subscriber_id, list_id)
+        .await
+        .context("Failed to revoke the pending subscription tokens.")?;
+    cancel_queued_emails(transaction, EmailTemplate::Confirmation, subscriber_id, list_id)
+        .await
+        .context("Failed to cancel the confirmation emails still queued.")?;
+
+    let subscription_token = generate_subscription_token();
+    store_token(
+        transaction,
+        subscriber_id,
+        list_id,
+        &subscription_token,
+        &new_subscriber.attributes,
+        subscription_tokens.expiry()
+    )
+    .await
+    .context("Failed to store the confirmation token for a new subscriber.")?;
+
+    enqueue_email(transaction, EmailTemplate::Confirmation, subscriber_id, Some(list_id))
+        .await
+        .context("Failed to queue the confirmation email.")?;
+
+    Ok(())
 }
 
 /// Returns `true` if the input satifies all our validation constraints
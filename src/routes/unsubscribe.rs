@@ -0,0 +1,274 @@
+use actix_web::{http::{header::ContentType, StatusCode}, HttpResponse, ResponseError, web};
+use anyhow::Context;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::{
+    email_queue::enqueue_email,
+    email_templates::EmailTemplate,
+    lists::{get_list_by_slug, List},
+    problem::{error_chain_fmt, ProblemDetails},
+    signed_token::{TokenPurpose, TokenSigner}
+};
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct UnsubscribeParameters {
+    // Left out by the link of the preference center, which leaves every list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    list: Option<String>,
+    token: String
+}
+
+impl UnsubscribeParameters {
+    /// Returns the email address the link was issued for, along with the
+    /// list it unsubscribes from.
+    async fn verify(
+        &self,
+        pool: &PgPool,
+        signer: &TokenSigner
+    ) -> Result<(String, Option<List>), UnsubscribeError> {
+
+        let slug = match &self.list {
+            Some(slug) => slug,
+            None => {
+                let email = signer
+                    .verify(TokenPurpose::UnsubscribeFromAll, &self.token)
+                    .map_err(|_| UnsubscribeError::InvalidToken)?;
+                return Ok((email, None));
+            }
+        };
+        let email = signer
+            .verify(TokenPurpose::UnsubscribeFromList(slug), &self.token)
+            .map_err(|_| UnsubscribeError::InvalidToken)?;
+        let list = get_list_by_slug(pool, slug)
+            .await
+            .context("Failed to retrieve the list.")
+            .map_err(UnsubscribeError::StorageError)?
+            .ok_or(UnsubscribeError::InvalidToken)?;
+
+        Ok((email, Some(list)))
+    }
+
+    /// The query string of the unsubscribe link, to post the form back to it.
+    fn query(&self) -> String {
+        serde_urlencoded::to_string(self).expect("A struct of strings is always encodable")
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum UnsubscribeError {
+    #[error("The unsubscribe link is not valid.")]
+    InvalidToken,
+    #[error("Failed to unsubscribe.")]
+    StorageError(#[source] anyhow::Error)
+}
+
+impl std::fmt::Debug for UnsubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for UnsubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            UnsubscribeError::InvalidToken => StatusCode::UNAUTHORIZED,
+            UnsubscribeError::StorageError(_) => StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        ProblemDetails::new(self.status_code())
+            .with_detail(self.to_string())
+            .into_response()
+    }
+}
+
+/// The page behind the link at the bottom of every issue.
+///
+/// It does not unsubscribe anybody by itself: link scanners and mail
+/// previews follow links, so leaving the list takes a `POST`.
+pub async fn unsubscribe_form(
+    parameters: web::Query<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+    signer: web::Data<TokenSigner>
+) -> Result<HttpResponse, UnsubscribeError> {
+
+    let (_, list) = parameters.verify(&pool, &signer).await?;
+    let list_name = match &list {
+        Some(list) => list.name.as_str(),
+        None => "our newsletters"
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribe</title>
+</head>
+<body>
+    <p>Do you want to stop receiving {}?</p>
+    <form action="/subscriptions/unsubscribe?{}" method="post">
+        <button type="submit">Unsubscribe</button>
+    </form>
+</body>
+</html>"#,
+            htmlescape::encode_minimal(list_name),
+            htmlescape::encode_minimal(&parameters.query())
+        )))
+}
+
+/// Handles both the form above and RFC 8058 one-click requests sent by
+/// mailbox providers, whose body only says `List-Unsubscribe=One-Click`.
+///
+/// Only the form sends an unsubscribe confirmation: one-click requests come
+/// from the mailbox provider, which already tells its user what happened.
+#[tracing::instrument(
+    name = "Unsubscribe a subscriber",
+    skip(parameters, body, pool, signer)
+)]
+pub async fn unsubscribe(
+    parameters: web::Query<UnsubscribeParameters>,
+    body: web::Bytes,
+    pool: web::Data<PgPool>,
+    signer: web::Data<TokenSigner>
+) -> Result<HttpResponse, UnsubscribeError> {
+
+    let (email, list) = parameters.verify(&pool, &signer).await?;
+    let list_id = list.as_ref().map(|list| list.list_id);
+    let is_one_click = body
+        .split(|byte| *byte == b'&')
+        .any(|pair| pair == b"List-Unsubscribe=One-Click");
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")
+        .map_err(UnsubscribeError::StorageError)?;
+    let unsubscribed = mark_as_unsubscribed(&mut transaction, &email, list_id)
+        .await
+        .context("Failed to mark the subscriber as unsubscribed.")
+        .map_err(UnsubscribeError::StorageError)?;
+    // Only once: repeated requests do not flood the inbox
+    if let Some(subscriber_id) = unsubscribed.filter(|_| !is_one_click) {
+        enqueue_email(&mut transaction, EmailTemplate::UnsubscribeConfirmation, subscriber_id, list_id)
+            .await
+            .context("Failed to queue the unsubscribe confirmation.")
+            .map_err(UnsubscribeError::StorageError)?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to unsubscribe a subscriber.")
+        .map_err(UnsubscribeError::StorageError)?;
+
+    let message = match &list {
+        Some(list) => format!(
+            "You have been unsubscribed from {}, you will not hear from it again.",
+            htmlescape::encode_minimal(&list.name)
+        ),
+        None => "You have been unsubscribed, you will not hear from us again.".to_string()
+    };
+
+    // Unknown or already unsubscribed addresses get the same answer:
+    // repeating a one-click request is not an error.
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribe</title>
+</head>
+<body>
+    <p>{}</p>
+</body>
+</html>"#,
+            message
+        )))
+}
+
+/// Keeps the memberships around with the `unsubscribed` status and drops the
+/// deliveries still queued for them.
+///
+/// Every membership of the subscriber is ended when no list is given.
+/// Returns the subscriber, or `None` if there was no membership left to end.
+#[tracing::instrument(
+    name = "Mark subscriber as unsubscribed",
+    skip(transaction, email)
+)]
+async fn mark_as_unsubscribed(
+    transaction: &mut Transaction<'static, Postgres>,
+    email: &str,
+    list_id: Option<Uuid>
+) -> Result<Option<Uuid>, sqlx::Error> {
+
+    let ended = sqlx::query!(
+        r#"
+        UPDATE list_memberships m
+        SET status = 'unsubscribed', unsubscribed_at = now()
+        FROM subscriptions s
+        WHERE
+            s.id = m.subscriber_id AND
+            s.email = $1 AND
+            ($2::uuid IS NULL OR m.list_id = $2) AND
+            m.status <> 'unsubscribed'
+        RETURNING m.subscriber_id
+        "#,
+        email,
+        list_id
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM issue_delivery_queue
+        WHERE subscriber_email = $1 AND ($2::uuid IS NULL OR list_id = $2)
+        "#,
+        email,
+        list_id
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    // Addresses are unique, the memberships are all theirs
+    Ok(ended.first().map(|membership| membership.subscriber_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UnsubscribeParameters;
+
+    #[test]
+    fn the_query_is_url_encoded() {
+        let parameters = UnsubscribeParameters {
+            list: Some("a&b=c".into()),
+            token: "d+e".into()
+        };
+        assert_eq!(parameters.query(), "list=a%26b%3Dc&token=d%2Be");
+    }
+
+    #[test]
+    fn links_without_a_list_only_carry_the_token() {
+        let parameters = UnsubscribeParameters {
+            list: None,
+            token: "abc".into()
+        };
+        assert_eq!(parameters.query(), "token=abc");
+    }
+}
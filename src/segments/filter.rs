@@ -0,0 +1,445 @@
+use chrono::{DateTime, NaiveDate, Utc};
+
+/// A predicate over subscribers, written by admins in a small DSL:
+///
+/// ```text
+/// subscribed_at >= "2024-01-01" and (plan = "pro" or seats > 10)
+/// not opened any of last 3 issues
+/// ```
+///
+/// `subscribed_at` compares with dates (`YYYY-MM-DD`) or RFC 3339 timestamps,
+/// any other identifier names a custom attribute, compared with a string,
+/// a number, `true` or `false`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    SubscribedAt(Comparison, DateTime<Utc>),
+    Attribute {
+        name: String,
+        comparison: Comparison,
+        value: AttributeValue
+    },
+    // Opened at least one of the given number of most recent issues
+    OpenedAnyOfLast(i32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge
+}
+
+impl Comparison {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            Comparison::Eq => "=",
+            Comparison::Ne => "<>",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">="
+        }
+    }
+
+    fn is_equality(&self) -> bool {
+        matches!(self, Comparison::Eq | Comparison::Ne)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    Text(String),
+    // Kept as written, Postgres parses it as a `numeric`
+    Number(String),
+    Bool(bool)
+}
+
+impl Filter {
+    // Filters are stored and sent over the wire, keep them reasonably small
+    const MAX_LENGTH: usize = 1000;
+    // Bounds the recursion of the parser
+    const MAX_DEPTH: usize = 32;
+    // Nobody needs to look further back than that
+    const MAX_ISSUES: i32 = 100;
+
+    /// Returns the filter described by `s`, or why it is not a valid filter.
+    pub fn parse(s: &str) -> Result<Filter, String> {
+        if s.len() > Self::MAX_LENGTH {
+            return Err(format!(
+                "The filter must not be longer than {} characters.",
+                Self::MAX_LENGTH
+            ));
+        }
+
+        let tokens = tokenize(s)?;
+        if tokens.is_empty() {
+            return Err("The filter cannot be empty.".into());
+        }
+        let mut parser = Parser { tokens, position: 0, depth: 0 };
+        let filter = parser.or()?;
+        match parser.peek() {
+            None => Ok(filter),
+            Some(token) => Err(format!("Unexpected {}.", token))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Text(String),
+    Number(String),
+    Comparison(Comparison),
+    LeftParen,
+    RightParen
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "`{}`", word),
+            Token::Text(text) => write!(f, "\"{}\"", text),
+            Token::Number(number) => write!(f, "`{}`", number),
+            Token::Comparison(comparison) => write!(f, "`{}`", comparison.as_sql()),
+            Token::LeftParen => write!(f, "`(`"),
+            Token::RightParen => write!(f, "`)`")
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' {
+            chars.next();
+            tokens.push(Token::LeftParen);
+        } else if c == ')' {
+            chars.next();
+            tokens.push(Token::RightParen);
+        } else if c == '"' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(escaped @ ('"' | '\\')) => text.push(escaped),
+                        _ => return Err("Only `\\\"` and `\\\\` can be escaped in strings.".into())
+                    },
+                    Some(c) => text.push(c),
+                    None => return Err("A string is missing its closing quote.".into())
+                }
+            }
+            tokens.push(Token::Text(text));
+        } else if c.is_ascii_digit() || c == '-' {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_ascii_digit() || c == '.' || (c == '-' && number.is_empty()) {
+                    number.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            if number.parse::<f64>().is_err() {
+                return Err(format!("`{}` is not a valid number.", number));
+            }
+            tokens.push(Token::Number(number));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_ascii_alphanumeric() || c == '_' {
+                    word.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            tokens.push(Token::Word(word));
+        } else {
+            chars.next();
+            let comparison = match (c, chars.peek()) {
+                ('=', _) => Comparison::Eq,
+                ('!', Some('=')) => Comparison::Ne,
+                ('<', Some('=')) => Comparison::Le,
+                ('>', Some('=')) => Comparison::Ge,
+                ('<', _) => Comparison::Lt,
+                ('>', _) => Comparison::Gt,
+                _ => return Err(format!("Unexpected character `{}`.", c))
+            };
+            if matches!(comparison, Comparison::Ne | Comparison::Le | Comparison::Ge) {
+                chars.next();
+            }
+            tokens.push(Token::Comparison(comparison));
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// A recursive descent parser, `and` binds tighter than `or`.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| "The filter ends too early.".to_string())?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), String> {
+        match self.next()? {
+            Token::Word(word) if word.eq_ignore_ascii_case(keyword) => Ok(()),
+            token => Err(format!("Expected `{}`, found {}.", keyword, token))
+        }
+    }
+
+    fn or(&mut self) -> Result<Filter, String> {
+        let mut filter = self.and()?;
+        while self.is_keyword("or") {
+            self.next()?;
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, String> {
+        let mut filter = self.unary()?;
+        while self.is_keyword("and") {
+            self.next()?;
+            filter = Filter::And(Box::new(filter), Box::new(self.unary()?));
+        }
+        Ok(filter)
+    }
+
+    fn unary(&mut self) -> Result<Filter, String> {
+        self.depth += 1;
+        if self.depth > Filter::MAX_DEPTH {
+            return Err("The filter is nested too deeply.".into());
+        }
+
+        let filter = if self.is_keyword("not") {
+            self.next()?;
+            Filter::Not(Box::new(self.unary()?))
+        } else if self.peek() == Some(&Token::LeftParen) {
+            self.next()?;
+            let filter = self.or()?;
+            match self.next()? {
+                Token::RightParen => filter,
+                token => return Err(format!("Expected `)`, found {}.", token))
+            }
+        } else {
+            self.predicate()?
+        };
+
+        self.depth -= 1;
+        Ok(filter)
+    }
+
+    fn predicate(&mut self) -> Result<Filter, String> {
+        let name = match self.next()? {
+            Token::Word(word) => word,
+            token => return Err(format!("Expected a field, found {}.", token))
+        };
+
+        if name.eq_ignore_ascii_case("opened") {
+            return self.opened();
+        }
+
+        let comparison = match self.next()? {
+            Token::Comparison(comparison) => comparison,
+            token => return Err(format!("Expected a comparison after `{}`, found {}.", name, token))
+        };
+
+        if name == "subscribed_at" {
+            let date = match self.next()? {
+                Token::Text(date) => parse_date(&date)?,
+                token => return Err(format!("Expected a date, found {}.", token))
+            };
+            return Ok(Filter::SubscribedAt(comparison, date));
+        }
+
+        let value = match self.next()? {
+            Token::Text(text) => AttributeValue::Text(text),
+            Token::Number(number) => AttributeValue::Number(number),
+            Token::Word(word) if word == "true" => AttributeValue::Bool(true),
+            Token::Word(word) if word == "false" => AttributeValue::Bool(false),
+            token => return Err(format!("Expected a value for `{}`, found {}.", name, token))
+        };
+        if !matches!(value, AttributeValue::Number(_)) && !comparison.is_equality() {
+            return Err(format!("`{}` only applies to numbers.", comparison.as_sql()));
+        }
+
+        Ok(Filter::Attribute { name, comparison, value })
+    }
+
+    /// `opened any of last <n> issues`, the `opened` keyword is already consumed.
+    fn opened(&mut self) -> Result<Filter, String> {
+        self.expect_keyword("any")?;
+        self.expect_keyword("of")?;
+        self.expect_keyword("last")?;
+        let n_issues = match self.next()? {
+            Token::Number(number) => number
+                .parse::<i32>()
+                .ok()
+                .filter(|n| (1..=Filter::MAX_ISSUES).contains(n))
+                .ok_or_else(|| format!(
+                    "The number of issues must be between 1 and {}.",
+                    Filter::MAX_ISSUES
+                ))?,
+            token => return Err(format!("Expected a number of issues, found {}.", token))
+        };
+        self.expect_keyword("issues")?;
+        Ok(Filter::OpenedAnyOfLast(n_issues))
+    }
+}
+
+/// Accepts `YYYY-MM-DD`, taken as midnight UTC, or an RFC 3339 timestamp.
+fn parse_date(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(DateTime::from_utc(date.and_hms(0, 0, 0), Utc));
+    }
+    DateTime::parse_from_rfc3339(s)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|_| format!("\"{}\" is not a valid date.", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use claim::{assert_err, assert_ok};
+
+    use super::{AttributeValue, Comparison, Filter};
+
+    fn attribute(name: &str, value: AttributeValue) -> Filter {
+        Filter::Attribute { name: name.into(), comparison: Comparison::Eq, value }
+    }
+
+    #[test]
+    fn attributes_are_compared_with_strings_numbers_and_booleans() {
+        assert_eq!(
+            assert_ok!(Filter::parse(r#"plan = "pro""#)),
+            attribute("plan", AttributeValue::Text("pro".into()))
+        );
+        assert_eq!(
+            assert_ok!(Filter::parse("seats = -1.5")),
+            attribute("seats", AttributeValue::Number("-1.5".into()))
+        );
+        assert_eq!(
+            assert_ok!(Filter::parse("beta = true")),
+            attribute("beta", AttributeValue::Bool(true))
+        );
+    }
+
+    #[test]
+    fn subscription_dates_are_parsed() {
+        assert_eq!(
+            assert_ok!(Filter::parse(r#"subscribed_at >= "2024-01-31""#)),
+            Filter::SubscribedAt(Comparison::Ge, Utc.ymd(2024, 1, 31).and_hms(0, 0, 0))
+        );
+        assert_ok!(Filter::parse(r#"subscribed_at < "2024-01-31T12:00:00+02:00""#));
+        assert_err!(Filter::parse(r#"subscribed_at < "yesterday""#));
+        assert_err!(Filter::parse("subscribed_at < 2024"));
+    }
+
+    #[test]
+    fn engagement_is_parsed() {
+        assert_eq!(
+            assert_ok!(Filter::parse("opened any of last 3 issues")),
+            Filter::OpenedAnyOfLast(3)
+        );
+        assert_err!(Filter::parse("opened any of last 0 issues"));
+        assert_err!(Filter::parse("opened any of last 3"));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let a = attribute("a", AttributeValue::Number("1".into()));
+        let b = attribute("b", AttributeValue::Number("2".into()));
+        let c = attribute("c", AttributeValue::Number("3".into()));
+        assert_eq!(
+            assert_ok!(Filter::parse("a = 1 or b = 2 and c = 3")),
+            Filter::Or(
+                Box::new(a.clone()),
+                Box::new(Filter::And(Box::new(b.clone()), Box::new(c.clone())))
+            )
+        );
+        assert_eq!(
+            assert_ok!(Filter::parse("(a = 1 or b = 2) and not c = 3")),
+            Filter::And(
+                Box::new(Filter::Or(Box::new(a), Box::new(b))),
+                Box::new(Filter::Not(Box::new(c)))
+            )
+        );
+    }
+
+    #[test]
+    fn ordering_only_applies_to_numbers() {
+        assert_ok!(Filter::parse("seats > 10"));
+        assert_err!(Filter::parse(r#"plan > "pro""#));
+        assert_err!(Filter::parse("beta < true"));
+    }
+
+    #[test]
+    fn strings_can_contain_escaped_quotes() {
+        assert_eq!(
+            assert_ok!(Filter::parse(r#"company = "the \"best\" one""#)),
+            attribute("company", AttributeValue::Text(r#"the "best" one"#.into()))
+        );
+    }
+
+    #[test]
+    fn malformed_filters_are_rejected() {
+        for filter in [
+            "",
+            "plan",
+            r#"plan = "pro"#,
+            r#"plan = "pro" and"#,
+            r#"(plan = "pro""#,
+            r#"plan = "pro")"#,
+            r#"plan ~ "pro""#,
+            "plan = pro",
+            "1 = 1",
+        ] {
+            assert_err!(Filter::parse(filter), "{} was accepted.", filter);
+        }
+    }
+
+    #[test]
+    fn deeply_nested_filters_are_rejected() {
+        let filter = format!("{}a = 1{}", "(".repeat(100), ")".repeat(100));
+        assert_err!(Filter::parse(&filter));
+    }
+
+    #[test]
+    fn long_filters_are_rejected() {
+        let filter = vec!["a = 1"; 200].join(" or ");
+        assert_err!(Filter::parse(&filter));
+    }
+}
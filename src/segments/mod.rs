@@ -0,0 +1,7 @@
+mod filter;
+mod persistence;
+mod sql;
+
+pub use filter::{AttributeValue, Comparison, Filter};
+pub use persistence::{count_subscribers, get_segment_filter};
+pub use sql::SqlFilter;
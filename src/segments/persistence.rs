@@ -0,0 +1,56 @@
+use anyhow::Context;
+use sqlx::PgPool;
+
+use super::Filter;
+
+/// Counts the subscribers matching the filter who are on at least one list,
+/// the ones an issue targeted at the segment could reach.
+#[tracing::instrument(name = "Count subscribers in a segment", skip(pool))]
+pub async fn count_subscribers(pool: &PgPool, filter: &Filter) -> Result<i64, sqlx::Error> {
+
+    let filter = filter.to_sql(1);
+    let sql = format!(
+        r#"
+        SELECT COUNT(*)
+        FROM subscriptions s
+        WHERE
+            EXISTS (
+                SELECT 1 FROM list_memberships m
+                WHERE m.subscriber_id = s.id AND m.status = 'confirmed'
+            ) AND
+            {}
+        "#,
+        filter.clause
+    );
+    let mut query = sqlx::query_scalar(&sql);
+    for param in filter.params {
+        query = query.bind(param);
+    }
+
+    query
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )
+}
+
+/// Returns the filter of the segment with the given name, if there is one.
+#[tracing::instrument(name = "Get segment filter", skip(pool))]
+pub async fn get_segment_filter(pool: &PgPool, name: &str) -> Result<Option<Filter>, anyhow::Error> {
+
+    let row = sqlx::query!(
+        r#"SELECT filter FROM segments WHERE name = $1"#,
+        name
+        )
+        .fetch_optional(pool)
+        .await
+        .context("Failed to retrieve the segment.")?;
+
+    // Filters are validated before they are stored
+    row.map(|row| Filter::parse(&row.filter).map_err(|e| anyhow::anyhow!(e)))
+        .transpose()
+        .context("Failed to parse a stored segment filter.")
+}
@@ -0,0 +1,129 @@
+use super::filter::{AttributeValue, Comparison, Filter};
+
+/// A filter compiled to a boolean SQL expression over `subscriptions`,
+/// which must be aliased as `s` in the query.
+///
+/// Values are never spliced into the expression: they are bound as text
+/// parameters, numbered from the index given to `Filter::to_sql`, and cast
+/// by Postgres.
+#[derive(Debug)]
+pub struct SqlFilter {
+    pub clause: String,
+    pub params: Vec<String>
+}
+
+impl Filter {
+    pub fn to_sql(&self, first_param: usize) -> SqlFilter {
+        let mut compiler = Compiler { first_param, params: Vec::new() };
+        let clause = compiler.compile(self);
+        SqlFilter { clause, params: compiler.params }
+    }
+}
+
+struct Compiler {
+    first_param: usize,
+    params: Vec<String>
+}
+
+impl Compiler {
+    /// Binds `value` and returns its placeholder.
+    fn bind(&mut self, value: String) -> String {
+        self.params.push(value);
+        format!("${}", self.first_param + self.params.len() - 1)
+    }
+
+    // Every predicate is `TRUE` or `FALSE`, never `NULL`,
+    // so that `NOT` matches what is left out.
+    fn compile(&mut self, filter: &Filter) -> String {
+        match filter {
+            Filter::And(left, right) => {
+                format!("({} AND {})", self.compile(left), self.compile(right))
+            }
+            Filter::Or(left, right) => {
+                format!("({} OR {})", self.compile(left), self.compile(right))
+            }
+            Filter::Not(filter) => format!("NOT {}", self.compile(filter)),
+            Filter::SubscribedAt(comparison, date) => {
+                let date = self.bind(date.to_rfc3339());
+                format!("(s.subscribed_at {} {}::timestamptz)", comparison.as_sql(), date)
+            }
+            Filter::Attribute { name, comparison, value } => {
+                let name = self.bind(name.clone());
+                let (value, cast) = match value {
+                    AttributeValue::Text(text) => (self.bind(text.clone()), "text"),
+                    AttributeValue::Number(number) => (self.bind(number.clone()), "numeric"),
+                    AttributeValue::Bool(b) => (self.bind(b.to_string()), "boolean")
+                };
+                let attribute = format!("s.attributes -> {}", name);
+                let value = format!("to_jsonb({}::{})", value, cast);
+                match comparison {
+                    // Subscribers without the attribute are not equal to anything
+                    Comparison::Ne => format!("({} IS DISTINCT FROM {})", attribute, value),
+                    Comparison::Eq => format!("COALESCE({} = {}, FALSE)", attribute, value),
+                    // jsonb orders values of different types, only compare numbers
+                    _ => format!(
+                        "COALESCE(jsonb_typeof({0}) = 'number' AND {0} {1} {2}, FALSE)",
+                        attribute,
+                        comparison.as_sql(),
+                        value
+                    )
+                }
+            }
+            Filter::OpenedAnyOfLast(n_issues) => {
+                let n_issues = self.bind(n_issues.to_string());
+                format!(
+                    "EXISTS (\
+                        SELECT 1 FROM issue_opens o \
+                        WHERE o.subscriber_id = s.id AND o.newsletter_issue_id IN (\
+                            SELECT newsletter_issue_id FROM newsletter_issues \
+                            ORDER BY published_at DESC LIMIT {}::integer\
+                        )\
+                    )",
+                    n_issues
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::assert_ok;
+
+    use super::Filter;
+
+    #[test]
+    fn values_are_bound_as_parameters() {
+        let filter = assert_ok!(Filter::parse(r#"plan = "pro" or not seats >= 10"#));
+
+        let sql = filter.to_sql(3);
+
+        assert_eq!(
+            sql.clause,
+            "(COALESCE(s.attributes -> $3 = to_jsonb($4::text), FALSE) OR \
+            NOT COALESCE(jsonb_typeof(s.attributes -> $5) = 'number' AND \
+            s.attributes -> $5 >= to_jsonb($6::numeric), FALSE))"
+        );
+        assert_eq!(sql.params, vec!["plan", "pro", "seats", "10"]);
+    }
+
+    #[test]
+    fn quotes_never_reach_the_clause() {
+        let filter = assert_ok!(Filter::parse(r#"plan = "'; DROP TABLE subscriptions; --""#));
+
+        let sql = filter.to_sql(1);
+
+        assert!(!sql.clause.contains("DROP"));
+        assert_eq!(sql.params[1], "'; DROP TABLE subscriptions; --");
+    }
+
+    #[test]
+    fn dates_are_bound_as_timestamps() {
+        let filter = assert_ok!(Filter::parse(r#"subscribed_at < "2024-01-31""#));
+
+        let sql = filter.to_sql(1);
+
+        assert_eq!(sql.clause, "(s.subscribed_at < $1::timestamptz)");
+        assert_eq!(sql.params, vec!["2024-01-31T00:00:00+00:00"]);
+    }
+}
@@ -0,0 +1,30 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::email_templates::EmailTemplate;
+
+/// Records which provider delivered an email sent outside of issues, whose
+/// deliveries are recorded in `issue_deliveries`.
+#[tracing::instrument(name = "Record a sent email", skip(pool, recipient))]
+pub async fn record_sent_email(
+    pool: &PgPool,
+    template: EmailTemplate,
+    recipient: &str,
+    provider: &str
+) -> Result<(), sqlx::Error> {
+
+    sqlx::query!(
+        r#"
+        INSERT INTO sent_emails (sent_email_id, template, recipient, provider, sent_at)
+        VALUES ($1, $2, $3, $4, now())
+        "#,
+        Uuid::new_v4(),
+        template.name(),
+        recipient,
+        provider
+        )
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
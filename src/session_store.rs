@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
+use actix_web::cookie::time::Duration;
+use anyhow::Context;
+use chrono::Utc;
+use rand::{distributions::Alphanumeric, Rng, rngs::OsRng};
+use sqlx::{types::Json, PgPool};
+
+type SessionState = HashMap<String, String>;
+
+/// Keeps session state in the `sessions` table.
+///
+/// Sessions survive restarts and are shared by every replica, without
+/// adding another service to run next to Postgres.
+#[derive(Clone)]
+pub struct PgSessionStore {
+    pool: PgPool
+}
+
+impl PgSessionStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for PgSessionStore {
+
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        let row = sqlx::query!(
+            r#"
+            SELECT state as "state: Json<SessionState>"
+            FROM sessions
+            WHERE session_key = $1 AND expires_at > now()
+            "#,
+            session_key.as_ref()
+            )
+            .fetch_optional(&self.pool)
+            .await
+            .context("Failed to load session state.")
+            .map_err(LoadError::Other)?;
+
+        Ok(row.map(|r| r.state.0))
+    }
+
+    async fn save(
+        &self,
+        session_state: SessionState,
+        ttl: &Duration
+    ) -> Result<SessionKey, SaveError> {
+        let session_key = generate_session_key();
+
+        sqlx::query!(
+            r#"
+            INSERT INTO sessions (session_key, state, expires_at)
+            VALUES ($1, $2, $3)
+            "#,
+            session_key.as_ref(),
+            Json(session_state) as _,
+            expires_at(ttl)
+            )
+            .execute(&self.pool)
+            .await
+            .context("Failed to save session state.")
+            .map_err(SaveError::Other)?;
+
+        Ok(session_key)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &Duration
+    ) -> Result<SessionKey, UpdateError> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE sessions
+            SET state = $2, expires_at = $3
+            WHERE session_key = $1
+            "#,
+            session_key.as_ref(),
+            Json(&session_state) as _,
+            expires_at(ttl)
+            )
+            .execute(&self.pool)
+            .await
+            .context("Failed to update session state.")
+            .map_err(UpdateError::Other)?;
+
+        // The session has been purged in the meantime, start a new one.
+        if result.rows_affected() == 0 {
+            return self
+                .save(session_state, ttl)
+                .await
+                .map_err(|e| match e {
+                    SaveError::Serialization(e) => UpdateError::Serialization(e),
+                    SaveError::Other(e) => UpdateError::Other(e)
+                });
+        }
+
+        Ok(session_key)
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"DELETE FROM sessions WHERE session_key = $1"#,
+            session_key.as_ref()
+            )
+            .execute(&self.pool)
+            .await
+            .context("Failed to delete session state.")?;
+
+        Ok(())
+    }
+}
+
+fn expires_at(ttl: &Duration) -> chrono::DateTime<Utc> {
+    Utc::now() + chrono::Duration::seconds(ttl.whole_seconds())
+}
+
+/// 64 alphanumeric characters drawn from the OS random number generator.
+fn generate_session_key() -> SessionKey {
+    let value: String = std::iter::repeat_with(|| OsRng.sample(Alphanumeric))
+        .map(char::from)
+        .take(64)
+        .collect();
+
+    // 64 ASCII characters are well below the limit on the size of a session key
+    value.try_into().unwrap()
+}
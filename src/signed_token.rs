@@ -0,0 +1,199 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a token grants access to.
+///
+/// The purpose is part of the signed message, so a token issued
+/// for one purpose cannot be replayed for another.
+#[derive(Clone, Copy, Debug)]
+pub enum TokenPurpose<'a> {
+    // Leaving every list at once, from the preference center
+    UnsubscribeFromAll,
+    // Leaving the list with the given slug
+    UnsubscribeFromList(&'a str),
+    ManagePreferences,
+    // Recording that an issue was opened
+    TrackOpens
+}
+
+impl TokenPurpose<'_> {
+    fn update(&self, mac: &mut HmacSha256) {
+        match self {
+            TokenPurpose::UnsubscribeFromAll => mac.update(b"unsubscribe-from-all"),
+            TokenPurpose::UnsubscribeFromList(slug) => {
+                // Slugs never contain ':', the separator stays unambiguous
+                mac.update(b"unsubscribe-from-list:");
+                mac.update(slug.as_bytes());
+            }
+            TokenPurpose::ManagePreferences => mac.update(b"manage-preferences"),
+            TokenPurpose::TrackOpens => mac.update(b"track-opens")
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("The token is not valid, or it expired.")]
+pub struct InvalidToken;
+
+/// Signs the subscriber tokens we embed in the links we send out.
+///
+/// Tokens are not stored anywhere: a link keeps working until it is
+/// `max_age` old, or until the key changes. The age should leave plenty of
+/// time for the unsubscribe links sitting in old emails.
+#[derive(Clone)]
+pub struct TokenSigner {
+    // `Secret<Vec<u8>>` cannot be cloned, clones share the key instead
+    key: Arc<Secret<Vec<u8>>>,
+    max_age: Duration
+}
+
+impl TokenSigner {
+    pub fn new(key: Secret<Vec<u8>>, max_age: Duration) -> Self {
+        Self { key: Arc::new(key), max_age }
+    }
+
+    /// Returns `<email>.<issued at>.<signature>`: the email and the signature
+    /// are encoded as URL-safe base64, the time is a Unix timestamp.
+    pub fn sign(&self, purpose: TokenPurpose, email: &str) -> String {
+        self.sign_at(purpose, email, Utc::now())
+    }
+
+    fn sign_at(&self, purpose: TokenPurpose, email: &str, issued_at: DateTime<Utc>) -> String {
+        let issued_at = issued_at.timestamp();
+        let signature = self.mac(purpose, email, issued_at).finalize().into_bytes();
+        format!(
+            "{}.{}.{}",
+            base64::encode_config(email, base64::URL_SAFE_NO_PAD),
+            issued_at,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Returns the email address the token was issued for.
+    pub fn verify(&self, purpose: TokenPurpose, token: &str) -> Result<String, InvalidToken> {
+        let mut parts = token.splitn(3, '.');
+        let (email, issued_at, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(email), Some(issued_at), Some(signature)) => (email, issued_at, signature),
+            _ => return Err(InvalidToken)
+        };
+        let email = base64::decode_config(email, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|email| String::from_utf8(email).ok())
+            .ok_or(InvalidToken)?;
+        let issued_at: i64 = issued_at.parse().map_err(|_| InvalidToken)?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| InvalidToken)?;
+
+        // `verify_slice` compares in constant time
+        self.mac(purpose, &email, issued_at)
+            .verify_slice(&signature)
+            .map_err(|_| InvalidToken)?;
+        let age = Utc::now().timestamp().saturating_sub(issued_at);
+        if age > i64::try_from(self.max_age.as_secs()).unwrap_or(i64::MAX) {
+            return Err(InvalidToken);
+        }
+        Ok(email)
+    }
+
+    fn mac(&self, purpose: TokenPurpose, email: &str, issued_at: i64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(self.key.expose_secret())
+            .expect("HMAC accepts keys of any size");
+        purpose.update(&mut mac);
+        mac.update(b":");
+        mac.update(issued_at.to_string().as_bytes());
+        mac.update(b":");
+        mac.update(email.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{TokenPurpose, TokenSigner};
+    use chrono::Utc;
+    use claim::{assert_err, assert_ok_eq};
+    use secrecy::Secret;
+
+    // Tokens are valid for a day
+    fn signer(key: &str) -> TokenSigner {
+        TokenSigner::new(Secret::new(key.as_bytes().to_vec()), Duration::from_secs(24 * 60 * 60))
+    }
+
+    #[test]
+    fn a_signed_token_is_verified() {
+        let signer = signer("a-key");
+        let token = signer.sign(TokenPurpose::UnsubscribeFromAll, "ursula@domain.com");
+        assert_ok_eq!(
+            signer.verify(TokenPurpose::UnsubscribeFromAll, &token),
+            "ursula@domain.com".to_string()
+        );
+    }
+
+    #[test]
+    fn a_token_signed_with_another_key_is_rejected() {
+        let token = signer("a-key").sign(TokenPurpose::UnsubscribeFromAll, "ursula@domain.com");
+        assert_err!(signer("another-key").verify(TokenPurpose::UnsubscribeFromAll, &token));
+    }
+
+    #[test]
+    fn a_token_for_another_email_is_rejected() {
+        let signer = signer("a-key");
+        let token = signer.sign(TokenPurpose::UnsubscribeFromAll, "ursula@domain.com");
+        let (_, issued_at_and_signature) = token.split_once('.').unwrap();
+        let forged = format!(
+            "{}.{}",
+            base64::encode_config("someone@domain.com", base64::URL_SAFE_NO_PAD),
+            issued_at_and_signature
+        );
+        assert_err!(signer.verify(TokenPurpose::UnsubscribeFromAll, &forged));
+    }
+
+    #[test]
+    fn an_expired_token_is_rejected() {
+        let signer = signer("a-key");
+        let issued_at = Utc::now() - chrono::Duration::hours(25);
+        let token = signer.sign_at(TokenPurpose::UnsubscribeFromAll, "ursula@domain.com", issued_at);
+        assert_err!(signer.verify(TokenPurpose::UnsubscribeFromAll, &token));
+    }
+
+    #[test]
+    fn a_token_with_another_issue_time_is_rejected() {
+        let signer = signer("a-key");
+        let token = signer.sign(TokenPurpose::UnsubscribeFromAll, "ursula@domain.com");
+        let (email, rest) = token.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let forged = format!("{}.{}.{}", email, Utc::now().timestamp() + 3600, signature);
+        assert_err!(signer.verify(TokenPurpose::UnsubscribeFromAll, &forged));
+    }
+
+    #[test]
+    fn a_token_issued_for_another_purpose_is_rejected() {
+        let signer = signer("a-key");
+        let token = signer.sign(TokenPurpose::ManagePreferences, "ursula@domain.com");
+        assert_err!(signer.verify(TokenPurpose::UnsubscribeFromAll, &token));
+    }
+
+    #[test]
+    fn a_token_issued_for_another_list_is_rejected() {
+        let signer = signer("a-key");
+        let token = signer.sign(TokenPurpose::UnsubscribeFromList("rust"), "ursula@domain.com");
+        assert_err!(signer.verify(TokenPurpose::UnsubscribeFromList("go"), &token));
+        assert_err!(signer.verify(TokenPurpose::UnsubscribeFromAll, &token));
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        let signer = signer("a-key");
+        for token in ["", "no-separator", ".", "..", "!!!.!!!", "!!!.1.!!!", "dXJzdWxh.soon.c2ln"] {
+            assert_err!(signer.verify(TokenPurpose::UnsubscribeFromAll, token));
+        }
+    }
+}
@@ -1,30 +1,39 @@
+use actix_session::SessionMiddleware;
 use actix_web::{HttpServer, App, web, dev::Server};
+use actix_web_flash_messages::{storage::CookieMessageStore, FlashMessagesFramework};
+use actix_web_lab::middleware::from_fn;
 use sqlx::{PgPool, postgres::PgPoolOptions};
 use tracing_actix_web::TracingLogger;
-use std::{net::TcpListener};
+use std::{future::Future, net::TcpListener, pin::Pin};
 
-use crate::{routes::*, email_client::EmailClient, configuration::{Settings}};
+use crate::{authentication::{create_first_user, redirect_anonymous_users, reject_anonymous_users}, routes::*, session_store::PgSessionStore, email_client::EmailClient, email_queue::run_email_queue_until_stopped, configuration::Settings, email_templates::EmailTemplates, sweeper::run_sweeper_until_stopped, issue_delivery_worker::run_worker_until_stopped, problem::extractor_error_handler};
+
+/// A task that runs next to the HTTP server for the whole lifetime of the application.
+type BackgroundTask = Pin<Box<dyn Future<Output = ()> + Send>>;
 
 pub struct Application {
     port: u16,
-    server: Server
+    server: Server,
+    background_tasks: Vec<BackgroundTask>
 }
 
 impl Application {
     // We have converted the `build` function into a a constructor for `Application`
     pub async fn build(configuration: Settings) -> Result<Self, std::io::Error> {
         let connection_pool = get_connection_pool(&configuration);
-        let sender_email = configuration
-            .email_client
-            .sender()
-            .expect("Invalid sender email address");
-
-        let email_client = EmailClient::new(
-            configuration.email_client.base_url,
-            sender_email,
-            configuration.email_client.authorization_token,
-            std::time::Duration::from_millis(1000)
-        );
+        let email_client = configuration.email_client
+        .clone()
+        .client(&connection_pool)
+        .map_err(std::io::Error::other)?;
+
+        if let Some(password) = configuration.admin.password.clone() {
+            let created = create_first_user(&configuration.admin.username, password, &connection_pool)
+                .await
+                .map_err(std::io::Error::other)?;
+            if created {
+                tracing::info!(username = %configuration.admin.username, "Created the admin user");
+            }
+        }
 
         let address = format!(
             "{}:{}",
@@ -32,11 +41,50 @@ impl Application {
             configuration.application.port
         );
 
+        let email_templates = configuration.email_templates
+            .templates()
+            .map_err(std::io::Error::other)?;
+
         let listener = TcpListener::bind(&address)?;
         let port = listener.local_addr()?.port();
-        let server = run(listener, connection_pool, email_client)?;
-
-        Ok(Self { port, server })
+        let server = run(
+            listener,
+            connection_pool.clone(),
+            email_client.clone(),
+            email_templates.clone(),
+            configuration.clone()
+        )?;
+
+        let signer = configuration.application.token_signer();
+        let background_tasks: Vec<BackgroundTask> = vec![
+            Box::pin(run_sweeper_until_stopped(
+                connection_pool.clone(),
+                configuration.subscription_tokens.sweeper_interval(),
+                configuration.idempotency.expiry()
+            )),
+            Box::pin(run_email_queue_until_stopped(
+                connection_pool.clone(),
+                email_client.clone(),
+                email_templates.clone(),
+                configuration.email_queue,
+                configuration.application.base_url.clone(),
+                signer.clone()
+            )),
+            Box::pin(run_worker_until_stopped(
+                connection_pool,
+                email_client,
+                email_templates,
+                configuration.issue_delivery,
+                configuration.application.base_url,
+                signer
+            ))
+        ];
+
+        Ok(Self {
+            port,
+            server,
+            background_tasks
+        })
     }
 
     pub fn port(&self) -> u16 {
@@ -45,6 +93,24 @@ impl Application {
 
     // A more expressive name that makes it clear that this function only returns when the application is stopped
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+        // Background tasks live as long as the HTTP server does
+        let handles: Vec<_> = self.background_tasks
+            .into_iter()
+            .map(tokio::spawn)
+            .collect();
+
+        let outcome = self.server.await;
+        for handle in handles {
+            handle.abort();
+        }
+        outcome
+    }
+
+    /// Runs the HTTP server without the background tasks.
+    ///
+    /// Nothing is sent until somebody drains the queues: tests do it when
+    /// they expect emails, instead of racing the workers.
+    pub async fn run_server_until_stopped(self) -> Result<(), std::io::Error> {
         self.server.await
     }
 
@@ -58,53 +124,124 @@ pub fn get_connection_pool(
         .connect_lazy_with(configuration.database.with_db())
 }
 
-pub async fn build(configuration: Settings) -> Result<Server, std::io::Error> {
-
-    let connection_pool = get_connection_pool(&configuration);
-    
-    let sender_email = configuration
-        .email_client
-        .sender()
-        .expect("Invalid sender email address");
-
-    let email_client = EmailClient::new(
-        configuration.email_client.base_url,
-        sender_email,
-        configuration.email_client.authorization_token,
-        std::time::Duration::from_millis(100)
-    );
-
-    let address = format!(
-        "{}:{}",
-        configuration.application.host,
-        configuration.application.port
-    );
-
-    let listener = TcpListener::bind(address)?;
-    run(
-        listener,
-        connection_pool,
-        email_client
-    )
-}
+// We need to define a wrapper type in order to retrieve the URL
+// in the `subscribe` handler.
+// Retrieval from the context, in actix-web, is type-based: using
+// a raw `String` would expose us to conflicts.
+pub struct ApplicationBaseUrl(pub String);
 
 pub fn run(
     listener: TcpListener,
     connection: PgPool,
-    email_client: EmailClient
+    email_client: EmailClient,
+    email_templates: EmailTemplates,
+    configuration: Settings
 ) -> Result<Server, std::io::Error> {
 
+    let Settings {
+        application,
+        subscription_tokens,
+        idempotency,
+        preferences,
+        lists,
+        ..
+    } = configuration;
+
+    let secret_key = application.cookie_key();
+    let message_store = CookieMessageStore::builder(secret_key.clone()).build();
+    let message_framework = FlashMessagesFramework::builder(message_store).build();
+    let session_store = PgSessionStore::new(connection.clone());
+
+    let token_signer = web::Data::new(application.token_signer());
     let con = web::Data::new(connection);
     let email_client = web::Data::new(email_client);
+    let email_templates = web::Data::new(email_templates);
+    let base_url = web::Data::new(ApplicationBaseUrl(application.base_url));
+    let subscription_tokens = web::Data::new(subscription_tokens);
+    let idempotency = web::Data::new(idempotency);
+    let preferences = web::Data::new(preferences);
+    let lists = web::Data::new(lists);
 
     let server = HttpServer::new(move || {
         App::new()
+            .wrap(message_framework.clone())
+            .wrap(SessionMiddleware::new(
+                session_store.clone(),
+                secret_key.clone()
+            ))
             .wrap(TracingLogger::default())
             .route("/health_check", web::get().to(health_check))
             .route("/subscriptions", web::post().to(subscribe))
             .route("/subscriptions/confirm", web::get().to(confirm))
+            .route("/subscriptions/unsubscribe", web::get().to(unsubscribe_form))
+            .route("/subscriptions/unsubscribe", web::post().to(unsubscribe))
+            .route("/subscriptions/preferences", web::get().to(preferences_form))
+            .route("/subscriptions/preferences", web::post().to(update_preferences))
+            .route("/subscriptions/opens", web::get().to(track_open))
+            .route("/login", web::get().to(login_form))
+            .route("/login", web::post().to(login))
+            .service(
+                web::resource("/newsletters")
+                    .wrap(from_fn(reject_anonymous_users))
+                    .route(web::post().to(publish_newsletter))
+            )
+            .service(
+                web::scope("/admin")
+                    // API routes, for scripts as well as for logged-in users
+                    .service(
+                        web::scope("/dead_letters")
+                            .wrap(from_fn(reject_anonymous_users))
+                            .route("", web::get().to(list_dead_letters))
+                            .route("/redrive", web::post().to(redrive_dead_letters))
+                    )
+                    .service(
+                        web::scope("/email_dead_letters")
+                            .wrap(from_fn(reject_anonymous_users))
+                            .route("", web::get().to(list_email_dead_letters))
+                            .route("/redrive", web::post().to(redrive_email_dead_letters))
+                    )
+                    .service(
+                        web::resource("/fields")
+                            .wrap(from_fn(reject_anonymous_users))
+                            .route(web::get().to(list_fields))
+                            .route(web::post().to(create_field))
+                    )
+                    .service(
+                        web::resource("/lists")
+                            .wrap(from_fn(reject_anonymous_users))
+                            .route(web::get().to(list_lists))
+                            .route(web::post().to(create_list))
+                    )
+                    .service(
+                        web::scope("/segments")
+                            .wrap(from_fn(reject_anonymous_users))
+                            .route("", web::get().to(list_segments))
+                            .route("", web::post().to(create_segment))
+                            .route("/preview", web::post().to(preview_segment))
+                    )
+                    // Pages, for logged-in users only
+                    .service(
+                        web::scope("")
+                            .wrap(from_fn(redirect_anonymous_users))
+                            .route("/dashboard", web::get().to(admin_dashboard))
+                            .route("/password", web::get().to(change_password_form))
+                            .route("/password", web::post().to(change_password))
+                            .route("/logout", web::post().to(log_out))
+                    )
+            )
+            // Malformed bodies and query strings are reported as problem details too
+            .app_data(web::JsonConfig::default().error_handler(extractor_error_handler))
+            .app_data(web::FormConfig::default().error_handler(extractor_error_handler))
+            .app_data(web::QueryConfig::default().error_handler(extractor_error_handler))
             .app_data(con.clone())
             .app_data(email_client.clone())
+            .app_data(email_templates.clone())
+            .app_data(base_url.clone())
+            .app_data(subscription_tokens.clone())
+            .app_data(token_signer.clone())
+            .app_data(idempotency.clone())
+            .app_data(preferences.clone())
+            .app_data(lists.clone())
     })
     .listen(listener)?
     .run();
@@ -0,0 +1,25 @@
+use anyhow::Context;
+use sqlx::PgPool;
+
+use crate::domain::{FieldDefinition, FieldType};
+
+/// Returns the custom fields admins defined, ordered by name.
+#[tracing::instrument(name = "Get subscriber field definitions", skip(pool))]
+pub async fn get_field_definitions(pool: &PgPool) -> Result<Vec<FieldDefinition>, anyhow::Error> {
+
+    let rows = sqlx::query!(
+        r#"SELECT name, field_type, required, options FROM subscriber_fields ORDER BY name"#
+        )
+        .fetch_all(pool)
+        .await
+        .context("Failed to retrieve the subscriber fields.")?;
+
+    // Definitions are validated before they are stored
+    rows.into_iter()
+        .map(|row| {
+            let field_type = FieldType::parse(&row.field_type, row.options)?;
+            FieldDefinition::parse(row.name, field_type, row.required)
+        })
+        .collect::<Result<_, _>>()
+        .context("Failed to parse a stored subscriber field.")
+}
@@ -0,0 +1,87 @@
+use chrono::Utc;
+use sqlx::PgPool;
+
+/// Periodically removes rows that are no longer useful to anybody.
+///
+/// It loops forever, it is meant to be spawned next to the HTTP server and
+/// dropped when the application shuts down.
+pub async fn run_sweeper_until_stopped(
+    pool: PgPool,
+    interval: std::time::Duration,
+    idempotency_expiry: chrono::Duration
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        // Failures are logged by the query itself, we will try again on the next tick.
+        let _ = purge_expired_subscription_tokens(&pool).await;
+        let _ = purge_expired_sessions(&pool).await;
+        let _ = purge_expired_idempotency_keys(&pool, idempotency_expiry).await;
+    }
+}
+
+#[tracing::instrument(
+    name = "Purge expired subscription tokens",
+    skip(pool)
+)]
+pub async fn purge_expired_subscription_tokens(pool: &PgPool) -> Result<u64, sqlx::Error> {
+
+    let result = sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE expires_at < now()"#
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    tracing::info!("Purged {} expired subscription tokens", result.rows_affected());
+    Ok(result.rows_affected())
+}
+
+#[tracing::instrument(
+    name = "Purge expired sessions",
+    skip(pool)
+)]
+pub async fn purge_expired_sessions(pool: &PgPool) -> Result<u64, sqlx::Error> {
+
+    let result = sqlx::query!(
+        r#"DELETE FROM sessions WHERE expires_at < now()"#
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    tracing::info!("Purged {} expired sessions", result.rows_affected());
+    Ok(result.rows_affected())
+}
+
+#[tracing::instrument(
+    name = "Purge expired idempotency keys",
+    skip(pool)
+)]
+pub async fn purge_expired_idempotency_keys(
+    pool: &PgPool,
+    expiry: chrono::Duration
+) -> Result<u64, sqlx::Error> {
+
+    let result = sqlx::query!(
+        r#"DELETE FROM idempotency WHERE created_at < $1"#,
+        Utc::now() - expiry
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        }
+    )?;
+
+    tracing::info!("Purged {} expired idempotency keys", result.rows_affected());
+    Ok(result.rows_affected())
+}
@@ -1,3 +1,4 @@
+use tokio::task::JoinHandle;
 use tracing::{Subscriber, subscriber::set_global_default};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
@@ -27,7 +28,7 @@ pub fn get_subscriber<Sink>(
         .unwrap_or_else(|_| EnvFilter::new(env_filter));
 
     let formatting_layer = BunyanFormattingLayer::new(
-        name.into(),
+        name,
         sink
     );
 
@@ -45,4 +46,17 @@ pub fn init_subscriber(
 ) {
     LogTracer::init().expect("failed to set logger");
     set_global_default(subscriber).expect("Fauled to set subscriber");
-}
\ No newline at end of file
+}
+
+/// Runs a CPU-heavy closure on tokio's blocking thread pool.
+///
+/// The closure is attached to the current span, so whatever it logs
+/// still shows up as part of the request it has been spawned from.
+pub fn spawn_blocking_with_tracing<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let current_span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || current_span.in_scope(f))
+}
@@ -0,0 +1,51 @@
+use std::{future::Future, pin::Pin};
+
+use actix_web::{dev::Payload, http::header::LOCATION, web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use serde::de::DeserializeOwned;
+
+/// Redirects the browser, turning the next request into a `GET`.
+pub fn see_other(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, location))
+        .finish()
+}
+
+// Return an opaque 500 while preserving the error root's cause for logging.
+pub fn e500<T>(e: T) -> actix_web::Error
+where
+    T: std::fmt::Debug + std::fmt::Display + 'static,
+{
+    actix_web::error::ErrorInternalServerError(e)
+}
+
+/// Extracts `T` from a JSON or a form-encoded body, depending on the `Content-Type` of the request.
+///
+/// Bodies that are not JSON are read as forms, so HTML forms and existing clients
+/// keep working. Errors are those of `web::Json` and `web::Form`, as configured on the app.
+pub struct JsonOrForm<T>(pub T);
+
+impl<T> JsonOrForm<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for JsonOrForm<T> {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        // `application/json` as well as `application/problem+json` and the like
+        let is_json = matches!(
+            req.mime_type(),
+            Ok(Some(mime)) if mime.subtype() == "json" || mime.suffix().is_some_and(|s| s == "json")
+        );
+        if is_json {
+            let json = web::Json::<T>::from_request(req, payload);
+            Box::pin(async move { Ok(JsonOrForm(json.await?.into_inner())) })
+        } else {
+            let form = web::Form::<T>::from_request(req, payload);
+            Box::pin(async move { Ok(JsonOrForm(form.await?.into_inner())) })
+        }
+    }
+}
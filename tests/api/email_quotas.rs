@@ -0,0 +1,144 @@
+use std::{num::NonZeroU32, time::Duration};
+
+use newsletter_service::{domain::SubscriberEmail, email_client::{BatchEmail, EmailClient, EmailError, EmailTransport, MessageOptions, PostmarkTransport, RateLimit}};
+use secrecy::Secret;
+use wiremock::{Mock, MockServer, ResponseTemplate, matchers::{method, path}};
+
+use crate::helpers::{spawn_app, TestApp};
+
+// A Postmark transport allowed one email a day, configured under `name`
+fn postmark(name: &str, server: &MockServer) -> (String, Box<dyn EmailTransport>, RateLimit) {
+    (
+        name.into(),
+        Box::new(PostmarkTransport::new(server.uri(), Secret::new("token".into()), Duration::from_secs(1))),
+        RateLimit { per_second: None, per_day: NonZeroU32::new(1) }
+    )
+}
+
+// A client allowed one email a day through the mock Postmark server
+fn email_client(app: &TestApp) -> EmailClient {
+    EmailClient::with_failover(
+        postmark("postmark", &app.email_server),
+        vec![],
+        SubscriberEmail::parse("rust@lists.com".into()).unwrap(),
+        5,
+        Duration::from_secs(60),
+        Some(app.db_pool.clone())
+    )
+}
+
+fn recipient() -> SubscriberEmail {
+    SubscriberEmail::parse("ursula@example.com".into()).unwrap()
+}
+
+#[tokio::test]
+async fn daily_quotas_hold_across_clients() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let first = email_client(&app).send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello").await;
+    // Another replica, or the same one after a restart
+    let second = email_client(&app).send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello").await;
+
+    // Assert
+    assert!(first.is_ok());
+    let e = second.unwrap_err();
+    let retry_after = e.retry_after().unwrap();
+    // The quota is renewed at midnight UTC
+    assert!(retry_after <= Duration::from_secs(24 * 60 * 60));
+}
+
+#[tokio::test]
+async fn emails_that_failed_do_not_use_up_the_daily_quota() {
+    // Arrange
+    let app = spawn_app().await;
+    let email_client = email_client(&app);
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let first = email_client.send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello").await;
+    let second = email_client.send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello").await;
+
+    // Assert
+    assert!(first.is_err());
+    assert!(second.is_ok());
+}
+
+#[tokio::test]
+async fn batches_go_out_in_part_when_the_daily_quota_runs_short() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let emails: Vec<BatchEmail> = (0..2)
+        .map(|_| BatchEmail {
+            recipient: recipient(),
+            subject: "Issue #1",
+            html_content: "<p>Hello</p>",
+            text_content: "Hello",
+            options: MessageOptions::default()
+        })
+        .collect();
+
+    // Act
+    let mut outcomes = email_client(&app).send_batch(&emails).await.into_iter();
+
+    // Assert
+    assert!(outcomes.next().unwrap().is_ok());
+    let e = outcomes.next().unwrap().unwrap_err();
+    assert!(matches!(e, EmailError::Throttled { retry_after: Some(_), .. }));
+}
+
+#[tokio::test]
+async fn transports_of_the_same_kind_have_a_daily_quota_each() {
+    // Arrange
+    let app = spawn_app().await;
+    let fallback_server = MockServer::start().await;
+    for server in [&app.email_server, &fallback_server] {
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(server)
+            .await;
+    }
+    let email_client = EmailClient::with_failover(
+        postmark("postmark", &app.email_server),
+        vec![postmark("postmark_fallback", &fallback_server)],
+        SubscriberEmail::parse("rust@lists.com".into()).unwrap(),
+        5,
+        Duration::from_secs(60),
+        Some(app.db_pool.clone())
+    );
+
+    // Act
+    let first = email_client.send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello").await;
+    let second = email_client.send_email(recipient(), "Issue #1", "<p>Hello</p>", "Hello").await;
+
+    // Assert
+    assert_eq!(first.unwrap().provider, "postmark");
+    assert_eq!(second.unwrap().provider, "postmark");
+}
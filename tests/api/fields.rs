@@ -0,0 +1,263 @@
+use newsletter_service::problem::ProblemDetails;
+use wiremock::{Mock, ResponseTemplate, matchers::{method, path}};
+
+use crate::helpers::{spawn_app, TestApp};
+
+async fn define_fields(app: &TestApp) {
+    for field in [
+        serde_json::json!({"name": "plan", "field_type": "enum", "required": true, "options": ["free", "pro"]}),
+        serde_json::json!({"name": "seats", "field_type": "number"}),
+        serde_json::json!({"name": "beta", "field_type": "bool"}),
+        serde_json::json!({"name": "renewal", "field_type": "date"}),
+        serde_json::json!({"name": "company", "field_type": "string"})
+    ] {
+        app.post_fields(field).await.error_for_status().unwrap();
+    }
+}
+
+#[tokio::test]
+async fn subscribe_stores_the_custom_fields_as_attributes() {
+    // Arrange
+    let app = spawn_app().await;
+    define_fields(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com\
+        &plan=pro&seats=12&beta=true&renewal=2025-01-31&company=";
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT attributes FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(
+        saved.attributes,
+        serde_json::json!({"plan": "pro", "seats": 12, "beta": true, "renewal": "2025-01-31"})
+    );
+}
+
+#[tokio::test]
+async fn json_bodies_can_send_typed_custom_fields() {
+    // Arrange
+    let app = spawn_app().await;
+    define_fields(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com",
+            "plan": "pro",
+            "seats": 12,
+            "beta": false
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT attributes FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.attributes, serde_json::json!({"plan": "pro", "seats": 12, "beta": false}));
+}
+
+#[tokio::test]
+async fn signing_up_again_while_pending_merges_the_attributes() {
+    // Arrange
+    let app = spawn_app().await;
+    define_fields(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&plan=free&seats=3".into())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Act - Part 1 - Sign up again
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&plan=pro&beta=true".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert - Part 1 - Nobody confirmed anything yet, the latest sign-up wins
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT attributes FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.attributes, serde_json::json!({"plan": "pro", "seats": 3, "beta": true}));
+
+    // Act - Part 2 - Follow the latest confirmation link
+    let email_request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    reqwest::get(confirmation_links.html).await.unwrap().error_for_status().unwrap();
+
+    // Assert - Part 2
+    let saved = sqlx::query!("SELECT attributes FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.attributes, serde_json::json!({"plan": "pro", "seats": 3, "beta": true}));
+}
+
+#[tokio::test]
+async fn confirmed_subscribers_signing_up_again_keep_their_attributes() {
+    // Arrange
+    let app = spawn_app().await;
+    define_fields(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&plan=free&seats=3".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+    let email_request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    reqwest::get(confirmation_links.html).await.unwrap().error_for_status().unwrap();
+
+    // Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&plan=pro&beta=true".into())
+        .await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT attributes FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.attributes, serde_json::json!({"plan": "free", "seats": 3}));
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_400_when_custom_fields_are_invalid() {
+    // Arrange
+    let app = spawn_app().await;
+    define_fields(&app).await;
+    let test_cases = [
+        ("", "plan", "missing required field"),
+        ("&plan=enterprise", "plan", "unknown option"),
+        ("&plan=pro&seats=many", "seats", "not a number"),
+        ("&plan=pro&beta=maybe", "beta", "not a boolean"),
+        ("&plan=pro&renewal=tomorrow", "renewal", "not a date"),
+        ("&plan=pro&shoe_size=42", "shoe_size", "undefined field")
+    ];
+
+    for (fields, field, description) in test_cases {
+        // Act
+        let body = format!("name=le%20guin&email=ursula_le_guin%40gmail.com{}", fields);
+        let response = app.post_subscriptions(body).await;
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the payload had a {}.",
+            description
+        );
+        let problem: ProblemDetails = response.json().await.unwrap();
+        assert_eq!(problem.invalid_params[0].name, field);
+    }
+}
+
+#[tokio::test]
+async fn fields_are_listed_with_their_definition() {
+    // Arrange
+    let app = spawn_app().await;
+    define_fields(&app).await;
+
+    // Act
+    let fields: Vec<serde_json::Value> = reqwest::Client::new()
+        .get(format!("{}/admin/fields", app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    // Assert
+    let names: Vec<_> = fields.iter().map(|field| field["name"].as_str().unwrap()).collect();
+    assert_eq!(names, ["beta", "company", "plan", "renewal", "seats"]);
+    assert_eq!(
+        fields[2],
+        serde_json::json!({"name": "plan", "field_type": "enum", "required": true, "options": ["free", "pro"]})
+    );
+}
+
+#[tokio::test]
+async fn invalid_fields_are_rejected_with_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = [
+        (serde_json::json!({"name": "Plan", "field_type": "string"}), "name"),
+        (serde_json::json!({"name": "email", "field_type": "string"}), "name"),
+        (serde_json::json!({"name": "plan", "field_type": "json"}), "field_type"),
+        (serde_json::json!({"name": "plan", "field_type": "enum"}), "options"),
+        (serde_json::json!({"name": "plan", "field_type": "string", "options": ["pro"]}), "options")
+    ];
+
+    for (body, field) in test_cases {
+        // Act
+        let response = app.post_fields(body.clone()).await;
+
+        // Assert
+        assert_eq!(400, response.status().as_u16(), "{} was accepted.", body);
+        let problem: ProblemDetails = response.json().await.unwrap();
+        assert_eq!(problem.invalid_params[0].name, field);
+    }
+}
+
+#[tokio::test]
+async fn field_names_are_unique() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = serde_json::json!({"name": "seats", "field_type": "number"});
+    app.post_fields(body.clone()).await.error_for_status().unwrap();
+
+    // Act
+    let response = app.post_fields(body).await;
+
+    // Assert
+    assert_eq!(409, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn fields_are_only_managed_by_authenticated_users() {
+    // Arrange
+    let app = spawn_app().await;
+
+    for method in [reqwest::Method::GET, reqwest::Method::POST] {
+        // Act
+        let response = reqwest::Client::new()
+            .request(method, format!("{}/admin/fields", app.address))
+            .json(&serde_json::json!({"name": "seats", "field_type": "number"}))
+            .send()
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(401, response.status().as_u16());
+    }
+}
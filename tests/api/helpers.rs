@@ -1,9 +1,10 @@
-use newsletter_service::{startup::{get_connection_pool, Application}, configuration::{get_configuration, DatabaseSettings}, telemetry::{get_subscriber, init_subscriber}, email_client::EmailClient};
+use newsletter_service::{startup::{get_connection_pool, Application}, configuration::{get_configuration, DatabaseSettings, EmailQueueSettings, IdempotencySettings, IssueDeliverySettings}, telemetry::{get_subscriber, init_subscriber}, email_client::EmailClient, email_queue::try_send_queued_email, email_templates::EmailTemplates, issue_delivery_worker::try_execute_task, signed_token::TokenSigner};
+use argon2::{password_hash::SaltString, Algorithm, Argon2, Params, PasswordHasher, Version};
 use once_cell::sync::Lazy;
-use secrecy::ExposeSecret;
+use secrecy::{ExposeSecret, Secret};
 use sqlx::{PgPool, PgConnection, Connection, Executor, Pool, Postgres};
 use uuid::Uuid;
-use wiremock::MockServer;
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate, matchers::{path, method}};
 
 // Ensures that the `tracing` stack is only initialised once using `once_cell`
 static TRACING: Lazy<()> = Lazy::new(|| {
@@ -35,20 +36,361 @@ static TRACING: Lazy<()> = Lazy::new(|| {
 
 pub struct TestApp {
     pub address: String,
+    pub port: u16,
     pub db_pool: PgPool,
-    pub email_server: MockServer
+    pub email_server: MockServer,
+    pub email_client: EmailClient,
+    pub email_templates: EmailTemplates,
+    pub issue_delivery: IssueDeliverySettings,
+    pub email_queue: EmailQueueSettings,
+    pub idempotency: IdempotencySettings,
+    pub base_url: String,
+    pub token_signer: TokenSigner,
+    pub test_user: TestUser,
+    // The application creates the `admin` user with it on startup
+    pub admin_password: String,
+    // Keeps cookies around and does not follow redirects, like a browser tab
+    // we can inspect after every step.
+    pub api_client: reqwest::Client
+}
+
+pub struct TestUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub password: String
+}
+
+impl TestUser {
+    pub fn generate() -> Self {
+        Self {
+            user_id: Uuid::new_v4(),
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string()
+        }
+    }
+
+    pub async fn store(&self, pool: &PgPool) {
+        let salt = SaltString::generate(&mut rand::thread_rng());
+        // Match the parameters of the default password
+        let password_hash = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(15000, 2, 1, None).unwrap()
+        )
+        .hash_password(self.password.as_bytes(), &salt)
+        .unwrap()
+        .to_string();
+
+        sqlx::query!(
+            "INSERT INTO users (user_id, username, password_hash) VALUES ($1, $2, $3)",
+            self.user_id,
+            self.username,
+            password_hash
+        )
+        .execute(pool)
+        .await
+        .expect("Failed to store test user.");
+    }
+}
+
+/// Confirmation links embedded in the request to the email API.
+pub struct ConfirmationLinks {
+    pub html: reqwest::Url,
+    pub plain_text: reqwest::Url
 }
 
 impl TestApp {
+    /// Drain the issue delivery queue and the email queue.
+    ///
+    /// The test application runs no workers: tasks waiting for a retry are
+    /// the only ones we may have to wait for.
+    pub async fn dispatch_all_pending_emails(&self) {
+        loop {
+            try_execute_task(
+                &self.db_pool,
+                &self.email_client,
+                &self.email_templates,
+                &self.issue_delivery,
+                &self.base_url,
+                &self.token_signer
+            )
+            .await
+            .unwrap();
+            try_send_queued_email(
+                &self.db_pool,
+                &self.email_client,
+                &self.email_templates,
+                &self.email_queue,
+                &self.base_url,
+                &self.token_signer
+            )
+            .await
+            .unwrap();
+
+            // Tasks waiting for a retry are still pending, the test configuration
+            // keeps their backoff short.
+            let pending = sqlx::query!(
+                r#"
+                SELECT
+                    (SELECT COUNT(*) FROM issue_delivery_queue) +
+                    (SELECT COUNT(*) FROM email_queue) as "count!"
+                "#
+                )
+                .fetch_one(&self.db_pool)
+                .await
+                .unwrap();
+            if pending.count == 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Extract the confirmation links embedded in the request to the email API.
+    pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
+        let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+
+        let get_link = |s: &str| {
+            // Branded emails also link to the logo of their list
+            let links: Vec<_> = linkify::LinkFinder::new()
+                .links(s)
+                .filter(|l| *l.kind() == linkify::LinkKind::Url)
+                .filter(|l| l.as_str().contains("/subscriptions/confirm"))
+                .collect();
+            assert_eq!(links.len(), 1);
+            let raw_link = links[0].as_str().to_owned();
+            let mut confirmation_link = reqwest::Url::parse(&raw_link).unwrap();
+            // Let's make sure we don't call random APIs on the web
+            assert_eq!(confirmation_link.host_str().unwrap(), "127.0.0.1");
+            // The base url from the configuration does not know about the random
+            // port the test application is listening on.
+            confirmation_link.set_port(Some(self.port)).unwrap();
+            confirmation_link
+        };
+
+        let html = get_link(body["HtmlBody"].as_str().unwrap());
+        let plain_text = get_link(body["TextBody"].as_str().unwrap());
+        ConfirmationLinks { html, plain_text }
+    }
+
+    /// Extract the link advertised in the `List-Unsubscribe` header of an issue.
+    pub fn get_unsubscribe_link(&self, email_request: &wiremock::Request) -> reqwest::Url {
+        let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+        let header = body["Headers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|h| h["Name"] == "List-Unsubscribe")
+            .expect("No List-Unsubscribe header.");
+        let raw_link = header["Value"]
+            .as_str()
+            .unwrap()
+            .trim_start_matches('<')
+            .trim_end_matches('>');
+        let mut unsubscribe_link = reqwest::Url::parse(raw_link).unwrap();
+        assert_eq!(unsubscribe_link.host_str().unwrap(), "127.0.0.1");
+        unsubscribe_link.set_port(Some(self.port)).unwrap();
+        unsubscribe_link
+    }
+
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
         reqwest::Client::new()
-            .post(&format!("{}/subscriptions", &self.address))
+            .post(format!("{}/subscriptions", &self.address))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(body)
             .send()
             .await
             .expect("Failed to execute reqest")
     }
+
+    pub async fn post_subscriptions_json(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", &self.address))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_dead_letters(&self) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/admin/dead_letters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_redrive_dead_letters(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/dead_letters/redrive", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_email_dead_letters(&self) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/admin/email_dead_letters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_redrive_email_dead_letters(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/email_dead_letters/redrive", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_lists(&self) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/admin/lists", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_lists(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/lists", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_fields(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/fields", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_segments(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/segments", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_segment_preview(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/segments/preview", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_login<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize
+    {
+        self.api_client
+            .post(format!("{}/login", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_login_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/login", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_admin_dashboard(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/dashboard", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_admin_dashboard_html(&self) -> String {
+        self.get_admin_dashboard().await.text().await.unwrap()
+    }
+
+    pub async fn get_change_password(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/password", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_change_password_html(&self) -> String {
+        self.get_change_password().await.text().await.unwrap()
+    }
+
+    pub async fn post_change_password<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize
+    {
+        self.api_client
+            .post(format!("{}/admin/password", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_logout(&self) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/logout", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_newsletters_with_idempotency_key(
+        &self,
+        user: &TestUser,
+        body: &serde_json::Value,
+        idempotency_key: &str
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", &self.address))
+            .basic_auth(&user.username, Some(&user.password))
+            .header("Idempotency-Key", idempotency_key)
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
 }
 
 pub async fn spawn_app() -> TestApp {
@@ -56,13 +398,25 @@ pub async fn spawn_app() -> TestApp {
     Lazy::force(&TRACING);
 
     let email_server = MockServer::start().await;
+    let admin_password = Uuid::new_v4().to_string();
 
     // Randomise configuration to ensure test isolation
     let configuration = {
         let mut c = get_configuration().expect("Failed to read configuration.");
         c.database.database_name = Uuid::new_v4().to_string();
         c.application.port = 0;
-        c.email_client.base_url = email_server.uri();
+        c.email_client.transports.get_mut("postmark").unwrap().base_url = email_server.uri();
+        c.admin.password = Some(Secret::new(admin_password.clone()));
+        // Retry failed deliveries and emails right away
+        c.issue_delivery.backoff_base_milliseconds = 10;
+        c.issue_delivery.backoff_max_milliseconds = 50;
+        c.email_queue.backoff_base_milliseconds = 10;
+        c.email_queue.backoff_max_milliseconds = 50;
+        // Probe the mock email server again right away, even once it failed repeatedly
+        c.email_client.circuit_breaker.cooldown_seconds = 0;
+        // Stop waiting on quotas that never come back before long
+        c.issue_delivery.max_throttled_retries = 10;
+        c.email_queue.max_throttled_retries = 10;
         c
     };
 
@@ -73,18 +427,133 @@ pub async fn spawn_app() -> TestApp {
         .await
         .expect("Failed to the the app");
 
-    let address = format!("http://127.0.0.1:{}", application.port());
-    let _ = tokio::spawn(application.run_until_stopped());
+    let application_port = application.port();
+    let address = format!("http://127.0.0.1:{}", application_port);
+    tokio::spawn(application.run_server_until_stopped());
+
+    let db_pool = get_connection_pool(&configuration);
+    let test_app = TestApp {
+        address,
+        port: application_port,
+        email_client: configuration.email_client.client(&db_pool).unwrap(),
+        db_pool,
+        email_server,
+        email_templates: configuration.email_templates.templates().unwrap(),
+        issue_delivery: configuration.issue_delivery,
+        email_queue: configuration.email_queue,
+        idempotency: configuration.idempotency,
+        token_signer: configuration.application.token_signer(),
+        base_url: configuration.application.base_url,
+        test_user: TestUser::generate(),
+        admin_password,
+        api_client: reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .cookie_store(true)
+            .build()
+            .unwrap()
+    };
+    test_app.test_user.store(&test_app.db_pool).await;
+    test_app
+}
+
+/// Use the public API of the application under test to create
+/// an unconfirmed subscriber.
+pub async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("Create unconfirmed subscriber")
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = &app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+
+    app.get_confirmation_links(email_request)
+}
+
+pub async fn create_confirmed_subscriber(app: &TestApp) {
+    let confirmation_links = create_unconfirmed_subscriber(app).await;
+
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("Welcome confirmed subscriber")
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+}
 
-    TestApp {
-        address: address,
-        db_pool: get_connection_pool(&configuration),
-        email_server
+/// Answers calls to the Postmark batch API, rejecting the emails sent to the given addresses.
+pub struct PostmarkBatchResponder {
+    pub rejected: &'static [&'static str]
+}
+
+impl Respond for PostmarkBatchResponder {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let emails: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+        let results: Vec<_> = emails
+            .iter()
+            .map(|email| match email["To"].as_str() {
+                Some(to) if self.rejected.contains(&to) => serde_json::json!({
+                    "ErrorCode": 406,
+                    "Message": "You tried to send to a recipient that has been marked as inactive."
+                }),
+                _ => serde_json::json!({"ErrorCode": 0, "Message": "OK"})
+            })
+            .collect();
+        ResponseTemplate::new(200).set_body_json(results)
     }
 }
 
+/// Signs `email` up to the list with the given slug and follows the confirmation link.
+pub async fn subscribe_and_confirm(app: &TestApp, email: &str, list: &str) {
+    // The confirmation email, then the welcome email
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount_as_scoped(&app.email_server)
+        .await;
+
+    let body = format!("name=le%20guin&email={}&list={}", email.replace('@', "%40"), list);
+    app.post_subscriptions(body).await.error_for_status().unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    reqwest::get(confirmation_links.html).await.unwrap().error_for_status().unwrap();
+    app.dispatch_all_pending_emails().await;
+}
+
+pub fn assert_is_redirect_to(response: &reqwest::Response, location: &str) {
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(response.headers().get("Location").unwrap(), location);
+}
+
 async fn configure_database(config: &DatabaseSettings) -> Pool<Postgres> {
-    let mut con = PgConnection::connect(&config.connection_string_without_db().expose_secret())
+    let mut con = PgConnection::connect(config.connection_string_without_db().expose_secret())
                                                             .await
                                                             .expect("Failled to connect");
     con.execute(format!(r#"CREATE DATABASE "{}";"#, config.database_name).as_str())
@@ -92,7 +561,7 @@ async fn configure_database(config: &DatabaseSettings) -> Pool<Postgres> {
                 .expect("Could not create Table");
     
     // Migrate DB
-    let connection_pool = PgPool::connect(&config.connection_string().expose_secret())
+    let connection_pool = PgPool::connect(config.connection_string().expose_secret())
                                                             .await
                                                             .expect("Failed to connect to Postgres");
 
@@ -0,0 +1,298 @@
+use newsletter_service::problem::ProblemDetails;
+use wiremock::{Mock, ResponseTemplate, matchers::{any, method, path}};
+
+use crate::helpers::{create_confirmed_subscriber, spawn_app, subscribe_and_confirm, TestApp};
+
+fn rust_list() -> serde_json::Value {
+    serde_json::json!({
+        "slug": "rust",
+        "name": "Rust Weekly",
+        "sender_email": "rust@lists.com",
+        "sender_name": "Rust Weekly",
+        "logo_url": "https://lists.com/rust.png",
+        "accent_color": "#ff6600",
+        "footer_text": "You get this because you love Rust."
+    })
+}
+
+fn newsletter_request_body(lists: serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        },
+        "lists": lists
+    })
+}
+
+/// The bodies of the emails sent so far.
+async fn sent_emails(app: &TestApp) -> Vec<serde_json::Value> {
+    app.email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .map(|r| serde_json::from_slice(&r.body).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn admins_can_create_lists() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app.post_lists(rust_list()).await;
+
+    // Assert
+    assert_eq!(201, response.status().as_u16());
+    let lists: Vec<serde_json::Value> = app.get_lists().await.json().await.unwrap();
+    let slugs: Vec<_> = lists.iter().map(|l| l["slug"].as_str().unwrap()).collect();
+    // The default list is created by the migrations
+    assert_eq!(slugs, vec!["newsletter", "rust"]);
+    assert_eq!(lists[1]["sender_email"], "rust@lists.com");
+}
+
+#[tokio::test]
+async fn lists_are_only_managed_by_authenticated_users() {
+    // Arrange
+    let app = spawn_app().await;
+
+    for request in [
+        reqwest::Client::new().get(format!("{}/admin/lists", app.address)),
+        reqwest::Client::new().post(format!("{}/admin/lists", app.address)).json(&rust_list()),
+    ] {
+        // Act
+        let response = request.send().await.unwrap();
+
+        // Assert
+        assert_eq!(401, response.status().as_u16());
+    }
+}
+
+#[tokio::test]
+async fn invalid_lists_are_rejected_with_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = [
+        ("slug", "Rust Weekly"),
+        ("sender_email", "not-an-email"),
+        ("logo_url", "javascript:alert(1)"),
+        ("accent_color", "red;background:url(x)"),
+    ];
+
+    for (field, value) in test_cases {
+        let mut body = rust_list();
+        body[field] = value.into();
+
+        // Act
+        let response = app.post_lists(body).await;
+
+        // Assert
+        assert_eq!(400, response.status().as_u16(), "{} was accepted.", value);
+        let problem: ProblemDetails = response.json().await.unwrap();
+        assert_eq!(problem.invalid_params[0].name, field);
+    }
+}
+
+#[tokio::test]
+async fn a_slug_can_only_be_used_once() {
+    // Arrange
+    let app = spawn_app().await;
+    app.post_lists(rust_list()).await.error_for_status().unwrap();
+
+    // Act
+    let response = app.post_lists(rust_list()).await;
+
+    // Assert
+    assert_eq!(409, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribing_to_an_unknown_list_is_rejected_with_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&list=gossip".into())
+        .await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+    let problem: ProblemDetails = response.json().await.unwrap();
+    assert_eq!(problem.invalid_params[0].name, "list");
+}
+
+#[tokio::test]
+async fn memberships_are_confirmed_per_list() {
+    // Arrange
+    let app = spawn_app().await;
+    app.post_lists(rust_list()).await.error_for_status().unwrap();
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&list=rust".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let email = sent_emails(&app).await.pop().unwrap();
+    assert_eq!(email["Subject"], "Welcome to Rust Weekly!");
+    assert_eq!(email["From"], r#""Rust Weekly" <rust@lists.com>"#);
+
+    let saved = sqlx::query!(
+        r#"
+        SELECT l.slug as "slug!", m.status as "status!"
+        FROM list_memberships m
+        JOIN lists l USING (list_id)
+        ORDER BY l.slug
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    let statuses: Vec<_> = saved.iter().map(|r| (r.slug.as_str(), r.status.as_str())).collect();
+    assert_eq!(statuses, vec![("newsletter", "confirmed"), ("rust", "pending_confirmation")]);
+}
+
+#[tokio::test]
+async fn issues_are_only_delivered_to_the_targeted_lists() {
+    // Arrange
+    let app = spawn_app().await;
+    app.post_lists(rust_list()).await.error_for_status().unwrap();
+    subscribe_and_confirm(&app, "newsletter@domain.com", "newsletter").await;
+    subscribe_and_confirm(&app, "rust@domain.com", "rust").await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletters(newsletter_request_body(serde_json::json!(["rust"]))).await;
+    assert_eq!(202, response.status().as_u16());
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let email = sent_emails(&app).await.pop().unwrap();
+    assert_eq!(email["To"], "rust@domain.com");
+    assert_eq!(email["From"], r#""Rust Weekly" <rust@lists.com>"#);
+    let html = email["HtmlBody"].as_str().unwrap();
+    assert!(html.contains(r#"<img src="https://lists.com/rust.png" alt="Rust Weekly">"#));
+    assert!(html.contains("border-top: 4px solid #ff6600;"));
+    assert!(email["TextBody"].as_str().unwrap().ends_with("You get this because you love Rust."));
+    assert!(html.contains("/subscriptions/unsubscribe?list=rust&token="));
+}
+
+#[tokio::test]
+async fn subscribers_of_several_targeted_lists_get_the_issue_once() {
+    // Arrange
+    let app = spawn_app().await;
+    app.post_lists(rust_list()).await.error_for_status().unwrap();
+    subscribe_and_confirm(&app, "ursula@domain.com", "newsletter").await;
+    subscribe_and_confirm(&app, "ursula@domain.com", "rust").await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let body = newsletter_request_body(serde_json::json!(["rust", "newsletter"]));
+    app.post_newsletters(body).await.error_for_status().unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert - The issue is sent through the first list that was named
+    let email = sent_emails(&app).await.pop().unwrap();
+    assert_eq!(email["From"], r#""Rust Weekly" <rust@lists.com>"#);
+}
+
+#[tokio::test]
+async fn publishing_to_unknown_lists_is_rejected_with_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    for lists in [serde_json::json!(["gossip"]), serde_json::json!([])] {
+        // Act
+        let response = app.post_newsletters(newsletter_request_body(lists.clone())).await;
+
+        // Assert
+        assert_eq!(400, response.status().as_u16(), "{} was accepted.", lists);
+        let problem: ProblemDetails = response.json().await.unwrap();
+        assert_eq!(problem.invalid_params[0].name, "lists");
+    }
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn unsubscribing_from_a_list_keeps_the_other_memberships() {
+    // Arrange
+    let app = spawn_app().await;
+    app.post_lists(rust_list()).await.error_for_status().unwrap();
+    subscribe_and_confirm(&app, "ursula@domain.com", "newsletter").await;
+    subscribe_and_confirm(&app, "ursula@domain.com", "rust").await;
+
+    // The issue, then the confirmation of the unsubscription
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    let body = newsletter_request_body(serde_json::json!(["rust"]));
+    app.post_newsletters(body).await.error_for_status().unwrap();
+    app.dispatch_all_pending_emails().await;
+    let email_request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let unsubscribe_link = app.get_unsubscribe_link(&email_request);
+
+    // Act
+    reqwest::Client::new()
+        .post(unsubscribe_link)
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let saved = sqlx::query!(
+        r#"
+        SELECT l.slug as "slug!", m.status as "status!"
+        FROM list_memberships m
+        JOIN lists l USING (list_id)
+        ORDER BY l.slug
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    let statuses: Vec<_> = saved.iter().map(|r| (r.slug.as_str(), r.status.as_str())).collect();
+    assert_eq!(statuses, vec![("newsletter", "confirmed"), ("rust", "unsubscribed")]);
+}
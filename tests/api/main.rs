@@ -1,4 +1,15 @@
+mod admin_dashboard;
+mod change_password;
+mod email_quotas;
+mod fields;
 mod helpers;
 mod health_check;
+mod lists;
+mod login;
+mod merge_tags;
+mod newsletters;
+mod preferences;
+mod segments;
 mod subscriptions;
-mod subscription_confirm;
\ No newline at end of file
+mod subscription_confirm;
+mod unsubscribe;
@@ -0,0 +1,164 @@
+use newsletter_service::problem::ProblemDetails;
+use wiremock::{Mock, ResponseTemplate, matchers::{method, path}};
+
+use crate::helpers::{spawn_app, PostmarkBatchResponder, TestApp};
+
+/// Signs a subscriber up with the given form body and confirms them.
+async fn subscribe_and_confirm_with(app: &TestApp, body: &str) {
+    app.post_subscriptions(body.into()).await.error_for_status().unwrap();
+    app.dispatch_all_pending_emails().await;
+    let email_request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    reqwest::get(confirmation_links.html).await.unwrap().error_for_status().unwrap();
+    app.dispatch_all_pending_emails().await;
+}
+
+/// The issues the email API was asked to send, as `(recipient, request body)`.
+async fn sent_issues(app: &TestApp, title_prefix: &str) -> Vec<(String, serde_json::Value)> {
+    let mut issues: Vec<_> = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .map(|request| serde_json::from_slice::<serde_json::Value>(&request.body).unwrap())
+        // Batch calls carry an array of emails
+        .flat_map(|body| match body {
+            serde_json::Value::Array(emails) => emails,
+            email => vec![email]
+        })
+        .filter(|body| body["Subject"].as_str().unwrap().starts_with(title_prefix))
+        .map(|body| (body["To"].as_str().unwrap().to_string(), body))
+        .collect();
+    issues.sort_by(|a, b| a.0.cmp(&b.0));
+    issues
+}
+
+#[tokio::test]
+async fn issues_are_personalized_for_each_recipient() {
+    // Arrange
+    let app = spawn_app().await;
+    app.post_fields(serde_json::json!({"name": "company", "field_type": "string"}))
+        .await
+        .error_for_status()
+        .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder { rejected: &[] })
+        .mount(&app.email_server)
+        .await;
+    subscribe_and_confirm_with(&app, "name=Ursula&email=ursula%40example.com&company=Acme%20%26%20Co").await;
+    subscribe_and_confirm_with(&app, "name=Octavia&email=octavia%40example.com").await;
+
+    // Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "News for {{ name }}",
+            "content": {
+                "text": "Hi {{ name }} from {{ custom.company | default: \"nowhere\" }}. Leave: {{ unsubscribe_url }}",
+                "html": "<p>Hi {{ name }} from {{ custom.company | default: \"nowhere\" }}.</p>"
+            }
+        }))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    let issues = sent_issues(&app, "News for").await;
+    assert_eq!(issues.len(), 2);
+
+    let (to, octavia) = &issues[0];
+    assert_eq!(to, "octavia@example.com");
+    assert_eq!(octavia["Subject"], "News for Octavia");
+    assert!(octavia["HtmlBody"].as_str().unwrap().contains("<p>Hi Octavia from nowhere.</p>"));
+
+    let (to, ursula) = &issues[1];
+    assert_eq!(to, "ursula@example.com");
+    assert_eq!(ursula["Subject"], "News for Ursula");
+    assert!(ursula["HtmlBody"].as_str().unwrap().contains("<p>Hi Ursula from Acme &amp; Co.</p>"));
+    let text = ursula["TextBody"].as_str().unwrap();
+    assert!(text.starts_with("Hi Ursula from Acme & Co. Leave: "));
+    // The merge tag renders the same link as the footer of the email
+    let unsubscribe_link = text.split("Leave: ").nth(1).unwrap().lines().next().unwrap();
+    assert!(text.contains(&format!("Unsubscribe: {}", unsubscribe_link)));
+}
+
+#[tokio::test]
+async fn issues_with_broken_merge_tags_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = [
+        ("title", "Hi {{ name", "<p>Hello</p>", "Hello"),
+        ("content.html", "Issue #1", "<p>Hi {{ nme }}</p>", "Hello"),
+        ("content.text", "Issue #1", "<p>Hello</p>", "Hi {{ name | upcase }}"),
+        ("content.text", "Issue #1", "<p>Hello</p>", "Hi {{ name | default: friend }}"),
+        ("content.html", "Issue #1", "<p>Hi {{ custom.company }}</p>", "Hello")
+    ];
+
+    for (field, title, html, text) in test_cases {
+        // Act
+        let response = app
+            .post_newsletters(serde_json::json!({
+                "title": title,
+                "content": {"html": html, "text": text}
+            }))
+            .await;
+
+        // Assert
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "The API did not reject {} / {} / {}",
+            title,
+            html,
+            text
+        );
+        let problem: ProblemDetails = response.json().await.unwrap();
+        assert_eq!(problem.invalid_params[0].name, field);
+    }
+    let issues = sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(issues.count, 0);
+}
+
+#[tokio::test]
+async fn stored_issues_with_broken_merge_tags_are_dead_lettered_instead_of_sent() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    subscribe_and_confirm_with(&app, "name=Ursula&email=ursula%40example.com").await;
+    app.post_newsletters(serde_json::json!({
+        "title": "News for {{ name }}",
+        "content": {"text": "Hello", "html": "<p>Hello</p>"}
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    // Publishing checks the tags, nothing does once the issue is stored
+    sqlx::query!("UPDATE newsletter_issues SET title = 'News for {{ nme }}'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert!(sent_issues(&app, "News for").await.is_empty());
+    let dead_letters: serde_json::Value = app.get_dead_letters().await.json().await.unwrap();
+    let dead_letters = dead_letters.as_array().unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0]["subscriber_email"], "ursula@example.com");
+    assert!(dead_letters[0]["last_error"].as_str().unwrap().contains("invalid merge tag"));
+}
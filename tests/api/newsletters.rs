@@ -0,0 +1,811 @@
+use chrono::{Duration, Utc};
+use newsletter_service::{configuration::get_configuration, problem::ProblemDetails, startup::Application, sweeper::purge_expired_idempotency_keys};
+use secrecy::Secret;
+use uuid::Uuid;
+use wiremock::{Mock, ResponseTemplate, matchers::{any, path, method}};
+
+use crate::helpers::{create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app, subscribe_and_confirm, PostmarkBatchResponder, TestApp, TestUser};
+
+#[tokio::test]
+async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        // We assert that no request is fired at Postmark!
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn newsletters_are_delivered_to_confirmed_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn deliveries_record_the_provider_that_sent_them() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let delivery = sqlx::query!("SELECT subscriber_email, provider FROM issue_deliveries")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the recorded delivery");
+    assert_eq!(delivery.subscriber_email, "ursula_le_guin@gmail.com");
+    assert_eq!(delivery.provider, "postmark");
+}
+
+#[tokio::test]
+async fn newsletters_returns_400_for_invalid_data() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (
+            serde_json::json!({
+                "content": {
+                    "text": "Newsletter body as plain text",
+                    "html": "<p>Newsletter body as HTML</p>",
+                }
+            }),
+            "missing title",
+        ),
+        (
+            serde_json::json!({"title": "Newsletter!"}),
+            "missing content",
+        ),
+    ];
+
+    for (invalid_body, error_message) in test_cases {
+        // Act
+        let response = app.post_newsletters(invalid_body).await;
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the payload was {}.",
+            error_message
+        );
+    }
+}
+
+#[tokio::test]
+async fn publishing_stores_the_issue_and_queues_one_task_per_confirmed_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+
+    let issue = sqlx::query!("SELECT newsletter_issue_id, title FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved issue");
+    assert_eq!(issue.title, "Newsletter title");
+
+    let tasks = sqlx::query!(
+        "SELECT subscriber_email FROM issue_delivery_queue WHERE newsletter_issue_id = $1",
+        issue.newsletter_issue_id
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .expect("Failed to fetch queued tasks");
+    // The worker might already be busy with it, but it cannot have finished:
+    // there is no mock accepting the email.
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(tasks[0].subscriber_email, "ursula_le_guin@gmail.com");
+}
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+#[tokio::test]
+async fn transient_delivery_failures_are_retried() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let dead_letters = sqlx::query!("SELECT subscriber_email FROM issue_delivery_dead_letters")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(dead_letters.is_empty());
+}
+
+#[tokio::test]
+async fn throttled_deliveries_do_not_use_up_their_attempts() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Throttled as many times as a delivery can fail, then accepted
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+        .up_to_n_times(app.issue_delivery.max_attempts as u64)
+        .expect(app.issue_delivery.max_attempts as u64)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let dead_letters = sqlx::query!("SELECT subscriber_email FROM issue_delivery_dead_letters")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(dead_letters.is_empty());
+}
+
+#[tokio::test]
+async fn deliveries_throttled_too_many_times_use_up_their_attempts() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // A quota that never comes back
+    let n_tries = app.issue_delivery.max_throttled_retries + app.issue_delivery.max_attempts;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+        .expect(n_tries as u64)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let dead_letters: serde_json::Value = app.get_dead_letters().await.json().await.unwrap();
+    let dead_letters = dead_letters.as_array().unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0]["n_attempts"], app.issue_delivery.max_attempts);
+}
+
+#[tokio::test]
+async fn deliveries_are_dead_lettered_once_they_ran_out_of_attempts() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(app.issue_delivery.max_attempts as u64)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let response = app.get_dead_letters().await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let dead_letters: serde_json::Value = response.json().await.unwrap();
+    let dead_letters = dead_letters.as_array().unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0]["subscriber_email"], "ursula_le_guin@gmail.com");
+    assert_eq!(dead_letters[0]["n_attempts"], app.issue_delivery.max_attempts);
+}
+
+#[tokio::test]
+async fn rejected_deliveries_are_dead_lettered_without_retrying() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+            "ErrorCode": 406,
+            "Message": "You tried to send to a recipient that has been marked as inactive."
+        })))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let dead_letters: serde_json::Value = app.get_dead_letters().await.json().await.unwrap();
+    let dead_letters = dead_letters.as_array().unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0]["n_attempts"], 1);
+    assert!(dead_letters[0]["last_error"].as_str().unwrap().contains("marked as inactive"));
+}
+
+#[tokio::test]
+async fn deliveries_that_cannot_be_built_are_dead_lettered_without_holding_up_the_others() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(newsletter_request_body()).await;
+    // A delivery to somebody who is not a subscriber
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_email, list_id)
+        SELECT newsletter_issue_id, 'octavia@domain.com', list_id
+        FROM newsletter_issues CROSS JOIN lists
+        LIMIT 1
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let dead_letters: serde_json::Value = app.get_dead_letters().await.json().await.unwrap();
+    let dead_letters = dead_letters.as_array().unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0]["subscriber_email"], "octavia@domain.com");
+    assert_eq!(dead_letters[0]["n_attempts"], 1);
+    assert!(dead_letters[0]["last_error"].as_str().unwrap().contains("does not exist"));
+}
+
+#[tokio::test]
+async fn issues_are_sent_in_batches_and_failures_are_handled_per_recipient() {
+    // Arrange
+    let app = spawn_app().await;
+    subscribe_and_confirm(&app, "ursula@domain.com", "newsletter").await;
+    subscribe_and_confirm(&app, "octavia@domain.com", "newsletter").await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder { rejected: &["octavia@domain.com"] })
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let requests = app.email_server.received_requests().await.unwrap();
+    let batch: Vec<serde_json::Value> = serde_json::from_slice(&requests.last().unwrap().body).unwrap();
+    assert_eq!(batch.len(), 2);
+    // Only the rejected email is dead-lettered, the other one was delivered
+    let dead_letters: serde_json::Value = app.get_dead_letters().await.json().await.unwrap();
+    let dead_letters = dead_letters.as_array().unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0]["subscriber_email"], "octavia@domain.com");
+    assert_eq!(dead_letters[0]["n_attempts"], 1);
+}
+
+#[tokio::test]
+async fn dead_letters_can_be_redriven() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    let failing_mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+    drop(failing_mock_guard);
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_redrive_dead_letters(serde_json::json!({})).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let outcome: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(outcome["redriven"], 1);
+
+    app.dispatch_all_pending_emails().await;
+
+    let dead_letters: serde_json::Value = app.get_dead_letters().await.json().await.unwrap();
+    assert!(dead_letters.as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn redriving_a_dead_letter_whose_delivery_is_queued_keeps_it() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    let failing_mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+    drop(failing_mock_guard);
+
+    // The same delivery made it back into the queue in the meantime
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_email, list_id)
+        SELECT newsletter_issue_id, subscriber_email, list_id FROM issue_delivery_dead_letters
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    // Act
+    let response = app.post_redrive_dead_letters(serde_json::json!({})).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let outcome: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(outcome["redriven"], 0);
+    assert_eq!(outcome["already_queued"], 1);
+
+    let dead_letters: serde_json::Value = app.get_dead_letters().await.json().await.unwrap();
+    assert_eq!(dead_letters.as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn requests_missing_authorization_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(r#"Basic realm="admin""#, response.headers()["WWW-Authenticate"]);
+}
+
+#[tokio::test]
+async fn non_existing_user_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    // Random credentials
+    let username = Uuid::new_v4().to_string();
+    let password = Uuid::new_v4().to_string();
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(username, Some(password))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(r#"Basic realm="admin""#, response.headers()["WWW-Authenticate"]);
+}
+
+#[tokio::test]
+async fn invalid_password_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let username = &app.test_user.username;
+    // Random password
+    let password = Uuid::new_v4().to_string();
+    assert_ne!(app.test_user.password, password);
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(username, Some(password))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(r#"Basic realm="admin""#, response.headers()["WWW-Authenticate"]);
+}
+
+#[tokio::test]
+async fn admin_routes_reject_anonymous_requests() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::get(&format!("{}/admin/dead_letters", &app.address))
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(r#"Basic realm="admin""#, response.headers()["WWW-Authenticate"]);
+}
+
+async fn count_newsletter_issues(app: &TestApp) -> i64 {
+    sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count
+}
+
+#[tokio::test]
+async fn newsletter_creation_is_idempotent() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let idempotency_key = Uuid::new_v4().to_string();
+
+    // Act - Part 1 - Publish the issue
+    let response = app
+        .post_newsletters_with_idempotency_key(&app.test_user, &newsletter_request_body(), &idempotency_key)
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+
+    // Act - Part 2 - Publish it again
+    let response = app
+        .post_newsletters_with_idempotency_key(&app.test_user, &newsletter_request_body(), &idempotency_key)
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+
+    // Assert
+    assert_eq!(count_newsletter_issues(&app).await, 1);
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn retries_get_the_saved_response_even_if_the_request_became_invalid() {
+    // Arrange
+    let app = spawn_app().await;
+    app.post_lists(serde_json::json!({"slug": "rust", "name": "Rust Weekly"}))
+        .await
+        .error_for_status()
+        .unwrap();
+    let mut body = newsletter_request_body();
+    body["lists"] = serde_json::json!(["rust"]);
+    let idempotency_key = Uuid::new_v4().to_string();
+
+    let response = app
+        .post_newsletters_with_idempotency_key(&app.test_user, &body, &idempotency_key)
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    sqlx::query!("UPDATE lists SET slug = 'rust-weekly' WHERE slug = 'rust'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = app
+        .post_newsletters_with_idempotency_key(&app.test_user, &body, &idempotency_key)
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    assert_eq!(count_newsletter_issues(&app).await, 1);
+}
+
+#[tokio::test]
+async fn reusing_an_idempotency_key_for_another_issue_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let idempotency_key = Uuid::new_v4().to_string();
+    let response = app
+        .post_newsletters_with_idempotency_key(&app.test_user, &newsletter_request_body(), &idempotency_key)
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+
+    let mut body = newsletter_request_body();
+    body["title"] = serde_json::json!("Another newsletter title");
+
+    // Act
+    let response = app
+        .post_newsletters_with_idempotency_key(&app.test_user, &body, &idempotency_key)
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 422);
+    assert_eq!(count_newsletter_issues(&app).await, 1);
+}
+
+#[tokio::test]
+async fn expired_idempotency_keys_are_treated_as_new() {
+    // Arrange
+    let app = spawn_app().await;
+    let idempotency_key = Uuid::new_v4().to_string();
+    let response = app
+        .post_newsletters_with_idempotency_key(&app.test_user, &newsletter_request_body(), &idempotency_key)
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    // The sweeper did not get to the key yet
+    sqlx::query!(
+        "UPDATE idempotency SET created_at = $1",
+        Utc::now() - app.idempotency.expiry() - Duration::hours(1)
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    // Act
+    let response = app
+        .post_newsletters_with_idempotency_key(&app.test_user, &newsletter_request_body(), &idempotency_key)
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    assert_eq!(count_newsletter_issues(&app).await, 2);
+}
+
+#[tokio::test]
+async fn concurrent_newsletter_publishing_is_handled_gracefully() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let idempotency_key = Uuid::new_v4().to_string();
+    let body = newsletter_request_body();
+
+    // Act - Submit two requests with the same key at the same time
+    let response1 = app.post_newsletters_with_idempotency_key(&app.test_user, &body, &idempotency_key);
+    let response2 = app.post_newsletters_with_idempotency_key(&app.test_user, &body, &idempotency_key);
+    let (response1, response2) = tokio::join!(response1, response2);
+
+    // Assert
+    assert_eq!(response1.status(), response2.status());
+    assert_eq!(response1.text().await.unwrap(), response2.text().await.unwrap());
+    assert_eq!(count_newsletter_issues(&app).await, 1);
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn idempotency_keys_are_scoped_per_user() {
+    // Arrange
+    let app = spawn_app().await;
+    let another_user = TestUser::generate();
+    another_user.store(&app.db_pool).await;
+    let idempotency_key = Uuid::new_v4().to_string();
+
+    // Act
+    let response = app
+        .post_newsletters_with_idempotency_key(&app.test_user, &newsletter_request_body(), &idempotency_key)
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+
+    let response = app
+        .post_newsletters_with_idempotency_key(&another_user, &newsletter_request_body(), &idempotency_key)
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+
+    // Assert
+    assert_eq!(count_newsletter_issues(&app).await, 2);
+}
+
+#[tokio::test]
+async fn newsletters_returns_400_for_an_invalid_idempotency_key() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_newsletters_with_idempotency_key(&app.test_user, &newsletter_request_body(), &"a".repeat(50))
+        .await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+    let problem: ProblemDetails = response.json().await.unwrap();
+    assert_eq!(problem.invalid_params[0].name, "Idempotency-Key");
+    assert_eq!(count_newsletter_issues(&app).await, 0);
+}
+
+#[tokio::test]
+async fn the_sweeper_purges_expired_idempotency_keys_only() {
+    // Arrange
+    let app = spawn_app().await;
+    for (idempotency_key, created_at) in [
+        ("an-expired-key", Utc::now() - Duration::hours(49)),
+        ("a-recent-key", Utc::now() - Duration::hours(1)),
+    ] {
+        sqlx::query!(
+            r#"
+            INSERT INTO idempotency (user_id, idempotency_key, request_hash, created_at)
+            VALUES ($1, $2, $3, $4)
+            "#,
+            app.test_user.user_id,
+            idempotency_key,
+            b"hash".as_slice(),
+            created_at
+        )
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    }
+
+    // Act
+    let purged = purge_expired_idempotency_keys(&app.db_pool, Duration::hours(48))
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(purged, 1);
+
+    let remaining = sqlx::query!("SELECT idempotency_key FROM idempotency")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].idempotency_key, "a-recent-key");
+}
+
+#[tokio::test]
+async fn the_admin_is_created_from_the_configuration() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/dead_letters", &app.address))
+        .basic_auth("admin", Some(&app.admin_password))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn no_admin_is_created_once_there_are_users() {
+    // Arrange
+    let app = spawn_app().await;
+    let database = sqlx::query!(r#"SELECT current_database() as "name!""#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.database.database_name = database.name;
+    configuration.application.port = 0;
+    configuration.admin.username = "renamed-admin".into();
+    configuration.admin.password = Some(Secret::new("another-password".into()));
+
+    // Act
+    Application::build(configuration).await.expect("Failed to build the application");
+
+    // Assert
+    let renamed = sqlx::query!("SELECT user_id FROM users WHERE username = 'renamed-admin'")
+        .fetch_optional(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(renamed.is_none());
+}
@@ -0,0 +1,406 @@
+use newsletter_service::signed_token::TokenPurpose;
+use wiremock::{Mock, ResponseTemplate, matchers::{method, path}};
+
+use crate::helpers::{create_confirmed_subscriber, spawn_app, subscribe_and_confirm, TestApp};
+
+const EMAIL: &str = "ursula_le_guin@gmail.com";
+
+fn preferences_path(app: &TestApp) -> String {
+    format!(
+        "/subscriptions/preferences?token={}",
+        app.token_signer.sign(TokenPurpose::ManagePreferences, EMAIL)
+    )
+}
+
+/// The token is signed again for every request: it changes every second.
+fn assert_is_redirect_to_preferences(response: &reqwest::Response) {
+    assert_eq!(response.status().as_u16(), 303);
+    let location = response.headers().get("Location").unwrap().to_str().unwrap();
+    assert!(location.starts_with("/subscriptions/preferences?token="));
+}
+
+async fn get_preferences_html(app: &TestApp) -> String {
+    app.api_client
+        .get(format!("{}{}", app.address, preferences_path(app)))
+        .send()
+        .await
+        .expect("Failed to execute request")
+        .text()
+        .await
+        .unwrap()
+}
+
+async fn post_preferences(app: &TestApp, body: &[(&str, &str)]) -> reqwest::Response {
+    app.api_client
+        .post(format!("{}{}", app.address, preferences_path(app)))
+        .form(body)
+        .send()
+        .await
+        .expect("Failed to execute request")
+}
+
+#[tokio::test]
+async fn the_preference_center_requires_a_valid_token() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let tokens = [
+        "not-a-token".to_string(),
+        // Unsubscribe links must not open the preference center
+        app.token_signer.sign(TokenPurpose::UnsubscribeFromAll, EMAIL),
+    ];
+
+    for token in tokens {
+        // Act
+        let response = reqwest::get(format!(
+            "{}/subscriptions/preferences?token={}",
+            app.address, token
+        ))
+        .await
+        .unwrap();
+
+        // Assert
+        assert_eq!(401, response.status().as_u16());
+    }
+}
+
+#[tokio::test]
+async fn the_preference_center_shows_the_defaults() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act
+    let html_page = get_preferences_html(&app).await;
+
+    // Assert
+    assert!(html_page.contains(r#"name="name" value="le guin""#));
+    assert!(html_page.contains(r#"value="newsletter" checked"#));
+    assert!(html_page.contains(r#"value="announcements" checked"#));
+    assert!(html_page.contains(r#"value="immediate" checked"#));
+    assert!(html_page.contains(r#"action="/subscriptions/unsubscribe?token="#));
+}
+
+#[tokio::test]
+async fn subscribers_can_update_their_preferences() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act - Part 1 - Submit the form
+    let response = post_preferences(
+        &app,
+        &[
+            ("name", "Ursula K. Le Guin"),
+            ("lists", "newsletter"),
+            ("topics", "events"),
+            ("digest_frequency", "weekly"),
+        ],
+    )
+    .await;
+    assert_is_redirect_to_preferences(&response);
+
+    // Act - Part 2 - Follow the redirect
+    let html_page = get_preferences_html(&app).await;
+
+    // Assert
+    assert!(html_page.contains("<p><i>Your preferences have been saved.</i></p>"));
+    assert!(html_page.contains(r#"value="Ursula K. Le Guin""#));
+    assert!(html_page.contains(r#"value="events" checked"#));
+    assert!(!html_page.contains(r#"value="announcements" checked"#));
+    assert!(html_page.contains(r#"value="weekly" checked"#));
+
+    let saved = sqlx::query!(
+        r#"
+        SELECT s.name as "name!", p.digest_frequency as "digest_frequency!", p.topics as "topics!"
+        FROM subscriptions s
+        JOIN subscriber_preferences p ON p.subscriber_id = s.id
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch saved preferences.");
+    assert_eq!(saved.name, "Ursula K. Le Guin");
+    assert_eq!(saved.digest_frequency, "weekly");
+    assert_eq!(saved.topics, vec!["events".to_string()]);
+}
+
+#[tokio::test]
+async fn invalid_preferences_are_not_saved() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let test_cases = vec![
+        (vec![("name", "")], "Invalid name"),
+        (vec![("name", "Ursula"), ("topics", "gossip")], "Invalid topics"),
+        (vec![("name", "Ursula"), ("lists", "gossip")], "Invalid lists"),
+        (vec![("name", "Ursula"), ("digest_frequency", "hourly")], "Invalid digest_frequency"),
+    ];
+
+    for (body, error_message) in test_cases {
+        // Act
+        let response = post_preferences(&app, &body).await;
+        assert_is_redirect_to_preferences(&response);
+
+        // Assert
+        let html_page = get_preferences_html(&app).await;
+        assert!(
+            html_page.contains(error_message),
+            "The page did not report `{}`.",
+            error_message
+        );
+    }
+
+    let saved = sqlx::query!("SELECT name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.name, "le guin");
+    let preferences = sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM subscriber_preferences"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(preferences.count, 0);
+}
+
+#[tokio::test]
+async fn unchecking_a_list_unsubscribes_from_it() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = post_preferences(&app, &[("name", "le guin")]).await;
+    assert_is_redirect_to_preferences(&response);
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let html_page = get_preferences_html(&app).await;
+    assert!(!html_page.contains(r#"value="newsletter" checked"#));
+    assert!(html_page.contains("You are not subscribed to any of our lists anymore."));
+    let saved = sqlx::query!("SELECT status FROM list_memberships")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "unsubscribed");
+    // The same confirmation as when unsubscribing through a link
+    let email_request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["Subject"], "You have been unsubscribed from Newsletter");
+}
+
+#[tokio::test]
+async fn the_unsubscribe_button_leaves_every_list() {
+    // Arrange
+    let app = spawn_app().await;
+    app.post_lists(serde_json::json!({
+        "slug": "rust",
+        "name": "Rust Weekly",
+        "sender_email": "rust@lists.com"
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    subscribe_and_confirm(&app, EMAIL, "newsletter").await;
+    subscribe_and_confirm(&app, EMAIL, "rust").await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let html_page = get_preferences_html(&app).await;
+    let start = html_page.find(r#"action="/subscriptions/unsubscribe?"#).unwrap() + r#"action=""#.len();
+    let end = start + html_page[start..].find('"').unwrap();
+
+    // Act
+    let response = app.api_client
+        .post(format!("{}{}", app.address, &html_page[start..end]))
+        .send()
+        .await
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let statuses = sqlx::query!("SELECT status FROM list_memberships")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(statuses.len(), 2);
+    assert!(statuses.iter().all(|membership| membership.status == "unsubscribed"));
+    let html_page = get_preferences_html(&app).await;
+    assert!(html_page.contains("You are not subscribed to any of our lists anymore."));
+}
+
+#[tokio::test]
+async fn pending_lists_cannot_be_confirmed_from_the_preference_center() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.post_lists(serde_json::json!({"slug": "rust", "name": "Rust Weekly"}))
+        .await
+        .error_for_status()
+        .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&list=rust".into())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let response = post_preferences(&app, &[("name", "le guin"), ("lists", "newsletter"), ("lists", "rust")]).await;
+    assert_is_redirect_to_preferences(&response);
+
+    // Assert
+    let html_page = get_preferences_html(&app).await;
+    assert!(html_page.contains("Invalid lists"));
+    assert!(html_page.contains("Rust Weekly (waiting for your confirmation)"));
+    let saved = sqlx::query!(
+        r#"
+        SELECT m.status as "status!"
+        FROM list_memberships m
+        JOIN lists l ON l.list_id = m.list_id
+        WHERE l.slug = 'rust'
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+fn issue_about(topic: &str) -> serde_json::Value {
+    serde_json::json!({
+        "title": format!("All about {}", topic),
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        },
+        "topic": topic
+    })
+}
+
+#[tokio::test]
+async fn issues_about_a_topic_only_go_to_subscribers_who_picked_it() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    post_preferences(
+        &app,
+        &[
+            ("name", "le guin"),
+            ("lists", "newsletter"),
+            ("topics", "events"),
+            ("digest_frequency", "immediate"),
+        ],
+    )
+    .await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(issue_about("tutorials")).await.error_for_status().unwrap();
+    app.post_newsletters(issue_about("events")).await.error_for_status().unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let email_request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["Subject"], "All about events");
+}
+
+#[tokio::test]
+async fn issues_about_an_unknown_topic_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app.post_newsletters(issue_about("gossip")).await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn weekly_subscribers_get_the_issues_of_the_week_in_one_digest() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    post_preferences(
+        &app,
+        &[
+            ("name", "le guin"),
+            ("lists", "newsletter"),
+            ("topics", "events"),
+            ("digest_frequency", "weekly"),
+        ],
+    )
+    .await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - Publish two issues during the week
+    let mut second_issue = issue_about("events");
+    second_issue["title"] = serde_json::json!("More about events");
+    app.post_newsletters(issue_about("events")).await.error_for_status().unwrap();
+    app.post_newsletters(second_issue).await.error_for_status().unwrap();
+
+    let held = sqlx::query!(
+        r#"
+        SELECT COUNT(*) as "count!"
+        FROM issue_delivery_queue
+        WHERE
+            digest AND
+            next_attempt_at > now() AND
+            next_attempt_at <= now() + interval '1 week'
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch the queued deliveries.");
+    assert_eq!(held.count, 2);
+
+    // Act - Part 2 - The next week starts
+    sqlx::query!("UPDATE issue_delivery_queue SET next_attempt_at = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let email_request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["Subject"], "Newsletter digest: 2 new issues");
+    for title in ["All about events", "More about events"] {
+        assert!(body["HtmlBody"].as_str().unwrap().contains(title));
+        assert!(body["TextBody"].as_str().unwrap().contains(title));
+    }
+    let deliveries = sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM issue_deliveries"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(deliveries.count, 2);
+}
@@ -0,0 +1,246 @@
+use newsletter_service::problem::ProblemDetails;
+use wiremock::{Mock, ResponseTemplate, matchers::{method, path}};
+
+use crate::helpers::{spawn_app, subscribe_and_confirm, TestApp};
+
+async fn set_attributes(app: &TestApp, email: &str, attributes: serde_json::Value) {
+    sqlx::query!(
+        "UPDATE subscriptions SET attributes = $2 WHERE email = $1",
+        email,
+        attributes
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+async fn preview(app: &TestApp, filter: &str) -> i64 {
+    let response = app.post_segment_preview(serde_json::json!({ "filter": filter })).await;
+    assert_eq!(200, response.status().as_u16(), "{} was not previewed.", filter);
+    let preview: serde_json::Value = response.json().await.unwrap();
+    preview["subscribers"].as_i64().unwrap()
+}
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+#[tokio::test]
+async fn previews_count_the_matching_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    subscribe_and_confirm(&app, "pro@domain.com", "newsletter").await;
+    subscribe_and_confirm(&app, "free@domain.com", "newsletter").await;
+    set_attributes(&app, "pro@domain.com", serde_json::json!({"plan": "pro", "seats": 12})).await;
+    set_attributes(&app, "free@domain.com", serde_json::json!({"plan": "free", "seats": 1})).await;
+
+    // Act & Assert
+    assert_eq!(preview(&app, r#"plan = "pro""#).await, 1);
+    assert_eq!(preview(&app, r#"plan != "pro""#).await, 1);
+    assert_eq!(preview(&app, "seats >= 10").await, 1);
+    assert_eq!(preview(&app, r#"plan = "pro" or seats < 2"#).await, 2);
+    assert_eq!(preview(&app, "beta = true").await, 0);
+    assert_eq!(preview(&app, "not beta = true").await, 2);
+    assert_eq!(preview(&app, r#"subscribed_at >= "2000-01-01""#).await, 2);
+    assert_eq!(preview(&app, r#"subscribed_at < "2000-01-01""#).await, 0);
+}
+
+#[tokio::test]
+async fn opening_an_issue_is_recorded_for_engagement_filters() {
+    // Arrange
+    let app = spawn_app().await;
+    subscribe_and_confirm(&app, "ursula@domain.com", "newsletter").await;
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_newsletters(newsletter_request_body()).await.error_for_status().unwrap();
+    app.dispatch_all_pending_emails().await;
+    assert_eq!(preview(&app, "opened any of last 3 issues").await, 0);
+
+    let email_request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let html = body["HtmlBody"].as_str().unwrap();
+    let start = html.find("http://127.0.0.1/subscriptions/opens?").expect("No tracking pixel.");
+    let end = start + html[start..].find('"').unwrap();
+    let mut pixel = reqwest::Url::parse(&html[start..end]).unwrap();
+    pixel.set_port(Some(app.port)).unwrap();
+
+    // Act
+    let response = reqwest::get(pixel).await.unwrap();
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(response.headers()["Content-Type"], "image/gif");
+    assert_eq!(preview(&app, "opened any of last 3 issues").await, 1);
+    assert_eq!(preview(&app, "not opened any of last 3 issues").await, 0);
+}
+
+#[tokio::test]
+async fn invalid_tracking_tokens_are_not_recorded() {
+    // Arrange
+    let app = spawn_app().await;
+    subscribe_and_confirm(&app, "ursula@domain.com", "newsletter").await;
+
+    // Act
+    let response = reqwest::get(format!(
+        "{}/subscriptions/opens?issue={}&token=forged",
+        app.address,
+        uuid::Uuid::new_v4()
+    ))
+    .await
+    .unwrap();
+
+    // Assert - The image is served anyway
+    assert_eq!(200, response.status().as_u16());
+    let opens = sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM issue_opens"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(opens.count, 0);
+}
+
+#[tokio::test]
+async fn saved_segments_report_their_size() {
+    // Arrange
+    let app = spawn_app().await;
+    subscribe_and_confirm(&app, "pro@domain.com", "newsletter").await;
+    set_attributes(&app, "pro@domain.com", serde_json::json!({"plan": "pro"})).await;
+
+    // Act
+    let response = app
+        .post_segments(serde_json::json!({"name": "Pro users", "filter": r#"plan = "pro""#}))
+        .await;
+
+    // Assert
+    assert_eq!(201, response.status().as_u16());
+    let segment: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(segment["subscribers"], 1);
+
+    let segments: Vec<serde_json::Value> = reqwest::Client::new()
+        .get(format!("{}/admin/segments", app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0]["name"], "Pro users");
+    assert_eq!(segments[0]["subscribers"], 1);
+}
+
+#[tokio::test]
+async fn invalid_segments_are_rejected_with_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = [
+        (serde_json::json!({"name": "", "filter": "seats > 1"}), "name"),
+        (serde_json::json!({"name": "Pro users", "filter": r#"plan > "pro""#}), "filter"),
+        (serde_json::json!({"name": "Pro users", "filter": "plan = "}), "filter"),
+    ];
+
+    for (body, field) in test_cases {
+        // Act
+        let response = app.post_segments(body.clone()).await;
+
+        // Assert
+        assert_eq!(400, response.status().as_u16(), "{} was accepted.", body);
+        let problem: ProblemDetails = response.json().await.unwrap();
+        assert_eq!(problem.invalid_params[0].name, field);
+    }
+}
+
+#[tokio::test]
+async fn segment_names_are_unique() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = serde_json::json!({"name": "Pro users", "filter": r#"plan = "pro""#});
+    app.post_segments(body.clone()).await.error_for_status().unwrap();
+
+    // Act
+    let response = app.post_segments(body).await;
+
+    // Assert
+    assert_eq!(409, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn segments_are_only_managed_by_authenticated_users() {
+    // Arrange
+    let app = spawn_app().await;
+
+    for (method, endpoint) in [
+        (reqwest::Method::GET, "/admin/segments"),
+        (reqwest::Method::POST, "/admin/segments"),
+        (reqwest::Method::POST, "/admin/segments/preview"),
+    ] {
+        // Act
+        let response = reqwest::Client::new()
+            .request(method, format!("{}{}", app.address, endpoint))
+            .json(&serde_json::json!({"name": "Pro users", "filter": "seats > 1"}))
+            .send()
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(401, response.status().as_u16(), "{} was not protected.", endpoint);
+    }
+}
+
+#[tokio::test]
+async fn issues_published_to_a_segment_only_reach_its_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    subscribe_and_confirm(&app, "pro@domain.com", "newsletter").await;
+    subscribe_and_confirm(&app, "free@domain.com", "newsletter").await;
+    set_attributes(&app, "pro@domain.com", serde_json::json!({"plan": "pro"})).await;
+    app.post_segments(serde_json::json!({"name": "Pro users", "filter": r#"plan = "pro""#}))
+        .await
+        .error_for_status()
+        .unwrap();
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let mut body = newsletter_request_body();
+    body["segment"] = "Pro users".into();
+    let response = app.post_newsletters(body).await;
+    assert_eq!(202, response.status().as_u16());
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let email_request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let email: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(email["To"], "pro@domain.com");
+}
+
+#[tokio::test]
+async fn publishing_to_an_unknown_segment_is_rejected_with_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let mut body = newsletter_request_body();
+    body["segment"] = "Nobody".into();
+    let response = app.post_newsletters(body).await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+    let problem: ProblemDetails = response.json().await.unwrap();
+    assert_eq!(problem.invalid_params[0].name, "segment");
+}
@@ -1,4 +1,56 @@
-use crate::helpers::spawn_app;
+use chrono::{DateTime, Duration, Utc};
+use newsletter_service::{problem::ProblemDetails, sweeper::purge_expired_subscription_tokens};
+use uuid::Uuid;
+use wiremock::{Mock, ResponseTemplate, matchers::{path, method}};
+
+use crate::helpers::{spawn_app, TestApp};
+
+/// Stores a subscriber waiting to be confirmed on the default list.
+async fn insert_pending_subscriber(app: &TestApp, subscriber_id: Uuid) {
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at)
+        VALUES ($1, 'ursula_le_guin@gmail.com', 'le guin', now())
+        "#,
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"
+        INSERT INTO list_memberships (list_id, subscriber_id, status, subscribed_at)
+        SELECT list_id, $1, 'pending_confirmation', now() FROM lists WHERE slug = 'newsletter'
+        "#,
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+async fn membership_status(app: &TestApp, subscriber_id: Uuid) -> String {
+    sqlx::query!("SELECT status FROM list_memberships WHERE subscriber_id = $1", subscriber_id)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved membership")
+        .status
+}
+
+async fn insert_token(app: &TestApp, subscriber_id: Uuid, token: &str, expires_at: DateTime<Utc>) {
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_tokens (subscription_token, subscriber_id, list_id, expires_at)
+        SELECT $1, $2, list_id, $3 FROM lists WHERE slug = 'newsletter'
+        "#,
+        token,
+        subscriber_id,
+        expires_at
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
 
 #[tokio::test]
 pub async fn conformations_without_token_are_rejected_with_a_400() {
@@ -12,4 +64,264 @@ pub async fn conformations_without_token_are_rejected_with_a_400() {
 
     // Assert
     assert_eq!(response.status().as_u16(), 400);
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+pub async fn confirmations_with_an_unknown_token_are_rejected_with_a_401() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::get(&format!(
+        "{}/subscriptions/confirm?subscription_token=unknown-token",
+        app.address
+    ))
+    .await
+    .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+pub async fn confirmations_with_a_known_token_confirm_the_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    let subscriber_id = Uuid::new_v4();
+
+    insert_pending_subscriber(&app, subscriber_id).await;
+
+    insert_token(&app, subscriber_id, "a-known-token", Utc::now() + Duration::hours(1)).await;
+
+    // Act
+    let response = reqwest::get(&format!(
+        "{}/subscriptions/confirm?subscription_token=a-known-token",
+        app.address
+    ))
+    .await
+    .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+
+    assert_eq!(membership_status(&app, subscriber_id).await, "confirmed");
+}
+
+#[tokio::test]
+pub async fn the_link_returned_by_subscribe_confirms_a_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+
+    // Act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+
+    let saved = sqlx::query!(
+        r#"
+        SELECT s.email as "email!", s.name as "name!", m.status as "status!"
+        FROM subscriptions s
+        JOIN list_memberships m ON m.subscriber_id = s.id
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch saved subscription");
+
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+    assert_eq!(saved.name, "le guin");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+pub async fn confirmed_subscribers_get_a_welcome_email() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+
+    // Act
+    reqwest::get(confirmation_links.html).await.unwrap().error_for_status().unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let email_request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let email: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(email["To"], "ursula_le_guin@gmail.com");
+    assert!(email["HtmlBody"].as_str().unwrap().contains("<p>Hi le guin,</p>"));
+    // There is no text template for this email, the text is generated from the HTML
+    let text = email["TextBody"].as_str().unwrap();
+    assert!(text.starts_with("Hi le guin,\n\n"));
+    assert!(text.contains("preferences (http://127.0.0.1/subscriptions/preferences?token="));
+}
+
+#[tokio::test]
+pub async fn confirmation_and_welcome_emails_record_the_provider_that_sent_them() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+
+    // Act
+    reqwest::get(confirmation_links.html).await.unwrap().error_for_status().unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let sent = sqlx::query!("SELECT template, recipient, provider FROM sent_emails ORDER BY sent_at")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch the sent emails");
+    let sent: Vec<_> = sent
+        .iter()
+        .map(|email| (email.template.as_str(), email.recipient.as_str(), email.provider.as_str()))
+        .collect();
+    assert_eq!(
+        sent,
+        vec![
+            ("confirmation", "ursula_le_guin@gmail.com", "postmark"),
+            ("welcome", "ursula_le_guin@gmail.com", "postmark")
+        ]
+    );
+}
+
+#[tokio::test]
+pub async fn confirmations_succeed_even_if_the_welcome_email_cannot_be_sent() {
+    // Arrange
+    let app = spawn_app().await;
+    let subscriber_id = Uuid::new_v4();
+    insert_pending_subscriber(&app, subscriber_id).await;
+    insert_token(&app, subscriber_id, "token", Utc::now() + Duration::hours(1)).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = reqwest::get(format!(
+        "{}/subscriptions/confirm?subscription_token=token",
+        app.address
+    ))
+    .await
+    .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(membership_status(&app, subscriber_id).await, "confirmed");
+
+    // The welcome email waits in the queue until the provider is back
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+pub async fn confirmations_with_an_expired_token_are_rejected_with_a_410() {
+    // Arrange
+    let app = spawn_app().await;
+    let subscriber_id = Uuid::new_v4();
+    insert_pending_subscriber(&app, subscriber_id).await;
+    insert_token(&app, subscriber_id, "an-expired-token", Utc::now() - Duration::hours(1)).await;
+
+    // Act
+    let response = reqwest::get(&format!(
+        "{}/subscriptions/confirm?subscription_token=an-expired-token",
+        app.address
+    ))
+    .await
+    .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 410);
+    let problem: ProblemDetails = response.json().await.unwrap();
+    assert_eq!(problem.detail.as_deref(), Some("The subscription token has expired."));
+
+    assert_eq!(membership_status(&app, subscriber_id).await, "pending_confirmation");
+}
+
+#[tokio::test]
+pub async fn a_token_can_only_be_used_once() {
+    // Arrange
+    let app = spawn_app().await;
+    let subscriber_id = Uuid::new_v4();
+    insert_pending_subscriber(&app, subscriber_id).await;
+    insert_token(&app, subscriber_id, "a-known-token", Utc::now() + Duration::hours(1)).await;
+    let confirmation_link = format!(
+        "{}/subscriptions/confirm?subscription_token=a-known-token",
+        app.address
+    );
+
+    // Act
+    let first_response = reqwest::get(&confirmation_link).await.unwrap();
+    let second_response = reqwest::get(&confirmation_link).await.unwrap();
+
+    // Assert
+    assert_eq!(first_response.status().as_u16(), 200);
+    assert_eq!(second_response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+pub async fn the_sweeper_purges_expired_tokens_only() {
+    // Arrange
+    let app = spawn_app().await;
+    let subscriber_id = Uuid::new_v4();
+    insert_pending_subscriber(&app, subscriber_id).await;
+    insert_token(&app, subscriber_id, "an-expired-token", Utc::now() - Duration::hours(1)).await;
+    insert_token(&app, subscriber_id, "a-valid-token", Utc::now() + Duration::hours(1)).await;
+
+    // Act
+    let purged = purge_expired_subscription_tokens(&app.db_pool).await.unwrap();
+
+    // Assert
+    assert_eq!(purged, 1);
+
+    let remaining = sqlx::query!("SELECT subscription_token FROM subscription_tokens")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].subscription_token, "a-valid-token");
+}
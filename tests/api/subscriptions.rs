@@ -1,3 +1,4 @@
+use newsletter_service::{email_queue::try_send_queued_email, problem::ProblemDetails};
 use wiremock::{Mock, ResponseTemplate, matchers::{path, method}};
 
 use crate::helpers::spawn_app;
@@ -47,6 +48,313 @@ async fn subscribe_returns_a_400_when_fields_are_present_but_invalid() {
     );
 }
 
+#[tokio::test]
+async fn subscribe_reports_which_field_is_invalid() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        ("name=&email=ursula_le_guin%40gmail.com", "name"),
+        ("name=Ursula&email=", "email"),
+        ("name=Ursula&email=definitely-not-an-email", "email")
+    ];
+
+    for (invalid_body, invalid_field) in test_cases {
+        // Act
+        let response = app.post_subscriptions(invalid_body.into()).await;
+
+        // Assert
+        assert_eq!(400, response.status().as_u16());
+        assert_eq!(
+            "application/problem+json",
+            response.headers()["Content-Type"]
+        );
+        let problem: ProblemDetails = response.json().await.unwrap();
+        assert_eq!(problem.status, 400);
+        assert_eq!(problem.invalid_params.len(), 1);
+        assert_eq!(
+            problem.invalid_params[0].name,
+            invalid_field,
+            "The API did not report the {} as invalid for {}.",
+            invalid_field,
+            invalid_body
+        );
+    }
+}
+
+#[tokio::test]
+async fn subscribe_returns_200_for_valid_json_data() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = serde_json::json!({"name": "le guin", "email": "ursula_le_guin@gmail.com"});
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_subscriptions_json(body).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+
+    let saved = sqlx::query!("SELECT email, name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription");
+
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+    assert_eq!(saved.name, "le guin");
+}
+
+#[tokio::test]
+async fn subscribe_reports_which_json_field_is_invalid() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (serde_json::json!({"name": "", "email": "ursula_le_guin@gmail.com"}), "name"),
+        (serde_json::json!({"name": "Ursula", "email": ""}), "email"),
+        (serde_json::json!({"name": "Ursula", "email": "definitely-not-an-email"}), "email"),
+        (serde_json::json!({"name": "Ursula", "email": "ursula_le_guin@gmail.com", "list": "rust"}), "list")
+    ];
+
+    for (invalid_body, invalid_field) in test_cases {
+        // Act
+        let response = app.post_subscriptions_json(invalid_body.clone()).await;
+
+        // Assert
+        assert_eq!(400, response.status().as_u16());
+        assert_eq!(
+            "application/problem+json",
+            response.headers()["Content-Type"]
+        );
+        let problem: ProblemDetails = response.json().await.unwrap();
+        assert_eq!(
+            problem.invalid_params[0].name,
+            invalid_field,
+            "The API did not report the {} as invalid for {}.",
+            invalid_field,
+            invalid_body
+        );
+    }
+}
+
+#[tokio::test]
+async fn subscribe_returns_problem_details_for_malformed_json() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        ("{\"name\": \"le guin\"", "truncated"),
+        ("{\"name\": \"le guin\"}", "missing the email"),
+        ("{\"name\": 42, \"email\": \"ursula_le_guin@gmail.com\"}", "a number as name"),
+        ("name=le%20guin&email=ursula_le_guin%40gmail.com", "form-encoded")
+    ];
+
+    for (invalid_body, description) in test_cases {
+        // Act
+        let response = reqwest::Client::new()
+            .post(format!("{}/subscriptions", &app.address))
+            .header("Content-Type", "application/json")
+            .body(invalid_body)
+            .send()
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the JSON payload was {}.",
+            description
+        );
+        assert_eq!(
+            "application/problem+json",
+            response.headers()["Content-Type"]
+        );
+    }
+}
+
+#[tokio::test]
+async fn subscribe_succeeds_even_if_the_confirmation_email_cannot_be_sent() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(u64::try_from(app.email_queue.max_attempts).unwrap())
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let dead_letter = sqlx::query!("SELECT template, n_attempts FROM email_dead_letters")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the dead letter.");
+    assert_eq!(dead_letter.template, "confirmation");
+    assert_eq!(dead_letter.n_attempts, app.email_queue.max_attempts);
+}
+
+#[tokio::test]
+async fn throttled_confirmation_emails_do_not_use_up_their_attempts() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    // Throttled as many times as an email can fail, then accepted
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+        .up_to_n_times(app.email_queue.max_attempts as u64)
+        .expect(app.email_queue.max_attempts as u64)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_subscriptions(body.into()).await.error_for_status().unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let dead_letters: serde_json::Value = app.get_email_dead_letters().await.json().await.unwrap();
+    assert!(dead_letters.as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn confirmation_emails_in_the_dead_letters_can_be_redriven() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    let failing_mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into()).await.error_for_status().unwrap();
+    app.dispatch_all_pending_emails().await;
+    drop(failing_mock_guard);
+
+    let dead_letters: serde_json::Value = app.get_email_dead_letters().await.json().await.unwrap();
+    let dead_letters = dead_letters.as_array().unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0]["template"], "confirmation");
+    assert_eq!(dead_letters[0]["subscriber_email"], "ursula_le_guin@gmail.com");
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_redrive_email_dead_letters(serde_json::json!({"subscriber_email": "ursula_le_guin@gmail.com"}))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let outcome: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(outcome["redriven"], 1);
+    assert_eq!(outcome["already_queued"], 0);
+
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    app.get_confirmation_links(&email_request);
+    let dead_letters: serde_json::Value = app.get_email_dead_letters().await.json().await.unwrap();
+    assert!(dead_letters.as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn email_dead_letters_are_only_available_to_authenticated_users() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let list_response = reqwest::get(format!("{}/admin/email_dead_letters", &app.address))
+        .await
+        .unwrap();
+    let redrive_response = reqwest::Client::new()
+        .post(format!("{}/admin/email_dead_letters/redrive", &app.address))
+        .json(&serde_json::json!({}))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(list_response.status().as_u16(), 401);
+    assert_eq!(redrive_response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn confirmation_emails_are_retried_when_the_database_fails() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into()).await.error_for_status().unwrap();
+    // Reading the subscriber fails until the column is back
+    sqlx::query("ALTER TABLE subscriptions RENAME COLUMN name TO full_name")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act - Part 1 - The database fails
+    try_send_queued_email(
+        &app.db_pool,
+        &app.email_client,
+        &app.email_templates,
+        &app.email_queue,
+        &app.base_url,
+        &app.token_signer
+    )
+    .await
+    .unwrap();
+
+    // Assert - Part 1
+    let queued = sqlx::query!("SELECT n_attempts FROM email_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("The email is no longer queued.");
+    assert_eq!(queued.n_attempts, 1);
+
+    // Act - Part 2 - The database is back
+    sqlx::query("ALTER TABLE subscriptions RENAME COLUMN full_name TO name")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert - Part 2
+    let dead_letters = sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM email_dead_letters"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(dead_letters.count, 0);
+}
+
 #[tokio::test]
 async fn subscribe_returns_a_400_when_data_is_missing() {
     // Arrange
@@ -88,6 +396,7 @@ async fn subscribe_sends_a_confirmation_email_for_valid_data() {
     
     // Act
     let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     // Assert
     assert_eq!(200, response.status().as_u16());
@@ -106,27 +415,16 @@ async fn subscriber_sends_a_confirmation_email_with_a_link() {
         .await;
     
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     // Assert
     // Get the first intercepted request
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
 
-    // Parse the body as JSON, starting from raw bytes
-    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
-
-    let get_link = |s: &str| {
-        let links: Vec<_> = linkify::LinkFinder::new()
-            .links(s)
-            .filter(|l| *l.kind() == linkify::LinkKind::Url)
-            .collect();
-            assert_eq!(links.len(), 1);
-        links[0].as_str().to_owned()
-    };
+    let confirmation_links = app.get_confirmation_links(email_request);
 
-    let html_link = get_link(&body["HtmlBody"].as_str().unwrap());
-    let text_link = get_link(&body["TextBody"].as_str().unwrap());
-
-    assert_eq!(html_link, text_link);
+    // The two links should be identical
+    assert_eq!(confirmation_links.html, confirmation_links.plain_text);
 }
 
 #[tokio::test]
@@ -148,3 +446,153 @@ async fn subscribe_returns_a_200_for_valid_form_data() {
     // Assert
     assert_eq!(200, response.status().as_u16());
 }
+
+
+#[tokio::test]
+async fn subscribe_persists_a_subscription_token_for_the_new_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let saved = sqlx::query!(
+        r#"
+        SELECT subscription_token as "subscription_token!", list_memberships.status as "status!"
+        FROM subscription_tokens
+        JOIN list_memberships USING (subscriber_id, list_id)
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch saved subscription token");
+
+    assert_eq!(saved.status, "pending_confirmation");
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    let token_in_link = confirmation_links
+        .html
+        .query_pairs()
+        .find(|(key, _)| key == "subscription_token")
+        .map(|(_, value)| value.into_owned());
+
+    assert_eq!(token_in_link, Some(saved.subscription_token));
+}
+
+#[tokio::test]
+async fn subscribing_twice_while_pending_sends_a_new_confirmation_email() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        // Two confirmation emails, then the welcome email
+        .expect(3)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let first_response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let second_response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(200, first_response.status().as_u16());
+    assert_eq!(200, second_response.status().as_u16());
+
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let first_links = app.get_confirmation_links(&email_requests[0]);
+    let second_links = app.get_confirmation_links(&email_requests[1]);
+    assert_ne!(first_links.html, second_links.html);
+
+    // Only the latest link is still valid
+    let response = reqwest::get(first_links.html).await.unwrap();
+    assert_eq!(401, response.status().as_u16());
+
+    let response = reqwest::get(second_links.html).await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn subscribing_again_while_pending_corrects_the_name() {
+    // Arrange
+    let app = spawn_app().await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20gwin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+
+    let saved = sqlx::query!("SELECT name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.name, "le guin");
+}
+
+#[tokio::test]
+async fn subscribing_again_once_confirmed_returns_200_without_sending_an_email() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        // The confirmation email and the welcome email
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+
+    let saved = sqlx::query!("SELECT status FROM list_memberships")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions");
+
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].status, "confirmed");
+}
@@ -0,0 +1,277 @@
+use newsletter_service::signed_token::TokenPurpose;
+use wiremock::{Mock, ResponseTemplate, matchers::{any, path, method}};
+
+use crate::helpers::{create_confirmed_subscriber, spawn_app, TestApp};
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+/// Publish an issue to the confirmed subscriber and return the unsubscribe
+/// link they received with it.
+async fn deliver_an_issue(app: &TestApp) -> reqwest::Url {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+
+    app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    app.get_unsubscribe_link(&email_request)
+}
+
+/// Unsubscribes the way mailbox providers do (RFC 8058).
+async fn one_click_unsubscribe(unsubscribe_link: reqwest::Url) {
+    reqwest::Client::new()
+        .post(unsubscribe_link)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("List-Unsubscribe=One-Click")
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+async fn subscriber_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM list_memberships")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription")
+        .status
+}
+
+#[tokio::test]
+async fn issues_carry_one_click_unsubscribe_headers() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act
+    deliver_an_issue(&app).await;
+
+    // Assert
+    let email_request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let headers = body["Headers"].as_array().unwrap();
+    assert!(headers.iter().any(|h| {
+        h["Name"] == "List-Unsubscribe-Post" && h["Value"] == "List-Unsubscribe=One-Click"
+    }));
+    let unsubscribe_link = app.get_unsubscribe_link(&email_request);
+    assert_eq!(unsubscribe_link.path(), "/subscriptions/unsubscribe");
+    assert!(body["TextBody"].as_str().unwrap().contains("Unsubscribe: "));
+    assert!(body["TextBody"].as_str().unwrap().contains("/subscriptions/preferences?token="));
+}
+
+#[tokio::test]
+async fn one_click_unsubscribe_marks_the_subscriber_as_unsubscribed() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let unsubscribe_link = deliver_an_issue(&app).await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(unsubscribe_link)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("List-Unsubscribe=One-Click")
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(subscriber_status(&app).await, "unsubscribed");
+}
+
+#[tokio::test]
+async fn unsubscribed_subscribers_do_not_receive_further_issues() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let unsubscribe_link = deliver_an_issue(&app).await;
+    one_click_unsubscribe(unsubscribe_link).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+
+    // Assert
+    assert_eq!(202, response.status().as_u16());
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn unsubscribing_sends_a_confirmation_once() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let unsubscribe_link = deliver_an_issue(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    for _ in 0..2 {
+        reqwest::Client::new()
+            .post(unsubscribe_link.clone())
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+    }
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let email_request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let email: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(email["To"], "ursula_le_guin@gmail.com");
+    assert_eq!(email["Subject"], "You have been unsubscribed from Newsletter");
+    assert!(email["TextBody"].as_str().unwrap().starts_with("You will not receive Newsletter anymore."));
+}
+
+#[tokio::test]
+async fn one_click_unsubscribes_send_no_confirmation() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let unsubscribe_link = deliver_an_issue(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    one_click_unsubscribe(unsubscribe_link).await;
+
+    // Assert
+    assert_eq!(subscriber_status(&app).await, "unsubscribed");
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn unsubscribing_twice_is_not_an_error() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let unsubscribe_link = deliver_an_issue(&app).await;
+
+    for _ in 0..2 {
+        // Act
+        let response = reqwest::Client::new()
+            .post(unsubscribe_link.clone())
+            .send()
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(200, response.status().as_u16());
+    }
+}
+
+#[tokio::test]
+async fn following_the_link_asks_for_confirmation_without_unsubscribing() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let unsubscribe_link = deliver_an_issue(&app).await;
+
+    // Act
+    let response = reqwest::get(unsubscribe_link).await.unwrap();
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains(r#"<form action="/subscriptions/unsubscribe?list=newsletter&amp;token="#));
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn forged_unsubscribe_tokens_are_rejected_with_a_401() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let token = app.token_signer.sign(TokenPurpose::UnsubscribeFromAll, "ursula_le_guin@gmail.com");
+    let forged_token = format!("{}x", token);
+
+    for method in [reqwest::Method::GET, reqwest::Method::POST] {
+        // Act
+        let response = reqwest::Client::new()
+            .request(
+                method.clone(),
+                format!("{}/subscriptions/unsubscribe?token={}", app.address, forged_token)
+            )
+            .send()
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(401, response.status().as_u16(), "{} was not rejected.", method);
+    }
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn unsubscribed_users_can_subscribe_again() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let token = app.token_signer.sign(TokenPurpose::UnsubscribeFromAll, "ursula_le_guin@gmail.com");
+    let unsubscribe_link = format!("{}/subscriptions/unsubscribe?token={}", app.address, token);
+    one_click_unsubscribe(unsubscribe_link.parse().unwrap()).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        // The confirmation email and the welcome email
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - Subscribe again
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    app.post_subscriptions(body.into()).await.error_for_status().unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Act - Part 2 - Confirm again
+    let email_request = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    reqwest::get(confirmation_links.html).await.unwrap().error_for_status().unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+    let membership = sqlx::query!("SELECT unsubscribed_at FROM list_memberships")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription");
+    assert!(membership.unsubscribed_at.is_none());
+}